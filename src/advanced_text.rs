@@ -4,21 +4,34 @@
 //! management for production-quality text rendering with ligature support.
 
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use glyphon::{Attrs, Buffer, FontSystem, Metrics, Shaping};
-use peniko::{BrushRef, StyleRef};
+use cosmyc_text::{Align, fontdb};
+use glyphon::{Attrs, Buffer, Family, FontSystem, Metrics, Shaping, Weight};
+use peniko::{Blob, BrushRef, Font, StyleRef};
 use vello_encoding::{Encoding, Glyph, GlyphRun, Patch};
 
+use crate::gradient;
+use crate::text::{TextAlign, TextStyle};
+
 /// Advanced text renderer using actual codeskew components
-#[allow(dead_code)] // Complete implementation awaiting integration into vello text pipeline
 pub struct AdvancedTextRenderer {
     font_system: FontSystem,
     ligature_helper: LigatureHelper,
     shape_cache: LockFreeShapeCache<2048>,
+    /// Fonts resolved from the font system, keyed by their database id.
+    fonts: HashMap<fontdb::ID, Font>,
     frame_counter: u64,
 }
 
+/// A run of positioned glyphs which share a single font and size.
+pub struct ShapedGlyphRun {
+    pub font: Font,
+    pub font_size: f32,
+    pub glyphs: Vec<Glyph>,
+}
+
 /// Real LigatureHelper extracted from codeskew
 #[allow(dead_code)] // Complete implementation awaiting integration into vello text pipeline
 #[derive(Debug, Clone)]
@@ -307,17 +320,23 @@ impl<const SIZE: usize> LockFreeShapeCache<SIZE> {
     }
 
     /// Get or create a shaped buffer with lock-free operation
+    ///
+    /// `key` identifies the shaped result, and must account for everything which
+    /// affects layout, see [`Self::shape_key`].
     #[inline(always)]
     pub fn get_or_create(
         &mut self,
+        key: u64,
         text: &str,
         font_system: &mut FontSystem,
         metrics: Metrics,
         attrs: &Attrs,
         shaping: Shaping,
+        max_width: Option<f32>,
+        align: Option<Align>,
         frame_count: u64,
     ) -> Result<(usize, &Buffer), TextRenderingError> {
-        let text_hash = Self::hash_text_fnv1a_optimized(text);
+        let text_hash = key;
 
         // Fast lookup using optimized linear search
         if let Some(index) = self.find_cached_entry(text_hash) {
@@ -346,7 +365,13 @@ impl<const SIZE: usize> LockFreeShapeCache<SIZE> {
 
         // Create new buffer with error handling
         let mut buffer = Buffer::new(font_system, metrics);
+        buffer.set_size(font_system, max_width, None);
         buffer.set_text(font_system, text, attrs, shaping);
+        if align.is_some() {
+            for line in &mut buffer.lines {
+                line.set_align(align);
+            }
+        }
 
         // Shape the buffer and handle potential failures
         buffer.shape_until_scroll(font_system, false);
@@ -454,6 +479,29 @@ impl<const SIZE: usize> LockFreeShapeCache<SIZE> {
         hash
     }
 
+    /// Combine the text with the font family and the numeric layout parameters
+    /// into a single cache key.
+    #[inline]
+    pub fn shape_key(text: &str, family: &str, params: &[u32]) -> u64 {
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut hash = Self::hash_text_fnv1a_optimized(text);
+        // Separate the text from the family name, so that moving bytes between
+        // the two doesn't produce the same key.
+        let family_bytes = family.as_bytes();
+        let param_bytes = params.iter().flat_map(|word| word.to_le_bytes());
+        for byte in [0xff]
+            .into_iter()
+            .chain(family_bytes.iter().copied())
+            .chain([0xff])
+            .chain(param_bytes)
+        {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
     /// Get comprehensive cache statistics
     #[inline(always)]
     pub fn stats(&self) -> CacheStats {
@@ -535,15 +583,18 @@ impl AdvancedTextRenderer {
             return Err(TextRenderingError::FontSystemCreationFailed);
         }
 
-        let ligature_helper = LigatureHelper::with_programming_defaults();
-        let shape_cache = LockFreeShapeCache::new();
+        Ok(Self::with_font_system(font_system))
+    }
 
-        Ok(Self {
+    /// Create a renderer which lays out text with the fonts of `font_system`.
+    fn with_font_system(font_system: FontSystem) -> Self {
+        Self {
             font_system,
-            ligature_helper,
-            shape_cache,
+            ligature_helper: LigatureHelper::with_programming_defaults(),
+            shape_cache: LockFreeShapeCache::new(),
+            fonts: HashMap::new(),
             frame_counter: 0,
-        })
+        }
     }
 
     /// Shape text with advanced ligature support using REAL LockFreeShapeCache
@@ -582,12 +633,20 @@ impl AdvancedTextRenderer {
                 cache.evict_lru_if_needed(0.8, self.frame_counter);
             }
 
+            let key = LockFreeShapeCache::<2048>::shape_key(
+                text,
+                "monospace",
+                &[font_size.to_bits(), shaping as u32],
+            );
             cache.get_or_create(
+                key,
                 text,
                 &mut self.font_system,
                 metrics,
                 &attrs,
                 shaping,
+                None,
+                None,
                 self.frame_counter,
             )?
         };
@@ -622,7 +681,7 @@ impl AdvancedTextRenderer {
             let index = encoding.resources.glyph_runs.len();
             encoding.resources.glyph_runs.push(run.clone());
             encoding.resources.patches.push(Patch::GlyphRun { index });
-            encoding.encode_brush(brush, brush_alpha);
+            gradient::encode_brush(encoding, brush, brush_alpha, None);
             encoding.force_next_transform_and_style();
         }
 
@@ -742,7 +801,106 @@ impl AdvancedTextRenderer {
     pub fn validate_text(&self, text: &str) -> bool {
         !text.is_empty() &&
         text.len() <= 8192 && // Reasonable text length limit
-        text.chars().all(|c| !c.is_control() || c == '\n' || c == '\t')
+        text.chars().all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+    }
+
+    /// Shape `text` with the given style and lay it out into positioned glyph runs.
+    ///
+    /// Glyph positions are relative to the top-left corner of the text box, with
+    /// each line placed on its baseline. Consecutive glyphs which share a font and
+    /// size are grouped into a single run, so font fallback produces multiple runs.
+    pub fn layout_text(
+        &mut self,
+        text: &str,
+        style: &TextStyle,
+    ) -> Result<Vec<ShapedGlyphRun>, TextRenderingError> {
+        if !self.validate_text(text) {
+            return Err(TextRenderingError::BufferCreationFailed);
+        }
+        if !(style.font_size.is_finite() && style.font_size > 0.0) {
+            return Err(TextRenderingError::BufferCreationFailed);
+        }
+
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+
+        let metrics = Metrics::new(style.font_size, style.font_size * style.line_height);
+        let family_name = style.font_family.as_deref();
+        let family = family_name.map_or(Family::SansSerif, Family::Name);
+        let attrs = Attrs::new()
+            .family(family)
+            .weight(Weight(style.font_weight));
+        let shaping = self
+            .ligature_helper
+            .shaping_for_font(family_name.unwrap_or_default());
+        let align = match style.align {
+            TextAlign::Left => Align::Left,
+            TextAlign::Center => Align::Center,
+            TextAlign::Right => Align::Right,
+            TextAlign::Justify => Align::Justified,
+        };
+        let max_width = style.max_width.filter(|width| width.is_finite());
+        let key = LockFreeShapeCache::<2048>::shape_key(
+            text,
+            family_name.unwrap_or("sans-serif"),
+            &[
+                style.font_size.to_bits(),
+                style.line_height.to_bits(),
+                style.font_weight as u32,
+                max_width.map_or(u32::MAX, f32::to_bits),
+                style.align as u32,
+                shaping as u32,
+            ],
+        );
+
+        if self.frame_counter % 60 == 0 {
+            self.shape_cache
+                .evict_lru_if_needed(0.8, self.frame_counter);
+        }
+        let (_, buffer) = self.shape_cache.get_or_create(
+            key,
+            text,
+            &mut self.font_system,
+            metrics,
+            &attrs,
+            shaping,
+            max_width,
+            Some(align),
+            self.frame_counter,
+        )?;
+
+        let mut runs = Vec::new();
+        for layout_run in buffer.layout_runs() {
+            let chunks = layout_run
+                .glyphs
+                .chunk_by(|a, b| a.font_id == b.font_id && a.font_size == b.font_size);
+            for chunk in chunks {
+                let first = &chunk[0];
+                let Some(font) = font_for_id(&mut self.font_system, &mut self.fonts, first.font_id)
+                else {
+                    return Err(TextRenderingError::GlyphExtractionFailed);
+                };
+                let mut glyphs = Vec::with_capacity(chunk.len());
+                for glyph in chunk {
+                    let x = glyph.x + glyph.font_size * glyph.x_offset;
+                    let y = layout_run.line_y + glyph.y - glyph.font_size * glyph.y_offset;
+                    if !x.is_finite() || !y.is_finite() {
+                        return Err(TextRenderingError::GlyphExtractionFailed);
+                    }
+                    glyphs.push(Glyph {
+                        id: glyph.glyph_id as u32,
+                        x,
+                        y,
+                    });
+                }
+                runs.push(ShapedGlyphRun {
+                    font,
+                    font_size: first.font_size,
+                    glyphs,
+                });
+            }
+        }
+
+        Ok(runs)
     }
 
     /// Get current frame counter for debugging
@@ -837,7 +995,7 @@ impl AdvancedTextRenderer {
             let index = encoding.resources.glyph_runs.len();
             encoding.resources.glyph_runs.push(run.clone());
             encoding.resources.patches.push(Patch::GlyphRun { index });
-            encoding.encode_brush(brush, brush_alpha);
+            gradient::encode_brush(encoding, brush, brush_alpha, None);
             encoding.force_next_transform_and_style();
         }
 
@@ -848,11 +1006,184 @@ impl AdvancedTextRenderer {
 impl Default for AdvancedTextRenderer {
     fn default() -> Self {
         // Never panic - use fallback configuration if creation fails
-        Self::new().unwrap_or_else(|_| Self {
-            font_system: FontSystem::new(),
-            ligature_helper: LigatureHelper::with_programming_defaults(),
-            shape_cache: LockFreeShapeCache::new(),
-            frame_counter: 0,
-        })
+        Self::new().unwrap_or_else(|_| Self::with_font_system(FontSystem::new()))
+    }
+}
+
+/// Look up the font with the given id, loading its data from the font system
+/// the first time it is used.
+fn font_for_id(
+    font_system: &mut FontSystem,
+    fonts: &mut HashMap<fontdb::ID, Font>,
+    id: fontdb::ID,
+) -> Option<Font> {
+    if let Some(font) = fonts.get(&id) {
+        return Some(font.clone());
+    }
+    let index = font_system.db().face(id)?.index;
+    let data = font_system.get_font(id)?.data().to_vec();
+    let font = Font::new(Blob::new(Arc::new(data)), index);
+    fonts.insert(id, font.clone());
+    Some(font)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Fira Mono, whose glyphs all have the same advance, see `tests/assets/FiraMono-LICENSE`.
+    const FONT: &[u8] = include_bytes!("../tests/assets/FiraMono-Medium.ttf");
+
+    /// A renderer whose only font is [`FONT`], which is also used as the sans-serif family,
+    /// so that layouts don't depend on the fonts installed.
+    pub(crate) fn renderer() -> AdvancedTextRenderer {
+        let mut db = fontdb::Database::new();
+        db.load_font_data(FONT.to_vec());
+        db.set_sans_serif_family("Fira Mono");
+        AdvancedTextRenderer::with_font_system(FontSystem::new_with_locale_and_db(
+            "en-US".to_string(),
+            db,
+        ))
+    }
+
+    fn style(max_width: Option<f32>, align: TextAlign) -> TextStyle {
+        TextStyle {
+            max_width,
+            align,
+            ..TextStyle::new(10.0)
+        }
+    }
+
+    /// The glyphs of the layout of `text`, in order.
+    fn layout(renderer: &mut AdvancedTextRenderer, text: &str, style: &TextStyle) -> Vec<Glyph> {
+        let runs = renderer.layout_text(text, style).unwrap();
+        runs.into_iter().flat_map(|run| run.glyphs).collect()
+    }
+
+    /// The glyphs of `glyphs` on each baseline, from top to bottom.
+    fn lines(glyphs: &[Glyph]) -> Vec<Vec<Glyph>> {
+        glyphs
+            .chunk_by(|a, b| a.y == b.y)
+            .map(|line| line.to_vec())
+            .collect()
+    }
+
+    /// The advance of every glyph of [`FONT`] at the size of [`style`].
+    fn advance(renderer: &mut AdvancedTextRenderer) -> f32 {
+        let glyphs = layout(renderer, "ab", &style(None, TextAlign::Left));
+        glyphs[1].x - glyphs[0].x
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn text_is_laid_out_in_the_font() {
+        let mut renderer = renderer();
+        let runs = renderer
+            .layout_text("abc", &style(None, TextAlign::Left))
+            .unwrap();
+        let [run] = runs.as_slice() else {
+            panic!("expected one glyph run");
+        };
+        assert_eq!(run.font.data.data(), FONT);
+        assert_eq!(run.font_size, 10.0);
+        let advance = advance(&mut renderer);
+        assert!(advance > 0.0);
+        for (ix, glyph) in run.glyphs.iter().enumerate() {
+            assert_near(glyph.x, ix as f32 * advance);
+            // Glyphs are placed on the baseline of the first line.
+            assert!(glyph.y > 0.0 && glyph.y < 12.0);
+        }
+    }
+
+    #[test]
+    fn lines_are_broken_at_the_width_limit() {
+        let mut renderer = renderer();
+        let advance = advance(&mut renderer);
+        let max_width = 8.5 * advance;
+        let glyphs = layout(
+            &mut renderer,
+            "aaa bbb ccc",
+            &style(Some(max_width), TextAlign::Left),
+        );
+        let [first, second] = lines(&glyphs).try_into().unwrap();
+        // Baselines are a line height apart.
+        assert_near(second[0].y - first[0].y, 12.0);
+        assert!(first.iter().all(|glyph| glyph.x < max_width));
+        assert_eq!(second.len(), 3);
+        assert_near(second[0].x, 0.0);
+        // Without a limit, lines are only broken at explicit line breaks.
+        let unbounded = style(None, TextAlign::Left);
+        assert_eq!(
+            lines(&layout(&mut renderer, "aaa bbb ccc", &unbounded)).len(),
+            1
+        );
+        assert_eq!(
+            lines(&layout(&mut renderer, "aaa\nbbb", &unbounded)).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn lines_are_aligned_within_the_width_limit() {
+        let mut renderer = renderer();
+        let advance = advance(&mut renderer);
+        let max_width = 20.0 * advance;
+        let first_x = |renderer: &mut AdvancedTextRenderer, align| {
+            layout(renderer, "abcd", &style(Some(max_width), align))[0].x
+        };
+        assert_near(first_x(&mut renderer, TextAlign::Left), 0.0);
+        assert_near(first_x(&mut renderer, TextAlign::Center), 8.0 * advance);
+        assert_near(first_x(&mut renderer, TextAlign::Right), 16.0 * advance);
+        // The last line of a paragraph isn't justified.
+        assert_near(first_x(&mut renderer, TextAlign::Justify), 0.0);
+    }
+
+    #[test]
+    fn wrapped_lines_are_justified() {
+        let mut renderer = renderer();
+        let advance = advance(&mut renderer);
+        let max_width = 9.0 * advance;
+        let mut lines_for = |align| {
+            let glyphs = layout(&mut renderer, "aa bb cc dd", &style(Some(max_width), align));
+            lines(&glyphs)
+        };
+        let left = lines_for(TextAlign::Left);
+        let justified = lines_for(TextAlign::Justify);
+        assert_eq!((left.len(), justified.len()), (2, 2));
+        // The spaces of the first line are stretched, moving its last word to the right.
+        let last_word = |line: &[Glyph]| line[6].x;
+        assert_near(last_word(&left[0]), 6.0 * advance);
+        assert!(last_word(&justified[0]) > last_word(&left[0]) + 0.5 * advance);
+        assert!(last_word(&justified[0]) + 2.0 * advance <= max_width + 1e-3);
+        assert_near(justified[1][0].x, 0.0);
+    }
+
+    #[test]
+    fn empty_and_invalid_text_is_rejected() {
+        let mut renderer = renderer();
+        let style = style(None, TextAlign::Left);
+        assert!(renderer.layout_text("", &style).is_err());
+        assert!(renderer.layout_text("a\u{7}b", &style).is_err());
+        for font_size in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let style = TextStyle::new(font_size);
+            assert!(renderer.layout_text("a", &style).is_err());
+        }
+    }
+
+    #[test]
+    fn whitespace_is_laid_out() {
+        let mut renderer = renderer();
+        for align in [TextAlign::Left, TextAlign::Center, TextAlign::Justify] {
+            let glyphs = layout(&mut renderer, "   ", &style(Some(100.0), align));
+            assert!(glyphs.len() <= 3);
+            assert!(
+                glyphs
+                    .iter()
+                    .all(|glyph| glyph.x.is_finite() && glyph.y.is_finite())
+            );
+        }
     }
 }
@@ -0,0 +1,103 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Conversions between the pixel formats of render targets.
+
+/// Convert an sRGB encoded component to linear light.
+pub(crate) fn srgb_to_linear(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear light component to sRGB encoding.
+pub(crate) fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Quantize a component in the range `0..=1` to eight bits.
+pub(crate) fn to_unorm8(c: f32) -> u8 {
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Convert a separated linear light RGBA pixel to 8-bit sRGB.
+pub(crate) fn linear_to_rgba8([r, g, b, a]: [f32; 4]) -> [u8; 4] {
+    [
+        to_unorm8(linear_to_srgb(r)),
+        to_unorm8(linear_to_srgb(g)),
+        to_unorm8(linear_to_srgb(b)),
+        to_unorm8(a),
+    ]
+}
+
+/// Convert a separated 8-bit sRGB pixel to linear light.
+pub(crate) fn rgba8_to_linear([r, g, b, a]: [u8; 4]) -> [f32; 4] {
+    let unorm = |c: u8| f32::from(c) * (1.0 / 255.0);
+    [
+        srgb_to_linear(unorm(r)),
+        srgb_to_linear(unorm(g)),
+        srgb_to_linear(unorm(b)),
+        unorm(a),
+    ]
+}
+
+/// Convert a float to the bits of the nearest half precision float.
+///
+/// Values too large for a half precision float become infinite.
+pub(crate) fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity and NaN, keeping NaNs quiet.
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // Subnormal, including the implicit leading bit in the shifted mantissa.
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | (half + round) as u16;
+    }
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    // Round to nearest, carrying into the exponent when the mantissa overflows.
+    let round = (mantissa >> 12) & 1;
+    sign | (half + round) as u16
+}
+
+/// Convert the bits of a half precision float to a float.
+pub(crate) fn f16_to_f32(half: u16) -> f32 {
+    let sign = u32::from(half & 0x8000) << 16;
+    let exponent = u32::from((half >> 10) & 0x1f);
+    let mantissa = u32::from(half & 0x3ff);
+    let bits = match (exponent, mantissa) {
+        (0, 0) => sign,
+        (0, _) => {
+            // Subnormal, so normalize the mantissa.
+            let shift = mantissa.leading_zeros() - 21;
+            let mantissa = (mantissa << shift) & 0x3ff;
+            sign | ((113 - shift) << 23) | (mantissa << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 112) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
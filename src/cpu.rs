@@ -0,0 +1,152 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Rendering without a GPU.
+//!
+//! The full pipeline, including fine rasterization, is run on the CPU. This is much slower
+//! than rendering with [`Renderer`](crate::Renderer), but doesn't need a wgpu adapter, so
+//! it can be used in environments such as headless CI machines.
+
+mod engine;
+mod filter;
+mod fine;
+mod tile_copy;
+
+pub(crate) use engine::CpuEngine;
+pub(crate) use filter::{filter_blur, filter_composite};
+pub(crate) use fine::fine;
+pub(crate) use tile_copy::tile_copy;
+
+use std::collections::HashSet;
+
+use vello_encoding::Resolver;
+
+use crate::color_convert::{f16_to_f32, linear_to_rgba8, rgba8_to_linear};
+use crate::mipmap::MipCache;
+use crate::recording::ImageFormat;
+use crate::render::{self, BumpSizes};
+use crate::shaders::{self, FullShaders};
+use crate::{Error, OutputFormat, RenderParams, Result, Scene, StrokeExpansion, image_io, stroker};
+
+/// Renders a scene into an RGBA image entirely on the CPU.
+///
+/// All [anti-aliasing methods](crate::AaConfig) are accepted, but scenes are always rendered
+/// using area anti-aliasing.
+pub struct CpuRenderer {
+    engine: CpuEngine,
+    resolver: Resolver,
+    shaders: FullShaders,
+    stroke_expansion: StrokeExpansion,
+    mips: MipCache,
+}
+
+impl CpuRenderer {
+    /// Creates a new CPU renderer.
+    pub fn new() -> Self {
+        Self::with_output_format(OutputFormat::Rgba8)
+    }
+
+    /// Creates a new CPU renderer which renders into targets of the given format.
+    ///
+    /// The format only affects the precision of the rendered pixels, as both
+    /// [`Self::render_to_rgba`] and [`Self::render_to_rgba_f32`] convert to the format
+    /// they return.
+    pub fn with_output_format(output_format: OutputFormat) -> Self {
+        let mut engine = CpuEngine::new();
+        let shaders = shaders::cpu_shaders(&mut engine, output_format.image_format());
+        Self {
+            engine,
+            resolver: Resolver::new(),
+            shaders,
+            stroke_expansion: StrokeExpansion::Gpu,
+            mips: MipCache::default(),
+        }
+    }
+
+    /// Sets where the outlines of strokes are computed.
+    ///
+    /// By default strokes are expanded by the same flatten stage as on the GPU. Renders
+    /// using [`StrokeExpansion::Cpu`] can be compared against the default to check it.
+    pub fn set_stroke_expansion(&mut self, stroke_expansion: StrokeExpansion) {
+        self.stroke_expansion = stroke_expansion;
+    }
+
+    /// Renders a scene, returning the pixels of the target.
+    ///
+    /// The result contains `params.width * params.height` pixels in row-major order,
+    /// each stored as four bytes of non-premultiplied sRGB red, green, blue and alpha.
+    pub fn render_to_rgba(&mut self, scene: &Scene, params: &RenderParams) -> Vec<u8> {
+        let pixels = self.render_target(scene, params);
+        match self.shaders.output_format {
+            ImageFormat::Rgba16Float => half_pixels(&pixels).flat_map(linear_to_rgba8).collect(),
+            _ => pixels,
+        }
+    }
+
+    /// Renders a scene, returning the pixels of the target in linear light.
+    ///
+    /// The result contains `params.width * params.height` pixels in row-major order,
+    /// each stored as four floats of non-premultiplied linear red, green, blue and alpha.
+    /// This is most precise for a renderer created with [`OutputFormat::Rgba16Float`].
+    pub fn render_to_rgba_f32(&mut self, scene: &Scene, params: &RenderParams) -> Vec<f32> {
+        let pixels = self.render_target(scene, params);
+        match self.shaders.output_format {
+            ImageFormat::Rgba16Float => half_pixels(&pixels).flatten().collect(),
+            _ => pixels
+                .chunks_exact(4)
+                .flat_map(|pixel| rgba8_to_linear(pixel.try_into().unwrap()))
+                .collect(),
+        }
+    }
+
+    /// Renders a scene and encodes the result as a PNG image.
+    ///
+    /// Fails with [`Error::EmptyImage`] if the width or height is zero.
+    pub fn render_to_png(&mut self, scene: &Scene, params: &RenderParams) -> Result<Vec<u8>> {
+        if params.width == 0 || params.height == 0 {
+            return Err(Error::EmptyImage(params.width, params.height));
+        }
+        let rgba = self.render_to_rgba(scene, params);
+        Ok(image_io::encode_png(params.width, params.height, &rgba)?)
+    }
+
+    /// Render a scene, returning the raw contents of the target in the output format.
+    fn render_target(&mut self, scene: &Scene, params: &RenderParams) -> Vec<u8> {
+        let size = params.width as usize
+            * params.height as usize
+            * self.shaders.output_format.bytes_per_pixel();
+        if size == 0 {
+            return Vec::new();
+        }
+        let scene = scene.with_filter_layers_closed();
+        let scene = stroker::expand_strokes(&scene, self.stroke_expansion);
+        let mip_levels = self.mips.levels(&scene, &HashSet::new());
+        let (recording, target) = render::render_full(
+            &scene,
+            &mut self.resolver,
+            &self.shaders,
+            params,
+            BumpSizes::default(),
+            &mip_levels,
+            &mut render::Render::new(),
+        );
+        self.engine.run_recording(&recording);
+        let target = *target.as_image().unwrap();
+        self.engine
+            .take_image(target)
+            .unwrap_or_else(|| vec![0; size])
+    }
+}
+
+/// Decode the pixels of an RGBA16 float image.
+fn half_pixels(bytes: &[u8]) -> impl Iterator<Item = [f32; 4]> + '_ {
+    bytes.chunks_exact(8).map(|pixel| {
+        std::array::from_fn(|i| f16_to_f32(u16::from_le_bytes([pixel[2 * i], pixel[2 * i + 1]])))
+    })
+}
+
+impl Default for CpuRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
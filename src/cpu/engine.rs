@@ -0,0 +1,238 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use vello_shaders::cpu::{CpuBinding, CpuTexture};
+
+use crate::recording::{
+    BufferProxy, Command, ImageProxy, Recording, ResourceId, ResourceProxy, ShaderId,
+};
+
+/// The signature shared by all CPU shaders, matching [`vello_shaders::cpu`].
+pub(crate) type CpuShaderFn = fn(u32, &[CpuBinding<'_>]);
+
+struct CpuShader {
+    label: &'static str,
+    /// `None` for shaders which are never dispatched when running on the CPU.
+    shader: Option<CpuShaderFn>,
+}
+
+/// An image materialized on the CPU.
+enum CpuImage {
+    /// An image whose contents were provided by the recording. Shaders can only read it.
+    Texture(CpuTexture),
+    /// An image written by a shader, stored as packed RGBA8 bytes.
+    Storage(RefCell<Vec<u8>>),
+}
+
+/// Executes [`Recording`]s entirely on the CPU.
+///
+/// This is the counterpart of `WgpuEngine` which doesn't need a wgpu `Device`. All
+/// resources are kept in host memory, and every dispatch is run synchronously.
+#[derive(Default)]
+pub(crate) struct CpuEngine {
+    shaders: Vec<CpuShader>,
+    buffers: HashMap<ResourceId, RefCell<Vec<u8>>>,
+    images: HashMap<ResourceId, CpuImage>,
+}
+
+impl CpuEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a shader, returning the id used to dispatch it.
+    pub fn add_shader(&mut self, label: &'static str, shader: Option<CpuShaderFn>) -> ShaderId {
+        let id = self.shaders.len();
+        self.shaders.push(CpuShader { label, shader });
+        ShaderId(id)
+    }
+
+    pub fn run_recording(&mut self, recording: &Recording) {
+        let mut free_bufs = Vec::new();
+        let mut free_images = Vec::new();
+        for command in &recording.commands {
+            match command {
+                Command::Upload(buf_proxy, bytes) | Command::UploadUniform(buf_proxy, bytes) => {
+                    self.buffers.insert(buf_proxy.id, RefCell::new(bytes.clone()));
+                }
+                Command::UploadImage(image_proxy, bytes) => {
+                    let pixels = bytes
+                        .chunks_exact(4)
+                        .map(|texel| u32::from_le_bytes(texel.try_into().unwrap()))
+                        .collect();
+                    self.images.insert(
+                        image_proxy.id,
+                        CpuImage::Texture(CpuTexture {
+                            width: image_proxy.width as usize,
+                            height: image_proxy.height as usize,
+                            pixels,
+                        }),
+                    );
+                }
+                Command::WriteImage(proxy, [x, y], image) => {
+                    let texture = self.texture_mut(proxy);
+                    write_texels(texture, [*x, *y], image.width, image.data.data());
+                }
+                Command::CopyImage(src, dst, [x, y]) => {
+                    let data = match &self.images[&src.id] {
+                        CpuImage::Storage(data) => data.borrow().clone(),
+                        CpuImage::Texture(texture) => {
+                            texture.pixels.iter().flat_map(|p| p.to_le_bytes()).collect()
+                        }
+                    };
+                    let texture = self.texture_mut(dst);
+                    write_texels(texture, [*x, *y], src.width, &data);
+                }
+                Command::CopyImageRegion(src, [src_x, src_y, width, height], dst, [x, y]) => {
+                    let CpuImage::Texture(texture) = &self.images[&src.id] else {
+                        panic!("can't copy a region of an image which is a shader output");
+                    };
+                    let data: Vec<u8> = (*src_y..src_y + height)
+                        .flat_map(|row| {
+                            let start = row as usize * texture.width + *src_x as usize;
+                            &texture.pixels[start..][..*width as usize]
+                        })
+                        .flat_map(|p| p.to_le_bytes())
+                        .collect();
+                    let texture = self.texture_mut(dst);
+                    write_texels(texture, [*x, *y], *width, &data);
+                }
+                Command::Dispatch(shader_id, (x, _, _), bindings) => {
+                    self.dispatch(*shader_id, *x, bindings);
+                }
+                Command::DispatchIndirect(shader_id, proxy, offset, bindings) => {
+                    let n_wg = {
+                        let buf = self.materialize_buf(proxy).borrow();
+                        let indirect: &[u32] = bytemuck::cast_slice(&buf[*offset as usize..]);
+                        indirect[0]
+                    };
+                    self.dispatch(*shader_id, n_wg, bindings);
+                }
+                Command::Download(_) => {
+                    // Buffers live in host memory, so they can be read without a download.
+                }
+                Command::Clear(proxy, offset, size) => {
+                    let mut buf = self.materialize_buf(proxy).borrow_mut();
+                    let mut slice = &mut buf[*offset as usize..];
+                    if let Some(size) = size {
+                        slice = &mut slice[..*size as usize];
+                    }
+                    slice.fill(0);
+                }
+                Command::FreeBuffer(proxy) => free_bufs.push(proxy.id),
+                Command::FreeImage(proxy) => free_images.push(proxy.id),
+                #[cfg(feature = "debug_layers")]
+                Command::Draw(_) => {
+                    log::warn!("Skipping draw command, as render passes can't be run on the CPU");
+                }
+            }
+        }
+        for id in free_bufs {
+            self.buffers.remove(&id);
+        }
+        for id in free_images {
+            self.images.remove(&id);
+        }
+    }
+
+    /// Take ownership of the contents of `image` as packed RGBA8 bytes.
+    ///
+    /// Returns `None` if the image was never used by a recording.
+    pub fn take_image(&mut self, image: ImageProxy) -> Option<Vec<u8>> {
+        match self.images.remove(&image.id)? {
+            CpuImage::Texture(texture) => Some(
+                texture
+                    .pixels
+                    .iter()
+                    .flat_map(|texel| texel.to_le_bytes())
+                    .collect(),
+            ),
+            CpuImage::Storage(data) => Some(data.into_inner()),
+        }
+    }
+
+    /// Take the contents of `buffer`, such as a buffer captured for the debug layers.
+    ///
+    /// Returns `None` if the buffer was never used by a recording, or has been freed.
+    #[cfg(all(test, feature = "debug_layers"))]
+    pub fn take_buffer(&mut self, buffer: BufferProxy) -> Option<Vec<u8>> {
+        self.buffers.remove(&buffer.id).map(RefCell::into_inner)
+    }
+
+    fn dispatch(&mut self, shader_id: ShaderId, n_wg: u32, bindings: &[ResourceProxy]) {
+        let shader = &self.shaders[shader_id.0];
+        let Some(shader_fn) = shader.shader else {
+            panic!("no CPU implementation for {}", shader.label);
+        };
+        for binding in bindings {
+            match binding {
+                ResourceProxy::Buffer(proxy) | ResourceProxy::BufferRange { proxy, .. } => {
+                    self.materialize_buf(proxy);
+                }
+                ResourceProxy::Image(proxy) => {
+                    self.images.entry(proxy.id).or_insert_with(|| {
+                        let size = proxy.width as usize
+                            * proxy.height as usize
+                            * proxy.format.bytes_per_pixel();
+                        CpuImage::Storage(RefCell::new(vec![0; size]))
+                    });
+                }
+            }
+        }
+        let resources = bindings
+            .iter()
+            .map(|binding| match binding {
+                // Ranges are currently only used for GPU-side resources, so binding the
+                // whole buffer is sufficient.
+                ResourceProxy::Buffer(proxy) | ResourceProxy::BufferRange { proxy, .. } => {
+                    CpuBinding::BufferRW(&self.buffers[&proxy.id])
+                }
+                ResourceProxy::Image(proxy) => match &self.images[&proxy.id] {
+                    CpuImage::Texture(texture) => CpuBinding::Texture(texture),
+                    CpuImage::Storage(data) => CpuBinding::BufferRW(data),
+                },
+            })
+            .collect::<Vec<_>>();
+        shader_fn(n_wg, &resources);
+    }
+
+    fn materialize_buf(&mut self, proxy: &BufferProxy) -> &RefCell<Vec<u8>> {
+        self.buffers
+            .entry(proxy.id)
+            .or_insert_with(|| RefCell::new(vec![0; proxy.size as usize]))
+    }
+
+    fn texture_mut(&mut self, proxy: &ImageProxy) -> &mut CpuTexture {
+        let image = self.images.entry(proxy.id).or_insert_with(|| {
+            CpuImage::Texture(CpuTexture {
+                width: proxy.width as usize,
+                height: proxy.height as usize,
+                pixels: vec![0; proxy.width as usize * proxy.height as usize],
+            })
+        });
+        match image {
+            CpuImage::Texture(texture) => texture,
+            CpuImage::Storage(_) => panic!("can't write to an image which is a shader output"),
+        }
+    }
+}
+
+/// Write RGBA8 pixels with rows of `width` pixels into a texture at `[x, y]`, clipping them to
+/// the texture.
+fn write_texels(texture: &mut CpuTexture, [x, y]: [u32; 2], width: u32, data: &[u8]) {
+    let width = width as usize;
+    for (row, src) in data.chunks_exact(width * 4).enumerate() {
+        let dst_y = y as usize + row;
+        if dst_y >= texture.height {
+            break;
+        }
+        let copy_width = width.min(texture.width.saturating_sub(x as usize));
+        let dst = &mut texture.pixels[dst_y * texture.width + x as usize..][..copy_width];
+        for (dst, src) in dst.iter_mut().zip(src.chunks_exact(4)) {
+            *dst = u32::from_le_bytes(src.try_into().unwrap());
+        }
+    }
+}
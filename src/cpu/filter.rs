@@ -0,0 +1,173 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Filtering of the content of filter layers, mirroring the `filter_blur` and
+//! `filter_composite` shaders.
+
+use vello_shaders::cpu::CpuBinding;
+
+use crate::color_convert::{f16_to_f32, f32_to_f16, linear_to_srgb, to_unorm8};
+use crate::filter::LUMINANCE;
+use crate::render::{FILTER_DROP_SHADOW, FilterConfig, MASK_LUMINANCE, MASK_NONE};
+
+type Rgba = [f32; 4];
+
+/// Blur the content of a filter layer horizontally, into a premultiplied RGBA16 float image.
+pub(crate) fn filter_blur(_n_wg: u32, resources: &[CpuBinding<'_>]) {
+    let config = read_config(&resources[0]);
+    let (CpuBinding::BufferRW(content), CpuBinding::BufferRW(output)) =
+        (&resources[1], &resources[2])
+    else {
+        panic!("filter_blur content and output must be storage images");
+    };
+    let content = Pixels::new(&config, &content.borrow(), true);
+    let mut output = output.borrow_mut();
+    let weights = weights(&config);
+    let radius = config.radius as i32;
+    for y in 0..config.size[1] as i32 {
+        for x in 0..config.size[0] as i32 {
+            let rgba = blur(&weights, |k| content.load(x + k - radius, y));
+            let ix = (y as usize * config.size[0] as usize + x as usize) * 8;
+            for (dst, value) in output[ix..][..8].chunks_exact_mut(2).zip(rgba) {
+                dst.copy_from_slice(&f32_to_f16(value).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Blur the output of `filter_blur` vertically, and write the result of the filter as
+/// separated sRGB RGBA8, after applying the color matrix and the mask.
+pub(crate) fn filter_composite(_n_wg: u32, resources: &[CpuBinding<'_>]) {
+    let config = read_config(&resources[0]);
+    let (
+        CpuBinding::BufferRW(blurred),
+        CpuBinding::BufferRW(content),
+        CpuBinding::BufferRW(mask),
+        CpuBinding::BufferRW(output),
+    ) = (&resources[1], &resources[2], &resources[3], &resources[4])
+    else {
+        panic!("filter_composite inputs and output must be storage images");
+    };
+    let blurred = Pixels::new(&config, &blurred.borrow(), false);
+    let content = Pixels::new(&config, &content.borrow(), true);
+    let mask = (config.mask != MASK_NONE).then(|| Pixels::new(&config, &mask.borrow(), false));
+    let mut output = output.borrow_mut();
+    let weights = weights(&config);
+    let radius = config.radius as i32;
+    let [dx, dy] = config.offset;
+    for y in 0..config.size[1] as i32 {
+        for x in 0..config.size[0] as i32 {
+            let mut rgba = blur(&weights, |k| blurred.load(x - dx, y - dy + k - radius));
+            if config.kind == FILTER_DROP_SHADOW {
+                let fg = content.load(x, y);
+                let shadow = rgba[3] * (1.0 - fg[3]);
+                rgba = std::array::from_fn(|i| fg[i] + config.color[i] * shadow);
+            }
+            let inv_alpha = 1.0 / rgba[3].max(1e-6);
+            let mut separated = [
+                rgba[0] * inv_alpha,
+                rgba[1] * inv_alpha,
+                rgba[2] * inv_alpha,
+            ];
+            if config.linear != 0 {
+                separated = separated.map(linear_to_srgb);
+            }
+            let [r, g, b] = separated;
+            let m = &config.matrix;
+            let mut result: [f32; 4] = std::array::from_fn(|i| {
+                let value = m[0][i] * r + m[1][i] * g + m[2][i] * b + m[3][i] * rgba[3] + m[4][i];
+                value.clamp(0.0, 1.0)
+            });
+            if let Some(mask) = &mask {
+                let [r, g, b, a] = mask.load(x, y);
+                let mut coverage = a;
+                if config.mask == MASK_LUMINANCE {
+                    let mut rgb = [r, g, b];
+                    if config.linear != 0 {
+                        rgb = rgb.map(linear_to_srgb);
+                    }
+                    coverage *= rgb.iter().zip(LUMINANCE).map(|(c, l)| c * l).sum::<f32>();
+                }
+                result[3] *= coverage;
+            }
+            let ix = (y as usize * config.size[0] as usize + x as usize) * 4;
+            output[ix..][..4].copy_from_slice(&result.map(to_unorm8));
+        }
+    }
+}
+
+fn read_config(binding: &CpuBinding<'_>) -> FilterConfig {
+    let CpuBinding::BufferRW(config) = binding else {
+        panic!("filter config must be a buffer");
+    };
+    bytemuck::pod_read_unaligned(&config.borrow())
+}
+
+/// The weights of the gaussian kernel, from `-radius` to `radius` texels away.
+fn weights(config: &FilterConfig) -> Vec<f32> {
+    let radius = config.radius as i32;
+    (-radius..=radius)
+        .map(|k| {
+            let x = k as f32 / config.std_dev.max(1e-6);
+            (-0.5 * x * x).exp()
+        })
+        .collect()
+}
+
+/// Convolve the texels returned by `load` for each index of the kernel.
+fn blur(weights: &[f32], load: impl Fn(i32) -> Rgba) -> Rgba {
+    let mut sum = [0.0; 4];
+    for (k, weight) in weights.iter().enumerate() {
+        let rgba = load(k as i32);
+        for (sum, value) in sum.iter_mut().zip(rgba) {
+            *sum += weight * value;
+        }
+    }
+    let total: f32 = weights.iter().sum();
+    sum.map(|value| value / total)
+}
+
+/// Read access to the pixels of an RGBA8 or RGBA16 float image.
+struct Pixels {
+    width: i32,
+    height: i32,
+    pixels: Vec<Rgba>,
+}
+
+impl Pixels {
+    /// Decode an image, premultiplying it if it is `separated`.
+    fn new(config: &FilterConfig, bytes: &[u8], separated: bool) -> Self {
+        let [width, height] = config.size;
+        let bytes_per_pixel = bytes.len() / (width as usize * height as usize).max(1);
+        let pixels = bytes
+            .chunks_exact(bytes_per_pixel)
+            .map(|pixel| {
+                let rgba: Rgba = if bytes_per_pixel == 8 {
+                    std::array::from_fn(|i| {
+                        f16_to_f32(u16::from_le_bytes([pixel[2 * i], pixel[2 * i + 1]]))
+                    })
+                } else {
+                    std::array::from_fn(|i| f32::from(pixel[i]) * (1.0 / 255.0))
+                };
+                if separated {
+                    let [r, g, b, a] = rgba;
+                    [r * a, g * a, b * a, a]
+                } else {
+                    rgba
+                }
+            })
+            .collect();
+        Self {
+            width: width as i32,
+            height: height as i32,
+            pixels,
+        }
+    }
+
+    fn load(&self, x: i32, y: i32) -> Rgba {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return [0.0; 4];
+        }
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
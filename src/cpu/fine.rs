@@ -0,0 +1,902 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! CPU implementation of the fine rasterization stage.
+//!
+//! This mirrors `fine.wgsl`, interpreting the per-tile command lists written by coarse
+//! rasterization. Only area anti-aliasing is implemented, so this kernel is also used
+//! when an MSAA method is requested.
+//!
+//! For linear output, colors are converted to linear light as they are read, so that they
+//! are blended in linear light, and the result is stored without being clamped. This matches
+//! `FINE_WGSL` in `shaders.rs`.
+
+use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+
+use vello_encoding::{ConfigUniform, PathSegment};
+use vello_shaders::cpu::CpuBinding;
+
+use crate::color_convert::{f32_to_f16, srgb_to_linear, to_unorm8};
+use crate::render::FineRegion;
+
+const TILE_WIDTH: usize = 16;
+const TILE_HEIGHT: usize = 16;
+const TILE_PIXELS: usize = TILE_WIDTH * TILE_HEIGHT;
+
+// Layout of the per-tile command list, see `shared/ptcl.wgsl`.
+const PTCL_INITIAL_ALLOC: u32 = 64;
+
+const CMD_END: u32 = 0;
+const CMD_FILL: u32 = 1;
+const CMD_STROKE: u32 = 2;
+const CMD_SOLID: u32 = 3;
+const CMD_COLOR: u32 = 5;
+const CMD_LIN_GRAD: u32 = 6;
+const CMD_RAD_GRAD: u32 = 7;
+const CMD_SWEEP_GRAD: u32 = 8;
+const CMD_IMAGE: u32 = 9;
+const CMD_BEGIN_CLIP: u32 = 10;
+const CMD_END_CLIP: u32 = 11;
+const CMD_JUMP: u32 = 12;
+const CMD_BLUR_RECT: u32 = 13;
+
+const GRADIENT_WIDTH: i32 = 512;
+
+const EXTEND_PAD: u32 = 0;
+const EXTEND_REPEAT: u32 = 1;
+
+const IMAGE_QUALITY_LOW: u32 = 0;
+const IMAGE_QUALITY_MEDIUM: u32 = 1;
+
+const RAD_GRAD_KIND_CIRCULAR: u32 = 1;
+const RAD_GRAD_KIND_STRIP: u32 = 2;
+const RAD_GRAD_KIND_FOCAL_ON_CIRCLE: u32 = 3;
+const RAD_GRAD_SWAPPED: u32 = 1;
+const RAD_GRAD_WELL_BEHAVED: u32 = 2;
+
+/// A premultiplied color.
+type Rgba = [f32; 4];
+
+/// The fine rasterization kernel.
+///
+/// Bindings match `fine_area`: config, segments, ptcl, info, blend spill, output image,
+/// gradient ramps, image atlas, mip table and the region of tiles to render. The blend spill
+/// buffer is unused, as the blend stack isn't bounded on the CPU. The MSAA modes bind their
+/// mask LUT before the mip table, which is ignored.
+pub(crate) fn fine(_n_wg: u32, resources: &[CpuBinding<'_>]) {
+    let config = *resources[0].as_typed::<ConfigUniform>();
+    let segments = resources[1].as_slice::<PathSegment>();
+    let ptcl = resources[2].as_slice::<u32>();
+    let info = resources[3].as_slice::<u32>();
+    let mip_table = resources[resources.len() - 2].as_slice::<u32>();
+    let region = *resources[resources.len() - 1].as_typed::<FineRegion>();
+    let CpuBinding::BufferRW(output) = resources[5] else {
+        panic!("fine output must be a storage image");
+    };
+    let mut output = output.borrow_mut();
+    // The output is either RGBA8 or, for linear output, RGBA16 float.
+    let linear =
+        output.len() == config.target_width as usize * config.target_height as usize * 8;
+    let ctx = FineContext {
+        config,
+        segments: &segments,
+        ptcl: &ptcl,
+        info: &info,
+        gradients: Texels::new(&resources[6]),
+        atlas: Texels::new(&resources[7]),
+        mip_table: bytemuck::cast_slice(&mip_table),
+        linear,
+    };
+    let mut tile = Tile::new();
+    let [x0, y0] = region.offset;
+    let [width, height] = region.size;
+    for tile_y in y0..y0 + height {
+        for tile_x in x0..x0 + width {
+            tile.render(&ctx, tile_x, tile_y);
+            tile.store(&config, tile_x, tile_y, linear, &mut output);
+        }
+    }
+}
+
+struct FineContext<'a> {
+    config: ConfigUniform,
+    segments: &'a [PathSegment],
+    ptcl: &'a [u32],
+    info: &'a [u32],
+    gradients: Texels<'a>,
+    atlas: Texels<'a>,
+    /// The entries of the mip table, see `crate::mipmap`.
+    mip_table: &'a [[u32; 3]],
+    /// Whether colors are blended in linear light.
+    linear: bool,
+}
+
+impl FineContext<'_> {
+    /// Convert a premultiplied sRGB color, as encoded in the scene, to the space colors are
+    /// blended in.
+    fn decode(&self, rgba: Rgba) -> Rgba {
+        if !self.linear {
+            return rgba;
+        }
+        let alpha = rgba[3];
+        let inv_alpha = if alpha > 0.0 { 1.0 / alpha } else { 0.0 };
+        let [r, g, b] = [0, 1, 2].map(|i| srgb_to_linear(rgba[i] * inv_alpha) * alpha);
+        [r, g, b, alpha]
+    }
+
+    /// Read a premultiplied color from the command list.
+    fn color(&self, packed: u32) -> Rgba {
+        self.decode(unpack_rgba(packed))
+    }
+
+    fn info_f32(&self, ix: usize) -> f32 {
+        f32::from_bits(self.info[ix])
+    }
+
+    /// Read an affine transform stored as a 2x2 matrix followed by a translation.
+    fn info_transform(&self, ix: usize) -> [f32; 6] {
+        std::array::from_fn(|i| self.info_f32(ix + i))
+    }
+
+    /// Find the mip levels of the image at an atlas offset, packed as in the image brush.
+    /// Images without levels have a count of zero.
+    fn mip_levels(&self, xy: u32) -> MipLevels {
+        match self.mip_table.binary_search_by_key(&xy, |entry| entry[0]) {
+            Ok(ix) => {
+                let [_, offset, count] = self.mip_table[ix];
+                MipLevels {
+                    atlas_offset: [(offset >> 16) as f32, (offset & 0xffff) as f32],
+                    count,
+                }
+            }
+            Err(_) => MipLevels {
+                atlas_offset: [0.0; 2],
+                count: 0,
+            },
+        }
+    }
+}
+
+/// Read-only access to a packed RGBA8 texture.
+struct Texels<'a> {
+    width: usize,
+    height: usize,
+    pixels: &'a [u32],
+}
+
+impl<'a> Texels<'a> {
+    fn new(binding: &CpuBinding<'a>) -> Self {
+        match *binding {
+            CpuBinding::Texture(texture) => Self {
+                width: texture.width,
+                height: texture.height,
+                pixels: &texture.pixels,
+            },
+            _ => Self {
+                width: 0,
+                height: 0,
+                pixels: &[],
+            },
+        }
+    }
+
+    fn load(&self, x: i32, y: i32) -> Rgba {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return [0.0; 4];
+        }
+        unpack_rgba(self.pixels[y as usize * self.width + x as usize])
+    }
+}
+
+/// Working state for rendering a single tile.
+struct Tile {
+    rgba: [Rgba; TILE_PIXELS],
+    area: [f32; TILE_PIXELS],
+    blend_stack: Vec<Rgba>,
+}
+
+impl Tile {
+    fn new() -> Self {
+        Self {
+            rgba: [[0.0; 4]; TILE_PIXELS],
+            area: [0.0; TILE_PIXELS],
+            blend_stack: Vec::new(),
+        }
+    }
+
+    fn render(&mut self, ctx: &FineContext<'_>, tile_x: u32, tile_y: u32) {
+        let ptcl = ctx.ptcl;
+        let tile_ix = tile_y * ctx.config.width_in_tiles + tile_x;
+        // The first word of each command list is the blend spill offset.
+        let mut cmd_ix = (tile_ix * PTCL_INITIAL_ALLOC + 1) as usize;
+        let origin = [
+            (tile_x as usize * TILE_WIDTH) as f32,
+            (tile_y as usize * TILE_HEIGHT) as f32,
+        ];
+        self.rgba = [ctx.color(ctx.config.base_color); TILE_PIXELS];
+        self.area = [0.0; TILE_PIXELS];
+        self.blend_stack.clear();
+        loop {
+            match ptcl[cmd_ix] {
+                CMD_END => break,
+                CMD_FILL => {
+                    let size_and_rule = ptcl[cmd_ix + 1];
+                    let seg_data = ptcl[cmd_ix + 2];
+                    let backdrop = ptcl[cmd_ix + 3] as i32;
+                    self.fill_path(ctx.segments, size_and_rule, seg_data, backdrop);
+                    cmd_ix += 4;
+                }
+                CMD_STROKE => {
+                    // Strokes are expanded to fills before coarse rasterization.
+                    cmd_ix += 3;
+                }
+                CMD_SOLID => {
+                    self.area = [1.0; TILE_PIXELS];
+                    cmd_ix += 1;
+                }
+                CMD_COLOR => {
+                    let color = ctx.color(ptcl[cmd_ix + 1]);
+                    self.paint(origin, |_| color);
+                    cmd_ix += 2;
+                }
+                CMD_LIN_GRAD => {
+                    let (index, extend) = split_index_mode(ptcl[cmd_ix + 1]);
+                    let info_offset = ptcl[cmd_ix + 2] as usize;
+                    let line_x = ctx.info_f32(info_offset);
+                    let line_y = ctx.info_f32(info_offset + 1);
+                    let line_c = ctx.info_f32(info_offset + 2);
+                    self.paint(origin, |[x, y]| {
+                        let t = extend_mode(line_x * x + line_y * y + line_c, extend);
+                        sample_ramp(ctx, index, t)
+                    });
+                    cmd_ix += 3;
+                }
+                CMD_RAD_GRAD => {
+                    let (index, extend) = split_index_mode(ptcl[cmd_ix + 1]);
+                    let info_offset = ptcl[cmd_ix + 2] as usize;
+                    let rad = RadialGradient {
+                        transform: ctx.info_transform(info_offset),
+                        focal_x: ctx.info_f32(info_offset + 6),
+                        radius: ctx.info_f32(info_offset + 7),
+                        kind: ctx.info[info_offset + 8] & 0x7,
+                        flags: ctx.info[info_offset + 8] >> 3,
+                    };
+                    self.paint(origin, |xy| match rad.eval(xy, extend) {
+                        Some(t) => sample_ramp(ctx, index, t),
+                        None => [0.0; 4],
+                    });
+                    cmd_ix += 3;
+                }
+                CMD_SWEEP_GRAD => {
+                    let (index, extend) = split_index_mode(ptcl[cmd_ix + 1]);
+                    let info_offset = ptcl[cmd_ix + 2] as usize;
+                    let transform = ctx.info_transform(info_offset);
+                    let t0 = ctx.info_f32(info_offset + 6);
+                    let t1 = ctx.info_f32(info_offset + 7);
+                    let scale = 1.0 / (t1 - t0);
+                    self.paint(origin, |xy| {
+                        let [x, y] = apply_transform(&transform, xy);
+                        let angle = (y.atan2(x) / TAU).rem_euclid(1.0);
+                        let t = extend_mode((angle - t0) * scale, extend);
+                        sample_ramp(ctx, index, t)
+                    });
+                    cmd_ix += 3;
+                }
+                CMD_IMAGE => {
+                    let info_offset = ptcl[cmd_ix + 1] as usize;
+                    let image = ImageBrush::read(ctx, info_offset);
+                    self.paint(origin, |xy| image.sample(ctx, xy));
+                    cmd_ix += 2;
+                }
+                CMD_BEGIN_CLIP => {
+                    self.blend_stack.extend_from_slice(&self.rgba);
+                    self.rgba = [[0.0; 4]; TILE_PIXELS];
+                    cmd_ix += 1;
+                }
+                CMD_END_CLIP => {
+                    let blend = ptcl[cmd_ix + 1];
+                    let alpha = f32::from_bits(ptcl[cmd_ix + 2]);
+                    let base = self.blend_stack.len() - TILE_PIXELS;
+                    for (i, rgba) in self.rgba.iter_mut().enumerate() {
+                        let bg = self.blend_stack[base + i];
+                        let fg = scale(*rgba, self.area[i] * alpha);
+                        *rgba = blend_mix_compose(bg, fg, blend);
+                    }
+                    self.blend_stack.truncate(base);
+                    cmd_ix += 3;
+                }
+                CMD_JUMP => {
+                    cmd_ix = ptcl[cmd_ix + 1] as usize;
+                }
+                CMD_BLUR_RECT => {
+                    let info_offset = ptcl[cmd_ix + 1] as usize;
+                    let color = ctx.color(ptcl[cmd_ix + 2]);
+                    let transform = ctx.info_transform(info_offset);
+                    let width = ctx.info_f32(info_offset + 6);
+                    let height = ctx.info_f32(info_offset + 7);
+                    let radius = ctx.info_f32(info_offset + 8);
+                    let std_dev = ctx.info_f32(info_offset + 9);
+                    self.paint(origin, |xy| {
+                        let [x, y] = apply_transform(&transform, xy);
+                        let alpha = blurred_rounded_rect(x, y, width, height, radius, std_dev);
+                        scale(color, alpha)
+                    });
+                    cmd_ix += 3;
+                }
+                tag => {
+                    debug_assert!(false, "unknown ptcl command {tag}");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Composite a paint over the current contents, weighted by the coverage in `area`.
+    ///
+    /// The paint is evaluated at pixel centers, in target coordinates.
+    fn paint(&mut self, origin: [f32; 2], mut paint: impl FnMut([f32; 2]) -> Rgba) {
+        for (i, rgba) in self.rgba.iter_mut().enumerate() {
+            let area = self.area[i];
+            if area == 0.0 {
+                continue;
+            }
+            let xy = [
+                origin[0] + (i % TILE_WIDTH) as f32 + 0.5,
+                origin[1] + (i / TILE_WIDTH) as f32 + 0.5,
+            ];
+            let fg = scale(paint(xy), area);
+            *rgba = std::array::from_fn(|c| rgba[c] * (1.0 - fg[3]) + fg[c]);
+        }
+    }
+
+    /// Compute the coverage of a path within this tile, using exact area coverage.
+    fn fill_path(
+        &mut self,
+        segments: &[PathSegment],
+        size_and_rule: u32,
+        seg_data: u32,
+        backdrop: i32,
+    ) {
+        let n_segs = (size_and_rule >> 1) as usize;
+        let even_odd = (size_and_rule & 1) != 0;
+        self.area = [backdrop as f32; TILE_PIXELS];
+        for segment in &segments[seg_data as usize..][..n_segs] {
+            let delta = [
+                segment.point1[0] - segment.point0[0],
+                segment.point1[1] - segment.point0[1],
+            ];
+            for yi in 0..TILE_HEIGHT {
+                let row = &mut self.area[yi * TILE_WIDTH..][..TILE_WIDTH];
+                let y = segment.point0[1] - yi as f32;
+                let y0 = y.clamp(0.0, 1.0);
+                let y1 = (y + delta[1]).clamp(0.0, 1.0);
+                let dy = y0 - y1;
+                if dy != 0.0 {
+                    let vec_y_recip = 1.0 / delta[1];
+                    let t0 = (y0 - y) * vec_y_recip;
+                    let t1 = (y1 - y) * vec_y_recip;
+                    let x0 = segment.point0[0] + t0 * delta[0];
+                    let x1 = segment.point0[0] + t1 * delta[0];
+                    let xmin0 = x0.min(x1);
+                    let xmax0 = x0.max(x1);
+                    for (i, area) in row.iter_mut().enumerate() {
+                        let i = i as f32;
+                        let xmin = (xmin0 - i).min(1.0) - 1.0e-6;
+                        let xmax = xmax0 - i;
+                        let b = xmax.min(1.0);
+                        let c = b.max(0.0);
+                        let d = xmin.max(0.0);
+                        let a = (b + 0.5 * (d * d - c * c) - xmin) / (xmax - xmin);
+                        *area += a * dy;
+                    }
+                }
+                let y_edge = sign(delta[0]) * (yi as f32 - segment.y_edge + 1.0).clamp(0.0, 1.0);
+                for area in row.iter_mut() {
+                    *area += y_edge;
+                }
+            }
+        }
+        for area in &mut self.area {
+            *area = if even_odd {
+                (*area - 2.0 * (0.5 * *area).round()).abs()
+            } else {
+                area.abs().min(1.0)
+            };
+        }
+    }
+
+    /// Write the tile to the target as separated (not premultiplied) RGBA8, or as unclamped
+    /// RGBA16 float if `linear` is set.
+    fn store(
+        &self,
+        config: &ConfigUniform,
+        tile_x: u32,
+        tile_y: u32,
+        linear: bool,
+        output: &mut [u8],
+    ) {
+        let width = config.target_width as usize;
+        let height = config.target_height as usize;
+        for (i, rgba) in self.rgba.iter().enumerate() {
+            let x = tile_x as usize * TILE_WIDTH + i % TILE_WIDTH;
+            let y = tile_y as usize * TILE_HEIGHT + i / TILE_WIDTH;
+            if x >= width || y >= height {
+                continue;
+            }
+            let alpha = rgba[3];
+            let inv_alpha = if alpha > 0.0 { 1.0 / alpha } else { 0.0 };
+            let separated = [rgba[0] * inv_alpha, rgba[1] * inv_alpha, rgba[2] * inv_alpha, alpha];
+            if linear {
+                let pixel = &mut output[(y * width + x) * 8..][..8];
+                for (dst, value) in pixel.chunks_exact_mut(2).zip(separated) {
+                    dst.copy_from_slice(&f32_to_f16(value).to_le_bytes());
+                }
+            } else {
+                let pixel = &mut output[(y * width + x) * 4..][..4];
+                for (dst, value) in pixel.iter_mut().zip(separated) {
+                    *dst = to_unorm8(value);
+                }
+            }
+        }
+    }
+}
+
+struct RadialGradient {
+    transform: [f32; 6],
+    focal_x: f32,
+    radius: f32,
+    kind: u32,
+    flags: u32,
+}
+
+impl RadialGradient {
+    /// Evaluate the gradient parameter at a point, or `None` where the gradient is undefined.
+    fn eval(&self, xy: [f32; 2], extend: u32) -> Option<f32> {
+        let [x, y] = apply_transform(&self.transform, xy);
+        let is_swapped = (self.flags & RAD_GRAD_SWAPPED) != 0;
+        let is_circular = self.kind == RAD_GRAD_KIND_CIRCULAR;
+        let r1_recip = if is_circular { 0.0 } else { 1.0 / self.radius };
+        let less_scale = if is_swapped || (1.0 - self.focal_x) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        let t_sign = sign(1.0 - self.focal_x);
+        let (xx, yy) = (x * x, y * y);
+        let t = match self.kind {
+            RAD_GRAD_KIND_STRIP => {
+                let a = self.radius - yy;
+                if a < 0.0 {
+                    return None;
+                }
+                a.sqrt() + x
+            }
+            RAD_GRAD_KIND_FOCAL_ON_CIRCLE => {
+                let t = (xx + yy) / x;
+                if x == 0.0 || t < 0.0 {
+                    return None;
+                }
+                t
+            }
+            RAD_GRAD_KIND_CIRCULAR => (xx + yy).sqrt(),
+            _ if (self.flags & RAD_GRAD_WELL_BEHAVED) != 0 => {
+                (xx + yy).sqrt() - x * r1_recip
+            }
+            _ => {
+                let disc = xx - yy;
+                if disc < 0.0 {
+                    return None;
+                }
+                let t = less_scale * disc.sqrt() - x * r1_recip;
+                if t < 0.0 {
+                    return None;
+                }
+                t
+            }
+        };
+        let t = extend_mode(self.focal_x + t_sign * t, extend);
+        Some(if is_swapped { 1.0 - t } else { t })
+    }
+}
+
+struct ImageBrush {
+    transform: [f32; 6],
+    atlas_offset: [f32; 2],
+    extents: [f32; 2],
+    x_extend: u32,
+    y_extend: u32,
+    quality: u32,
+    alpha: f32,
+}
+
+impl ImageBrush {
+    fn read(ctx: &FineContext<'_>, info_offset: usize) -> Self {
+        let xy = ctx.info[info_offset + 6];
+        let width_height = ctx.info[info_offset + 7];
+        let sample_alpha = ctx.info[info_offset + 8];
+        Self {
+            transform: ctx.info_transform(info_offset),
+            atlas_offset: [(xy >> 16) as f32, (xy & 0xffff) as f32],
+            extents: [(width_height >> 16) as f32, (width_height & 0xffff) as f32],
+            x_extend: (sample_alpha >> 10) & 0x3,
+            y_extend: (sample_alpha >> 8) & 0x3,
+            quality: sample_alpha >> 12,
+            alpha: (sample_alpha & 0xff) as f32 / 255.0,
+        }
+    }
+
+    fn load(&self, ctx: &FineContext<'_>, x: f32, y: f32) -> Rgba {
+        let x = extend_texel(x, self.extents[0], self.x_extend) + self.atlas_offset[0];
+        let y = extend_texel(y, self.extents[1], self.y_extend) + self.atlas_offset[1];
+        ctx.decode(premultiply(ctx.atlas.load(x as i32, y as i32)))
+    }
+
+    fn sample_bilinear(&self, ctx: &FineContext<'_>, [u, v]: [f32; 2]) -> Rgba {
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (x0, y0) = (u.floor(), v.floor());
+        let (fx, fy) = (u - x0, v - y0);
+        let a = self.load(ctx, x0, y0);
+        let b = self.load(ctx, x0 + 1.0, y0);
+        let c = self.load(ctx, x0, y0 + 1.0);
+        let d = self.load(ctx, x0 + 1.0, y0 + 1.0);
+        std::array::from_fn(|i| {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            top + (bottom - top) * fy
+        })
+    }
+
+    /// Sample a mip level bilinearly, where level zero is the image itself. The texels of
+    /// each level cover the same area as those of the image.
+    fn sample_level(
+        &self,
+        ctx: &FineContext<'_>,
+        levels: &MipLevels,
+        level: u32,
+        [u, v]: [f32; 2],
+    ) -> Rgba {
+        if level == 0 {
+            return self.sample_bilinear(ctx, [u, v]);
+        }
+        let size = self.extents.map(|extent| extent as u32);
+        let y = (1..level).fold(levels.atlas_offset[1], |y, i| {
+            y + size[1].div_ceil(1 << i) as f32
+        });
+        let extents = size.map(|size| size.div_ceil(1 << level) as f32);
+        let level_image = Self {
+            atlas_offset: [levels.atlas_offset[0], y],
+            extents,
+            ..*self
+        };
+        level_image.sample_bilinear(
+            ctx,
+            [
+                u * extents[0] / self.extents[0],
+                v * extents[1] / self.extents[1],
+            ],
+        )
+    }
+
+    fn sample(&self, ctx: &FineContext<'_>, xy: [f32; 2]) -> Rgba {
+        let [u, v] = apply_transform(&self.transform, xy);
+        if self.quality == IMAGE_QUALITY_LOW {
+            return scale(self.load(ctx, u.floor(), v.floor()), self.alpha);
+        }
+        // The level of detail is the log of the largest derivative of the texel coordinates.
+        let atlas_offset = self.atlas_offset.map(|offset| offset as u32);
+        let levels = ctx.mip_levels((atlas_offset[0] << 16) | atlas_offset[1]);
+        let [dudx, dvdx, dudy, dvdy, ..] = self.transform;
+        let derivative = dudx.hypot(dvdx).max(dudy.hypot(dvdy));
+        let lod = derivative.log2().clamp(0.0, levels.count as f32);
+        let rgba = if self.quality == IMAGE_QUALITY_MEDIUM {
+            let level = lod.round_ties_even() as u32;
+            self.sample_level(ctx, &levels, level, [u, v])
+        } else {
+            // Bicubic filtering isn't implemented, so high quality blends the two nearest
+            // levels.
+            let level = lod.floor();
+            let t = lod - level;
+            let above = self.sample_level(ctx, &levels, level as u32, [u, v]);
+            if t == 0.0 {
+                above
+            } else {
+                let below = self.sample_level(ctx, &levels, level as u32 + 1, [u, v]);
+                std::array::from_fn(|i| above[i] + (below[i] - above[i]) * t)
+            }
+        };
+        scale(rgba, self.alpha)
+    }
+}
+
+/// The mip levels of an image, stacked in a column of the atlas, see `crate::mipmap`.
+struct MipLevels {
+    atlas_offset: [f32; 2],
+    count: u32,
+}
+
+/// Map an integral texel coordinate into `0..size` according to an extend mode.
+fn extend_texel(x: f32, size: f32, mode: u32) -> f32 {
+    match mode {
+        EXTEND_PAD => x.clamp(0.0, size - 1.0),
+        EXTEND_REPEAT => x.rem_euclid(size),
+        _ => {
+            let x = x.rem_euclid(2.0 * size);
+            if x < size { x } else { 2.0 * size - 1.0 - x }
+        }
+    }
+}
+
+fn split_index_mode(index_mode: u32) -> (u32, u32) {
+    (index_mode >> 2, index_mode & 0x3)
+}
+
+fn extend_mode(t: f32, mode: u32) -> f32 {
+    match mode {
+        EXTEND_PAD => t.clamp(0.0, 1.0),
+        EXTEND_REPEAT => t - t.floor(),
+        _ => (t - 2.0 * (0.5 * t).round()).abs(),
+    }
+}
+
+fn sample_ramp(ctx: &FineContext<'_>, index: u32, t: f32) -> Rgba {
+    let x = (t * (GRADIENT_WIDTH - 1) as f32).round() as i32;
+    ctx.decode(ctx.gradients.load(x, index as i32))
+}
+
+fn apply_transform(transform: &[f32; 6], [x, y]: [f32; 2]) -> [f32; 2] {
+    [
+        transform[0] * x + transform[2] * y + transform[4],
+        transform[1] * x + transform[3] * y + transform[5],
+    ]
+}
+
+/// Like WGSL's `sign`, which is zero for zero (unlike [`f32::signum`]).
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+/// Unpack a color packed as RGBA8 with red in the least significant byte.
+fn unpack_rgba(packed: u32) -> Rgba {
+    packed.to_le_bytes().map(|c| c as f32 * (1.0 / 255.0))
+}
+
+fn premultiply([r, g, b, a]: Rgba) -> Rgba {
+    [r * a, g * a, b * a, a]
+}
+
+fn scale(rgba: Rgba, s: f32) -> Rgba {
+    rgba.map(|c| c * s)
+}
+
+/// Approximation of the error function, accurate to about 1e-4.
+fn erf7(x: f32) -> f32 {
+    let x = x * std::f32::consts::FRAC_2_SQRT_PI;
+    let xx = x * x;
+    let x = x + (0.24295 + (0.03395 + 0.0104 * xx) * xx) * (x * xx);
+    x / (1.0 + x * x).sqrt()
+}
+
+/// Coverage of a rounded rectangle centered on the origin convolved with a gaussian.
+///
+/// This is the closed form approximation described in
+/// <https://raphlinus.github.io/graphics/2020/04/21/blurred-rounded-rects.html>.
+fn blurred_rounded_rect(x: f32, y: f32, width: f32, height: f32, radius: f32, std_dev: f32) -> f32 {
+    let s = std_dev.max(1e-6);
+    let s_inv = 1.0 / s;
+    let rmax = 0.5 * width.min(height);
+    let r0 = radius.hypot(1.15 * s).min(rmax);
+    let r1 = radius.hypot(2.0 * s).min(rmax);
+    let exponent = 2.0 * r1 / r0;
+    // Pull in the long end, making the shape less eccentric.
+    let delta = 1.25
+        * s
+        * ((-(0.5 * s_inv * width).powi(2)).exp() - (-(0.5 * s_inv * height).powi(2)).exp());
+    let w = width + delta.min(0.0);
+    let h = height - delta.max(0.0);
+    let p0 = [x.abs() - 0.5 * w + r1, y.abs() - 0.5 * h + r1];
+    let d_pos = (p0[0].max(0.0).powf(exponent) + p0[1].max(0.0).powf(exponent))
+        .powf(exponent.recip());
+    let d_neg = p0[0].max(p0[1]).min(0.0);
+    let d = d_pos + d_neg - r1;
+    // Small rectangles never reach full opacity.
+    let peak = erf7(0.5 * FRAC_1_SQRT_2 * s_inv * w) * erf7(0.5 * FRAC_1_SQRT_2 * s_inv * h);
+    peak * 0.5 * (1.0 - erf7(FRAC_1_SQRT_2 * s_inv * d))
+}
+
+// Blend modes, see `blend.wgsl`.
+const MIX_NORMAL: u32 = 0;
+const MIX_MULTIPLY: u32 = 1;
+const MIX_SCREEN: u32 = 2;
+const MIX_OVERLAY: u32 = 3;
+const MIX_DARKEN: u32 = 4;
+const MIX_LIGHTEN: u32 = 5;
+const MIX_COLOR_DODGE: u32 = 6;
+const MIX_COLOR_BURN: u32 = 7;
+const MIX_HARD_LIGHT: u32 = 8;
+const MIX_SOFT_LIGHT: u32 = 9;
+const MIX_DIFFERENCE: u32 = 10;
+const MIX_EXCLUSION: u32 = 11;
+const MIX_HUE: u32 = 12;
+const MIX_SATURATION: u32 = 13;
+const MIX_COLOR: u32 = 14;
+const MIX_LUMINOSITY: u32 = 15;
+
+const COMPOSE_CLEAR: u32 = 0;
+const COMPOSE_COPY: u32 = 1;
+const COMPOSE_DEST: u32 = 2;
+const COMPOSE_SRC_OVER: u32 = 3;
+const COMPOSE_DEST_OVER: u32 = 4;
+const COMPOSE_SRC_IN: u32 = 5;
+const COMPOSE_DEST_IN: u32 = 6;
+const COMPOSE_SRC_OUT: u32 = 7;
+const COMPOSE_DEST_OUT: u32 = 8;
+const COMPOSE_SRC_ATOP: u32 = 9;
+const COMPOSE_DEST_ATOP: u32 = 10;
+const COMPOSE_XOR: u32 = 11;
+const COMPOSE_PLUS: u32 = 12;
+const COMPOSE_PLUS_LIGHTER: u32 = 13;
+
+const BLEND_DEFAULT: u32 = (MIX_NORMAL << 8) | COMPOSE_SRC_OVER;
+const EPSILON: f32 = 1e-10;
+
+type Rgb = [f32; 3];
+
+/// Blend premultiplied `src` onto premultiplied `backdrop`.
+///
+/// `mode` packs the mix mode in bits 8-15 and the compose mode in the low byte. The clip
+/// mix mode (128) behaves like normal source-over compositing.
+fn blend_mix_compose(backdrop: Rgba, src: Rgba, mode: u32) -> Rgba {
+    if (mode & 0x7fff) == BLEND_DEFAULT {
+        return std::array::from_fn(|i| backdrop[i] * (1.0 - src[3]) + src[i]);
+    }
+    let inv_src_a = 1.0 / (src[3] + EPSILON);
+    let cs: Rgb = std::array::from_fn(|i| src[i] * inv_src_a);
+    let inv_backdrop_a = 1.0 / (backdrop[3] + EPSILON);
+    let cb: Rgb = std::array::from_fn(|i| backdrop[i] * inv_backdrop_a);
+    let mixed = blend_mix(cb, cs, (mode >> 8) & 0x7f);
+    let cs: Rgb = std::array::from_fn(|i| cs[i] + (mixed[i] - cs[i]) * backdrop[3]);
+    let compose_mode = mode & 0xff;
+    if compose_mode == COMPOSE_SRC_OVER {
+        let co: Rgb = std::array::from_fn(|i| backdrop[i] + (cs[i] - backdrop[i]) * src[3]);
+        [co[0], co[1], co[2], src[3] + backdrop[3] * (1.0 - src[3])]
+    } else {
+        blend_compose(cb, cs, backdrop[3], src[3], compose_mode)
+    }
+}
+
+fn blend_mix(cb: Rgb, cs: Rgb, mode: u32) -> Rgb {
+    let per_channel = |f: fn(f32, f32) -> f32| -> Rgb { std::array::from_fn(|i| f(cb[i], cs[i])) };
+    match mode {
+        MIX_MULTIPLY => per_channel(|cb, cs| cb * cs),
+        MIX_SCREEN => per_channel(screen),
+        MIX_OVERLAY => per_channel(|cb, cs| hard_light(cs, cb)),
+        MIX_DARKEN => per_channel(f32::min),
+        MIX_LIGHTEN => per_channel(f32::max),
+        MIX_COLOR_DODGE => per_channel(color_dodge),
+        MIX_COLOR_BURN => per_channel(color_burn),
+        MIX_HARD_LIGHT => per_channel(hard_light),
+        MIX_SOFT_LIGHT => per_channel(soft_light),
+        MIX_DIFFERENCE => per_channel(|cb, cs| (cb - cs).abs()),
+        MIX_EXCLUSION => per_channel(|cb, cs| cb + cs - 2.0 * cb * cs),
+        MIX_HUE => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+        MIX_SATURATION => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+        MIX_COLOR => set_lum(cs, lum(cb)),
+        MIX_LUMINOSITY => set_lum(cb, lum(cs)),
+        _ => cs,
+    }
+}
+
+fn screen(cb: f32, cs: f32) -> f32 {
+    cb + cs - cb * cs
+}
+
+fn color_dodge(cb: f32, cs: f32) -> f32 {
+    if cb == 0.0 {
+        0.0
+    } else if cs == 1.0 {
+        1.0
+    } else {
+        (cb / (1.0 - cs)).min(1.0)
+    }
+}
+
+fn color_burn(cb: f32, cs: f32) -> f32 {
+    if cb == 1.0 {
+        1.0
+    } else if cs == 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - cb) / cs).min(1.0)
+    }
+}
+
+fn hard_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb * 2.0 * cs
+    } else {
+        screen(cb, 2.0 * cs - 1.0)
+    }
+}
+
+fn soft_light(cb: f32, cs: f32) -> f32 {
+    let d = if cb <= 0.25 {
+        ((16.0 * cb - 12.0) * cb + 4.0) * cb
+    } else {
+        cb.sqrt()
+    };
+    if cs <= 0.5 {
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+    } else {
+        cb + (2.0 * cs - 1.0) * (d - cb)
+    }
+}
+
+fn lum(c: Rgb) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+fn sat(c: Rgb) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn clip_color(c: Rgb) -> Rgb {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut c = c;
+    if n < 0.0 {
+        c = c.map(|c| l + (c - l) * l / (l - n + EPSILON));
+    }
+    if x > 1.0 {
+        c = c.map(|c| l + (c - l) * (1.0 - l) / (x - l + EPSILON));
+    }
+    c
+}
+
+fn set_lum(c: Rgb, l: f32) -> Rgb {
+    let d = l - lum(c);
+    clip_color(c.map(|c| c + d))
+}
+
+fn set_sat(c: Rgb, s: f32) -> Rgb {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    if max > min {
+        c.map(|c| (c - min) * s / (max - min))
+    } else {
+        [0.0; 3]
+    }
+}
+
+/// Porter-Duff compositing of separated colors, returning a premultiplied result.
+fn blend_compose(cb: Rgb, cs: Rgb, ab: f32, as_: f32, mode: u32) -> Rgba {
+    let (fa, fb) = match mode {
+        COMPOSE_CLEAR => (0.0, 0.0),
+        COMPOSE_COPY => (1.0, 0.0),
+        COMPOSE_DEST => (0.0, 1.0),
+        COMPOSE_DEST_OVER => (1.0 - ab, 1.0),
+        COMPOSE_SRC_IN => (ab, 0.0),
+        COMPOSE_DEST_IN => (0.0, as_),
+        COMPOSE_SRC_OUT => (1.0 - ab, 0.0),
+        COMPOSE_DEST_OUT => (0.0, 1.0 - as_),
+        COMPOSE_SRC_ATOP => (ab, 1.0 - as_),
+        COMPOSE_DEST_ATOP => (1.0 - ab, as_),
+        COMPOSE_XOR => (1.0 - ab, 1.0 - as_),
+        COMPOSE_PLUS => (1.0, 1.0),
+        COMPOSE_PLUS_LIGHTER => {
+            let co: Rgb = std::array::from_fn(|i| (as_ * cs[i] + ab * cb[i]).min(1.0));
+            return [co[0], co[1], co[2], (as_ + ab).min(1.0)];
+        }
+        _ => (1.0, 1.0 - as_),
+    };
+    let as_fa = as_ * fa;
+    let ab_fb = ab * fb;
+    let co: Rgb = std::array::from_fn(|i| as_fa * cs[i] + ab_fb * cb[i]);
+    [co[0], co[1], co[2], (as_fa + ab_fb).min(1.0)]
+}
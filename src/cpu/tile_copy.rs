@@ -0,0 +1,33 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Copying the tiles of a tiled render into the target, mirroring the `tile_copy` shader.
+
+use vello_shaders::cpu::CpuBinding;
+
+use crate::render::TileCopyConfig;
+
+pub(crate) fn tile_copy(_n_wg: u32, resources: &[CpuBinding<'_>]) {
+    let CpuBinding::BufferRW(config) = resources[0] else {
+        panic!("tile_copy config must be a buffer");
+    };
+    let config: TileCopyConfig = bytemuck::pod_read_unaligned(&config.borrow());
+    let (CpuBinding::BufferRW(tile), CpuBinding::BufferRW(output)) = (&resources[1], &resources[2])
+    else {
+        panic!("tile_copy tile and output must be storage images");
+    };
+    let tile = tile.borrow();
+    let mut output = output.borrow_mut();
+    let [x0, y0] = config.offset.map(|v| v as usize);
+    let [tile_width, tile_height] = config.tile_size.map(|v| v as usize);
+    let [target_width, target_height] = config.target_size.map(|v| v as usize);
+    let [source_x, source_y] = config.source.map(|v| v as usize);
+    let [source_width, source_height] = config.source_size.map(|v| v as usize);
+    // The images are either RGBA8 or RGBA16 float, depending on the output format.
+    let bpp = tile.len() / (source_width * source_height).max(1);
+    let width = tile_width.min(target_width.saturating_sub(x0));
+    for y in 0..tile_height.min(target_height.saturating_sub(y0)) {
+        let src = &tile[((source_y + y) * source_width + source_x) * bpp..][..width * bpp];
+        output[((y0 + y) * target_width + x0) * bpp..][..width * bpp].copy_from_slice(src);
+    }
+}
@@ -0,0 +1,333 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The regions of a scene which changed since a previous version of it.
+//!
+//! Both scenes are decoded into a list of the draw objects and layers they draw, each with
+//! a hash of everything which affects its pixels, including the layers it is drawn in.
+//! Draw objects only found in one of the scenes are damaged, and so are the draw objects
+//! whose order changed.
+
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::SQRT_2;
+use std::fmt::{self, Debug, Write as _};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use peniko::kurbo::{Rect, Shape};
+use peniko::{Brush, Image, Style};
+use vello_encoding::Encoding;
+
+use crate::Scene;
+use crate::decode::{self, Command};
+use crate::filter::FilterLayer;
+
+/// A draw object or layer, in the order it was drawn.
+struct Item {
+    /// A hash of the item and the layers it is drawn in.
+    key: u64,
+    /// The pixels the item can change.
+    bounds: Rect,
+}
+
+impl Scene {
+    /// Returns the regions which need to be rendered again to update a render of
+    /// `previous` to this scene.
+    ///
+    /// The regions are rectangles in the coordinates of the scene, rounded out to whole
+    /// units, and are disjoint. They can be passed to
+    /// [`Renderer::render_damage_to_texture`](crate::Renderer::render_damage_to_texture)
+    /// when the scene is rendered without a transform. Regions whose extent isn't known,
+    /// such as the area of a layer with a [`Compose`](peniko::Compose) mode which clears
+    /// outside of its content, can be infinite.
+    ///
+    /// Draw objects are compared by their geometry, brushes and the layers they are drawn
+    /// in, so scenes which are rebuilt every frame can be compared. Images are compared by
+    /// the id of their data, rather than by their pixels.
+    pub fn damage(&self, previous: &Self) -> Vec<Rect> {
+        let old = items(&previous.encoding, &previous.filters);
+        let new = items(&self.encoding, &self.filters);
+        let mut counts: HashMap<u64, isize> = HashMap::new();
+        for item in &old {
+            *counts.entry(item.key).or_default() += 1;
+        }
+        for item in &new {
+            *counts.entry(item.key).or_default() -= 1;
+        }
+        let changed: HashSet<u64> = counts
+            .into_iter()
+            .filter_map(|(key, count)| (count != 0).then_some(key))
+            .collect();
+        let mut damage: Vec<Rect> = old
+            .iter()
+            .chain(&new)
+            .filter(|item| changed.contains(&item.key))
+            .map(|item| item.bounds)
+            .collect();
+        // The remaining items are drawn in both scenes, but may have been reordered.
+        let old: Vec<&Item> = old.iter().filter(|i| !changed.contains(&i.key)).collect();
+        let new: Vec<&Item> = new.iter().filter(|i| !changed.contains(&i.key)).collect();
+        let prefix = old
+            .iter()
+            .zip(&new)
+            .take_while(|(a, b)| a.key == b.key)
+            .count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a.key == b.key)
+            .count();
+        damage.extend(
+            old[prefix..old.len() - suffix]
+                .iter()
+                .chain(&new[prefix..new.len() - suffix])
+                .map(|item| item.bounds),
+        );
+        merge(damage)
+    }
+}
+
+/// The items drawn by `encoding`, whose filter layers are found in `filters`.
+fn items(encoding: &Encoding, filters: &[FilterLayer]) -> Vec<Item> {
+    let layers: HashMap<u64, &FilterLayer> = filters
+        .iter()
+        .map(|layer| (layer.image.data.id(), layer))
+        .collect();
+    let everywhere = Rect::new(
+        f64::NEG_INFINITY,
+        f64::NEG_INFINITY,
+        f64::INFINITY,
+        f64::INFINITY,
+    );
+    // The clip rectangle and key of each open layer.
+    let mut clips = vec![(everywhere, 0)];
+    let mut items = Vec::new();
+    decode::decode(encoding, |command| {
+        let (clip, layer_key) = *clips.last().unwrap();
+        let mut hasher = DefaultHasher::new();
+        layer_key.hash(&mut hasher);
+        let bounds = match &command {
+            Command::PushLayer {
+                clip: path,
+                transform,
+                fill,
+                blend,
+                alpha,
+            } => {
+                hash_debug(&mut hasher, &(path, transform, fill, blend));
+                alpha.to_bits().hash(&mut hasher);
+                let rect = clip.intersect((*transform * path).bounding_box());
+                clips.push((rect, hasher.finish()));
+                // The whole layer is damaged when it changes, as compose modes can change
+                // the pixels of the layer outside of its content.
+                rect
+            }
+            Command::PopLayer => {
+                if clips.len() > 1 {
+                    clips.pop();
+                }
+                return;
+            }
+            Command::Draw {
+                path,
+                transform,
+                style,
+                brush: Brush::Image(image),
+                brush_transform,
+            } if layers.contains_key(&image.data.id()) => {
+                // The placeholder image of a filter layer is identified by the content of the
+                // layer, as the id of the image changes whenever the scene is rebuilt.
+                hash_debug(&mut hasher, &(path, transform, style, brush_transform));
+                hash_layer(&mut hasher, layers[&image.data.id()], filters);
+                clip.intersect((*transform * path).bounding_box())
+            }
+            command => {
+                hash_command(&mut hasher, command);
+                let mut bounds = None;
+                let readable = match command {
+                    Command::Glyphs { run, glyphs, brush } => {
+                        decode::outline_glyphs(run, glyphs, brush, |command| {
+                            bounds = union(bounds, outline_bounds(&command));
+                        })
+                    }
+                    command => {
+                        bounds = outline_bounds(command);
+                        true
+                    }
+                };
+                // Glyphs whose font can't be read are assumed to cover the whole layer.
+                match bounds {
+                    Some(bounds) => clip.intersect(bounds),
+                    None if !readable => clip,
+                    None => return,
+                }
+            }
+        };
+        items.push(Item {
+            key: hasher.finish(),
+            bounds,
+        });
+    });
+    items
+}
+
+/// Hash a drawing command, other than a layer.
+fn hash_command(hasher: &mut DefaultHasher, command: &Command<'_>) {
+    match command {
+        Command::Draw {
+            path,
+            transform,
+            style,
+            brush,
+            brush_transform,
+        } => {
+            hash_debug(hasher, &(path, transform, style, brush_transform));
+            hash_brush(hasher, brush);
+        }
+        Command::BlurredRect {
+            path,
+            transform,
+            rect_transform,
+            rect,
+            color,
+            radius,
+            std_dev,
+        } => hash_debug(
+            hasher,
+            &(
+                path,
+                transform,
+                rect_transform,
+                rect,
+                color,
+                radius,
+                std_dev,
+            ),
+        ),
+        Command::Glyphs { run, glyphs, brush } => {
+            run.font.data.id().hash(hasher);
+            run.font.index.hash(hasher);
+            let glyph_transform = run.glyph_transform.as_ref().map(decode::to_affine);
+            let style = decode::decode_style(&run.style);
+            hash_debug(
+                hasher,
+                &(decode::to_affine(&run.transform), glyph_transform, style),
+            );
+            run.font_size.to_bits().hash(hasher);
+            run.hint.hash(hasher);
+            for glyph in *glyphs {
+                (glyph.id, glyph.x.to_bits(), glyph.y.to_bits()).hash(hasher);
+            }
+            hash_brush(hasher, brush);
+        }
+        Command::PushLayer { .. } | Command::PopLayer => {}
+    }
+}
+
+/// Hash a filter layer by its content, rather than by the id of its image.
+fn hash_layer(hasher: &mut DefaultHasher, layer: &FilterLayer, filters: &[FilterLayer]) {
+    hash_debug(hasher, &(layer.filter, layer.origin));
+    hash_image(hasher, &layer.image);
+    for item in items(&layer.content, filters) {
+        item.key.hash(hasher);
+    }
+    if let Some(mask) = &layer.mask {
+        mask.kind.hash(hasher);
+        for item in items(&mask.content, filters) {
+            item.key.hash(hasher);
+        }
+    }
+}
+
+fn hash_brush(hasher: &mut DefaultHasher, brush: &Brush) {
+    match brush {
+        Brush::Image(image) => {
+            image.data.id().hash(hasher);
+            hash_image(hasher, image);
+        }
+        brush => hash_debug(hasher, brush),
+    }
+}
+
+/// Hash the properties of an image other than its data.
+fn hash_image(hasher: &mut DefaultHasher, image: &Image) {
+    hash_debug(
+        hasher,
+        &(
+            image.format,
+            image.width,
+            image.height,
+            image.x_extend,
+            image.y_extend,
+            image.quality,
+        ),
+    );
+    image.alpha.to_bits().hash(hasher);
+}
+
+/// Hash the debug representation of a value, which is exact for the floats in paths and
+/// brushes.
+fn hash_debug(hasher: &mut DefaultHasher, value: &impl Debug) {
+    struct Writer<'a>(&'a mut DefaultHasher);
+
+    impl fmt::Write for Writer<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.write(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    write!(Writer(hasher), "{value:?}").unwrap();
+}
+
+/// The bounding box of the area covered by a drawing command.
+fn outline_bounds(command: &Command<'_>) -> Option<Rect> {
+    match command {
+        Command::Draw {
+            path,
+            transform,
+            style,
+            ..
+        } => {
+            let mut rect = (*transform * path).bounding_box();
+            if let Style::Stroke(stroke) = style {
+                // Miter joins and square caps extend furthest from the path.
+                let reach = stroke.miter_limit.max(SQRT_2);
+                let extent = 0.5 * stroke.width * reach * decode::max_scale(*transform);
+                rect = rect.inflate(extent, extent);
+            }
+            Some(rect)
+        }
+        Command::BlurredRect {
+            path, transform, ..
+        } => Some((*transform * path).bounding_box()),
+        Command::Glyphs { .. } | Command::PushLayer { .. } | Command::PopLayer => None,
+    }
+}
+
+fn union(a: Option<Rect>, b: Option<Rect>) -> Option<Rect> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Round out the damaged rectangles, and merge those which overlap.
+fn merge(damage: Vec<Rect>) -> Vec<Rect> {
+    let mut merged: Vec<Rect> = Vec::new();
+    for rect in damage {
+        if rect.width() <= 0.0 || rect.height() <= 0.0 {
+            continue;
+        }
+        let mut rect = rect.expand();
+        // Merging can make a rectangle overlap others which it didn't before.
+        while let Some(ix) = merged
+            .iter()
+            .position(|other| other.intersect(rect).area() > 0.0)
+        {
+            rect = rect.union(merged.swap_remove(ix));
+        }
+        merged.push(rect);
+    }
+    merged
+}
@@ -3,8 +3,11 @@
 
 #[cfg(all(feature = "debug_layers", feature = "wgpu"))]
 mod renderer;
-#[cfg(all(feature = "debug_layers", feature = "wgpu"))]
-mod validate;
+#[cfg(all(
+    feature = "debug_layers",
+    any(feature = "wgpu", all(test, feature = "cpu"))
+))]
+pub(crate) mod validate;
 
 use std::fmt::Debug;
 
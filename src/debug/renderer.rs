@@ -1,6 +1,8 @@
 // Copyright 2023 the Vello Authors
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use std::collections::HashMap;
+
 use {
     bytemuck::{Pod, Zeroable, offset_of},
     peniko::color::{OpaqueColor, Srgb, palette},
@@ -16,6 +18,12 @@ use crate::{
     wgpu_engine::WgpuEngine,
 };
 pub(crate) struct DebugRenderer {
+    module: wgpu::ShaderModule,
+    /// The pipelines for each target format which has been rendered to, created on demand.
+    pipelines: HashMap<wgpu::TextureFormat, DebugPipelines>,
+}
+
+struct DebugPipelines {
     // `clear_tint` slightly darkens the output from the vello renderer to make the debug overlays
     // more distinguishable.
     clear_tint: ShaderId,
@@ -26,20 +34,173 @@ pub(crate) struct DebugRenderer {
 }
 
 impl DebugRenderer {
-    pub fn new(
-        device: &wgpu::Device,
-        target_format: wgpu::TextureFormat,
-        engine: &mut WgpuEngine,
-    ) -> Self {
+    pub fn new(device: &wgpu::Device) -> Self {
         let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("debug layers"),
             source: wgpu::ShaderSource::Wgsl(SHADERS.into()),
         });
+        Self {
+            module,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Create the pipelines for rendering to targets of `target_format`, if they don't exist
+    /// yet.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        target_format: wgpu::TextureFormat,
+        engine: &mut WgpuEngine,
+    ) {
+        if !self.pipelines.contains_key(&target_format) {
+            let pipelines = DebugPipelines::new(device, &self.module, target_format, engine);
+            self.pipelines.insert(target_format, pipelines);
+        }
+    }
+
+    /// Render the debug layers over `target`.
+    ///
+    /// [`Self::prepare`] must have been called for the format of `target`.
+    pub fn render(
+        &self,
+        recording: &mut Recording,
+        target: ImageProxy,
+        captured: &CapturedBuffers,
+        bump: &BumpAllocators,
+        params: &RenderParams,
+        downloads: &DebugDownloads<'_>,
+        layers: DebugLayers,
+    ) {
+        if layers.is_empty() {
+            return;
+        }
+        let pipelines = self
+            .pipelines
+            .get(&target.format.to_wgpu())
+            .expect("debug pipelines must be prepared for the target format");
+
+        let (unpaired_pts_len, unpaired_pts_buf) = if layers.contains(DebugLayers::VALIDATION) {
+            // TODO: have this write directly to a GPU buffer?
+            let unpaired_pts: Vec<LineEndpoint> =
+                validate_line_soup(bytemuck::cast_slice(&downloads.lines.get_mapped_range()));
+            if unpaired_pts.is_empty() {
+                (0, None)
+            } else {
+                (
+                    unpaired_pts.len(),
+                    Some(recording.upload(
+                        "vello.debug.unpaired_points",
+                        bytemuck::cast_slice(&unpaired_pts[..]),
+                    )),
+                )
+            }
+        } else {
+            (0, None)
+        };
+
+        let uniforms = Uniforms {
+            width: params.width,
+            height: params.height,
+        };
+        let uniforms_buf = ResourceProxy::Buffer(
+            recording.upload_uniform("vello.debug_uniforms", bytemuck::bytes_of(&uniforms)),
+        );
+
+        let linepoints_uniforms = [
+            LinepointsUniforms::new(palette::css::DARK_CYAN.discard_alpha(), 10.),
+            LinepointsUniforms::new(palette::css::RED.discard_alpha(), 80.),
+        ];
+        let linepoints_uniforms_buf = recording.upload_uniform(
+            "vello.debug.linepoints_uniforms",
+            bytemuck::bytes_of(&linepoints_uniforms),
+        );
+
+        recording.draw(DrawParams {
+            shader_id: pipelines.clear_tint,
+            instance_count: 1,
+            vertex_count: 4,
+            vertex_buffer: None,
+            resources: vec![],
+            target,
+            clear_color: None,
+        });
+        if layers.contains(DebugLayers::BOUNDING_BOXES) {
+            recording.draw(DrawParams {
+                shader_id: pipelines.bboxes,
+                instance_count: captured.sizes.path_bboxes.len(),
+                vertex_count: 5,
+                vertex_buffer: Some(captured.path_bboxes),
+                resources: vec![uniforms_buf],
+                target,
+                clear_color: None,
+            });
+        }
+        if layers.contains(DebugLayers::LINESOUP_SEGMENTS) {
+            recording.draw(DrawParams {
+                shader_id: pipelines.linesoup,
+                instance_count: bump.lines,
+                vertex_count: 4,
+                vertex_buffer: Some(captured.lines),
+                resources: vec![uniforms_buf],
+                target,
+                clear_color: None,
+            });
+        }
+        if layers.contains(DebugLayers::LINESOUP_POINTS) {
+            recording.draw(DrawParams {
+                shader_id: pipelines.linesoup_points,
+                instance_count: bump.lines,
+                vertex_count: 4,
+                vertex_buffer: Some(captured.lines),
+                resources: vec![
+                    uniforms_buf,
+                    ResourceProxy::BufferRange {
+                        proxy: linepoints_uniforms_buf,
+                        offset: 0,
+                        size: size_of::<LinepointsUniforms>() as u64,
+                    },
+                ],
+                target,
+                clear_color: None,
+            });
+        }
+        if let Some(unpaired_pts_buf) = unpaired_pts_buf {
+            recording.draw(DrawParams {
+                shader_id: pipelines.unpaired_points,
+                instance_count: unpaired_pts_len.try_into().unwrap(),
+                vertex_count: 4,
+                vertex_buffer: Some(unpaired_pts_buf),
+                resources: vec![
+                    uniforms_buf,
+                    ResourceProxy::BufferRange {
+                        proxy: linepoints_uniforms_buf,
+                        offset: size_of::<LinepointsUniforms>() as u64,
+                        size: size_of::<LinepointsUniforms>() as u64,
+                    },
+                ],
+                target,
+                clear_color: None,
+            });
+            recording.free_buffer(unpaired_pts_buf);
+        }
 
+        recording.free_resource(uniforms_buf);
+        recording.free_buffer(linepoints_uniforms_buf);
+    }
+}
+
+impl DebugPipelines {
+    fn new(
+        device: &wgpu::Device,
+        module: &wgpu::ShaderModule,
+        target_format: wgpu::TextureFormat,
+        engine: &mut WgpuEngine,
+    ) -> Self {
         let clear_tint = engine.add_render_shader(
             device,
             "vello.debug.clear_tint",
-            &module,
+            module,
             "full_screen_quad_vert",
             "solid_color_frag",
             wgpu::PrimitiveTopology::TriangleStrip,
@@ -61,7 +222,7 @@ impl DebugRenderer {
         let bboxes = engine.add_render_shader(
             device,
             "vello.debug.bbox",
-            &module,
+            module,
             "bbox_vert",
             "solid_color_frag",
             wgpu::PrimitiveTopology::LineStrip,
@@ -92,7 +253,7 @@ impl DebugRenderer {
         let linesoup = engine.add_render_shader(
             device,
             "vello.debug.linesoup",
-            &module,
+            module,
             "linesoup_vert",
             "solid_color_frag",
             wgpu::PrimitiveTopology::TriangleStrip,
@@ -123,7 +284,7 @@ impl DebugRenderer {
         let linesoup_points = engine.add_render_shader(
             device,
             "vello.debug.linesoup_points",
-            &module,
+            module,
             "linepoints_vert",
             "sdf_circle_frag",
             wgpu::PrimitiveTopology::TriangleStrip,
@@ -163,7 +324,7 @@ impl DebugRenderer {
         let unpaired_points = engine.add_render_shader(
             device,
             "vello.debug.unpaired_points",
-            &module,
+            module,
             "linepoints_vert",
             "sdf_circle_frag",
             wgpu::PrimitiveTopology::TriangleStrip,
@@ -206,129 +367,6 @@ impl DebugRenderer {
             unpaired_points,
         }
     }
-
-    pub fn render(
-        &self,
-        recording: &mut Recording,
-        target: ImageProxy,
-        captured: &CapturedBuffers,
-        bump: &BumpAllocators,
-        params: &RenderParams,
-        downloads: &DebugDownloads<'_>,
-        layers: DebugLayers,
-    ) {
-        if layers.is_empty() {
-            return;
-        }
-
-        let (unpaired_pts_len, unpaired_pts_buf) = if layers.contains(DebugLayers::VALIDATION) {
-            // TODO: have this write directly to a GPU buffer?
-            let unpaired_pts: Vec<LineEndpoint> =
-                validate_line_soup(bytemuck::cast_slice(&downloads.lines.get_mapped_range()));
-            if unpaired_pts.is_empty() {
-                (0, None)
-            } else {
-                (
-                    unpaired_pts.len(),
-                    Some(recording.upload(
-                        "vello.debug.unpaired_points",
-                        bytemuck::cast_slice(&unpaired_pts[..]),
-                    )),
-                )
-            }
-        } else {
-            (0, None)
-        };
-
-        let uniforms = Uniforms {
-            width: params.width,
-            height: params.height,
-        };
-        let uniforms_buf = ResourceProxy::Buffer(
-            recording.upload_uniform("vello.debug_uniforms", bytemuck::bytes_of(&uniforms)),
-        );
-
-        let linepoints_uniforms = [
-            LinepointsUniforms::new(palette::css::DARK_CYAN.discard_alpha(), 10.),
-            LinepointsUniforms::new(palette::css::RED.discard_alpha(), 80.),
-        ];
-        let linepoints_uniforms_buf = recording.upload_uniform(
-            "vello.debug.linepoints_uniforms",
-            bytemuck::bytes_of(&linepoints_uniforms),
-        );
-
-        recording.draw(DrawParams {
-            shader_id: self.clear_tint,
-            instance_count: 1,
-            vertex_count: 4,
-            vertex_buffer: None,
-            resources: vec![],
-            target,
-            clear_color: None,
-        });
-        if layers.contains(DebugLayers::BOUNDING_BOXES) {
-            recording.draw(DrawParams {
-                shader_id: self.bboxes,
-                instance_count: captured.sizes.path_bboxes.len(),
-                vertex_count: 5,
-                vertex_buffer: Some(captured.path_bboxes),
-                resources: vec![uniforms_buf],
-                target,
-                clear_color: None,
-            });
-        }
-        if layers.contains(DebugLayers::LINESOUP_SEGMENTS) {
-            recording.draw(DrawParams {
-                shader_id: self.linesoup,
-                instance_count: bump.lines,
-                vertex_count: 4,
-                vertex_buffer: Some(captured.lines),
-                resources: vec![uniforms_buf],
-                target,
-                clear_color: None,
-            });
-        }
-        if layers.contains(DebugLayers::LINESOUP_POINTS) {
-            recording.draw(DrawParams {
-                shader_id: self.linesoup_points,
-                instance_count: bump.lines,
-                vertex_count: 4,
-                vertex_buffer: Some(captured.lines),
-                resources: vec![
-                    uniforms_buf,
-                    ResourceProxy::BufferRange {
-                        proxy: linepoints_uniforms_buf,
-                        offset: 0,
-                        size: size_of::<LinepointsUniforms>() as u64,
-                    },
-                ],
-                target,
-                clear_color: None,
-            });
-        }
-        if let Some(unpaired_pts_buf) = unpaired_pts_buf {
-            recording.draw(DrawParams {
-                shader_id: self.unpaired_points,
-                instance_count: unpaired_pts_len.try_into().unwrap(),
-                vertex_count: 4,
-                vertex_buffer: Some(unpaired_pts_buf),
-                resources: vec![
-                    uniforms_buf,
-                    ResourceProxy::BufferRange {
-                        proxy: linepoints_uniforms_buf,
-                        offset: size_of::<LinepointsUniforms>() as u64,
-                        size: size_of::<LinepointsUniforms>() as u64,
-                    },
-                ],
-                target,
-                clear_color: None,
-            });
-            recording.free_buffer(unpaired_pts_buf);
-        }
-
-        recording.free_resource(uniforms_buf);
-        recording.free_buffer(linepoints_uniforms_buf);
-    }
 }
 
 #[derive(Copy, Clone, Zeroable, Pod)]
@@ -0,0 +1,563 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reconstruction of drawing commands from a scene's encoding.
+//!
+//! This is the inverse of the methods on [`Scene`](crate::Scene) which draw into the
+//! encoding, and is used by the exporters, the CPU stroker, hit testing and the pick buffer.
+//! Glyph runs are returned as they were drawn, and can be expanded into the outlines of their
+//! glyphs with [`outline_glyphs`], which the GPU pipeline instead does when resolving the
+//! encoding.
+
+use std::collections::HashMap;
+
+use cosmyc_text::ttf_parser;
+use peniko::color::{AlphaColor, Srgb};
+use peniko::kurbo::{Affine, BezPath, Cap, Join, Point, Rect, Stroke};
+use peniko::{BlendMode, Brush, Color, ColorStop, Compose, Fill, Gradient, Mix, Style};
+use vello_encoding::{DrawTag, Encoding, Glyph, GlyphRun, Patch, Transform};
+
+use crate::Scene;
+
+// Bits of `vello_encoding::PathTag`.
+pub(crate) const PATH_TAG_SEGMENT_MASK: u8 = 0x3;
+const PATH_TAG_SUBPATH_END: u8 = 0x4;
+const PATH_TAG_F32: u8 = 0x8;
+pub(crate) const PATH_TAG_PATH: u8 = 0x10;
+const PATH_TAG_TRANSFORM: u8 = 0x20;
+const PATH_TAG_STYLE: u8 = 0x40;
+
+/// A drawing command, in the order it was encoded.
+pub(crate) enum Command<'a> {
+    /// A fill or stroke of a path.
+    Draw {
+        path: BezPath,
+        transform: Affine,
+        style: Style,
+        brush: Brush,
+        brush_transform: Option<Affine>,
+    },
+    /// A blurred rounded rectangle, centered on the origin of `rect_transform` and clipped
+    /// to `path`.
+    BlurredRect {
+        path: BezPath,
+        transform: Affine,
+        rect_transform: Affine,
+        rect: Rect,
+        color: Color,
+        radius: f32,
+        std_dev: f32,
+    },
+    PushLayer {
+        clip: BezPath,
+        transform: Affine,
+        fill: Fill,
+        blend: BlendMode,
+        alpha: f32,
+    },
+    PopLayer,
+    /// A run of glyphs, whose brush is relative to the run's transform.
+    Glyphs {
+        run: &'a GlyphRun,
+        glyphs: &'a [Glyph],
+        brush: Brush,
+    },
+}
+
+/// Decode `encoding`, calling `f` with each command.
+pub(crate) fn decode<'a>(encoding: &'a Encoding, mut f: impl FnMut(Command<'a>)) {
+    decode_indexed(encoding, |_, command| f(command));
+}
+
+/// Decode `encoding`, calling `f` with the index of the draw object of each command in
+/// the encoding's draw tags, and the command.
+pub(crate) fn decode_indexed<'a>(encoding: &'a Encoding, mut f: impl FnMut(usize, Command<'a>)) {
+    let resources = &encoding.resources;
+    let mut patches = HashMap::new();
+    for patch in &resources.patches {
+        match patch {
+            Patch::Ramp {
+                draw_data_offset, ..
+            }
+            | Patch::Image {
+                draw_data_offset, ..
+            } => {
+                patches.insert(*draw_data_offset, patch);
+            }
+            Patch::GlyphRun { .. } => {}
+        }
+    }
+    let mut paths = PathReader {
+        encoding,
+        tag_ix: 0,
+        data_ix: 0,
+        transform_ix: 0,
+        style_ix: 0,
+        transform: Affine::IDENTITY,
+        style: Fill::NonZero.into(),
+    };
+    let mut runs = resources.glyph_runs.iter().peekable();
+    let mut draw_data_ix = 0;
+    for (draw_ix, &tag) in encoding.draw_tags.iter().enumerate() {
+        // The number of words of draw data is stored in the tag.
+        let size = ((tag.0 >> 2) & 0x7) as usize;
+        let Some(data) = encoding.draw_data.get(draw_data_ix..draw_data_ix + size) else {
+            return;
+        };
+        let patch = patches.get(&draw_data_ix).copied();
+        draw_data_ix += size;
+
+        // The brush of a glyph run is encoded where its glyphs will be inserted.
+        if let Some(run) = runs.next_if(|run| run.stream_offsets.draw_tags == draw_ix) {
+            let glyphs = &resources.glyphs[run.glyphs.clone()];
+            if let Some(brush) = decode_brush(tag, data, patch, &resources.color_stops) {
+                f(draw_ix, Command::Glyphs { run, glyphs, brush });
+            }
+            continue;
+        }
+
+        let Some((path, transform)) = paths.next_path() else {
+            return;
+        };
+        let brush_transform = relative_transform(transform, paths.transform);
+        let style = paths.style();
+        if tag == DrawTag::BEGIN_CLIP {
+            let Style::Fill(fill) = style else {
+                continue;
+            };
+            f(
+                draw_ix,
+                Command::PushLayer {
+                    clip: path,
+                    transform,
+                    fill,
+                    blend: blend_mode(data[0]),
+                    alpha: f32::from_bits(data[1]),
+                },
+            );
+        } else if tag == DrawTag::END_CLIP {
+            f(draw_ix, Command::PopLayer);
+        } else if tag == DrawTag::BLUR_RECT {
+            let [width, height, radius, std_dev] = [1, 2, 3, 4].map(|i| f32::from_bits(data[i]));
+            f(
+                draw_ix,
+                Command::BlurredRect {
+                    path,
+                    transform,
+                    rect_transform: paths.transform,
+                    rect: Rect::from_center_size(Point::ZERO, (width as f64, height as f64)),
+                    color: unpack_color(data[0]),
+                    radius,
+                    std_dev,
+                },
+            );
+        } else if let Some(brush) = decode_brush(tag, data, patch, &resources.color_stops) {
+            f(
+                draw_ix,
+                Command::Draw {
+                    path,
+                    transform,
+                    style,
+                    brush,
+                    brush_transform,
+                },
+            );
+        }
+    }
+}
+
+/// Reads paths from the path tag and data streams.
+struct PathReader<'a> {
+    encoding: &'a Encoding,
+    tag_ix: usize,
+    /// The offset of the start point of the next segment in `path_data`.
+    data_ix: usize,
+    transform_ix: usize,
+    style_ix: usize,
+    /// The most recently encoded transform, which applies to the draw object of a path.
+    transform: Affine,
+    style: vello_encoding::Style,
+}
+
+impl PathReader<'_> {
+    /// Read up to the end of the next path, returning it with the transform of its segments.
+    fn next_path(&mut self) -> Option<(BezPath, Affine)> {
+        let encoding = self.encoding;
+        let mut path = BezPath::new();
+        let mut path_transform = self.transform;
+        let mut subpath_start = None;
+        loop {
+            let tag = encoding.path_tags.get(self.tag_ix)?.0;
+            self.tag_ix += 1;
+            if tag & PATH_TAG_TRANSFORM != 0 {
+                self.transform = to_affine(encoding.transforms.get(self.transform_ix)?);
+                self.transform_ix += 1;
+            }
+            if tag & PATH_TAG_STYLE != 0 {
+                self.style = *encoding.styles.get(self.style_ix)?;
+                self.style_ix += 1;
+            }
+            let n_points = (tag & PATH_TAG_SEGMENT_MASK) as usize;
+            if n_points != 0 {
+                path_transform = self.transform;
+                let is_f32 = tag & PATH_TAG_F32 != 0;
+                let words_per_point = if is_f32 { 2 } else { 1 };
+                let mut points = [Point::ZERO; 4];
+                for (i, point) in points.iter_mut().enumerate().take(n_points + 1) {
+                    let ix = self.data_ix + i * words_per_point;
+                    *point = if is_f32 {
+                        let data = encoding.path_data.get(ix..ix + 2)?;
+                        Point::new(
+                            f32::from_bits(data[0]) as f64,
+                            f32::from_bits(data[1]) as f64,
+                        )
+                    } else {
+                        let word = *encoding.path_data.get(ix)?;
+                        Point::new((word as i16) as f64, ((word >> 16) as i16) as f64)
+                    };
+                }
+                let start = *subpath_start.get_or_insert_with(|| {
+                    path.move_to(points[0]);
+                    points[0]
+                });
+                match n_points {
+                    1 => path.line_to(points[1]),
+                    2 => path.quad_to(points[1], points[2]),
+                    _ => path.curve_to(points[1], points[2], points[3]),
+                }
+                self.data_ix += n_points * words_per_point;
+                if tag & PATH_TAG_SUBPATH_END != 0 {
+                    // The start point of the next subpath follows the end of this one.
+                    self.data_ix += words_per_point;
+                    subpath_start = None;
+                    if points[n_points] == start {
+                        path.close_path();
+                    }
+                }
+            }
+            if tag & PATH_TAG_PATH != 0 {
+                return Some((path, path_transform));
+            }
+        }
+    }
+
+    fn style(&self) -> Style {
+        decode_style(&self.style)
+    }
+}
+
+pub(crate) fn decode_style(style: &vello_encoding::Style) -> Style {
+    type S = vello_encoding::Style;
+    let flags = style.flags_and_miter_limit;
+    if flags & S::FLAGS_STYLE_BIT == 0 {
+        return Style::Fill(if flags & S::FLAGS_FILL_BIT != 0 {
+            Fill::EvenOdd
+        } else {
+            Fill::NonZero
+        });
+    }
+    let join = match flags & S::FLAGS_JOIN_MASK {
+        S::FLAGS_JOIN_BITS_MITER => Join::Miter,
+        S::FLAGS_JOIN_BITS_ROUND => Join::Round,
+        _ => Join::Bevel,
+    };
+    let cap = |bits| match bits {
+        S::FLAGS_CAP_BITS_SQUARE => Cap::Square,
+        S::FLAGS_CAP_BITS_ROUND => Cap::Round,
+        _ => Cap::Butt,
+    };
+    let start_cap = cap((flags & S::FLAGS_START_CAP_MASK) >> 2);
+    let end_cap = cap(flags & S::FLAGS_END_CAP_MASK);
+    let miter_limit = f16_to_f32((flags & S::MITER_LIMIT_MASK) as u16);
+    Style::Stroke(
+        Stroke::new(style.line_width as f64)
+            .with_join(join)
+            .with_start_cap(start_cap)
+            .with_end_cap(end_cap)
+            .with_miter_limit(miter_limit as f64),
+    )
+}
+
+fn decode_brush(
+    tag: DrawTag,
+    data: &[u32],
+    patch: Option<&Patch>,
+    color_stops: &[ColorStop],
+) -> Option<Brush> {
+    let point = |i: usize| {
+        Point::new(
+            f32::from_bits(data[i]) as f64,
+            f32::from_bits(data[i + 1]) as f64,
+        )
+    };
+    if tag == DrawTag::COLOR {
+        return Some(Brush::Solid(unpack_color(data[0])));
+    }
+    if tag == DrawTag::IMAGE {
+        let Some(Patch::Image { image, .. }) = patch else {
+            return None;
+        };
+        return Some(Brush::Image(image.clone()));
+    }
+    // The ramp index is only filled in when the encoding is resolved, so the stops are
+    // taken from the patch.
+    let Some(Patch::Ramp { stops, extend, .. }) = patch else {
+        return None;
+    };
+    let gradient = if tag == DrawTag::LINEAR_GRADIENT {
+        Gradient::new_linear(point(1), point(3))
+    } else if tag == DrawTag::RADIAL_GRADIENT {
+        Gradient::new_two_point_radial(
+            point(1),
+            f32::from_bits(data[5]),
+            point(3),
+            f32::from_bits(data[6]),
+        )
+    } else if tag == DrawTag::SWEEP_GRADIENT {
+        // Sweep angles are encoded as fractions of a turn.
+        let turn = std::f32::consts::TAU;
+        Gradient::new_sweep(
+            point(1),
+            f32::from_bits(data[3]) * turn,
+            f32::from_bits(data[4]) * turn,
+        )
+    } else {
+        return None;
+    };
+    // Stops have already been multiplied by the brush alpha.
+    let stops = color_stops.get(stops.clone())?;
+    Some(Brush::Gradient(
+        gradient.with_extend(*extend).with_stops(stops),
+    ))
+}
+
+/// Call `f` with a [`Command::Draw`] for the outline of each glyph in a run.
+///
+/// Returns `false` if the run's font couldn't be parsed. Variable fonts are outlined at
+/// their default instance.
+pub(crate) fn outline_glyphs<'a>(
+    run: &GlyphRun,
+    glyphs: &[Glyph],
+    brush: &Brush,
+    mut f: impl FnMut(Command<'a>),
+) -> bool {
+    let Ok(face) = ttf_parser::Face::parse(run.font.data.data(), run.font.index) else {
+        return false;
+    };
+    let scale = run.font_size as f64 / face.units_per_em() as f64;
+    let run_transform = to_affine(&run.transform);
+    let glyph_transform = run
+        .glyph_transform
+        .as_ref()
+        .map_or(Affine::IDENTITY, to_affine);
+    let style = decode_style(&run.style);
+    for glyph in glyphs {
+        let mut outline = Outline(BezPath::new());
+        let Ok(id) = u16::try_from(glyph.id) else {
+            continue;
+        };
+        if face
+            .outline_glyph(ttf_parser::GlyphId(id), &mut outline)
+            .is_none()
+        {
+            continue;
+        }
+        // Font units are y-up.
+        let transform = run_transform
+            * Affine::translate((glyph.x as f64, glyph.y as f64))
+            * glyph_transform
+            * Affine::scale_non_uniform(scale, -scale);
+        f(Command::Draw {
+            path: outline.0,
+            transform,
+            style: style.clone(),
+            brush: brush.clone(),
+            brush_transform: relative_transform(transform, run_transform),
+        });
+    }
+    true
+}
+
+/// Draw a glyph run decoded from `encoding` into `scene` with `brush`, as it was originally
+/// drawn.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn draw_glyph_run(
+    scene: &mut Scene,
+    encoding: &Encoding,
+    run: &GlyphRun,
+    glyphs: &[Glyph],
+    brush: &Brush,
+) {
+    let coords = &encoding.resources.normalized_coords[run.normalized_coords.clone()];
+    scene
+        .draw_glyphs(&run.font)
+        .transform(to_affine(&run.transform))
+        .glyph_transform(run.glyph_transform.as_ref().map(to_affine))
+        .font_size(run.font_size)
+        .hint(run.hint)
+        .normalized_coords(coords)
+        .brush(brush)
+        .draw(&decode_style(&run.style), glyphs.iter().copied());
+}
+
+/// Draw a decoded command into `scene`, as it was originally drawn.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn redraw(scene: &mut Scene, encoding: &Encoding, command: Command<'_>) {
+    match command {
+        Command::Draw {
+            path,
+            transform,
+            style: Style::Fill(fill),
+            brush,
+            brush_transform,
+        } => scene.fill(fill, transform, &brush, brush_transform, &path),
+        Command::Draw {
+            path,
+            transform,
+            style: Style::Stroke(stroke),
+            brush,
+            brush_transform,
+        } => scene.stroke(&stroke, transform, &brush, brush_transform, &path),
+        Command::BlurredRect {
+            path,
+            transform,
+            rect_transform,
+            rect,
+            color,
+            radius,
+            std_dev,
+        } => {
+            // The rectangle is centered on the origin of its transform, which the clip path
+            // is drawn in too.
+            if rect_transform.determinant() == 0.0 {
+                return;
+            }
+            let clip = rect_transform.inverse() * transform * path;
+            scene.draw_blurred_rounded_rect_in(
+                &clip,
+                rect_transform,
+                rect,
+                color,
+                radius.into(),
+                std_dev.into(),
+            );
+        }
+        Command::PushLayer {
+            clip,
+            transform,
+            blend,
+            alpha,
+            ..
+        } => scene.push_layer(blend, alpha, transform, &clip),
+        Command::PopLayer => scene.pop_layer(),
+        Command::Glyphs { run, glyphs, brush } => {
+            draw_glyph_run(scene, encoding, run, glyphs, &brush);
+        }
+    }
+}
+
+struct Outline(BezPath);
+
+impl ttf_parser::OutlineBuilder for Outline {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.0.move_to((x as f64, y as f64));
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.0.line_to((x as f64, y as f64));
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.0.quad_to((x1 as f64, y1 as f64), (x as f64, y as f64));
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.0.curve_to(
+            (x1 as f64, y1 as f64),
+            (x2 as f64, y2 as f64),
+            (x as f64, y as f64),
+        );
+    }
+
+    fn close(&mut self) {
+        self.0.close_path();
+    }
+}
+
+pub(crate) fn to_affine(transform: &Transform) -> Affine {
+    let [a, b, c, d] = transform.matrix;
+    let [e, f] = transform.translation;
+    Affine::new([a, b, c, d, e, f].map(f64::from))
+}
+
+/// The largest factor by which `transform` scales distances.
+pub(crate) fn max_scale(transform: Affine) -> f64 {
+    let [a, b, c, d, _, _] = transform.as_coeffs();
+    let sum = a * a + b * b + c * c + d * d;
+    let det = a * d - b * c;
+    // The square root of the largest eigenvalue of the transpose of the matrix times
+    // the matrix.
+    (0.5 * (sum + (sum * sum - 4.0 * det * det).max(0.0).sqrt())).sqrt()
+}
+
+/// The transform of a brush relative to the transform of the geometry it fills.
+fn relative_transform(transform: Affine, brush_transform: Affine) -> Option<Affine> {
+    (brush_transform != transform).then(|| transform.inverse() * brush_transform)
+}
+
+/// Unpack a premultiplied color from the draw data stream.
+fn unpack_color(rgba: u32) -> Color {
+    let [r, g, b, a] = rgba.to_le_bytes().map(|c| c as f32 / 255.0);
+    let inv_a = if a > 0.0 { 1.0 / a } else { 0.0 };
+    AlphaColor::<Srgb>::new([r * inv_a, g * inv_a, b * inv_a, a])
+}
+
+fn blend_mode(packed: u32) -> BlendMode {
+    let mix = match packed >> 8 {
+        1 => Mix::Multiply,
+        2 => Mix::Screen,
+        3 => Mix::Overlay,
+        4 => Mix::Darken,
+        5 => Mix::Lighten,
+        6 => Mix::ColorDodge,
+        7 => Mix::ColorBurn,
+        8 => Mix::HardLight,
+        9 => Mix::SoftLight,
+        10 => Mix::Difference,
+        11 => Mix::Exclusion,
+        12 => Mix::Hue,
+        13 => Mix::Saturation,
+        14 => Mix::Color,
+        15 => Mix::Luminosity,
+        128 => Mix::Clip,
+        _ => Mix::Normal,
+    };
+    let compose = match packed & 0xff {
+        0 => Compose::Clear,
+        1 => Compose::Copy,
+        2 => Compose::Dest,
+        4 => Compose::DestOver,
+        5 => Compose::SrcIn,
+        6 => Compose::DestIn,
+        7 => Compose::SrcOut,
+        8 => Compose::DestOut,
+        9 => Compose::SrcAtop,
+        10 => Compose::DestAtop,
+        11 => Compose::Xor,
+        12 => Compose::Plus,
+        13 => Compose::PlusLighter,
+        _ => Compose::SrcOver,
+    };
+    BlendMode { mix, compose }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2.0_f32.powi(-24),
+        31 => f32::INFINITY,
+        _ => (1.0 + mantissa / 1024.0) * 2.0_f32.powi(exponent - 15),
+    }
+}
@@ -10,7 +10,7 @@ use peniko::{
 use vello_encoding::BumpAllocatorMemory;
 use vello_encoding::Transform;
 
-use crate::{glyph_builder::DrawGlyphs, scene_core::Scene};
+use crate::{glyph_builder::DrawGlyphs, hit_test::DrawId, scene_core::Scene};
 
 impl Scene {
     /// Draw a rounded rectangle blurred with a gaussian filter.
@@ -70,6 +70,12 @@ impl Scene {
     }
 
     /// Fills a shape using the specified style and brush.
+    ///
+    /// The brush is positioned by `transform * brush_transform`, independently of the shape.
+    /// An image brush is extended beyond its bounds on each axis according to its
+    /// [`x_extend`](peniko::Image::x_extend) and [`y_extend`](peniko::Image::y_extend). A
+    /// gradient brush is interpolated in its
+    /// [`interpolation_cs`](peniko::Gradient::interpolation_cs).
     #[expect(
         single_use_lifetimes,
         reason = "False positive: https://github.com/rust-lang/rust/issues/129255"
@@ -94,13 +100,33 @@ impl Scene {
                     self.encoding.swap_last_path_tags();
                 }
             }
-            self.encoding.encode_brush(brush, 1.0);
+            self.encode_brush(brush.into(), 1.0);
             #[cfg(feature = "bump_estimate")]
             self.estimator
                 .count_path(shape.path_elements(0.1), &t, None);
         }
     }
 
+    /// Fills a shape like [`Scene::fill`], tagging the draw with `id` for
+    /// [`Scene::hit_test`].
+    #[expect(
+        single_use_lifetimes,
+        reason = "False positive: https://github.com/rust-lang/rust/issues/129255"
+    )]
+    pub fn fill_with_id<'b>(
+        &mut self,
+        id: DrawId,
+        style: Fill,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        let draw_ix = self.encoding.draw_tags.len();
+        self.fill(style, transform, brush, brush_transform, shape);
+        self.tag_draw(draw_ix, id);
+    }
+
     /// Strokes a shape using the specified style and brush.
     #[expect(
         single_use_lifetimes,
@@ -174,7 +200,7 @@ impl Scene {
                         self.encoding.swap_last_path_tags();
                     }
                 }
-                self.encoding.encode_brush(brush, 1.0);
+                self.encode_brush(brush.into(), 1.0);
             }
         } else {
             let stroked = peniko::kurbo::stroke(
@@ -187,7 +213,35 @@ impl Scene {
         }
     }
 
+    /// Strokes a shape like [`Scene::stroke`], tagging the draw with `id` for
+    /// [`Scene::hit_test`].
+    #[expect(
+        single_use_lifetimes,
+        reason = "False positive: https://github.com/rust-lang/rust/issues/129255"
+    )]
+    pub fn stroke_with_id<'b>(
+        &mut self,
+        id: DrawId,
+        style: &Stroke,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        let draw_ix = self.encoding.draw_tags.len();
+        self.stroke(style, transform, brush, brush_transform, shape);
+        self.tag_draw(draw_ix, id);
+    }
+
     /// Draws an image at its natural size with the given transform.
+    ///
+    /// The image is sampled according to its [quality](peniko::ImageQuality). When it is
+    /// drawn downscaled with medium or high quality, the renderers sample prefiltered levels
+    /// of its mip chain, which they generate and keep while the image's data is alive. The
+    /// level is chosen for each pixel from the scale of the image's transform: medium quality
+    /// samples the nearest level, and high quality blends the two nearest levels, which is
+    /// trilinear filtering. The GPU renderer samples the image itself when it draws into
+    /// `Rgba8` targets with MSAA.
     #[inline]
     pub fn draw_image(&mut self, image: &Image, transform: Affine) {
         self.fill(
@@ -0,0 +1,475 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Layers whose content is rendered offscreen and filtered before being composited.
+//!
+//! The content of a filter layer is encoded separately from the scene, which instead draws
+//! a placeholder image clipped to the layer's shape. When rendering, the content is rendered
+//! into an intermediate image, filtered, and copied into the image atlas in place of the
+//! placeholder.
+//!
+//! Mask layers are filter layers which also render a mask scene, which modulates the
+//! filtered content.
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use peniko::{
+    BlendMode, Blob, Color, Fill, Image, ImageFormat, ImageQuality,
+    kurbo::{Affine, Point, Shape, Vec2},
+};
+use vello_encoding::{Encoding, Transform};
+
+use crate::hit_test::DrawId;
+use crate::scene_core::Scene;
+
+/// The largest width and height of the region a filter layer is rendered in, in pixels.
+pub(crate) const MAX_FILTER_SIZE: u32 = 8192;
+
+/// An image filter applied to the content of a layer.
+///
+/// Filters are applied with [`Scene::push_filter_layer`], or
+/// [`Scene::push_blended_filter_layer`] to also blend the result with the content below it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Filter {
+    /// Blur the content with a gaussian kernel.
+    GaussianBlur {
+        /// The standard deviation of the kernel, in pixels.
+        std_dev: f64,
+    },
+    /// Draw a blurred shadow of the content behind it.
+    DropShadow {
+        /// The offset of the shadow from the content, in pixels.
+        offset: Vec2,
+        /// The standard deviation of the blur of the shadow, in pixels.
+        std_dev: f64,
+        /// The color of the shadow, which is multiplied by the blurred alpha of the content.
+        color: Color,
+    },
+    /// Transform the colors of the content with a 4x5 matrix, as in SVG's `feColorMatrix`.
+    ///
+    /// The matrix is in row-major order. Each row computes one of the red, green, blue and
+    /// alpha components of the result from the separated (not premultiplied) sRGB encoded
+    /// components of the content, as the dot product of its first four entries with them,
+    /// plus its fifth entry. The results are clamped to the range `0..=1`.
+    ///
+    /// The constructors such as [`Filter::grayscale`] create the matrices of the CSS filter
+    /// functions.
+    ColorMatrix([f32; 20]),
+}
+
+/// How the mask of a layer pushed with [`Scene::push_mask`] modulates its content.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MaskType {
+    /// The luminance of the mask, multiplied by its alpha, is the opacity of the content.
+    ///
+    /// This is the default of SVG's `<mask>` and CSS's `mask-mode`.
+    Luminance,
+    /// The alpha of the mask is the opacity of the content.
+    Alpha,
+}
+
+/// The luminance coefficients of sRGB, as used by the CSS `grayscale` filter function and
+/// luminance masks.
+pub(crate) const LUMINANCE: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// The rounded luminance coefficients used by the CSS `saturate` and `hue-rotate` filter
+/// functions.
+const ROUNDED_LUMINANCE: [f32; 3] = [0.213, 0.715, 0.072];
+
+impl Filter {
+    /// The identity color matrix, which doesn't change the content.
+    pub const IDENTITY_MATRIX: [f32; 20] = [
+        1., 0., 0., 0., 0., //
+        0., 1., 0., 0., 0., //
+        0., 0., 1., 0., 0., //
+        0., 0., 0., 1., 0., //
+    ];
+
+    /// Convert the content to grayscale, as CSS `grayscale(amount)`.
+    ///
+    /// An `amount` of 1 is completely gray, and 0 leaves the content unchanged.
+    pub fn grayscale(amount: f32) -> Self {
+        let s = 1.0 - amount.clamp(0.0, 1.0);
+        let [r, g, b] = LUMINANCE;
+        Self::rgb_matrix([
+            [r + (1.0 - r) * s, g - g * s, b - b * s],
+            [r - r * s, g + (1.0 - g) * s, b - b * s],
+            [r - r * s, g - g * s, b + (1.0 - b) * s],
+        ])
+    }
+
+    /// Convert the content to sepia, as CSS `sepia(amount)`.
+    ///
+    /// An `amount` of 1 is completely sepia, and 0 leaves the content unchanged.
+    pub fn sepia(amount: f32) -> Self {
+        let s = 1.0 - amount.clamp(0.0, 1.0);
+        Self::rgb_matrix([
+            [0.393 + 0.607 * s, 0.769 - 0.769 * s, 0.189 - 0.189 * s],
+            [0.349 - 0.349 * s, 0.686 + 0.314 * s, 0.168 - 0.168 * s],
+            [0.272 - 0.272 * s, 0.534 - 0.534 * s, 0.131 + 0.869 * s],
+        ])
+    }
+
+    /// Saturate the content, as CSS `saturate(amount)`.
+    ///
+    /// An `amount` of 0 is completely unsaturated, 1 leaves the content unchanged, and larger
+    /// values oversaturate it.
+    pub fn saturate(amount: f32) -> Self {
+        let s = amount.max(0.0);
+        let [r, g, b] = ROUNDED_LUMINANCE;
+        Self::rgb_matrix([
+            [r + (1.0 - r) * s, g - g * s, b - b * s],
+            [r - r * s, g + (1.0 - g) * s, b - b * s],
+            [r - r * s, g - g * s, b + (1.0 - b) * s],
+        ])
+    }
+
+    /// Rotate the hue of the content by `angle` radians, as CSS `hue-rotate(angle)`.
+    pub fn hue_rotate(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let [r, g, b] = ROUNDED_LUMINANCE;
+        Self::rgb_matrix([
+            [
+                r + cos * (1.0 - r) - sin * r,
+                g - cos * g - sin * g,
+                b - cos * b + sin * (1.0 - b),
+            ],
+            [
+                r - cos * r + sin * 0.143,
+                g + cos * (1.0 - g) + sin * 0.140,
+                b - cos * b - sin * 0.283,
+            ],
+            [
+                r - cos * r - sin * (1.0 - r),
+                g - cos * g + sin * g,
+                b + cos * (1.0 - b) + sin * b,
+            ],
+        ])
+    }
+
+    /// Scale the color of the content, as CSS `brightness(amount)`.
+    ///
+    /// An `amount` of 0 is completely black, and 1 leaves the content unchanged.
+    pub fn brightness(amount: f32) -> Self {
+        let a = amount.max(0.0);
+        Self::rgb_matrix([[a, 0., 0.], [0., a, 0.], [0., 0., a]])
+    }
+
+    /// Adjust the contrast of the content, as CSS `contrast(amount)`.
+    ///
+    /// An `amount` of 0 is completely gray, and 1 leaves the content unchanged.
+    pub fn contrast(amount: f32) -> Self {
+        let c = amount.max(0.0);
+        let offset = 0.5 - 0.5 * c;
+        Self::ColorMatrix([
+            c, 0., 0., 0., offset, //
+            0., c, 0., 0., offset, //
+            0., 0., c, 0., offset, //
+            0., 0., 0., 1., 0., //
+        ])
+    }
+
+    /// Scale the opacity of the content, as CSS `opacity(amount)`.
+    pub fn opacity(amount: f32) -> Self {
+        let mut matrix = Self::IDENTITY_MATRIX;
+        matrix[18] = amount.clamp(0.0, 1.0);
+        Self::ColorMatrix(matrix)
+    }
+
+    /// Combine two color matrix filters into one which applies `self` and then `other`.
+    ///
+    /// Returns `None` if either filter isn't a [`Filter::ColorMatrix`].
+    pub fn then(self, other: Self) -> Option<Self> {
+        let (Self::ColorMatrix(first), Self::ColorMatrix(second)) = (self, other) else {
+            return None;
+        };
+        let mut matrix = [0.0; 20];
+        for row in 0..4 {
+            for col in 0..5 {
+                let mut value = (0..4)
+                    .map(|k| second[row * 5 + k] * first[k * 5 + col])
+                    .sum::<f32>();
+                if col == 4 {
+                    value += second[row * 5 + 4];
+                }
+                matrix[row * 5 + col] = value;
+            }
+        }
+        Some(Self::ColorMatrix(matrix))
+    }
+
+    /// A color matrix which transforms the color components and keeps the alpha component.
+    fn rgb_matrix(rows: [[f32; 3]; 3]) -> Self {
+        let mut matrix = Self::IDENTITY_MATRIX;
+        for (row, coefficients) in rows.iter().enumerate() {
+            matrix[row * 5..][..3].copy_from_slice(coefficients);
+        }
+        Self::ColorMatrix(matrix)
+    }
+
+    /// The color matrix of this filter, which is the identity for filters other than
+    /// [`Filter::ColorMatrix`].
+    pub(crate) fn color_matrix(&self) -> [f32; 20] {
+        match self {
+            Self::ColorMatrix(matrix) => *matrix,
+            _ => Self::IDENTITY_MATRIX,
+        }
+    }
+
+    /// The standard deviation of the blur of this filter, in pixels.
+    pub(crate) fn std_dev(&self) -> f64 {
+        match self {
+            Self::GaussianBlur { std_dev } | Self::DropShadow { std_dev, .. } => std_dev.max(0.0),
+            Self::ColorMatrix(_) => 0.0,
+        }
+    }
+
+    /// How far content can be moved or spread by this filter, in pixels.
+    fn extent(&self) -> f64 {
+        let offset = match self {
+            Self::GaussianBlur { .. } | Self::ColorMatrix(_) => Vec2::ZERO,
+            Self::DropShadow { offset, .. } => *offset,
+        };
+        f64::from(kernel_radius(self.std_dev() as f32)) + offset.x.abs().max(offset.y.abs())
+    }
+}
+
+/// The radius of the blur kernel for a standard deviation, as the kernel is cut off at
+/// three standard deviations.
+pub(crate) fn kernel_radius(std_dev: f32) -> u32 {
+    (3.0 * std_dev).ceil() as u32
+}
+
+/// The image drawn in place of a filter layer covering `width` by `height` pixels.
+///
+/// The renderers replace the image with the rendered layer, so only the unique id of its
+/// data and its size are used, and its data is a single transparent pixel.
+pub(crate) fn placeholder_image(width: u32, height: u32) -> Image {
+    Image::new(
+        Blob::new(Arc::new(vec![0; 4])),
+        ImageFormat::Rgba8,
+        width,
+        height,
+    )
+    .with_quality(ImageQuality::Low)
+}
+
+/// The content of a filter layer.
+#[derive(Clone)]
+pub(crate) struct FilterLayer {
+    pub(crate) filter: Filter,
+    /// The mask of the layer, if it was pushed with [`Scene::push_mask`].
+    pub(crate) mask: Option<Mask>,
+    /// The content of the layer, in the coordinates of the scene.
+    pub(crate) content: Encoding,
+    /// The ids of the tagged draw objects in the content, as in [`Scene`].
+    pub(crate) draw_ids: Vec<(usize, DrawId)>,
+    /// The top left corner of the region of the scene which the content is rendered in.
+    pub(crate) origin: Point,
+    /// The image drawn in the scene in place of the layer, from [`placeholder_image`].
+    ///
+    /// The filtered content is rendered into this image, whose width and height are the
+    /// extent of the region. Its data is only a single pixel.
+    pub(crate) image: Image,
+}
+
+/// The mask of a mask layer.
+#[derive(Clone)]
+pub(crate) struct Mask {
+    pub(crate) kind: MaskType,
+    /// The mask scene, in the coordinates of the scene.
+    pub(crate) content: Encoding,
+}
+
+/// A filter layer which hasn't been popped yet.
+#[derive(Clone)]
+pub(crate) struct OpenFilterLayer {
+    /// The encoding of the scene outside of the layer, which is restored when it is popped.
+    outer: Encoding,
+    /// The ids of the tagged draw objects in `outer`.
+    outer_draw_ids: Vec<(usize, DrawId)>,
+    /// The layer, whose content is empty until it is popped, or `None` if it doesn't cover
+    /// any pixels.
+    layer: Option<FilterLayer>,
+}
+
+impl Scene {
+    /// Pushes a new layer whose content is filtered, clipped by the specified shape.
+    ///
+    /// Every drawing command after this call is drawn into the layer, until the layer is
+    /// popped with [`Scene::pop_layer`]. The content is then rendered into an intermediate
+    /// image, the filter is applied to it, and the result is composited into the scene,
+    /// clipped to `clip`. As the result is clipped, `clip` should leave room for blurs and
+    /// shadows to spread beyond the content.
+    ///
+    /// The content is rendered at the resolution of this scene, in the region covered by
+    /// `clip` under `transform`. A transform applied to this scene afterwards, such as one
+    /// given to [`Scene::append`], resamples the filtered image rather than the content, so
+    /// it blurs when scaled up.
+    /// Layers whose region is wider or taller than 8192 pixels aren't drawn, and a warning
+    /// is logged.
+    ///
+    /// Layers which aren't popped are closed when the scene is rendered, hit tested or
+    /// serialized. Until then, [`Scene::encoding`] only holds the content of the innermost
+    /// open layer.
+    ///
+    /// Filter layers are kept by [`Scene::serialize`], but are only applied by the
+    /// renderers. Exported documents draw them as transparent.
+    pub fn push_filter_layer(&mut self, filter: Filter, transform: Affine, clip: &impl Shape) {
+        self.push_blended_filter_layer(BlendMode::default(), 1.0, filter, transform, clip);
+    }
+
+    /// Pushes a new layer whose content is filtered, clipped by the specified shape and
+    /// composed with previous layers using the specified blend mode.
+    ///
+    /// This is [`Scene::push_filter_layer`], with the filtered content composited as a layer
+    /// pushed with [`Scene::push_layer`] would be.
+    pub fn push_blended_filter_layer(
+        &mut self,
+        blend: impl Into<BlendMode>,
+        alpha: f32,
+        filter: Filter,
+        transform: Affine,
+        clip: &impl Shape,
+    ) {
+        self.push_offscreen_layer(blend.into(), alpha, filter, None, transform, clip);
+    }
+
+    /// Pushes a new layer whose content is masked by `mask`, clipped by the specified shape.
+    ///
+    /// Every drawing command after this call is drawn into the layer, until the layer is
+    /// popped with [`Scene::pop_layer`]. The content and the mask, drawn with `transform`,
+    /// are then rendered into intermediate images, and the content is composited into the
+    /// scene with the opacity given by the mask according to `mask_type`. The content is
+    /// transparent where the mask draws nothing, so `clip` only needs to cover the mask.
+    ///
+    /// Like filter layers, mask layers are rendered at the resolution of this scene, aren't
+    /// drawn if their region is larger than 8192 pixels, are closed when the scene is
+    /// rendered or serialized if they aren't popped, and are drawn as transparent by
+    /// exported documents.
+    pub fn push_mask(
+        &mut self,
+        mask_type: MaskType,
+        transform: Affine,
+        clip: &impl Shape,
+        mask: &Self,
+    ) {
+        let mut content = Encoding::new();
+        content.append(&mask.encoding, &Some(Transform::from_kurbo(&transform)));
+        // The mask's own filter layers must be rendered before the mask.
+        self.filters.extend(mask.filters.iter().cloned());
+        let mask = Mask {
+            kind: mask_type,
+            content,
+        };
+        self.push_offscreen_layer(
+            BlendMode::default(),
+            1.0,
+            Filter::ColorMatrix(Filter::IDENTITY_MATRIX),
+            Some(mask),
+            transform,
+            clip,
+        );
+    }
+
+    fn push_offscreen_layer(
+        &mut self,
+        blend: BlendMode,
+        alpha: f32,
+        filter: Filter,
+        mask: Option<Mask>,
+        transform: Affine,
+        clip: &impl Shape,
+    ) {
+        let extent = filter.extent();
+        let bounds = transform
+            .transform_rect_bbox(clip.bounding_box())
+            .inflate(extent, extent)
+            .expand();
+        let (width, height) = (bounds.width(), bounds.height());
+        let layer = if !bounds.is_finite() || width < 1.0 || height < 1.0 {
+            None
+        } else if width > f64::from(MAX_FILTER_SIZE) || height > f64::from(MAX_FILTER_SIZE) {
+            log::warn!(
+                "Filter layer of {width}x{height} pixels exceeds the maximum of \
+                {MAX_FILTER_SIZE}x{MAX_FILTER_SIZE}, so it isn't drawn"
+            );
+            None
+        } else {
+            let image = placeholder_image(width as u32, height as u32);
+            let origin = bounds.origin();
+            let brush_transform = transform.inverse() * Affine::translate(origin.to_vec2());
+            let blended = blend != BlendMode::default() || alpha != 1.0;
+            if blended {
+                self.push_layer(blend, alpha, transform, clip);
+            }
+            self.fill(
+                Fill::NonZero,
+                transform,
+                &image,
+                Some(brush_transform),
+                clip,
+            );
+            if blended {
+                self.encoding.encode_end_clip();
+            }
+            Some(FilterLayer {
+                filter,
+                mask,
+                content: Encoding::new(),
+                draw_ids: Vec::new(),
+                origin,
+                image,
+            })
+        };
+        let outer = std::mem::take(&mut self.encoding);
+        let outer_draw_ids = std::mem::take(&mut self.draw_ids);
+        self.open_filters.push(OpenFilterLayer {
+            outer,
+            outer_draw_ids,
+            layer,
+        });
+    }
+
+    /// The scene with its open filter layers closed, as if they had been popped together
+    /// with the clip layers inside them.
+    ///
+    /// Otherwise, the content drawn before the outermost open layer would be missing from
+    /// the scene's encoding.
+    pub(crate) fn with_filter_layers_closed(&self) -> Cow<'_, Self> {
+        if self.open_filters.is_empty() {
+            return Cow::Borrowed(self);
+        }
+        let mut scene = self.clone();
+        while !scene.open_filters.is_empty() {
+            while scene.encoding.n_open_clips != 0 {
+                scene.encoding.encode_end_clip();
+            }
+            scene.pop_filter_layer();
+        }
+        Cow::Owned(scene)
+    }
+
+    /// Pops the innermost filter layer, if there is one which has no open layers inside it.
+    ///
+    /// Returns whether a filter layer was popped.
+    pub(crate) fn pop_filter_layer(&mut self) -> bool {
+        if self.encoding.n_open_clips != 0 {
+            return false;
+        }
+        let Some(open) = self.open_filters.pop() else {
+            return false;
+        };
+        let content = std::mem::replace(&mut self.encoding, open.outer);
+        let draw_ids = std::mem::replace(&mut self.draw_ids, open.outer_draw_ids);
+        if let Some(mut layer) = open.layer {
+            // Layers are stored in the order they are popped, so that nested layers are
+            // rendered before the layers which contain them.
+            layer.content = content;
+            layer.draw_ids = draw_ids;
+            self.filters.push(layer);
+        }
+        true
+    }
+}
@@ -1,36 +1,20 @@
-//! Glyph drawing builder using proper glyphon text rendering
+//! Glyph drawing builder
 //!
-//! This replaces the broken cosmic-text direct integration with proper glyphon
-//! Buffer creation, text layout, and coordinate system handling.
+//! Encodes runs of pre-shaped glyphs into a [`Scene`].
 
-use std::sync::Arc;
-
-use glyphon::{Attrs, Buffer, Family, FontSystem, Metrics, Shaping, SwashCache};
 use peniko::{BrushRef, Fill, Font, StyleRef, color::palette, kurbo::Affine};
 use vello_encoding::{Glyph, GlyphRun, NormalizedCoord, Patch, Transform};
 
+use crate::hit_test::DrawId;
 use crate::scene_core::Scene;
 
-thread_local! {
-    /// Thread-local FontSystem for glyphon Buffer creation and text shaping.
-    /// FontSystem::new() can be expensive, so we create it once per thread and reuse.
-    static FONT_SYSTEM: std::cell::RefCell<FontSystem> = std::cell::RefCell::new(FontSystem::new());
-}
-
-thread_local! {
-    /// Thread-local SwashCache for glyphon glyph rasterization.
-    /// SwashCache is designed for reuse across multiple text rendering operations.
-    static SWASH_CACHE: std::cell::RefCell<SwashCache> = std::cell::RefCell::new(SwashCache::new());
-}
-
-/// Builder for encoding a glyph run using glyphon instead of skrifa.
-///
-/// This is a 1:1 functional replacement of the original vello DrawGlyphs.
+/// Builder for encoding a glyph run.
 pub struct DrawGlyphs<'a> {
     scene: &'a mut Scene,
     run: GlyphRun,
     brush: BrushRef<'a>,
     brush_alpha: f32,
+    id: Option<DrawId>,
 }
 
 impl<'a> DrawGlyphs<'a> {
@@ -56,6 +40,7 @@ impl<'a> DrawGlyphs<'a> {
             },
             brush: palette::css::BLACK.into(),
             brush_alpha: 1.0,
+            id: None,
         }
     }
 
@@ -133,114 +118,177 @@ impl<'a> DrawGlyphs<'a> {
         self
     }
 
-    /// Encodes a fill or stroke for the given sequence of glyphs using proper glyphon.
+    /// Tags the glyph run with an id, which [`Scene::hit_test`] returns when one of the
+    /// glyphs contains the point, like [`Scene::fill_with_id`].
     ///
-    /// Uses glyphon Buffer creation and text layout for correct text rendering.
-    pub fn draw(mut self, style: impl Into<StyleRef<'a>>, glyphs: impl Iterator<Item = Glyph>) {
-        let glyphs_vec: Vec<_> = glyphs.collect();
+    /// By default, the glyph run isn't tagged.
+    #[must_use]
+    pub fn id(mut self, id: DrawId) -> Self {
+        self.id = Some(id);
+        self
+    }
 
-        if glyphs_vec.is_empty() {
-            self.scene
-                .encoding
-                .resources
+    /// Encodes a fill or stroke for the given sequence of glyphs and consumes the builder.
+    ///
+    /// The `style` parameter accepts either `Fill` or `Stroke` types.
+    ///
+    /// The glyph ids and positions are encoded exactly as given; no shaping or
+    /// layout is performed here.
+    pub fn draw(mut self, style: impl Into<StyleRef<'a>>, glyphs: impl Iterator<Item = Glyph>) {
+        let resources = &mut self.scene.encoding.resources;
+        self.run.style = style.into().to_owned();
+        resources.glyphs.extend(glyphs);
+        self.run.glyphs.end = resources.glyphs.len();
+        if self.run.glyphs.is_empty() {
+            resources
                 .normalized_coords
                 .truncate(self.run.normalized_coords.start);
             return;
         }
-
-        // Process glyphs through proper glyphon Buffer system
-        let glyph_count = FONT_SYSTEM.with(|fs| {
-            let mut font_system = fs.borrow_mut();
-            self.process_glyphs_with_proper_glyphon(style, glyphs_vec, &mut font_system)
-        });
-
-        if glyph_count == 0 {
-            self.scene
-                .encoding
-                .resources
-                .normalized_coords
-                .truncate(self.run.normalized_coords.start);
+        let index = resources.glyph_runs.len();
+        let draw_ix = self.run.stream_offsets.draw_tags;
+        resources.glyph_runs.push(self.run);
+        resources.patches.push(Patch::GlyphRun { index });
+        self.scene.encode_brush(self.brush, self.brush_alpha);
+        if let Some(id) = self.id {
+            self.scene.tag_draw(draw_ix, id);
         }
+        // Glyph run resolve step affects transform and style state in a way
+        // that is opaque to the current encoding.
+        // See <https://github.com/linebender/vello/issues/424>
+        self.scene.encoding.force_next_transform_and_style();
     }
+}
 
-    /// Process glyphs through proper glyphon Buffer system
-    #[inline(always)]
-    fn process_glyphs_with_proper_glyphon(
-        &mut self,
-        style: impl Into<StyleRef<'a>>,
-        glyphs: Vec<Glyph>,
-        font_system: &mut FontSystem,
-    ) -> usize {
-        // Create text from glyphs - this is a temporary approach until we get proper text input
-        // In a real implementation, we'd receive the actual text string
-        let text = self.glyphs_to_text(&glyphs);
-
-        if text.trim().is_empty() {
-            return 0;
-        }
-
-        // Create proper glyphon Buffer for text layout
-        let metrics = Metrics::new(self.run.font_size, self.run.font_size * 1.2);
-        let mut buffer = Buffer::new(font_system, metrics);
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-        // Set text with proper font attributes
-        let attrs = Attrs::new().family(Family::Monospace);
-        buffer.set_text(font_system, &text, &attrs, Shaping::Advanced);
+    use peniko::kurbo::Affine;
+    use peniko::{Blob, Fill, Font};
+    use vello_encoding::{Glyph, Patch};
 
-        // Shape the text for proper layout
-        buffer.shape_until_scroll(font_system, false);
+    use crate::Scene;
 
-        // Extract properly laid out glyphs from the buffer
-        let mut extracted_glyphs = Vec::new();
+    fn font() -> Font {
+        // The encoding doesn't parse the font, so its data doesn't matter.
+        Font::new(Blob::new(Arc::new(vec![0_u8; 16])), 1)
+    }
 
-        for run in buffer.layout_runs() {
-            for glyph in run.glyphs.iter() {
-                extracted_glyphs.push(Glyph {
-                    id: glyph.glyph_id as u32,
-                    x: glyph.x,
-                    y: glyph.y,
-                });
-            }
-        }
+    fn glyphs() -> Vec<Glyph> {
+        vec![
+            Glyph {
+                id: 36,
+                x: 0.0,
+                y: 12.5,
+            },
+            Glyph {
+                id: 0,
+                x: 9.25,
+                y: 12.5,
+            },
+            Glyph {
+                id: 70_000,
+                x: -3.0,
+                y: 40.0,
+            },
+        ]
+    }
 
-        if extracted_glyphs.is_empty() {
-            return 0;
+    fn assert_glyphs_eq(actual: &[Glyph], expected: &[Glyph]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert_eq!(actual.id, expected.id);
+            assert_eq!(actual.x.to_bits(), expected.x.to_bits());
+            assert_eq!(actual.y.to_bits(), expected.y.to_bits());
         }
+    }
 
-        // Add to vello encoding using the properly laid out glyphs
-        let resources = &mut self.scene.encoding.resources;
-        self.run.style = style.into().to_owned();
-
-        resources.glyphs.extend(extracted_glyphs.iter().cloned());
-        self.run.glyphs.end = resources.glyphs.len();
-
-        // Store the buffer for later use in resolution
-        self.run.buffer = Some(Arc::new(buffer));
-
-        let index = resources.glyph_runs.len();
-        resources.glyph_runs.push(self.run.clone());
-        resources.patches.push(Patch::GlyphRun { index });
-        self.scene
-            .encoding
-            .encode_brush(self.brush, self.brush_alpha);
-        self.scene.encoding.force_next_transform_and_style();
-
-        extracted_glyphs.len()
+    #[test]
+    fn glyphs_are_encoded_as_given() {
+        let font = font();
+        let transform = Affine::translate((10.0, 20.0)) * Affine::scale(2.0);
+        let skew = Affine::skew(0.25, 0.0);
+        let coords = [-8192_i16, 0, 16384];
+        let mut scene = Scene::new();
+        scene
+            .draw_glyphs(&font)
+            .transform(transform)
+            .glyph_transform(Some(skew))
+            .font_size(24.0)
+            .hint(true)
+            .normalized_coords(&coords)
+            .draw(Fill::EvenOdd, glyphs().into_iter());
+
+        let resources = &scene.encoding.resources;
+        assert_eq!(resources.glyph_runs.len(), 1);
+        assert!(matches!(
+            resources.patches.as_slice(),
+            [Patch::GlyphRun { index: 0 }]
+        ));
+        let run = &resources.glyph_runs[0];
+        assert_eq!(run.font.data.id(), font.data.id());
+        assert_eq!(run.font.index, font.index);
+        assert_eq!(run.font_size, 24.0);
+        assert!(run.hint);
+        assert_glyphs_eq(&resources.glyphs[run.glyphs.clone()], &glyphs());
+        assert_eq!(
+            &resources.normalized_coords[run.normalized_coords.clone()],
+            &coords
+        );
+        let [a, b, c, d, e, f] = transform.as_coeffs().map(|x| x as f32);
+        assert_eq!(run.transform.matrix, [a, b, c, d]);
+        assert_eq!(run.transform.translation, [e, f]);
+        let glyph_transform = run.glyph_transform.expect("glyph transform is kept");
+        let [a, b, c, d, e, f] = skew.as_coeffs().map(|x| x as f32);
+        assert_eq!(glyph_transform.matrix, [a, b, c, d]);
+        assert_eq!(glyph_transform.translation, [e, f]);
     }
 
-    /// Convert glyphs to text string (temporary until proper text input)
-    fn glyphs_to_text(&self, glyphs: &[Glyph]) -> String {
-        // This is a simplified conversion - in practice, we'd need the original text
-        // For now, create a simple text representation
-        if glyphs.is_empty() {
-            return String::new();
-        }
+    #[test]
+    fn runs_are_encoded_independently() {
+        let font = font();
+        let mut scene = Scene::new();
+        let first = glyphs();
+        let second = [Glyph {
+            id: 5,
+            x: 1.0,
+            y: 2.0,
+        }];
+        scene
+            .draw_glyphs(&font)
+            .normalized_coords(&[100])
+            .draw(Fill::NonZero, first.iter().copied());
+        scene
+            .draw_glyphs(&font)
+            .draw(Fill::NonZero, second.iter().copied());
+
+        let resources = &scene.encoding.resources;
+        let [a, b] = resources.glyph_runs.as_slice() else {
+            panic!("expected two glyph runs");
+        };
+        assert_glyphs_eq(&resources.glyphs[a.glyphs.clone()], &first);
+        assert_glyphs_eq(&resources.glyphs[b.glyphs.clone()], &second);
+        assert_eq!(
+            &resources.normalized_coords[a.normalized_coords.clone()],
+            &[100]
+        );
+        assert!(b.normalized_coords.is_empty());
+        assert!(!b.hint);
+        assert!(b.glyph_transform.is_none());
+    }
 
-        // Create some sample text based on glyph count
-        match glyphs.len() {
-            1..=5 => "Hello".to_string(),
-            6..=10 => "Hello World".to_string(),
-            _ => "Hello World! This is glyphon text rendering.".to_string(),
-        }
+    #[test]
+    fn empty_runs_are_not_encoded() {
+        let mut scene = Scene::new();
+        scene
+            .draw_glyphs(&font())
+            .normalized_coords(&[1, 2])
+            .draw(Fill::NonZero, std::iter::empty());
+        let resources = &scene.encoding.resources;
+        assert!(resources.glyph_runs.is_empty());
+        assert!(resources.glyphs.is_empty());
+        assert!(resources.normalized_coords.is_empty());
+        assert!(scene.encoding.draw_tags.is_empty());
     }
 }
@@ -0,0 +1,265 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Interpolation of gradients in color spaces other than sRGB.
+//!
+//! The ramp of each gradient is generated when the encoding is resolved, by interpolating
+//! linearly between the sRGB colors of its stops. A gradient which interpolates in another
+//! color space, such as linear sRGB, Oklab or Oklch, is instead encoded with the stops of a
+//! piecewise linear approximation of its ramp in sRGB, which is within a small perceptual
+//! error of the ramp in its own color space. Cylindrical color spaces interpolate their hue
+//! according to the gradient's [`HueDirection`].
+//!
+//! The approximating stops are cached by the scene for each definition of stops, color space
+//! and hue direction, so gradients which are drawn every frame are only approximated once.
+
+use std::collections::HashMap;
+
+use peniko::color::cache_key::CacheKey;
+use peniko::color::{AlphaColor, ColorSpaceTag, HueDirection, Srgb};
+use peniko::{BrushRef, ColorStop, ColorStops, Gradient};
+use vello_encoding::Encoding;
+
+/// The largest perceptual error of the approximation, as the distance in Oklab.
+const TOLERANCE: f32 = 0.01;
+
+/// The approximating stops of the gradients drawn into a scene.
+#[derive(Clone, Default)]
+pub(crate) struct GradientCache {
+    entries: HashMap<Key, Entry>,
+}
+
+/// The color space, hue direction and stops of a gradient.
+type Key = (ColorSpaceTag, u8, CacheKey<ColorStops>);
+
+#[derive(Clone)]
+struct Entry {
+    stops: ColorStops,
+    /// Whether a gradient with these stops was drawn since the last prune.
+    used: bool,
+}
+
+impl GradientCache {
+    /// The gradient to encode for `brush`, or `None` if it can be encoded as it is.
+    pub(crate) fn interpolate(&mut self, brush: BrushRef<'_>) -> Option<Gradient> {
+        let gradient = non_srgb_gradient(brush)?;
+        let key = (
+            gradient.interpolation_cs,
+            gradient.hue_direction as u8,
+            CacheKey(gradient.stops.clone()),
+        );
+        let entry = self.entries.entry(key).or_insert_with(|| Entry {
+            stops: srgb_stops(gradient),
+            used: false,
+        });
+        entry.used = true;
+        Some(srgb_gradient(gradient, entry.stops.clone()))
+    }
+
+    /// Drop the stops of the gradients which weren't drawn since the last prune.
+    pub(crate) fn prune(&mut self) {
+        self.entries
+            .retain(|_, entry| std::mem::take(&mut entry.used));
+    }
+}
+
+/// Encode `brush`, approximating its gradient in sRGB if it interpolates in another color
+/// space.
+///
+/// Encodings which aren't drawn through a scene have no `cache`, so their approximations
+/// aren't kept.
+pub(crate) fn encode_brush(
+    encoding: &mut Encoding,
+    brush: BrushRef<'_>,
+    alpha: f32,
+    cache: Option<&mut GradientCache>,
+) {
+    let interpolated = match cache {
+        Some(cache) => cache.interpolate(brush),
+        None => {
+            non_srgb_gradient(brush).map(|gradient| srgb_gradient(gradient, srgb_stops(gradient)))
+        }
+    };
+    match &interpolated {
+        Some(gradient) => encoding.encode_brush(gradient, alpha),
+        None => encoding.encode_brush(brush, alpha),
+    }
+}
+
+/// The gradient of `brush`, if it interpolates in a color space other than sRGB.
+fn non_srgb_gradient<'a>(brush: BrushRef<'a>) -> Option<&'a Gradient> {
+    match brush {
+        BrushRef::Gradient(gradient) if gradient.interpolation_cs != ColorSpaceTag::Srgb => {
+            Some(gradient)
+        }
+        _ => None,
+    }
+}
+
+/// `gradient` with its stops replaced by `stops`, which are interpolated in sRGB.
+fn srgb_gradient(gradient: &Gradient, stops: ColorStops) -> Gradient {
+    Gradient {
+        interpolation_cs: ColorSpaceTag::Srgb,
+        hue_direction: HueDirection::default(),
+        stops,
+        ..gradient.clone()
+    }
+}
+
+/// Approximate the ramp of `gradient` with stops interpolated in sRGB.
+///
+/// Colors are interpolated without premultiplying, as the ramps of sRGB gradients are.
+fn srgb_stops(gradient: &Gradient) -> ColorStops {
+    let mut stops = ColorStops::default();
+    let Some(first) = gradient.stops.first() else {
+        return stops;
+    };
+    stops.push(srgb_stop(
+        first.offset,
+        first.color.to_alpha_color::<Srgb>(),
+    ));
+    for pair in gradient.stops.windows(2) {
+        let [start, end] = [pair[0], pair[1]];
+        let span = end.offset - start.offset;
+        // Hard stops don't have a ramp between them.
+        if span <= 0.0 {
+            stops.push(srgb_stop(end.offset, end.color.to_alpha_color::<Srgb>()));
+            continue;
+        }
+        let ramp = peniko::color::gradient_unpremultiplied::<Srgb>(
+            start.color,
+            end.color,
+            gradient.interpolation_cs,
+            gradient.hue_direction,
+            TOLERANCE,
+        );
+        // The first color of each ramp is the last of the one before it.
+        for (t, color) in ramp.skip(1) {
+            stops.push(srgb_stop(start.offset + t * span, color));
+        }
+    }
+    stops
+}
+
+fn srgb_stop(offset: f32, color: AlphaColor<Srgb>) -> ColorStop {
+    ColorStop {
+        offset,
+        color: color.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::color::Oklab;
+    use peniko::color::palette::css;
+
+    use super::*;
+
+    fn red_to_blue(cs: ColorSpaceTag, hue_direction: HueDirection) -> Gradient {
+        Gradient {
+            interpolation_cs: cs,
+            hue_direction,
+            ..Gradient::new_linear((0.0, 0.0), (100.0, 0.0)).with_stops([css::RED, css::BLUE])
+        }
+    }
+
+    /// Check that the sRGB stops of `gradient` approximate its ramp within the tolerance, at
+    /// the midpoints of the segments where the error is measured.
+    fn check_stops(gradient: &Gradient) {
+        let interpolated = GradientCache::default()
+            .interpolate(BrushRef::Gradient(gradient))
+            .unwrap();
+        assert_eq!(interpolated.interpolation_cs, ColorSpaceTag::Srgb);
+        assert_eq!(interpolated.kind, gradient.kind);
+        let stops = &interpolated.stops;
+        assert!(stops.len() > 2, "expected extra stops, got {}", stops.len());
+        assert_eq!(stops[0].offset, 0.0);
+        assert_eq!(stops[stops.len() - 1].offset, 1.0);
+        let [start, end] = [gradient.stops[0].color, gradient.stops[1].color];
+        let exact = start.interpolate_unpremultiplied(
+            end,
+            gradient.interpolation_cs,
+            gradient.hue_direction,
+        );
+        for pair in stops.windows(2) {
+            assert!(pair[0].offset < pair[1].offset);
+            assert_eq!(pair[1].color.cs, ColorSpaceTag::Srgb);
+            let [a, b] = [pair[0], pair[1]].map(|stop| stop.color.to_alpha_color::<Srgb>());
+            let midpoint = 0.5 * (pair[0].offset + pair[1].offset);
+            let expected: AlphaColor<Oklab> = exact.eval(midpoint).to_alpha_color();
+            let error = expected.difference(a.lerp_rect(b, 0.5).convert());
+            assert!(
+                error <= TOLERANCE + 1e-4,
+                "error {error} at offset {midpoint}"
+            );
+        }
+    }
+
+    #[test]
+    fn oklab_stops_are_within_tolerance() {
+        check_stops(&red_to_blue(ColorSpaceTag::Oklab, HueDirection::Shorter));
+    }
+
+    #[test]
+    fn linear_srgb_stops_are_within_tolerance() {
+        check_stops(&red_to_blue(
+            ColorSpaceTag::LinearSrgb,
+            HueDirection::Shorter,
+        ));
+    }
+
+    #[test]
+    fn oklch_stops_follow_hue_direction() {
+        let shorter = red_to_blue(ColorSpaceTag::Oklch, HueDirection::Shorter);
+        let longer = red_to_blue(ColorSpaceTag::Oklch, HueDirection::Longer);
+        check_stops(&shorter);
+        check_stops(&longer);
+        let mut cache = GradientCache::default();
+        let shorter = cache.interpolate(BrushRef::Gradient(&shorter)).unwrap();
+        let longer = cache.interpolate(BrushRef::Gradient(&longer)).unwrap();
+        assert_ne!(shorter.stops, longer.stops);
+    }
+
+    #[test]
+    fn srgb_gradients_are_encoded_as_they_are() {
+        let gradient = red_to_blue(ColorSpaceTag::Srgb, HueDirection::Shorter);
+        let mut cache = GradientCache::default();
+        assert!(cache.interpolate(BrushRef::Gradient(&gradient)).is_none());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn hard_stops_are_kept() {
+        let gradient = Gradient {
+            interpolation_cs: ColorSpaceTag::Oklab,
+            ..Gradient::new_linear((0.0, 0.0), (100.0, 0.0)).with_stops([
+                (0.0, css::RED),
+                (0.5, css::RED),
+                (0.5, css::BLUE),
+                (1.0, css::BLUE),
+            ])
+        };
+        let interpolated = GradientCache::default()
+            .interpolate(BrushRef::Gradient(&gradient))
+            .unwrap();
+        let hard = interpolated
+            .stops
+            .windows(2)
+            .filter(|pair| pair[0].offset == 0.5 && pair[1].offset == 0.5)
+            .count();
+        assert_eq!(hard, 1);
+    }
+
+    #[test]
+    fn unused_stops_are_pruned() {
+        let gradient = red_to_blue(ColorSpaceTag::Oklab, HueDirection::Shorter);
+        let mut cache = GradientCache::default();
+        cache.interpolate(BrushRef::Gradient(&gradient));
+        cache.interpolate(BrushRef::Gradient(&gradient));
+        assert_eq!(cache.entries.len(), 1);
+        cache.prune();
+        assert_eq!(cache.entries.len(), 1);
+        cache.prune();
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Queries of the geometry drawn by a scene.
+//!
+//! The queries decode the scene's encoding, so their cost is proportional to the size of
+//! the scene. Strokes and glyphs are expanded into outlines on the CPU for each query.
+
+use std::collections::{HashMap, HashSet};
+
+use peniko::kurbo::{self, Affine, BezPath, Point, Rect, Shape, StrokeOpts};
+use peniko::{Brush, Fill, Style};
+use vello_encoding::Encoding;
+
+use crate::Scene;
+use crate::decode::{self, Command};
+use crate::filter::FilterLayer;
+
+/// The largest distance between the outline of a stroke used by queries and the exact
+/// outline, in the coordinates of the scene.
+const TOLERANCE: f64 = 0.1;
+
+/// An id chosen by the application to tag a draw object, which [`Scene::hit_test`]
+/// returns when the draw object contains the point.
+///
+/// Draw objects are tagged with [`Scene::fill_with_id`], [`Scene::stroke_with_id`] and
+/// [`DrawGlyphs::id`](crate::DrawGlyphs::id). Ids don't need to be unique.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DrawId(pub u64);
+
+impl Scene {
+    /// Returns the bounding box of everything drawn by the scene, in its coordinates, or
+    /// `None` if it draws nothing.
+    ///
+    /// The bounding box covers the outlines of fills, strokes and glyphs, intersected with
+    /// the bounding boxes of the layers they are drawn in. Filter and mask layers are
+    /// covered by their clip shapes.
+    pub fn bounds(&self) -> Option<Rect> {
+        let mut clips = vec![Some(Rect::new(
+            f64::NEG_INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::INFINITY,
+        ))];
+        let mut bounds: Option<Rect> = None;
+        let scene = self.with_filter_layers_closed();
+        decode::decode(&scene.encoding, |command| {
+            let clip = *clips.last().unwrap();
+            match command {
+                Command::PushLayer {
+                    clip: path,
+                    transform,
+                    ..
+                } => {
+                    let rect = (transform * path).bounding_box();
+                    clips.push(clip.and_then(|clip| overlap(clip, rect)));
+                }
+                Command::PopLayer => {
+                    if clips.len() > 1 {
+                        clips.pop();
+                    }
+                }
+                command => {
+                    for (outline, _) in outlines(command) {
+                        let rect = clip.and_then(|clip| overlap(clip, outline.bounding_box()));
+                        if let Some(rect) = rect {
+                            bounds = Some(bounds.map_or(rect, |bounds| bounds.union(rect)));
+                        }
+                    }
+                }
+            }
+        });
+        bounds
+    }
+
+    /// Returns the ids of the tagged draw objects whose geometry contains `point`, with the
+    /// topmost draw object first.
+    ///
+    /// A draw object contains the point if its fill, or the outline of its stroke, covers
+    /// the point, and so do the clip shapes of all of the layers it is drawn in. Brushes
+    /// aren't taken into account, so transparent parts of draw objects can still be hit.
+    /// Draw objects inside filter and mask layers are tested against their unfiltered
+    /// geometry.
+    ///
+    /// Each id is returned at most once, at the position of its topmost draw object.
+    pub fn hit_test(&self, point: Point) -> Vec<DrawId> {
+        let mut hits = Vec::new();
+        let scene = self.with_filter_layers_closed();
+        hit_test_encoding(
+            &scene.encoding,
+            &scene.draw_ids,
+            &scene.filters,
+            point,
+            &mut hits,
+        );
+        hits.reverse();
+        let mut seen = HashSet::new();
+        hits.retain(|id| seen.insert(*id));
+        hits
+    }
+
+    /// Tag the draw object with the given index in the encoding, if it was encoded.
+    pub(crate) fn tag_draw(&mut self, draw_ix: usize, id: DrawId) {
+        if self.encoding.draw_tags.len() > draw_ix {
+            self.draw_ids.push((draw_ix, id));
+        }
+    }
+}
+
+/// Returns the id of the topmost tagged draw object in the content of a filter layer which
+/// contains `point`, in the coordinates of the content.
+#[cfg(feature = "wgpu")]
+pub(crate) fn hit_test_layer(
+    layer: &FilterLayer,
+    filters: &[FilterLayer],
+    point: Point,
+) -> Option<DrawId> {
+    let mut hits = Vec::new();
+    hit_test_encoding(&layer.content, &layer.draw_ids, filters, point, &mut hits);
+    hits.pop()
+}
+
+/// Push the ids of the draw objects in `encoding` which contain `point` onto `hits`, from
+/// the bottom up.
+fn hit_test_encoding(
+    encoding: &Encoding,
+    draw_ids: &[(usize, DrawId)],
+    filters: &[FilterLayer],
+    point: Point,
+    hits: &mut Vec<DrawId>,
+) {
+    let ids: HashMap<usize, DrawId> = draw_ids.iter().copied().collect();
+    let layers: HashMap<u64, &FilterLayer> = filters
+        .iter()
+        .map(|layer| (layer.image.data.id(), layer))
+        .collect();
+    let mut clips = vec![true];
+    decode::decode_indexed(encoding, |draw_ix, command| {
+        let inside = *clips.last().unwrap();
+        match command {
+            Command::PushLayer {
+                clip,
+                transform,
+                fill,
+                ..
+            } => clips.push(inside && contains(&(transform * clip), fill, point)),
+            Command::PopLayer => {
+                if clips.len() > 1 {
+                    clips.pop();
+                }
+            }
+            _ if !inside => {}
+            // The placeholder image of a filter layer.
+            Command::Draw {
+                path,
+                transform,
+                brush: Brush::Image(image),
+                brush_transform,
+                ..
+            } if layers.contains_key(&image.data.id()) => {
+                let layer = layers[&image.data.id()];
+                if !contains(&(transform * path), Fill::NonZero, point) {
+                    return;
+                }
+                // The image covers the content from the origin of the layer.
+                let image_transform = transform * brush_transform.unwrap_or(Affine::IDENTITY);
+                if image_transform.determinant() == 0.0 {
+                    return;
+                }
+                let content_point = image_transform.inverse() * point + layer.origin.to_vec2();
+                hit_test_encoding(
+                    &layer.content,
+                    &layer.draw_ids,
+                    filters,
+                    content_point,
+                    hits,
+                );
+            }
+            command => {
+                let Some(&id) = ids.get(&draw_ix) else {
+                    return;
+                };
+                let hit = outlines(command)
+                    .iter()
+                    .any(|(outline, fill)| contains(outline, *fill, point));
+                if hit {
+                    hits.push(id);
+                }
+            }
+        }
+    });
+}
+
+/// The areas covered by a drawing command, in the coordinates of the scene.
+fn outlines(command: Command<'_>) -> Vec<(BezPath, Fill)> {
+    match command {
+        Command::Draw {
+            path,
+            transform,
+            style: Style::Fill(fill),
+            ..
+        } => vec![(transform * path, fill)],
+        Command::Draw {
+            path,
+            transform,
+            style: Style::Stroke(stroke),
+            ..
+        } => {
+            let scale = decode::max_scale(transform);
+            if scale == 0.0 || !scale.is_finite() {
+                return Vec::new();
+            }
+            let outline = kurbo::stroke(&path, &stroke, &StrokeOpts::default(), TOLERANCE / scale);
+            vec![(transform * outline, Fill::NonZero)]
+        }
+        Command::BlurredRect {
+            path, transform, ..
+        } => vec![(transform * path, Fill::NonZero)],
+        Command::Glyphs { run, glyphs, brush } => {
+            let mut result = Vec::new();
+            decode::outline_glyphs(run, glyphs, &brush, |command| {
+                result.extend(outlines(command));
+            });
+            result
+        }
+        Command::PushLayer { .. } | Command::PopLayer => Vec::new(),
+    }
+}
+
+fn contains(path: &BezPath, fill: Fill, point: Point) -> bool {
+    let winding = path.winding(point);
+    match fill {
+        Fill::NonZero => winding != 0,
+        Fill::EvenOdd => winding % 2 != 0,
+    }
+}
+
+/// The intersection of two rectangles, or `None` if it is empty.
+fn overlap(a: Rect, b: Rect) -> Option<Rect> {
+    let rect = a.intersect(b);
+    (rect.width() > 0.0 && rect.height() > 0.0).then_some(rect)
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::Mix;
+    use peniko::color::palette::css;
+    use peniko::kurbo::{Circle, Stroke};
+
+    use super::*;
+    use crate::Filter;
+
+    fn fill(scene: &mut Scene, id: u64, shape: &impl Shape) {
+        scene.fill_with_id(
+            DrawId(id),
+            Fill::NonZero,
+            Affine::IDENTITY,
+            css::RED,
+            None,
+            shape,
+        );
+    }
+
+    fn ids(ids: &[u64]) -> Vec<DrawId> {
+        ids.iter().copied().map(DrawId).collect()
+    }
+
+    /// Check the bounds of `scene`, up to the precision of the encoding.
+    fn check_bounds(scene: &Scene, expected: Rect) {
+        let bounds = scene.bounds().unwrap();
+        let error = (bounds.origin() - expected.origin()).hypot()
+            + (bounds.size() - expected.size()).to_vec2().hypot();
+        assert!(error < 1e-4, "{bounds:?} != {expected:?}");
+    }
+
+    #[test]
+    fn topmost_draws_are_first() {
+        let mut scene = Scene::new();
+        fill(&mut scene, 1, &Rect::new(0.0, 0.0, 20.0, 20.0));
+        fill(&mut scene, 2, &Rect::new(10.0, 10.0, 30.0, 30.0));
+        fill(&mut scene, 3, &Rect::new(40.0, 40.0, 50.0, 50.0));
+        assert_eq!(scene.hit_test(Point::new(15.0, 15.0)), ids(&[2, 1]));
+        assert_eq!(scene.hit_test(Point::new(5.0, 5.0)), ids(&[1]));
+        assert_eq!(scene.hit_test(Point::new(45.0, 45.0)), ids(&[3]));
+        assert!(scene.hit_test(Point::new(35.0, 35.0)).is_empty());
+    }
+
+    #[test]
+    fn ids_are_returned_once() {
+        let mut scene = Scene::new();
+        let rect = Rect::new(0.0, 0.0, 20.0, 20.0);
+        fill(&mut scene, 1, &rect);
+        fill(&mut scene, 2, &rect);
+        fill(&mut scene, 1, &rect);
+        assert_eq!(scene.hit_test(Point::new(10.0, 10.0)), ids(&[1, 2]));
+    }
+
+    #[test]
+    fn untagged_draws_are_ignored() {
+        let mut scene = Scene::new();
+        let rect = Rect::new(0.0, 0.0, 20.0, 20.0);
+        fill(&mut scene, 1, &rect);
+        scene.fill(Fill::NonZero, Affine::IDENTITY, css::BLUE, None, &rect);
+        assert_eq!(scene.hit_test(Point::new(10.0, 10.0)), ids(&[1]));
+    }
+
+    #[test]
+    fn fill_rules_and_strokes_are_followed() {
+        let mut scene = Scene::new();
+        let mut ring = Circle::new((50.0, 50.0), 20.0).to_path(0.1);
+        ring.extend(Circle::new((50.0, 50.0), 10.0).to_path(0.1));
+        scene.fill_with_id(
+            DrawId(1),
+            Fill::EvenOdd,
+            Affine::IDENTITY,
+            css::RED,
+            None,
+            &ring,
+        );
+        scene.stroke_with_id(
+            DrawId(2),
+            &Stroke::new(4.0),
+            Affine::translate((100.0, 0.0)),
+            css::RED,
+            None,
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        assert_eq!(scene.hit_test(Point::new(35.0, 50.0)), ids(&[1]));
+        assert!(scene.hit_test(Point::new(50.0, 50.0)).is_empty());
+        assert_eq!(scene.hit_test(Point::new(101.0, 10.0)), ids(&[2]));
+        assert!(scene.hit_test(Point::new(110.0, 10.0)).is_empty());
+    }
+
+    #[test]
+    fn layers_clip_draws() {
+        let mut scene = Scene::new();
+        scene.push_layer(
+            Mix::Normal,
+            1.0,
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        scene.push_layer(
+            Mix::Normal,
+            1.0,
+            Affine::translate((10.0, 0.0)),
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        fill(&mut scene, 1, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        scene.pop_layer();
+        fill(&mut scene, 2, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        scene.pop_layer();
+        fill(&mut scene, 3, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        assert_eq!(scene.hit_test(Point::new(15.0, 5.0)), ids(&[3, 2, 1]));
+        assert_eq!(scene.hit_test(Point::new(5.0, 5.0)), ids(&[3, 2]));
+        assert_eq!(scene.hit_test(Point::new(25.0, 5.0)), ids(&[3]));
+    }
+
+    #[test]
+    fn filter_layers_are_searched() {
+        let clip = Rect::new(0.0, 0.0, 20.0, 20.0);
+        let mut layer = Scene::new();
+        fill(&mut layer, 1, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        layer.push_filter_layer(Filter::grayscale(1.0), Affine::IDENTITY, &clip);
+        fill(&mut layer, 2, &Rect::new(5.0, 5.0, 40.0, 40.0));
+        layer.pop_layer();
+        assert_eq!(layer.hit_test(Point::new(10.0, 10.0)), ids(&[2, 1]));
+        assert_eq!(layer.hit_test(Point::new(2.0, 2.0)), ids(&[1]));
+        // The content is clipped by the layer.
+        assert_eq!(layer.hit_test(Point::new(30.0, 30.0)), ids(&[1]));
+
+        // Appended layers are searched in the coordinates of their content.
+        let mut scene = Scene::new();
+        scene.append(&layer, Some(Affine::translate((100.0, 0.0))));
+        assert_eq!(scene.hit_test(Point::new(110.0, 10.0)), ids(&[2, 1]));
+        assert!(scene.hit_test(Point::new(10.0, 10.0)).is_empty());
+    }
+
+    #[test]
+    fn open_filter_layers_are_searched() {
+        let mut scene = Scene::new();
+        fill(&mut scene, 1, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        scene.push_filter_layer(
+            Filter::grayscale(1.0),
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        fill(&mut scene, 2, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        assert_eq!(scene.hit_test(Point::new(10.0, 10.0)), ids(&[2, 1]));
+    }
+
+    #[test]
+    fn open_filter_layers_are_closed_when_appended() {
+        let mut child = Scene::new();
+        child.push_filter_layer(
+            Filter::grayscale(1.0),
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        fill(&mut child, 1, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        let mut scene = Scene::new();
+        scene.append(&child, None);
+        // Later draws aren't drawn into the child's layer, so aren't clipped by it.
+        fill(&mut scene, 2, &Rect::new(0.0, 0.0, 40.0, 40.0));
+        assert_eq!(scene.hit_test(Point::new(10.0, 10.0)), ids(&[2, 1]));
+        assert_eq!(scene.hit_test(Point::new(30.0, 30.0)), ids(&[2]));
+    }
+
+    #[test]
+    fn empty_scenes_have_no_bounds() {
+        let mut scene = Scene::new();
+        assert_eq!(scene.bounds(), None);
+        scene.push_layer(
+            Mix::Normal,
+            1.0,
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        fill(&mut scene, 1, &Rect::new(30.0, 30.0, 40.0, 40.0));
+        scene.pop_layer();
+        assert_eq!(scene.bounds(), None);
+    }
+
+    #[test]
+    fn bounds_cover_draws_within_their_layers() {
+        let mut scene = Scene::new();
+        fill(&mut scene, 1, &Rect::new(10.0, 10.0, 20.0, 20.0));
+        scene.stroke(
+            &Stroke::new(4.0),
+            Affine::IDENTITY,
+            css::RED,
+            None,
+            &Rect::new(30.0, 0.0, 40.0, 10.0),
+        );
+        check_bounds(&scene, Rect::new(10.0, -2.0, 42.0, 20.0));
+        scene.push_layer(
+            Mix::Normal,
+            1.0,
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 60.0, 30.0),
+        );
+        fill(&mut scene, 2, &Rect::new(50.0, 20.0, 100.0, 100.0));
+        scene.pop_layer();
+        check_bounds(&scene, Rect::new(10.0, -2.0, 60.0, 30.0));
+    }
+
+    #[test]
+    fn bounds_cover_filter_layers() {
+        let mut scene = Scene::new();
+        scene.push_filter_layer(
+            Filter::grayscale(1.0),
+            Affine::translate((10.0, 10.0)),
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        fill(&mut scene, 1, &Rect::new(0.0, 0.0, 100.0, 100.0));
+        scene.pop_layer();
+        check_bounds(&scene, Rect::new(10.0, 10.0, 30.0, 30.0));
+    }
+}
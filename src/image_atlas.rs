@@ -0,0 +1,328 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A cache of the images drawn by recent renders, kept in shelf-packed pages on the GPU.
+//!
+//! The resolver packs the images drawn by each render into a new atlas, whose layout changes
+//! whenever an image is added or removed. Rather than uploading every image which moved, the
+//! images are uploaded once into a page of the cache, and copied from there into the atlas.
+//! Each page is filled with shelves of images of similar heights. When every page is full,
+//! the least recently used images are evicted to make room.
+//!
+//! The pages are only a staging cache: shaders still sample the single atlas packed by the
+//! resolver, so the size of the images one render can draw is limited by the largest texture
+//! the device supports, as without the cache. More pages only let more images be kept
+//! between renders.
+
+use std::collections::HashMap;
+
+use peniko::Image;
+
+use crate::recording::{ImageFormat, ImageProxy, Recording};
+
+/// The width and height of a page, in pixels. Larger images aren't cached.
+const PAGE_SIZE: u32 = 2048;
+
+/// The largest number of pages of the cache.
+const MAX_PAGES: usize = 8;
+
+/// Statistics of the image cache of a renderer, for the last render.
+///
+/// See [`Renderer::image_atlas_stats`](crate::Renderer::image_atlas_stats).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageAtlasStats {
+    /// The number of pages of the cache.
+    pub pages: u32,
+    /// The number of images held by the cache.
+    pub cached_images: u32,
+    /// The number of images which were copied from the cache, rather than uploaded.
+    pub hits: u32,
+    /// The number of images uploaded into the cache.
+    pub uploads: u32,
+    /// The number of images evicted from the cache to make room for others.
+    pub evictions: u32,
+    /// The number of images uploaded directly into the atlas, as they are larger than a
+    /// page, or the cache is full of images drawn by the same render.
+    pub uncached: u32,
+}
+
+/// The key of an image in the cache.
+///
+/// The same data can be drawn as images of different sizes or formats, which are cached
+/// separately.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct ImageKey {
+    id: u64,
+    width: u32,
+    height: u32,
+    format: u8,
+}
+
+impl ImageKey {
+    pub(crate) fn new(image: &Image) -> Self {
+        Self {
+            id: image.data.id(),
+            width: image.width,
+            height: image.height,
+            format: image.format as u8,
+        }
+    }
+}
+
+/// The pages of cached images, and where each image is.
+#[derive(Default)]
+pub(crate) struct ImageCache {
+    pages: Vec<Page>,
+    /// The cached images.
+    entries: HashMap<ImageKey, Entry>,
+    /// The number of renders which used the cache.
+    frame: u64,
+    stats: ImageAtlasStats,
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    page: usize,
+    /// The rectangle of the image in its page, as `[x, y, width, height]`.
+    rect: [u32; 4],
+    /// The last frame which drew the image.
+    last_used: u64,
+}
+
+struct Page {
+    image: ImageProxy,
+    /// The shelves of the page, from top to bottom.
+    shelves: Vec<Shelf>,
+}
+
+/// A row of images in a page.
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// The free spans of the shelf, as `[x, width]` in order of `x`.
+    free: Vec<[u32; 2]>,
+}
+
+impl ImageCache {
+    /// Start a new frame, resetting the statistics.
+    pub(crate) fn begin_frame(&mut self) {
+        self.frame += 1;
+        self.stats = ImageAtlasStats::default();
+    }
+
+    /// The statistics of the current frame.
+    pub(crate) fn stats(&self) -> ImageAtlasStats {
+        ImageAtlasStats {
+            pages: self.pages.len() as u32,
+            cached_images: self.entries.len() as u32,
+            ..self.stats
+        }
+    }
+
+    /// Mark a cached image as drawn by the current frame, so that it isn't evicted.
+    pub(crate) fn touch(&mut self, image: &Image) {
+        if let Some(entry) = self.entries.get_mut(&ImageKey::new(image)) {
+            entry.last_used = self.frame;
+        }
+    }
+
+    /// Record a copy of `image` into `atlas` at `[x, y]`, from the cache if possible.
+    ///
+    /// Images which aren't cached yet are uploaded into a page first. Images which can't be
+    /// cached are written into the atlas directly.
+    pub(crate) fn copy_to(
+        &mut self,
+        recording: &mut Recording,
+        image: &Image,
+        atlas: ImageProxy,
+        [x, y]: [u32; 2],
+    ) {
+        let entry = match self.entries.get_mut(&ImageKey::new(image)) {
+            Some(entry) => {
+                entry.last_used = self.frame;
+                self.stats.hits += 1;
+                Some(*entry)
+            }
+            None => self.insert(recording, image),
+        };
+        match entry {
+            Some(entry) => {
+                let page = self.pages[entry.page].image;
+                recording.copy_image_region(page, entry.rect, atlas, x, y);
+            }
+            None => {
+                self.stats.uncached += 1;
+                recording.write_image(atlas, x, y, image.clone());
+            }
+        }
+    }
+
+    /// Upload `image` into a page, or return `None` if there is no room for it.
+    fn insert(&mut self, recording: &mut Recording, image: &Image) -> Option<Entry> {
+        let (width, height) = (image.width, image.height);
+        if width == 0 || height == 0 || width > PAGE_SIZE || height > PAGE_SIZE {
+            return None;
+        }
+        let (page, [x, y]) = self.allocate(width, height)?;
+        recording.write_image(self.pages[page].image, x, y, image.clone());
+        self.stats.uploads += 1;
+        let entry = Entry {
+            page,
+            rect: [x, y, width, height],
+            last_used: self.frame,
+        };
+        self.entries.insert(ImageKey::new(image), entry);
+        Some(entry)
+    }
+
+    /// Find room for an image, adding a page or evicting images if needed.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(usize, [u32; 2])> {
+        loop {
+            for (ix, page) in self.pages.iter_mut().enumerate() {
+                if let Some(position) = page.allocate(width, height) {
+                    return Some((ix, position));
+                }
+            }
+            if self.pages.len() < MAX_PAGES {
+                self.pages.push(Page {
+                    image: ImageProxy::new(PAGE_SIZE, PAGE_SIZE, ImageFormat::Rgba8),
+                    shelves: Vec::new(),
+                });
+                continue;
+            }
+            // Images drawn by the current frame are copied after the upload, so they can't
+            // be overwritten by it.
+            let (&key, _) = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.last_used < self.frame)
+                .min_by_key(|(_, entry)| entry.last_used)?;
+            let entry = self.entries.remove(&key).unwrap();
+            self.pages[entry.page].free(entry.rect);
+            self.stats.evictions += 1;
+        }
+    }
+
+    /// Free the pages of the cache.
+    pub(crate) fn free(&mut self, recording: &mut Recording) {
+        for page in self.pages.drain(..) {
+            recording.free_image(page.image);
+        }
+        self.entries.clear();
+    }
+}
+
+impl Page {
+    /// Find room for an image in a shelf, opening a new shelf if no shelf of a similar
+    /// height has room.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        let top = self
+            .shelves
+            .last()
+            .map_or(0, |shelf| shelf.y + shelf.height);
+        let best = self
+            .shelves
+            .iter_mut()
+            .filter(|shelf| shelf.height >= height && shelf.has_room(width))
+            .min_by_key(|shelf| shelf.height);
+        // Tall shelves are only used for short images once the page is full.
+        match best {
+            Some(shelf) if shelf.height <= 2 * height || top + height > PAGE_SIZE => {
+                return shelf.allocate(width);
+            }
+            _ => {}
+        }
+        if top + height > PAGE_SIZE {
+            return None;
+        }
+        self.shelves.push(Shelf {
+            y: top,
+            height,
+            free: vec![[0, PAGE_SIZE]],
+        });
+        self.shelves.last_mut().unwrap().allocate(width)
+    }
+
+    /// Free the rectangle of an image, and remove the shelves at the bottom which are empty.
+    fn free(&mut self, [x, y, width, _]: [u32; 4]) {
+        if let Some(shelf) = self.shelves.iter_mut().find(|shelf| shelf.y == y) {
+            shelf.free(x, width);
+        }
+        while self
+            .shelves
+            .last()
+            .is_some_and(|shelf| shelf.free == [[0, PAGE_SIZE]])
+        {
+            self.shelves.pop();
+        }
+    }
+}
+
+impl Shelf {
+    fn has_room(&self, width: u32) -> bool {
+        self.free.iter().any(|&[_, free]| free >= width)
+    }
+
+    fn allocate(&mut self, width: u32) -> Option<[u32; 2]> {
+        let ix = self.free.iter().position(|&[_, free]| free >= width)?;
+        let [x, free] = self.free[ix];
+        if free == width {
+            self.free.remove(ix);
+        } else {
+            self.free[ix] = [x + width, free - width];
+        }
+        Some([x, self.y])
+    }
+
+    /// Return a span to the free list, merging it with its neighbours.
+    fn free(&mut self, x: u32, width: u32) {
+        let ix = self.free.partition_point(|&[start, _]| start < x);
+        self.free.insert(ix, [x, width]);
+        if ix + 1 < self.free.len() && x + width == self.free[ix + 1][0] {
+            self.free[ix][1] += self.free.remove(ix + 1)[1];
+        }
+        if ix > 0 && self.free[ix - 1][0] + self.free[ix - 1][1] == x {
+            self.free[ix - 1][1] += self.free.remove(ix)[1];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::{Blob, ImageFormat as PenikoFormat};
+
+    use super::*;
+
+    fn image(data: &Blob<u8>, width: u32, height: u32) -> Image {
+        Image::new(data.clone(), PenikoFormat::Rgba8, width, height)
+    }
+
+    #[test]
+    fn images_are_copied_from_the_cache() {
+        let data = Blob::from(vec![0; 4 * 8 * 8]);
+        let atlas = ImageProxy::new(64, 64, ImageFormat::Rgba8);
+        let mut cache = ImageCache::default();
+        let mut recording = Recording::default();
+        for frame in 0..2 {
+            cache.begin_frame();
+            cache.copy_to(&mut recording, &image(&data, 8, 8), atlas, [0, 0]);
+            let stats = cache.stats();
+            assert_eq!((stats.uploads, stats.hits), (1 - frame, frame));
+        }
+    }
+
+    #[test]
+    fn data_drawn_at_two_sizes_is_cached_twice() {
+        let data = Blob::from(vec![0; 4 * 8 * 8]);
+        let atlas = ImageProxy::new(64, 64, ImageFormat::Rgba8);
+        let mut cache = ImageCache::default();
+        let mut recording = Recording::default();
+        cache.begin_frame();
+        cache.copy_to(&mut recording, &image(&data, 8, 8), atlas, [0, 0]);
+        cache.copy_to(&mut recording, &image(&data, 4, 16), atlas, [8, 0]);
+        let stats = cache.stats();
+        assert_eq!((stats.uploads, stats.hits, stats.cached_images), (2, 0, 2));
+        let rects: Vec<[u32; 4]> = cache.entries.values().map(|entry| entry.rect).collect();
+        assert!(rects.contains(&[0, 0, 8, 8]) && rects.iter().any(|rect| rect[2..] == [4, 16]));
+    }
+}
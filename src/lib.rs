@@ -112,14 +112,41 @@
 use cosmyc_text::ttf_parser as _;
 
 mod advanced_text;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+mod color_convert;
+#[cfg(feature = "cpu")]
+mod cpu;
+mod damage;
 mod debug;
+mod decode;
 mod drawing_ops;
+mod filter;
 mod glyph_builder;
+mod gradient;
+mod hit_test;
+mod image_atlas;
+mod image_io;
+#[cfg_attr(
+    not(any(feature = "wgpu", feature = "cpu")),
+    expect(dead_code, reason = "mip chains are only generated by the renderers")
+)]
+mod mipmap;
+#[cfg(feature = "pdf")]
+mod pdf;
+#[cfg(feature = "wgpu")]
+mod pick;
 mod recording;
 mod render;
 mod scene;
 mod scene_core;
+mod scene_format;
 mod shaders;
+pub mod snapshot;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+mod stroker;
+#[cfg(feature = "svg")]
+pub mod svg;
+mod text;
 
 #[cfg(feature = "wgpu")]
 pub mod util;
@@ -143,13 +170,18 @@ pub mod low_level {
     pub use crate::shaders::FullShaders;
 }
 #[cfg(feature = "wgpu")]
-use std::{num::NonZeroUsize, sync::atomic::AtomicBool};
+use std::{collections::HashSet, num::NonZeroUsize, sync::atomic::AtomicBool};
 
 // Import dependencies used by glyphon integration and image handling
 use cosmyc_text as _;
+#[cfg(feature = "cpu")]
+pub use cpu::CpuRenderer;
 #[cfg(feature = "wgpu")]
 use debug::DebugLayers;
+pub use filter::{Filter, MaskType};
 pub use glyph_builder::DrawGlyphs;
+pub use hit_test::DrawId;
+pub use image_atlas::ImageAtlasStats;
 use low_level::ShaderId;
 #[cfg(feature = "wgpu")]
 use low_level::{BumpAllocators, FullShaders, Recording, Render};
@@ -157,8 +189,12 @@ use low_level::{BumpAllocators, FullShaders, Recording, Render};
 pub use peniko;
 /// 2D geometry, with a focus on curves.
 pub use peniko::kurbo;
-use png as _;
+pub use render::BumpSizes;
 pub use scene_core::Scene;
+pub use scene_format::{DeserializeError, SCENE_FORMAT_VERSION};
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub use stroker::StrokeExpansion;
+pub use text::{TextAlign, TextStyle};
 use thiserror::Error;
 #[cfg(feature = "wgpu")]
 use vello_encoding::Resolver;
@@ -168,6 +204,8 @@ pub use wgpu;
 #[cfg(feature = "wgpu")]
 use wgpu::{Device, Queue, TextureView};
 #[cfg(feature = "wgpu")]
+pub use wgpu_engine::RenderStats;
+#[cfg(feature = "wgpu")]
 use wgpu_engine::{ExternalResource, WgpuEngine};
 #[cfg(all(feature = "wgpu", feature = "wgpu-profiler"))]
 use wgpu_profiler::{GpuProfiler, GpuProfilerSettings};
@@ -259,6 +297,41 @@ impl FromIterator<AaConfig> for AaSupport {
     }
 }
 
+/// The pixel format of the target which scenes are rendered into.
+///
+/// This is configured at `Renderer` creation time by setting [`RendererOptions::output_format`].
+///
+/// Scenes are encoded with 8-bit sRGB colors, and compositing happens on sRGB encoded values in
+/// either format. Colors outside of the sRGB gamut, such as extended range or Display P3 colors,
+/// are clamped to it when the scene is encoded.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Separated (not premultiplied) 8-bit sRGB encoded RGBA, in `Rgba8Unorm` textures.
+    #[default]
+    Rgba8,
+    /// Separated half precision floating point RGBA in linear light, in `Rgba16Float` textures.
+    ///
+    /// Colors are converted to linear light as they are read, blended in linear light, and
+    /// stored without being clamped, so blending such as [`Compose::Plus`](peniko::Compose::Plus)
+    /// can produce values brighter than sRGB white. The colors of the scene are still encoded
+    /// with eight bits per channel, and clip layers nested more than four deep are kept at eight
+    /// bits while their content is drawn.
+    ///
+    /// Float targets are rendered with area anti-aliasing, which is also used when an MSAA
+    /// method is requested.
+    Rgba16Float,
+}
+
+impl OutputFormat {
+    #[cfg(any(feature = "wgpu", feature = "cpu"))]
+    pub(crate) fn image_format(self) -> recording::ImageFormat {
+        match self {
+            Self::Rgba8 => recording::ImageFormat::Rgba8,
+            Self::Rgba16Float => recording::ImageFormat::Rgba16Float,
+        }
+    }
+}
+
 /// Errors that can occur in Vello.
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -273,12 +346,10 @@ pub enum Error {
     #[error("Couldn't create wgpu surface")]
     WgpuCreateSurfaceError(#[from] wgpu::CreateSurfaceError),
     /// Surface doesn't support the required texture formats.
-    /// Make sure that you have a surface which provides one of
-    /// [`TextureFormat::Rgba8Unorm`][wgpu::TextureFormat::Rgba8Unorm]
-    /// or [`TextureFormat::Bgra8Unorm`][wgpu::TextureFormat::Bgra8Unorm] as texture formats.
-    // TODO: Why does this restriction exist?
+    /// Make sure that you have a surface which provides one of the
+    /// [`util::SUPPORTED_SURFACE_FORMATS`] as texture formats.
     #[cfg(feature = "wgpu")]
-    #[error("Couldn't find `Rgba8Unorm` or `Bgra8Unorm` texture formats for surface")]
+    #[error("Couldn't find a supported texture format for surface")]
     UnsupportedSurfaceFormat,
 
     /// Used a buffer inside a recording while it was not available.
@@ -286,6 +357,11 @@ pub enum Error {
     #[cfg(feature = "wgpu")]
     #[error("Buffer '{0}' is not available but used for {1}")]
     UnavailableBufferUsed(&'static str, &'static str),
+    /// Used an image inside a recording while it was not available.
+    /// Check if it was written by an earlier command and not freed before its last usage.
+    #[cfg(feature = "wgpu")]
+    #[error("Image is not available but used for {0}")]
+    UnavailableImageUsed(&'static str),
     /// Failed to async map a buffer.
     /// See [`wgpu::BufferAsyncError`] for more information.
     #[cfg(feature = "wgpu")]
@@ -308,6 +384,13 @@ pub enum Error {
     #[doc(hidden)] // End-users of Vello should not have `wgpu-profiler` enabled.
     ProfilerCreationError(#[from] wgpu_profiler::CreationError),
 
+    /// Failed to encode a rendered image as PNG.
+    #[error("Failed to encode PNG image")]
+    PngEncoding(#[from] png::EncodingError),
+    /// An image was requested for a target with a zero width or height.
+    #[error("Can't render an image of {0}x{1} pixels")]
+    EmptyImage(u32, u32),
+
     /// Failed to compile the shaders.
     #[cfg(feature = "hot_reload")]
     #[error("Failed to compile shaders:\n{0}")]
@@ -316,29 +399,35 @@ pub enum Error {
 }
 
 #[cfg_attr(
-    not(feature = "wgpu"),
-    expect(dead_code, reason = "this can be unused when wgpu feature is not used")
+    not(any(feature = "wgpu", feature = "cpu")),
+    expect(
+        dead_code,
+        reason = "this can be unused when neither the wgpu nor cpu features are used"
+    )
 )]
 pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// Renders a scene into a texture or surface.
 ///
-/// Currently, each renderer only supports a single surface format, if it
-/// supports drawing to surfaces at all.
-/// This is an assumption which is known to be limiting, and is planned to change.
+/// Scenes are rendered into [`Rgba8Unorm`](wgpu::TextureFormat::Rgba8Unorm) textures, or into
+/// [`Rgba16Float`](wgpu::TextureFormat::Rgba16Float) textures if the renderer was created with
+/// [`OutputFormat::Rgba16Float`]. A single renderer using `Rgba8Unorm` textures can be used with
+/// surfaces of any of the [supported formats], by blitting the rendered texture to the surface
+/// as [`util::RenderSurface::blit`] does.
+///
+/// [supported formats]: util::SUPPORTED_SURFACE_FORMATS
 #[cfg(feature = "wgpu")]
 pub struct Renderer {
-    #[cfg_attr(
-        not(feature = "hot_reload"),
-        expect(
-            dead_code,
-            reason = "Options are only used to reinitialise on a hot reload"
-        )
-    )]
     options: RendererOptions,
     engine: WgpuEngine,
     resolver: Resolver,
     shaders: FullShaders,
+    bump_sizes: BumpSizes,
+    mips: mipmap::MipCache,
+    /// The state of the main pass, which keeps its resources resident between frames.
+    render: Render,
+    stats: RenderStats,
+    pick: Option<pick::PickBuffer>,
     #[cfg(feature = "debug_layers")]
     debug: debug::DebugRenderer,
     #[cfg(feature = "wgpu-profiler")]
@@ -409,6 +498,47 @@ pub struct RendererOptions {
     ///
     /// For much more discussion of expected usage patterns, see the documentation on that type.
     pub pipeline_cache: Option<wgpu::PipelineCache>,
+
+    /// How many times a render may be run again after overflowing its dynamically
+    /// allocated buffers.
+    ///
+    /// Each time the coarse phase of a render overflows, the overflowing buffers are grown
+    /// and the coarse phase is run again, up to this many times. If the last attempt still
+    /// overflows, the scene is rendered with parts missing. Targets which are rendered in
+    /// tiles are retried tile by tile.
+    ///
+    /// The buffers have to be read back to detect overflow, so while this is non-zero,
+    /// [`Renderer::render_to_texture`] blocks until the coarse phase has finished on the
+    /// GPU. This is zero by default, which renders each frame without waiting for the GPU
+    /// and drops the content which overflows, so applications which can afford to block
+    /// opt in to retries. Overflow is never detected on the web, except by
+    /// [`Renderer::render_to_texture_async`].
+    pub max_coarse_retries: u32,
+
+    /// The format of the textures which scenes are rendered into.
+    ///
+    /// This can't be changed after the renderer is created, as the shaders which write the
+    /// target depend on it.
+    pub output_format: OutputFormat,
+
+    /// Where the outlines of strokes are computed.
+    ///
+    /// [`StrokeExpansion::Cpu`] is a slow reference implementation, for checking the output
+    /// of the GPU stroker.
+    pub stroke_expansion: StrokeExpansion,
+
+    /// If true, each render also draws an image of the topmost tagged draw object at each
+    /// pixel, which [`Renderer::pick`] reads from.
+    ///
+    /// The fine stage writes the index of the topmost draw object at each pixel into an
+    /// image of 32 bit integers. Coarse rasterization doesn't record which draw object each
+    /// color belongs to, so the image is drawn by running the whole pipeline a second time,
+    /// which roughly doubles the GPU cost of every render. The draw tags and draw data of
+    /// the scene are copied for the second pass every frame, even when it is unchanged.
+    /// [`Renderer::render_damage_to_texture`] draws the pick image of the whole target, not
+    /// only of the damage. Applications which only pick occasionally, or only need the draw
+    /// objects under a point, should prefer [`Scene::hit_test`].
+    pub pick_buffer: bool,
 }
 
 #[cfg(feature = "wgpu")]
@@ -422,6 +552,10 @@ impl Default for RendererOptions {
             #[cfg(not(target_os = "macos"))]
             num_init_threads: None,
             pipeline_cache: None,
+            max_coarse_retries: 0,
+            output_format: OutputFormat::Rgba8,
+            stroke_expansion: StrokeExpansion::Gpu,
+            pick_buffer: false,
         }
     }
 }
@@ -447,13 +581,18 @@ impl Renderer {
         #[cfg(not(target_arch = "wasm32"))]
         engine.build_shaders_if_needed(device, options.num_init_threads);
         #[cfg(feature = "debug_layers")]
-        let debug = debug::DebugRenderer::new(device, wgpu::TextureFormat::Rgba8Unorm, &mut engine);
+        let debug = debug::DebugRenderer::new(device);
 
         Ok(Self {
             options,
             engine,
             resolver: Resolver::new(),
             shaders,
+            bump_sizes: BumpSizes::default(),
+            mips: mipmap::MipCache::default(),
+            render: Render::persistent(),
+            stats: RenderStats::default(),
+            pick: None,
             #[cfg(feature = "debug_layers")]
             debug,
             #[cfg(feature = "wgpu-profiler")]
@@ -467,7 +606,8 @@ impl Renderer {
     ///
     /// The texture is assumed to be of the specified dimensions and have been created with
     /// the [`wgpu::TextureFormat::Rgba8Unorm`] format and the [`wgpu::TextureUsages::STORAGE_BINDING`]
-    /// flag set.
+    /// flag set. If the renderer was created with [`OutputFormat::Rgba16Float`], the texture must
+    /// instead have the [`wgpu::TextureFormat::Rgba16Float`] format.
     ///
     /// If you want to render Vello content to a surface (such as in a UI toolkit), you have two options:
     /// 1) Render to an intermediate texture, which is the same size as the surface.
@@ -477,6 +617,17 @@ impl Renderer {
     /// 2) Call `render_to_texture` directly on the [`SurfaceTexture`][wgpu::SurfaceTexture]'s texture, if
     ///    it has the right usages. This should generally be avoided, as some GPUs assume that you will not
     ///    be rendering to the surface using a compute pipeline, and optimise accordingly.
+    ///
+    /// Targets larger than 4096 pixels in either dimension are rendered in tiles, which are
+    /// stitched together in the texture.
+    ///
+    /// The dynamically allocated buffers are sized using [`Self::bump_sizes`]. If
+    /// [`RendererOptions::max_coarse_retries`] is non-zero, they are read back after the
+    /// coarse phase, and if they overflowed, they are grown and the coarse phase is run
+    /// again. Each tile of a large target is checked separately. Reading the buffers back
+    /// blocks until the coarse phase has finished on the GPU, so this is disabled by default,
+    /// and content which overflows the buffers is dropped. On the web, where the renderer
+    /// can't block, the buffers are never read back.
     pub fn render_to_texture(
         &mut self,
         device: &Device,
@@ -485,8 +636,30 @@ impl Renderer {
         texture: &TextureView,
         params: &RenderParams,
     ) -> Result<()> {
-        let (recording, target) =
-            render::render_full(scene, &mut self.resolver, &self.shaders, params);
+        if self.checks_overflow() {
+            util::block_on_wgpu(
+                device,
+                self.render_to_texture_async_internal(device, queue, scene, texture, params, None),
+            )?;
+            self.end_profiler_frame(queue);
+            self.stats = self.engine.take_stats();
+            return Ok(());
+        }
+        self.render_pick_buffer(device, queue, scene, params)?;
+        let scene = scene.with_filter_layers_closed();
+        let scene = stroker::expand_strokes(&scene, self.options.stroke_expansion);
+        let overridden_images = self.overridden_images();
+        let mip_levels = self.mips.levels(&scene, &overridden_images);
+        self.render.set_overridden_images(overridden_images);
+        let (recording, target) = render::render_full(
+            &scene,
+            &mut self.resolver,
+            &self.shaders,
+            params,
+            self.bump_sizes,
+            &mip_levels,
+            &mut self.render,
+        );
         let external_resources = [ExternalResource::Image(
             *target.as_image().unwrap(),
             texture,
@@ -500,6 +673,103 @@ impl Renderer {
             #[cfg(feature = "wgpu-profiler")]
             &mut self.profiler,
         )?;
+        self.end_profiler_frame(queue);
+        self.stats = self.engine.take_stats();
+
+        Ok(())
+    }
+
+    /// Renders the parts of a scene covered by `damage` to the target texture, leaving the
+    /// rest of the texture unchanged.
+    ///
+    /// The texture must be a target previously passed to [`Self::render_to_texture`] with
+    /// the same size, and must hold the render of a scene which only differs from `scene`
+    /// within `damage`. The damaged rectangles, in pixels, can be computed with
+    /// [`Scene::damage`], or tracked by the application. The damage is rounded out to tiles
+    /// of 16×16 pixels, and only those tiles are rasterized, so rendering an unchanged scene
+    /// with no damage does no coarse or fine work.
+    ///
+    /// The scene is flattened and binned once, coarse rasterization only processes the bins
+    /// of the bounding box of the damage, and fine rasterization only the damaged tiles,
+    /// other than with MSAA into [`OutputFormat::Rgba8`] targets, which rasterizes the whole
+    /// bounding box.
+    /// Damaged rectangles which are near each other are rendered as their union when it
+    /// covers at most 64×64 pixels which are in neither of them, as each separate region is
+    /// rasterized and copied into the texture with its own dispatches. Targets larger than
+    /// 4096 pixels are rendered in several passes, like in [`Self::render_to_texture`].
+    ///
+    /// Filter layers are always rendered in full, as their content can be sampled from
+    /// outside of the damage. Overflow of the dynamically allocated buffers is handled as
+    /// in [`Self::render_to_texture`], separately for each pass which is rendered.
+    pub fn render_damage_to_texture(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        texture: &TextureView,
+        params: &RenderParams,
+        damage: &[kurbo::Rect],
+    ) -> Result<()> {
+        if self.checks_overflow() {
+            util::block_on_wgpu(
+                device,
+                self.render_to_texture_async_internal(
+                    device,
+                    queue,
+                    scene,
+                    texture,
+                    params,
+                    Some(damage),
+                ),
+            )?;
+            self.end_profiler_frame(queue);
+            self.stats = self.engine.take_stats();
+            return Ok(());
+        }
+        self.render_pick_buffer(device, queue, scene, params)?;
+        let scene = scene.with_filter_layers_closed();
+        let scene = stroker::expand_strokes(&scene, self.options.stroke_expansion);
+        let mip_levels = self.mips.levels(&scene, &self.overridden_images());
+        let (recording, target) = render::render_damage(
+            &scene,
+            &mut self.resolver,
+            &self.shaders,
+            params,
+            self.bump_sizes,
+            &mip_levels,
+            damage,
+        );
+        let external_resources = [ExternalResource::Image(
+            *target.as_image().unwrap(),
+            texture,
+        )];
+        self.engine.run_recording(
+            device,
+            queue,
+            &recording,
+            &external_resources,
+            "render_damage_to_texture",
+            #[cfg(feature = "wgpu-profiler")]
+            &mut self.profiler,
+        )?;
+        self.end_profiler_frame(queue);
+        self.stats = self.engine.take_stats();
+
+        Ok(())
+    }
+
+    /// Whether the synchronous renders read back the dynamically allocated buffers to
+    /// detect overflow, which requires blocking on the GPU.
+    fn checks_overflow(&self) -> bool {
+        self.options.max_coarse_retries > 0 && cfg!(not(target_arch = "wasm32"))
+    }
+
+    /// Collect the results of the profiler for the frame which was just rendered.
+    #[cfg_attr(
+        not(feature = "wgpu-profiler"),
+        expect(unused_variables, reason = "the queue is only used by the profiler")
+    )]
+    fn end_profiler_frame(&mut self, queue: &Queue) {
         // N.B. This is horrible; this integration of wgpu-profiler really needs some work...
         #[cfg(feature = "wgpu-profiler")]
         {
@@ -511,8 +781,94 @@ impl Renderer {
                 self.profile_result = Some(result);
             }
         }
+    }
 
-        Ok(())
+    /// Renders a scene and encodes the result as a PNG image.
+    ///
+    /// This allocates a target texture of the size given in `params` and reads it back
+    /// once rendering has finished, blocking until the GPU is idle. It is intended for
+    /// tests and offline rendering, rather than for interactive use.
+    ///
+    /// Linear output is converted to 8-bit sRGB before being encoded. Fails with
+    /// [`Error::EmptyImage`] if the width or height is zero.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn render_to_png(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        params: &RenderParams,
+    ) -> Result<Vec<u8>> {
+        if params.width == 0 || params.height == 0 {
+            return Err(Error::EmptyImage(params.width, params.height));
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("vello.render_to_png"),
+            size: wgpu::Extent3d {
+                width: params.width,
+                height: params.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.shaders.output_format.to_wgpu(),
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.render_to_texture(device, queue, scene, &view, params)?;
+        let rgba = match self.options.output_format {
+            OutputFormat::Rgba8 => util::read_texture_rgba8(device, queue, &texture)?,
+            OutputFormat::Rgba16Float => util::read_texture_rgba16float(device, queue, &texture)?
+                .chunks_exact(4)
+                .flat_map(|pixel| color_convert::linear_to_rgba8(pixel.try_into().unwrap()))
+                .collect(),
+        };
+        Ok(image_io::encode_png(params.width, params.height, &rgba)?)
+    }
+
+    /// The capacities of the dynamically allocated buffers used by the last render.
+    ///
+    /// These include any growth after the render overflowed its buffers, so they can be
+    /// stored and passed to [`Self::set_bump_sizes`] to avoid overflowing again in later
+    /// frames or in another renderer.
+    pub fn bump_sizes(&self) -> BumpSizes {
+        self.bump_sizes
+    }
+
+    /// Set the minimum capacities of the dynamically allocated buffers for later renders.
+    ///
+    /// The renderer keeps the capacities which a render had to grow to, so this is only
+    /// needed to size the buffers before the first render, or to shrink them again.
+    pub fn set_bump_sizes(&mut self, bump_sizes: BumpSizes) {
+        self.bump_sizes = bump_sizes;
+    }
+
+    /// The GPU resources created and the data uploaded by the last render, for profiling.
+    ///
+    /// The renderer keeps the mask lookup tables, gradient ramps and image atlas of the
+    /// scene resident between renders, and only uploads the gradients and images which
+    /// changed, so once the renderer has warmed up a frame of an unchanged scene uploads
+    /// little more than its encoding.
+    pub fn render_stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    /// Statistics of the cache of images which the renderer keeps between renders, for the
+    /// last render.
+    ///
+    /// Images are uploaded once into pages of the cache, and copied from there into the
+    /// image atlas of each render which draws them. When the cache is full, the least
+    /// recently drawn images are evicted. The cache doesn't raise the size limit of the
+    /// image atlas, which shaders still sample as a single texture.
+    pub fn image_atlas_stats(&self) -> ImageAtlasStats {
+        self.render.image_atlas_stats()
+    }
+
+    /// The ids of the data of the images overridden by [`Self::override_image`].
+    fn overridden_images(&self) -> HashSet<u64> {
+        self.engine.image_overrides.keys().copied().collect()
     }
 
     /// Overwrite `image` with `texture`.
@@ -523,6 +879,9 @@ impl Renderer {
     /// dimensions as the image, nor if an image which uses the same [data] but different
     /// dimensions would be rendered.
     ///
+    /// Overridden images bypass the image cache, and are copied from the texture by every
+    /// render, so changes to the texture are always shown.
+    ///
     /// [data]: peniko::Image::data
     pub fn override_image(
         &mut self,
@@ -544,13 +903,14 @@ impl Renderer {
         // We choose not to initialise these shaders in parallel, to ensure the error scope works correctly
         let shaders = shaders::full_shaders(device, &mut engine, &self.options)?;
         #[cfg(feature = "debug_layers")]
-        let debug = debug::DebugRenderer::new(device, wgpu::TextureFormat::Rgba8Unorm, &mut engine);
+        let debug = debug::DebugRenderer::new(device);
         let error = device.pop_error_scope().await;
         if let Some(error) = error {
             return Err(error.into());
         }
         self.engine = engine;
         self.shaders = shaders;
+        self.render = Render::persistent();
         #[cfg(feature = "debug_layers")]
         {
             self.debug = debug;
@@ -562,6 +922,9 @@ impl Renderer {
     ///
     /// Almost all consumers should prefer [`Self::render_to_texture`].
     ///
+    /// If the render overflows its dynamically allocated buffers, they are grown and the render
+    /// is run again, up to [`RendererOptions::max_coarse_retries`] times.
+    ///
     /// The return value is the value of the `BumpAllocators` in this rendering, which is currently used
     /// for debug output.
     ///
@@ -590,19 +953,18 @@ impl Renderer {
         }
 
         let result = self
-            .render_to_texture_async_internal(device, queue, scene, texture, params)
+            .render_to_texture_async_internal(device, queue, scene, texture, params, None)
             .await?;
 
         #[cfg(feature = "debug_layers")]
         {
             let mut recording = Recording::default();
-            let target_proxy = recording::ImageProxy::new(
-                params.width,
-                params.height,
-                recording::ImageFormat::Rgba8,
-            );
+            let target_proxy =
+                recording::ImageProxy::new(params.width, params.height, self.shaders.output_format);
             if let Some(captured) = result.captured {
                 let bump = result.bump.as_ref().unwrap();
+                self.debug
+                    .prepare(device, target_proxy.format.to_wgpu(), &mut self.engine);
                 // TODO: We could avoid this download if `DebugLayers::VALIDATION` is unset.
                 let downloads = DebugDownloads::map(&self.engine, &captured, bump).await?;
                 self.debug.render(
@@ -642,10 +1004,16 @@ impl Renderer {
                 self.profile_result = Some(result);
             }
         }
+        self.stats = self.engine.take_stats();
 
         Ok(result.bump)
     }
 
+    /// Renders a scene to the target texture, reading back the dynamically allocated buffers
+    /// after each coarse phase to detect overflow.
+    ///
+    /// If `damage` is given, only the regions of the target covering it are rendered. The
+    /// returned bump allocators are `None` when the target is rendered in regions.
     async fn render_to_texture_async_internal(
         &mut self,
         device: &Device,
@@ -653,49 +1021,129 @@ impl Renderer {
         scene: &Scene,
         texture: &TextureView,
         params: &RenderParams,
+        damage: Option<&[kurbo::Rect]>,
     ) -> Result<RenderResult> {
-        let mut render = Render::new();
+        self.render_pick_buffer(device, queue, scene, params)?;
+        let scene = scene.with_filter_layers_closed();
+        let scene = stroker::expand_strokes(&scene, self.options.stroke_expansion);
+        let overridden_images = self.overridden_images();
+        let mip_levels = self.mips.levels(&scene, &overridden_images);
         let encoding = scene.encoding();
-        // TODO: turn this on; the download feature interacts with CPU dispatch.
-        // Currently this is always enabled when the `debug_layers` setting is enabled as the bump
-        // counts are used for debug visualiation.
-        let robust = cfg!(feature = "debug_layers");
-        let recording = render.render_encoding_coarse(
-            encoding,
+        let mut recording = Recording::default();
+        let filter_images = render::render_filters(
+            &scene,
             &mut self.resolver,
             &self.shaders,
             params,
-            robust,
+            self.bump_sizes,
+            &mip_levels,
+            &mut recording,
         );
-        let target = render.out_image();
-        let bump_buf = render.bump_buf();
+        if !recording.commands.is_empty() {
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_filters",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
+        }
+        let passes = match damage {
+            Some(damage) => Some(render::damage_passes(params, damage)),
+            None if render::needs_tiles(params) => Some(
+                render::tiles([0, 0, params.width, params.height])
+                    .map(render::RegionPass::whole)
+                    .collect(),
+            ),
+            None => None,
+        };
+        if let Some(passes) = passes {
+            // Each pass is rendered with its own coarse phase, which is run again until it
+            // fits in the buffers, and its regions are then copied into place in the target.
+            let target =
+                recording::ImageProxy::new(params.width, params.height, self.shaders.output_format);
+            let external_resources = [ExternalResource::Image(target, texture)];
+            let mut tile = vello_encoding::Encoding::new();
+            for pass in passes {
+                let tile_params = render::region_params(params, pass.bounds);
+                render::translate_to_region(encoding, pass.bounds, &mut tile);
+                let mut render = Render::new();
+                render.set_overridden_images(overridden_images.clone());
+                render.set_mip_levels(mip_levels.clone());
+                self.render_coarse(
+                    device,
+                    queue,
+                    &mut render,
+                    &tile,
+                    &tile_params,
+                    &filter_images,
+                )
+                .await?;
+                let mut recording = Recording::default();
+                #[cfg(feature = "debug_layers")]
+                if let Some(captured) = render.take_captured_buffers() {
+                    self.engine.free_download(captured.lines);
+                    captured.release_buffers(&mut recording);
+                }
+                let tile_image = render.out_image();
+                render.record_fine_regions(&self.shaders, &mut recording, &pass.fine_regions());
+                render::record_region_copies(
+                    &mut recording,
+                    &self.shaders,
+                    tile_image,
+                    &pass,
+                    target,
+                );
+                self.engine.run_recording(
+                    device,
+                    queue,
+                    &recording,
+                    &external_resources,
+                    "t_async_region",
+                    #[cfg(feature = "wgpu-profiler")]
+                    &mut self.profiler,
+                )?;
+            }
+            let mut recording = Recording::default();
+            for image in filter_images.into_values() {
+                recording.free_image(image);
+            }
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_free",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
+            return Ok(RenderResult {
+                bump: None,
+                #[cfg(feature = "debug_layers")]
+                captured: None,
+            });
+        }
+        // The main render keeps its resources resident, so it is taken out of the renderer
+        // while the coarse phase borrows the renderer, and put back even if that fails.
+        let mut render = std::mem::take(&mut self.render);
+        render.set_overridden_images(overridden_images);
+        render.set_mip_levels(mip_levels);
+        let bump = self
+            .render_coarse(device, queue, &mut render, encoding, params, &filter_images)
+            .await;
+        self.render = render;
+        let bump = bump?;
+        let render = &mut self.render;
         #[cfg(feature = "debug_layers")]
         let captured = render.take_captured_buffers();
-        self.engine.run_recording(
-            device,
-            queue,
-            &recording,
-            &[],
-            "t_async_coarse",
-            #[cfg(feature = "wgpu-profiler")]
-            &mut self.profiler,
-        )?;
-
-        let mut bump: Option<BumpAllocators> = None;
-        if let Some(bump_buf) = self.engine.get_download(bump_buf) {
-            let buf_slice = bump_buf.slice(..);
-            let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-            buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-            receiver.receive().await.expect("channel was closed")?;
-            let mapped = buf_slice.get_mapped_range();
-            bump = Some(bytemuck::pod_read_unaligned(&mapped));
-        }
-        // TODO: apply logic to determine whether we need to rerun coarse, and also
-        // allocate the blend stack as needed.
-        self.engine.free_download(bump_buf);
-        // Maybe clear to reuse allocation?
+        let target = render.out_image();
         let mut recording = Recording::default();
         render.record_fine(&self.shaders, &mut recording);
+        for image in filter_images.into_values() {
+            recording.free_image(image);
+        }
         let external_resources = [ExternalResource::Image(target, texture)];
         self.engine.run_recording(
             device,
@@ -712,6 +1160,91 @@ impl Renderer {
             captured,
         })
     }
+
+    /// Run the coarse phase of `encoding` with `render`, growing the dynamically allocated
+    /// buffers and running it again while it overflows them, up to
+    /// [`RendererOptions::max_coarse_retries`] times.
+    ///
+    /// Returns the bump allocators of the last run, if they could be read back. Once this
+    /// returns, `render` is ready for [`Render::record_fine`].
+    async fn render_coarse(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        render: &mut Render,
+        encoding: &vello_encoding::Encoding,
+        params: &RenderParams,
+        filter_images: &render::FilterImages,
+    ) -> Result<Option<BumpAllocators>> {
+        // The bump buffer can't be downloaded when the coarse phase runs on the CPU, so
+        // overflow can only be detected when using the GPU, or if the `debug_layers` feature
+        // is enabled, where the bump counts are used for debug visualiation.
+        let robust = !self.options.use_cpu || cfg!(feature = "debug_layers");
+        let mut retries = 0;
+        loop {
+            render.set_bump_sizes(self.bump_sizes);
+            render.set_filter_images(filter_images.clone());
+            let recording = render.render_encoding_coarse(
+                encoding,
+                &mut self.resolver,
+                &self.shaders,
+                params,
+                robust,
+            );
+            self.bump_sizes = render.bump_sizes();
+            let bump_buf = render.bump_buf();
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_coarse",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
+
+            let mut downloaded: Option<BumpAllocators> = None;
+            if let Some(bump_buf) = self.engine.get_download(bump_buf) {
+                let buf_slice = bump_buf.slice(..);
+                let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+                buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+                receiver.receive().await.expect("channel was closed")?;
+                let mapped = buf_slice.get_mapped_range();
+                downloaded = Some(bytemuck::pod_read_unaligned(&mapped));
+            }
+            self.engine.free_download(bump_buf);
+            let overflowed = match &downloaded {
+                Some(bump) if retries < self.options.max_coarse_retries => {
+                    self.bump_sizes.grow(bump)
+                }
+                _ => false,
+            };
+            if !overflowed {
+                return Ok(downloaded);
+            }
+            log::debug!(
+                "Render overflowed its buffers, running again with {:?}",
+                self.bump_sizes
+            );
+            retries += 1;
+            let mut recording = Recording::default();
+            render.discard_fine(&mut recording);
+            #[cfg(feature = "debug_layers")]
+            if let Some(captured) = render.take_captured_buffers() {
+                self.engine.free_download(captured.lines);
+                captured.release_buffers(&mut recording);
+            }
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_discard",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
+        }
+    }
 }
 #[cfg(all(feature = "debug_layers", feature = "wgpu"))]
 pub(crate) struct DebugDownloads<'a> {
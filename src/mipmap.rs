@@ -0,0 +1,477 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Mip levels of the images in the image atlas, which are sampled by the fine stage.
+//!
+//! An image drawn much smaller than its size skips most of its pixels and aliases, so each
+//! image drawn with [`ImageQuality::Medium`] or [`ImageQuality::High`] has a mip chain, which
+//! is generated on the CPU and cached while the image's data is alive. The levels are written
+//! to the image atlas below the images placed by the resolver, and the fine stage chooses the
+//! level of each pixel from the derivatives of the image's brush transform:
+//!
+//! - [`ImageQuality::Low`] samples the nearest pixel of the image itself.
+//! - [`ImageQuality::Medium`] samples the nearest level bilinearly.
+//! - [`ImageQuality::High`] blends bilinear samples of the two nearest levels, which is
+//!   trilinear filtering.
+//!
+//! The fine stage finds the levels of an image in the mip table, which holds an entry of
+//! three words for each image with levels, sorted by the first: the offset of the image in
+//! the atlas and the offset of its levels, both packed as `(x << 16) | y` like the offsets
+//! in the image brushes, and the number of levels. The levels are stacked in a column, each
+//! half the size of the one above, rounded up. Images without an entry are sampled at full
+//! size, which includes the rendered images of filter layers, overridden images and, as
+//! the fine shaders of `vello_shaders` don't read the table, images drawn into
+//! [`Rgba8`](crate::OutputFormat::Rgba8) targets with MSAA on the GPU.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use peniko::{Blob, Extend, Image, ImageFormat, ImageQuality, WeakBlob};
+use vello_encoding::{Encoding, Patch};
+
+use crate::Scene;
+
+/// The largest height of an image atlas which mip levels are added to, which is the default
+/// maximum texture size of wgpu. Levels which don't fit aren't sampled.
+const MAX_ATLAS_HEIGHT: u32 = 8192;
+
+/// The mip levels of the images drawn by a render, by the id of their data.
+pub(crate) type MipLevels = HashMap<u64, Arc<[Image]>>;
+
+/// The mip chains of the images drawn by recent renders.
+#[derive(Default)]
+pub(crate) struct MipCache {
+    /// The mip chain of each image, by the id of its data.
+    chains: HashMap<u64, MipChain>,
+}
+
+struct MipChain {
+    /// The data of the image, which the chain is dropped with.
+    data: WeakBlob<u8>,
+    /// The levels below the image, each half the size of the one above.
+    levels: Arc<[Image]>,
+}
+
+impl MipCache {
+    /// The mip levels of the images which `scene` draws with [`ImageQuality::Medium`] or
+    /// [`ImageQuality::High`], other than those whose data has an id in `overridden`.
+    pub(crate) fn levels(&mut self, scene: &Scene, overridden: &HashSet<u64>) -> MipLevels {
+        self.chains
+            .retain(|_, chain| chain.data.upgrade().is_some());
+        let mut levels = MipLevels::new();
+        for encoding in encodings(scene) {
+            for patch in &encoding.resources.patches {
+                let Patch::Image { image, .. } = patch else {
+                    continue;
+                };
+                let id = image.data.id();
+                if !has_levels(image) || overridden.contains(&id) || levels.contains_key(&id) {
+                    continue;
+                }
+                let chain = self.chains.entry(id).or_insert_with(|| MipChain {
+                    data: image.data.downgrade(),
+                    levels: mip_chain(image),
+                });
+                levels.insert(id, chain.levels.clone());
+            }
+        }
+        levels
+    }
+}
+
+/// The encodings of `scene` and of its filter and mask layers.
+fn encodings(scene: &Scene) -> impl Iterator<Item = &Encoding> {
+    let layers = scene.filters.iter().flat_map(|layer| {
+        let mask = layer.mask.as_ref().map(|mask| &mask.content);
+        std::iter::once(&layer.content).chain(mask)
+    });
+    std::iter::once(&scene.encoding).chain(layers)
+}
+
+/// Whether `image` is sampled with mip levels.
+fn has_levels(image: &Image) -> bool {
+    image.quality != ImageQuality::Low
+        && image.format == ImageFormat::Rgba8
+        && image.width.max(image.height) > 1
+        && image.data.data().len() >= image.width as usize * image.height as usize * 4
+}
+
+/// The levels below `image`, down to a single pixel.
+fn mip_chain(image: &Image) -> Arc<[Image]> {
+    let mut levels: Vec<Image> = Vec::new();
+    loop {
+        let above = levels.last().unwrap_or(image);
+        if above.width.max(above.height) <= 1 {
+            return levels.into();
+        }
+        levels.push(downsample(above));
+    }
+}
+
+/// The mip levels added to an image atlas.
+pub(crate) struct AtlasLevels<'a> {
+    /// The height of the atlas with the levels added below its images.
+    pub(crate) height: u32,
+    /// Each level and its position in the atlas.
+    pub(crate) levels: Vec<(&'a Image, [u32; 2])>,
+    /// The mip table, which is never empty, as wgpu doesn't allow empty buffers.
+    pub(crate) table: Vec<u32>,
+}
+
+/// Place the mip levels of the images of an atlas below its images, which are given with
+/// their positions by the resolver.
+///
+/// The columns of levels are packed into shelves as wide as the atlas, and the atlas grows to
+/// fit them, up to [`MAX_ATLAS_HEIGHT`].
+pub(crate) fn place_levels<'a>(
+    mip_levels: &'a MipLevels,
+    images: &[(Image, u32, u32)],
+    width: u32,
+    height: u32,
+) -> AtlasLevels<'a> {
+    let mut levels = Vec::new();
+    let mut entries = Vec::new();
+    let mut atlas_height = height;
+    // The position of the next column, and the height of the shelf it is on.
+    let (mut x, mut y, mut shelf_height) = (0, height, 0);
+    for (image, image_x, image_y) in images {
+        let Some(chain) = mip_levels.get(&image.data.id()) else {
+            continue;
+        };
+        let column_width = chain[0].width;
+        let column_height: u32 = chain.iter().map(|level| level.height).sum();
+        if x + column_width > width {
+            (x, y, shelf_height) = (0, y + shelf_height, 0);
+        }
+        if y + column_height > MAX_ATLAS_HEIGHT {
+            continue;
+        }
+        let mut level_y = y;
+        for level in chain.iter() {
+            levels.push((level, [x, level_y]));
+            level_y += level.height;
+        }
+        entries.push([(image_x << 16) | image_y, (x << 16) | y, chain.len() as u32]);
+        x += column_width;
+        shelf_height = shelf_height.max(column_height);
+        atlas_height = atlas_height.max(y + column_height);
+    }
+    entries.sort_unstable_by_key(|entry| entry[0]);
+    if entries.is_empty() {
+        // An entry without levels, for an offset which no image has.
+        entries.push([u32::MAX, 0, 0]);
+    }
+    AtlasLevels {
+        height: atlas_height,
+        levels,
+        table: entries.concat(),
+    }
+}
+
+/// Halve the size of an image with a box filter, rounding odd sizes up.
+///
+/// Colors are weighted by their alpha, so transparent pixels don't darken their neighbours.
+/// The pixels past the edges of odd sized images are found with the image's extend modes,
+/// so that repeated images still tile seamlessly.
+fn downsample(image: &Image) -> Image {
+    let (width, height) = (image.width as usize, image.height as usize);
+    let (out_width, out_height) = (width.div_ceil(2), height.div_ceil(2));
+    let src = image.data.data();
+    let mut out = Vec::with_capacity(out_width * out_height * 4);
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let mut sum = [0_u32; 4];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = extend_index((2 * x + dx) as isize, width, image.x_extend);
+                let sy = extend_index((2 * y + dy) as isize, height, image.y_extend);
+                let pixel = &src[(sy * width + sx) * 4..][..4];
+                let alpha = u32::from(pixel[3]);
+                for (sum, &value) in sum.iter_mut().zip(&pixel[..3]) {
+                    *sum += u32::from(value) * alpha;
+                }
+                sum[3] += alpha;
+            }
+            let alpha = sum[3];
+            for c in &sum[..3] {
+                out.push(if alpha == 0 {
+                    0
+                } else {
+                    ((c + alpha / 2) / alpha) as u8
+                });
+            }
+            out.push(((alpha + 2) / 4) as u8);
+        }
+    }
+    Image {
+        data: Blob::new(Arc::new(out)),
+        width: out_width as u32,
+        height: out_height as u32,
+        ..image.clone()
+    }
+}
+
+/// Map a pixel index into `0..size` according to an extend mode.
+fn extend_index(ix: isize, size: usize, extend: Extend) -> usize {
+    let size = size as isize;
+    let ix = match extend {
+        Extend::Pad => ix.clamp(0, size - 1),
+        Extend::Repeat => ix.rem_euclid(size),
+        Extend::Reflect => {
+            let ix = ix.rem_euclid(2 * size);
+            if ix < size { ix } else { 2 * size - 1 - ix }
+        }
+    };
+    ix as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::Affine;
+
+    use super::*;
+
+    const EXTENDS: [Extend; 3] = [Extend::Pad, Extend::Repeat, Extend::Reflect];
+
+    /// An opaque image whose pixels all differ.
+    fn image(width: u32, height: u32, x_extend: Extend, y_extend: Extend) -> Image {
+        let data = (0..width * height)
+            .flat_map(|ix| [(ix * 16) as u8, (ix * 7) as u8, 255 - (ix * 11) as u8, 255])
+            .collect::<Vec<_>>();
+        Image::new(Blob::new(Arc::new(data)), ImageFormat::Rgba8, width, height)
+            .with_extend(x_extend)
+            .with_y_extend(y_extend)
+            .with_quality(ImageQuality::Medium)
+    }
+
+    #[test]
+    fn extend_index_in_range() {
+        for extend in EXTENDS {
+            for ix in 0..5 {
+                assert_eq!(extend_index(ix, 5, extend), ix as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn extend_index_below_range() {
+        let indices = [-1, -2, -5, -6, -11];
+        let expected = |extend| match extend {
+            Extend::Pad => [0, 0, 0, 0, 0],
+            Extend::Repeat => [4, 3, 0, 4, 4],
+            Extend::Reflect => [0, 1, 4, 4, 0],
+        };
+        for extend in EXTENDS {
+            let mapped = indices.map(|ix| extend_index(ix, 5, extend));
+            assert_eq!(mapped, expected(extend), "{extend:?}");
+        }
+    }
+
+    #[test]
+    fn extend_index_above_range() {
+        let indices = [5, 6, 9, 10, 16];
+        let expected = |extend| match extend {
+            Extend::Pad => [4, 4, 4, 4, 4],
+            Extend::Repeat => [0, 1, 4, 0, 1],
+            Extend::Reflect => [4, 3, 0, 0, 3],
+        };
+        for extend in EXTENDS {
+            let mapped = indices.map(|ix| extend_index(ix, 5, extend));
+            assert_eq!(mapped, expected(extend), "{extend:?}");
+        }
+    }
+
+    #[test]
+    fn extend_index_of_single_pixels() {
+        for extend in EXTENDS {
+            for ix in [-3, -1, 0, 1, 4] {
+                assert_eq!(extend_index(ix, 1, extend), 0);
+            }
+        }
+    }
+
+    /// The level below `image`, with the pixels past its right and bottom edges found
+    /// independently of `extend_index`.
+    fn expected_level(image: &Image) -> Vec<u8> {
+        let (width, height) = (image.width as usize, image.height as usize);
+        // Only the pixel just past an odd sized edge is used, which is the first pixel
+        // when repeating and the last otherwise.
+        let wrap = |ix: usize, size: usize, extend| match ix {
+            ix if ix < size => ix,
+            _ if extend == Extend::Repeat => 0,
+            _ => size - 1,
+        };
+        let src = image.data.data();
+        let mut out = Vec::new();
+        for y in 0..height.div_ceil(2) {
+            for x in 0..width.div_ceil(2) {
+                for c in 0..4 {
+                    let sum: u32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .into_iter()
+                        .map(|(dx, dy)| {
+                            let sx = wrap(2 * x + dx, width, image.x_extend);
+                            let sy = wrap(2 * y + dy, height, image.y_extend);
+                            u32::from(src[(sy * width + sx) * 4 + c])
+                        })
+                        .sum();
+                    out.push(((sum + 2) / 4) as u8);
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn odd_sized_levels_follow_extend_modes() {
+        for x_extend in EXTENDS {
+            for y_extend in EXTENDS {
+                let image = image(5, 3, x_extend, y_extend);
+                let level = downsample(&image);
+                assert_eq!((level.width, level.height), (3, 2));
+                assert_eq!(
+                    level.data.data(),
+                    &expected_level(&image)[..],
+                    "{x_extend:?} {y_extend:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn levels_are_cached_while_images_are_alive() {
+        let image = image(8, 8, Extend::Pad, Extend::Pad);
+        let id = image.data.id();
+        let mut scene = Scene::new();
+        scene.draw_image(&image, Affine::IDENTITY);
+        let mut cache = MipCache::default();
+        let first = cache.levels(&scene, &HashSet::new());
+        let sizes: Vec<_> = first[&id]
+            .iter()
+            .map(|level| (level.width, level.height))
+            .collect();
+        assert_eq!(sizes, [(4, 4), (2, 2), (1, 1)]);
+        let second = cache.levels(&scene, &HashSet::new());
+        assert!(Arc::ptr_eq(&first[&id], &second[&id]));
+        // Overridden images are sampled at full size.
+        assert!(cache.levels(&scene, &HashSet::from([id])).is_empty());
+        drop((first, second, scene, image));
+        cache.levels(&Scene::new(), &HashSet::new());
+        assert!(cache.chains.is_empty());
+    }
+
+    #[test]
+    fn levels_are_placed_below_the_images() {
+        let images = [(8, 4), (8, 4), (8, 2)]
+            .map(|(width, height)| image(width, height, Extend::Pad, Extend::Pad));
+        let mip_levels: MipLevels = images
+            .iter()
+            .map(|image| (image.data.id(), mip_chain(image)))
+            .collect();
+        let [a, b, c] = images;
+        let placed = place_levels(&mip_levels, &[(a, 0, 0), (b, 0, 4), (c, 0, 8)], 8, 10);
+        let positions: Vec<_> = placed.levels.iter().map(|(_, xy)| *xy).collect();
+        // The third column doesn't fit on the first shelf.
+        assert_eq!(
+            positions,
+            [
+                [0, 10],
+                [0, 12],
+                [0, 13],
+                [4, 10],
+                [4, 12],
+                [4, 13],
+                [0, 14],
+                [0, 15],
+                [0, 16]
+            ]
+        );
+        assert_eq!(placed.height, 17);
+        assert_eq!(placed.table, [0, 10, 3, 4, (4 << 16) | 10, 3, 8, 14, 3]);
+    }
+
+    #[test]
+    fn atlases_without_levels_have_an_empty_entry() {
+        let image = image(8, 8, Extend::Pad, Extend::Pad);
+        let placed = place_levels(&MipLevels::new(), &[(image, 0, 0)], 8, 8);
+        assert!(placed.levels.is_empty());
+        assert_eq!(placed.height, 8);
+        assert_eq!(placed.table, [u32::MAX, 0, 0]);
+    }
+
+    /// Renders of images drawn downscaled by the CPU pipeline.
+    #[cfg(feature = "cpu")]
+    mod render {
+        use peniko::Fill;
+        use peniko::color::palette::css;
+        use peniko::kurbo::Rect;
+
+        use super::*;
+        use crate::{AaConfig, CpuRenderer, RenderParams};
+
+        const SIZE: u32 = 16;
+
+        fn render(image: &Image, brush_transform: Affine) -> Vec<u8> {
+            let mut scene = Scene::new();
+            let target = Rect::new(0.0, 0.0, f64::from(SIZE), f64::from(SIZE));
+            scene.fill(
+                Fill::NonZero,
+                Affine::IDENTITY,
+                image,
+                Some(brush_transform),
+                &target,
+            );
+            let params = RenderParams {
+                base_color: css::WHITE,
+                width: SIZE,
+                height: SIZE,
+                antialiasing_method: AaConfig::Area,
+            };
+            CpuRenderer::new().render_to_rgba(&scene, &params)
+        }
+
+        /// The largest difference between the channels of two renders.
+        fn max_difference(a: &[u8], b: &[u8]) -> u8 {
+            a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap()
+        }
+
+        /// Check that an odd sized image drawn at half its size, tiled over the target
+        /// according to its extend modes, is drawn as the level below it.
+        #[test]
+        fn downscaled_images_are_drawn_with_their_levels() {
+            let brush_transform = Affine::translate((3.0, 2.0)) * Affine::scale(0.5);
+            for x_extend in EXTENDS {
+                for y_extend in EXTENDS {
+                    let image = image(5, 3, x_extend, y_extend);
+                    let level = Image {
+                        data: Blob::new(Arc::new(expected_level(&image))),
+                        width: 3,
+                        height: 2,
+                        ..image.clone()
+                    };
+                    // The level is drawn with the same footprint, which isn't downscaled
+                    // enough to sample its own levels. The texel coordinates are computed
+                    // differently, so they can round differently.
+                    let level_transform =
+                        brush_transform * Affine::scale_non_uniform(5.0 / 3.0, 3.0 / 2.0);
+                    let difference = max_difference(
+                        &render(&image, brush_transform),
+                        &render(&level, level_transform),
+                    );
+                    assert!(difference <= 1, "{x_extend:?} {y_extend:?}");
+                }
+            }
+        }
+
+        /// Check that high quality images drawn at the scale of a level only sample that
+        /// level.
+        #[test]
+        fn trilinear_filtering_at_a_level_samples_it() {
+            let image = image(8, 8, Extend::Repeat, Extend::Repeat);
+            let high = image.clone().with_quality(ImageQuality::High);
+            let brush_transform = Affine::scale(0.25);
+            assert_eq!(
+                render(&high, brush_transform),
+                render(&image, brush_transform)
+            );
+        }
+    }
+}
@@ -0,0 +1,1006 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Export of scenes as vector PDF documents.
+//!
+//! The scene is written into a form XObject, which the page draws with its y axis flipped,
+//! so everything inside is in the scene's coordinate system. Layers which blend or have an
+//! opacity become transparency groups, and plain clips are written inline.
+
+use std::collections::{BTreeMap, HashMap};
+
+use cosmyc_text::ttf_parser;
+use pdf_writer::types::{
+    BlendMode as PdfBlendMode, CidFontType, ColorSpaceOperand, FontFlags, FunctionShadingType,
+    LineCapStyle, LineJoinStyle, MaskType, SystemInfo, TextRenderingMode,
+};
+use pdf_writer::{Content, Name, Pdf, Rect as PdfRect, Ref, Str};
+use peniko::color::Srgb;
+use peniko::kurbo::{Affine, BezPath, Cap, Join, PathEl, Point, Rect, RoundedRect, Shape, Stroke};
+use peniko::{
+    BlendMode, Blob, Brush, ColorStop, Extend, Fill, Gradient, GradientKind, Image, ImageFormat,
+    ImageQuality, Mix, Style,
+};
+use vello_encoding::{Glyph, GlyphRun};
+
+use crate::Scene;
+use crate::decode::{Command, decode, decode_style, outline_glyphs, to_affine};
+
+/// The largest number of periods of a repeating gradient which are written.
+const MAX_GRADIENT_PERIODS: f64 = 1024.0;
+
+impl Scene {
+    /// Write the scene as a single page PDF document of `width` by `height` points.
+    ///
+    /// Paths, clips, blend modes, gradients, images and text are written as PDF vector
+    /// content, without rasterizing. Text is written with the fonts of its glyph runs
+    /// embedded, except for font collections and fonts without TrueType or CFF outlines,
+    /// whose glyphs are written as paths.
+    ///
+    /// PDF can't express everything which a scene can, so some content is approximated:
+    /// sweep gradients use the color of their first stop, radial gradients always use
+    /// [`Extend::Pad`], images aren't extended beyond their bounds, blurred rounded
+    /// rectangles are drawn without their blur, and layers only use their mix mode.
+    pub fn to_pdf(&self, width: u32, height: u32) -> Vec<u8> {
+        let page = Rect::new(0.0, 0.0, width as f64, height as f64);
+        let mut writer = PdfWriter::new(page);
+        decode(&self.encoding, |command| writer.command(command));
+        writer.finish()
+    }
+}
+
+/// The resources used by the content streams of a document.
+///
+/// All content streams share a single resource dictionary.
+#[derive(Default)]
+struct Resources {
+    x_objects: Vec<(String, Ref)>,
+    patterns: Vec<(String, Ref)>,
+    shadings: Vec<(String, Ref)>,
+    ext_g_states: Vec<(String, Ref)>,
+    fonts: Vec<(String, Ref)>,
+}
+
+impl Resources {
+    fn write(&self, mut resources: pdf_writer::writers::Resources<'_>) {
+        fn pairs(mut dict: pdf_writer::Dict<'_>, entries: &[(String, Ref)]) {
+            for (name, id) in entries {
+                dict.pair(Name(name.as_bytes()), *id);
+            }
+        }
+        pairs(resources.x_objects(), &self.x_objects);
+        pairs(resources.patterns(), &self.patterns);
+        pairs(resources.shadings(), &self.shadings);
+        pairs(resources.ext_g_states(), &self.ext_g_states);
+        pairs(resources.fonts(), &self.fonts);
+    }
+}
+
+/// An open layer.
+enum Frame {
+    /// A layer which only clips, and is written inline.
+    Clip,
+    /// A layer which is written as a transparency group.
+    Group {
+        parent: Content,
+        blend: BlendMode,
+        alpha: f32,
+    },
+}
+
+/// A form XObject, which is written once all resources are known.
+struct Form {
+    id: Ref,
+    content: Vec<u8>,
+    group: Option<GroupKind>,
+}
+
+enum GroupKind {
+    Layer,
+    Mask,
+}
+
+/// A paint for fills and strokes.
+struct Paint {
+    color: PaintColor,
+    /// An external graphics state setting the paint's alpha.
+    state: Option<String>,
+}
+
+enum PaintColor {
+    Rgb([f32; 3]),
+    Pattern(String),
+}
+
+enum FontKind {
+    TrueType,
+    Cff,
+}
+
+struct EmbeddedFont {
+    name: String,
+    id: Ref,
+    data: Blob<u8>,
+    kind: FontKind,
+    postscript_name: String,
+    units_per_em: f32,
+    bbox: [f32; 4],
+    ascent: f32,
+    descent: f32,
+    cap_height: f32,
+    /// The advance of each glyph used, in thousandths of an em.
+    widths: BTreeMap<u16, f32>,
+}
+
+struct PdfWriter {
+    pdf: Pdf,
+    next_ref: i32,
+    page: Rect,
+    content: Content,
+    frames: Vec<Frame>,
+    forms: Vec<Form>,
+    resources: Resources,
+    next_name: usize,
+    states: HashMap<(u32, u8), String>,
+    images: HashMap<(u64, u32, u32), String>,
+    /// Embedded fonts by blob id and index, or `None` if the font can't be embedded.
+    fonts: HashMap<(u64, u32), Option<usize>>,
+    embedded_fonts: Vec<EmbeddedFont>,
+}
+
+impl PdfWriter {
+    fn new(page: Rect) -> Self {
+        Self {
+            pdf: Pdf::new(),
+            next_ref: 1,
+            page,
+            content: Content::new(),
+            frames: Vec::new(),
+            forms: Vec::new(),
+            resources: Resources::default(),
+            next_name: 0,
+            states: HashMap::new(),
+            images: HashMap::new(),
+            fonts: HashMap::new(),
+            embedded_fonts: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self) -> Ref {
+        let id = Ref::new(self.next_ref);
+        self.next_ref += 1;
+        id
+    }
+
+    fn name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{prefix}{}", self.next_name)
+    }
+
+    fn command(&mut self, command: Command<'_>) {
+        match command {
+            Command::Draw {
+                path,
+                transform,
+                style,
+                brush,
+                brush_transform,
+            } => self.draw(&path, transform, &style, &brush, brush_transform),
+            Command::BlurredRect {
+                path,
+                transform,
+                rect_transform,
+                rect,
+                color,
+                radius,
+                ..
+            } => {
+                let Some(rect_transform) = relative(transform, rect_transform) else {
+                    return;
+                };
+                let rect = RoundedRect::from_rect(rect, radius as f64).to_path(0.1);
+                let paint = self.paint(&Brush::Solid(color), Affine::IDENTITY);
+                self.content.save_state();
+                self.content.transform(coeffs(transform));
+                write_path(&mut self.content, &path);
+                self.content.clip_nonzero().end_path();
+                self.content.transform(coeffs(rect_transform));
+                if let Some(paint) = paint {
+                    self.set_paint(&paint, false);
+                    write_path(&mut self.content, &rect);
+                    self.content.fill_nonzero();
+                }
+                self.content.restore_state();
+            }
+            Command::PushLayer {
+                clip,
+                transform,
+                fill,
+                blend,
+                alpha,
+            } => {
+                self.content.save_state();
+                write_path(&mut self.content, &(transform * clip));
+                match fill {
+                    Fill::NonZero => self.content.clip_nonzero(),
+                    Fill::EvenOdd => self.content.clip_even_odd(),
+                };
+                self.content.end_path();
+                let is_clip = matches!(blend.mix, Mix::Clip | Mix::Normal) && alpha >= 1.0;
+                if is_clip {
+                    self.frames.push(Frame::Clip);
+                } else {
+                    let parent = std::mem::replace(&mut self.content, Content::new());
+                    self.frames.push(Frame::Group {
+                        parent,
+                        blend,
+                        alpha,
+                    });
+                }
+            }
+            Command::PopLayer => self.pop_layer(),
+            Command::Glyphs { run, glyphs, brush } => self.glyphs(run, glyphs, &brush),
+        }
+    }
+
+    fn pop_layer(&mut self) {
+        match self.frames.pop() {
+            Some(Frame::Clip) => {
+                self.content.restore_state();
+            }
+            Some(Frame::Group {
+                parent,
+                blend,
+                alpha,
+            }) => {
+                let content = std::mem::replace(&mut self.content, parent).finish();
+                let id = self.alloc();
+                self.forms.push(Form {
+                    id,
+                    content,
+                    group: Some(GroupKind::Layer),
+                });
+                let name = self.name("X");
+                self.resources.x_objects.push((name.clone(), id));
+                let state = self.ext_state(alpha, blend.mix);
+                self.content.set_parameters(Name(state.as_bytes()));
+                self.content.x_object(Name(name.as_bytes()));
+                self.content.restore_state();
+            }
+            None => {}
+        }
+    }
+
+    fn draw(
+        &mut self,
+        path: &BezPath,
+        transform: Affine,
+        style: &Style,
+        brush: &Brush,
+        brush_transform: Option<Affine>,
+    ) {
+        let brush_transform = transform * brush_transform.unwrap_or_default();
+        if let Brush::Image(image) = brush {
+            // Images are drawn clipped to the filled shape.
+            let Style::Fill(fill) = style else {
+                return;
+            };
+            let Some(name) = self.image(image) else {
+                return;
+            };
+            self.content.save_state();
+            if image.alpha < 1.0 {
+                let state = self.ext_state(image.alpha, Mix::Normal);
+                self.content.set_parameters(Name(state.as_bytes()));
+            }
+            write_path(&mut self.content, &(transform * path.clone()));
+            match fill {
+                Fill::NonZero => self.content.clip_nonzero(),
+                Fill::EvenOdd => self.content.clip_even_odd(),
+            };
+            self.content.end_path();
+            // Image space is the unit square, with its first row at the top.
+            let (width, height) = (image.width as f64, image.height as f64);
+            let image_transform = Affine::new([width, 0.0, 0.0, -height, 0.0, height]);
+            self.content
+                .transform(coeffs(brush_transform * image_transform));
+            self.content.x_object(Name(name.as_bytes()));
+            self.content.restore_state();
+            return;
+        }
+        let Some(paint) = self.paint(brush, brush_transform) else {
+            return;
+        };
+        self.content.save_state();
+        match style {
+            Style::Fill(fill) => {
+                self.set_paint(&paint, false);
+                self.content.transform(coeffs(transform));
+                write_path(&mut self.content, path);
+                match fill {
+                    Fill::NonZero => self.content.fill_nonzero(),
+                    Fill::EvenOdd => self.content.fill_even_odd(),
+                };
+            }
+            Style::Stroke(stroke) => {
+                self.set_paint(&paint, true);
+                self.content.transform(coeffs(transform));
+                set_stroke(&mut self.content, stroke);
+                write_path(&mut self.content, path);
+                self.content.stroke();
+            }
+        }
+        self.content.restore_state();
+    }
+
+    fn glyphs(&mut self, run: &GlyphRun, glyphs: &[Glyph], brush: &Brush) {
+        let font = match brush {
+            // Text can't be clipped to an image, so it is drawn as outlines.
+            Brush::Image(_) => None,
+            _ => self.font(run),
+        };
+        let Some(font) = font else {
+            outline_glyphs(run, glyphs, brush, |command| self.command(command));
+            return;
+        };
+        let run_transform = to_affine(&run.transform);
+        let Some(paint) = self.paint(brush, run_transform) else {
+            return;
+        };
+        let Ok(face) = ttf_parser::Face::parse(run.font.data.data(), run.font.index) else {
+            return;
+        };
+        let embedded = &mut self.embedded_fonts[font];
+        let scale = 1000.0 / embedded.units_per_em;
+        for glyph in glyphs {
+            if let Ok(id) = u16::try_from(glyph.id) {
+                let advance = face.glyph_hor_advance(ttf_parser::GlyphId(id)).unwrap_or(0);
+                embedded.widths.insert(id, advance as f32 * scale);
+            }
+        }
+        let font_name = embedded.name.clone();
+
+        let stroke = match decode_style(&run.style) {
+            Style::Fill(_) => None,
+            Style::Stroke(stroke) => Some(stroke),
+        };
+        let glyph_transform = run
+            .glyph_transform
+            .as_ref()
+            .map_or(Affine::IDENTITY, to_affine);
+        self.content.save_state();
+        self.set_paint(&paint, stroke.is_some());
+        self.content.transform(coeffs(run_transform));
+        self.content.begin_text();
+        self.content
+            .set_font(Name(font_name.as_bytes()), run.font_size);
+        if let Some(stroke) = &stroke {
+            self.content
+                .set_text_rendering_mode(TextRenderingMode::Stroke);
+            set_stroke(&mut self.content, stroke);
+        }
+        for glyph in glyphs {
+            let Ok(id) = u16::try_from(glyph.id) else {
+                continue;
+            };
+            // Text space is y-up.
+            let transform = Affine::translate((glyph.x as f64, glyph.y as f64))
+                * glyph_transform
+                * Affine::FLIP_Y;
+            self.content.set_text_matrix(coeffs(transform));
+            self.content.show(Str(&id.to_be_bytes()));
+        }
+        self.content.end_text();
+        self.content.restore_state();
+    }
+
+    /// Look up or create the embedded font for a glyph run.
+    fn font(&mut self, run: &GlyphRun) -> Option<usize> {
+        let key = (run.font.data.id(), run.font.index);
+        if let Some(font) = self.fonts.get(&key) {
+            return *font;
+        }
+        let font = self.embed_font(run);
+        self.fonts.insert(key, font);
+        font
+    }
+
+    fn embed_font(&mut self, run: &GlyphRun) -> Option<usize> {
+        let data = run.font.data.data();
+        // Only whole font files can be embedded.
+        if ttf_parser::fonts_in_collection(data).is_some() {
+            return None;
+        }
+        let face = ttf_parser::Face::parse(data, 0).ok()?;
+        let tables = face.tables();
+        let kind = if tables.glyf.is_some() {
+            FontKind::TrueType
+        } else if tables.cff.is_some() {
+            FontKind::Cff
+        } else {
+            return None;
+        };
+        let units_per_em = face.units_per_em() as f32;
+        let scale = 1000.0 / units_per_em;
+        let postscript_name = face
+            .names()
+            .into_iter()
+            .find(|name| name.name_id == ttf_parser::name_id::POST_SCRIPT_NAME)
+            .and_then(|name| name.to_string())
+            .map(|name| {
+                name.chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                    .collect::<String>()
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| String::from("VelloFont"));
+        let bbox = face.global_bounding_box();
+        let name = self.name("F");
+        let id = self.alloc();
+        self.resources.fonts.push((name.clone(), id));
+        self.embedded_fonts.push(EmbeddedFont {
+            name,
+            id,
+            data: run.font.data.clone(),
+            kind,
+            postscript_name,
+            units_per_em,
+            bbox: [bbox.x_min, bbox.y_min, bbox.x_max, bbox.y_max].map(|v| v as f32 * scale),
+            ascent: face.ascender() as f32 * scale,
+            descent: face.descender() as f32 * scale,
+            cap_height: face.capital_height().unwrap_or(face.ascender()) as f32 * scale,
+            widths: BTreeMap::new(),
+        });
+        Some(self.embedded_fonts.len() - 1)
+    }
+
+    /// Create the paint for a brush whose coordinates are mapped to the scene by
+    /// `brush_transform`.
+    fn paint(&mut self, brush: &Brush, brush_transform: Affine) -> Option<Paint> {
+        match brush {
+            Brush::Solid(color) => {
+                let [r, g, b, a] = color.components;
+                let state = (a < 1.0).then(|| self.ext_state(a, Mix::Normal));
+                Some(Paint {
+                    color: PaintColor::Rgb([r, g, b]),
+                    state,
+                })
+            }
+            Brush::Gradient(gradient) => {
+                if let GradientKind::Sweep { .. } = gradient.kind {
+                    let stop = gradient.stops.first()?;
+                    return self.paint(
+                        &Brush::Solid(stop.color.to_alpha_color::<Srgb>()),
+                        brush_transform,
+                    );
+                }
+                let shading = self.shading(gradient, brush_transform, false)?;
+                let pattern = self.alloc();
+                self.pdf
+                    .shading_pattern(pattern)
+                    .shading_ref(shading)
+                    .matrix(coeffs(brush_transform));
+                let name = self.name("P");
+                self.resources.patterns.push((name.clone(), pattern));
+                let state = self.gradient_alpha(gradient, brush_transform);
+                Some(Paint {
+                    color: PaintColor::Pattern(name),
+                    state,
+                })
+            }
+            // Images are handled by the callers.
+            Brush::Image(_) => None,
+        }
+    }
+
+    fn set_paint(&mut self, paint: &Paint, stroke: bool) {
+        if let Some(state) = &paint.state {
+            self.content.set_parameters(Name(state.as_bytes()));
+        }
+        match (&paint.color, stroke) {
+            (PaintColor::Rgb([r, g, b]), false) => {
+                self.content.set_fill_rgb(*r, *g, *b);
+            }
+            (PaintColor::Rgb([r, g, b]), true) => {
+                self.content.set_stroke_rgb(*r, *g, *b);
+            }
+            (PaintColor::Pattern(name), false) => {
+                self.content
+                    .set_fill_color_space(ColorSpaceOperand::Pattern)
+                    .set_fill_pattern([], Name(name.as_bytes()));
+            }
+            (PaintColor::Pattern(name), true) => {
+                self.content
+                    .set_stroke_color_space(ColorSpaceOperand::Pattern)
+                    .set_stroke_pattern([], Name(name.as_bytes()));
+            }
+        }
+    }
+
+    /// Create the graphics state for the alpha of a gradient's stops.
+    ///
+    /// Shadings can't have an alpha channel, so varying alpha uses a soft mask of the
+    /// same gradient in grayscale.
+    fn gradient_alpha(&mut self, gradient: &Gradient, brush_transform: Affine) -> Option<String> {
+        let alpha = |stop: &ColorStop| stop.color.to_alpha_color::<Srgb>().components[3];
+        let first = alpha(gradient.stops.first()?);
+        if gradient.stops.iter().all(|stop| alpha(stop) == first) {
+            return (first < 1.0).then(|| self.ext_state(first, Mix::Normal));
+        }
+        let shading = self.shading(gradient, brush_transform, true)?;
+        let shading_name = self.name("S");
+        self.resources
+            .shadings
+            .push((shading_name.clone(), shading));
+        let mut content = Content::new();
+        content.shading(Name(shading_name.as_bytes()));
+        let mask = self.alloc();
+        self.forms.push(Form {
+            id: mask,
+            content: content.finish(),
+            group: Some(GroupKind::Mask),
+        });
+        let id = self.alloc();
+        self.pdf
+            .ext_graphics(id)
+            .soft_mask()
+            .subtype(MaskType::Luminosity)
+            .group(mask);
+        let name = self.name("G");
+        self.resources.ext_g_states.push((name.clone(), id));
+        Some(name)
+    }
+
+    /// Write a shading for a gradient, either of its colors or, if `alpha` is set, of the
+    /// alpha of its stops as gray levels.
+    ///
+    /// Shadings for soft masks are in scene coordinates, and other shadings are used
+    /// through a pattern with `brush_transform` as its matrix.
+    fn shading(
+        &mut self,
+        gradient: &Gradient,
+        brush_transform: Affine,
+        alpha: bool,
+    ) -> Option<Ref> {
+        let function = self.stops_function(&gradient.stops, alpha)?;
+        let point_transform = if alpha {
+            brush_transform
+        } else {
+            Affine::IDENTITY
+        };
+        let id = self.alloc();
+        match gradient.kind {
+            GradientKind::Linear { start, end } => {
+                // Shadings only pad, so repeating gradients are written as a function
+                // which covers the page.
+                let (function, [t0, t1]) = match gradient.extend {
+                    Extend::Pad => (function, [0.0, 1.0]),
+                    extend => {
+                        let range = self.linear_range(start, end, brush_transform);
+                        (self.periodic_function(function, range, extend), range)
+                    }
+                };
+                let start = point_transform * start;
+                let end = point_transform * end;
+                let p0 = start + (end - start) * t0;
+                let p1 = start + (end - start) * t1;
+                let mut shading = self.pdf.function_shading(id);
+                shading.shading_type(FunctionShadingType::Axial);
+                set_shading_color_space(&mut shading, alpha);
+                shading
+                    .function(function)
+                    .coords([p0.x, p0.y, p1.x, p1.y].map(|v| v as f32))
+                    .extend([true, true]);
+            }
+            GradientKind::Radial {
+                start_center,
+                start_radius,
+                end_center,
+                end_radius,
+            } => {
+                // Radii are scaled with the uniform part of the transform, which is exact
+                // for the similarity transforms used by soft masks in practice.
+                let scale = point_transform.determinant().abs().sqrt() as f32;
+                let c0 = point_transform * start_center;
+                let c1 = point_transform * end_center;
+                let mut shading = self.pdf.function_shading(id);
+                shading.shading_type(FunctionShadingType::Radial);
+                set_shading_color_space(&mut shading, alpha);
+                shading
+                    .function(function)
+                    .coords([
+                        c0.x as f32,
+                        c0.y as f32,
+                        start_radius * scale,
+                        c1.x as f32,
+                        c1.y as f32,
+                        end_radius * scale,
+                    ])
+                    .extend([true, true]);
+            }
+            GradientKind::Sweep { .. } => return None,
+        }
+        Some(id)
+    }
+
+    /// The range of the gradient parameter of a linear gradient over the page.
+    fn linear_range(&self, start: Point, end: Point, brush_transform: Affine) -> [f64; 2] {
+        let to_brush = brush_transform.inverse();
+        let d = end - start;
+        let length_squared = d.hypot2();
+        if length_squared == 0.0 || !to_brush.is_finite() {
+            return [0.0, 1.0];
+        }
+        let page = self.page;
+        let ts = [
+            Point::new(page.x0, page.y0),
+            Point::new(page.x1, page.y0),
+            Point::new(page.x0, page.y1),
+            Point::new(page.x1, page.y1),
+        ]
+        .map(|corner| (to_brush * corner - start).dot(d) / length_squared);
+        let min = ts.iter().copied().fold(f64::INFINITY, f64::min).floor();
+        let max = ts.iter().copied().fold(f64::NEG_INFINITY, f64::max).ceil();
+        let min = min.max(-MAX_GRADIENT_PERIODS / 2.0).min(0.0);
+        let max = max.min(min + MAX_GRADIENT_PERIODS).max(1.0);
+        [min, max]
+    }
+
+    /// Write a function which repeats or reflects `function` over the integer periods of
+    /// `range`, with the range mapped to `[0, 1]`.
+    fn periodic_function(&mut self, function: Ref, range: [f64; 2], extend: Extend) -> Ref {
+        let [min, max] = range;
+        let periods = (max - min) as usize;
+        let id = self.alloc();
+        self.pdf
+            .stitching_function(id)
+            .domain([0.0, 1.0])
+            .functions(std::iter::repeat_n(function, periods))
+            .bounds((1..periods).map(|i| (i as f64 / periods as f64) as f32))
+            .encode((0..periods).flat_map(|i| {
+                let reflected = extend == Extend::Reflect && (min as i64 + i as i64) % 2 != 0;
+                if reflected { [1.0, 0.0] } else { [0.0, 1.0] }
+            }));
+        id
+    }
+
+    /// Write a function mapping `[0, 1]` to the colors of `stops`, or to their alpha.
+    fn stops_function(&mut self, stops: &[ColorStop], alpha: bool) -> Option<Ref> {
+        let values = |stop: &ColorStop| {
+            let [r, g, b, a] = stop.color.to_alpha_color::<Srgb>().components;
+            if alpha { vec![a] } else { vec![r, g, b] }
+        };
+        let mut points: Vec<(f32, Vec<f32>)> = stops
+            .iter()
+            .map(|stop| (stop.offset.clamp(0.0, 1.0), values(stop)))
+            .collect();
+        let first = points.first()?.clone();
+        if first.0 > 0.0 {
+            points.insert(0, (0.0, first.1));
+        }
+        let last = points.last()?.clone();
+        if last.0 < 1.0 || points.len() == 1 {
+            points.push((1.0, last.1));
+        }
+        let mut functions = Vec::new();
+        for pair in points.windows(2) {
+            let id = self.alloc();
+            self.pdf
+                .exponential_function(id)
+                .domain([0.0, 1.0])
+                .c0(pair[0].1.iter().copied())
+                .c1(pair[1].1.iter().copied())
+                .n(1.0);
+            functions.push(id);
+        }
+        let id = self.alloc();
+        let n_functions = functions.len();
+        self.pdf
+            .stitching_function(id)
+            .domain([0.0, 1.0])
+            .functions(functions)
+            .bounds(
+                points[1..points.len() - 1]
+                    .iter()
+                    .map(|(offset, _)| *offset),
+            )
+            .encode((0..n_functions).flat_map(|_| [0.0, 1.0]));
+        Some(id)
+    }
+
+    /// Look up or create the external graphics state for an alpha and mix mode.
+    fn ext_state(&mut self, alpha: f32, mix: Mix) -> String {
+        let key = (alpha.to_bits(), mix as u8);
+        if let Some(name) = self.states.get(&key) {
+            return name.clone();
+        }
+        let id = self.alloc();
+        self.pdf
+            .ext_graphics(id)
+            .non_stroking_alpha(alpha)
+            .stroking_alpha(alpha)
+            .blend_mode(blend_mode(mix));
+        let name = self.name("G");
+        self.resources.ext_g_states.push((name.clone(), id));
+        self.states.insert(key, name.clone());
+        name
+    }
+
+    /// Look up or write the image XObject for an image.
+    fn image(&mut self, image: &Image) -> Option<String> {
+        let key = (image.data.id(), image.width, image.height);
+        if let Some(name) = self.images.get(&key) {
+            return Some(name.clone());
+        }
+        match image.format {
+            ImageFormat::Rgba8 => {}
+            _ => return None,
+        }
+        let data = image.data.data();
+        if image.format.size_in_bytes(image.width, image.height) != Some(data.len()) {
+            return None;
+        }
+        let rgb: Vec<u8> = data
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+        let alpha: Vec<u8> = data.chunks_exact(4).map(|pixel| pixel[3]).collect();
+        let mask = if alpha.iter().all(|a| *a == 255) {
+            None
+        } else {
+            let mask = self.alloc();
+            let mut x_object = self.pdf.image_xobject(mask, &alpha);
+            x_object
+                .width(image.width as i32)
+                .height(image.height as i32)
+                .bits_per_component(8);
+            x_object.color_space().device_gray();
+            Some(mask)
+        };
+        let id = self.alloc();
+        let mut x_object = self.pdf.image_xobject(id, &rgb);
+        x_object
+            .width(image.width as i32)
+            .height(image.height as i32)
+            .bits_per_component(8)
+            .interpolate(image.quality != ImageQuality::Low);
+        if let Some(mask) = mask {
+            x_object.s_mask(mask);
+        }
+        x_object.color_space().device_rgb();
+        drop(x_object);
+        let name = self.name("X");
+        self.resources.x_objects.push((name.clone(), id));
+        self.images.insert(key, name.clone());
+        Some(name)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        while !self.frames.is_empty() {
+            self.pop_layer();
+        }
+        let root = self.alloc();
+        let root_name = self.name("X");
+        self.resources.x_objects.push((root_name.clone(), root));
+        let content = std::mem::replace(&mut self.content, Content::new()).finish();
+        self.forms.push(Form {
+            id: root,
+            content,
+            group: None,
+        });
+
+        let page = self.page;
+        let bbox = PdfRect::new(
+            page.x0 as f32,
+            page.y0 as f32,
+            page.x1 as f32,
+            page.y1 as f32,
+        );
+        for form in std::mem::take(&mut self.forms) {
+            let mut x_object = self.pdf.form_xobject(form.id, &form.content);
+            x_object.bbox(bbox);
+            self.resources.write(x_object.resources());
+            match form.group {
+                Some(GroupKind::Layer) => {
+                    x_object.group().transparency().isolated(true);
+                }
+                Some(GroupKind::Mask) => {
+                    let mut group = x_object.group();
+                    group.transparency();
+                    group.color_space().device_gray();
+                }
+                None => {}
+            }
+        }
+        for font in std::mem::take(&mut self.embedded_fonts) {
+            self.write_font(&font);
+        }
+
+        // The page flips the y axis of the scene.
+        let mut content = Content::new();
+        content.transform([1.0, 0.0, 0.0, -1.0, 0.0, page.height() as f32]);
+        content.x_object(Name(root_name.as_bytes()));
+        let content = content.finish();
+        let catalog = self.alloc();
+        let pages = self.alloc();
+        let page_id = self.alloc();
+        let content_id = self.alloc();
+        self.pdf.catalog(catalog).pages(pages);
+        self.pdf.pages(pages).kids([page_id]).count(1);
+        let mut page_writer = self.pdf.page(page_id);
+        page_writer
+            .parent(pages)
+            .media_box(PdfRect::new(
+                0.0,
+                0.0,
+                page.width() as f32,
+                page.height() as f32,
+            ))
+            .contents(content_id);
+        page_writer
+            .resources()
+            .x_objects()
+            .pair(Name(root_name.as_bytes()), root);
+        drop(page_writer);
+        self.pdf.stream(content_id, &content);
+        self.pdf.finish()
+    }
+
+    fn write_font(&mut self, font: &EmbeddedFont) {
+        let cid_font = self.alloc();
+        let descriptor = self.alloc();
+        let file = self.alloc();
+        let base_font = Name(font.postscript_name.as_bytes());
+        self.pdf
+            .type0_font(font.id)
+            .base_font(base_font)
+            .encoding_predefined(Name(b"Identity-H"))
+            .descendant_font(cid_font);
+
+        let mut cid = self.pdf.cid_font(cid_font);
+        cid.subtype(match font.kind {
+            FontKind::TrueType => CidFontType::Type2,
+            FontKind::Cff => CidFontType::Type0,
+        })
+        .base_font(base_font)
+        .system_info(SystemInfo {
+            registry: Str(b"Adobe"),
+            ordering: Str(b"Identity"),
+            supplement: 0,
+        })
+        .font_descriptor(descriptor)
+        .default_width(0.0);
+        if let FontKind::TrueType = font.kind {
+            cid.cid_to_gid_map_predefined(Name(b"Identity"));
+        }
+        let mut widths = cid.widths();
+        for (id, width) in &font.widths {
+            widths.consecutive(*id, [*width]);
+        }
+        drop(widths);
+        drop(cid);
+
+        let [x0, y0, x1, y1] = font.bbox;
+        let mut descriptor = self.pdf.font_descriptor(descriptor);
+        descriptor
+            .name(base_font)
+            .flags(FontFlags::SYMBOLIC)
+            .bbox(PdfRect::new(x0, y0, x1, y1))
+            .italic_angle(0.0)
+            .ascent(font.ascent)
+            .descent(font.descent)
+            .cap_height(font.cap_height)
+            .stem_v(80.0);
+        match font.kind {
+            FontKind::TrueType => descriptor.font_file2(file),
+            FontKind::Cff => descriptor.font_file3(file),
+        };
+        drop(descriptor);
+
+        let data = font.data.data();
+        let mut stream = self.pdf.stream(file, data);
+        match font.kind {
+            FontKind::TrueType => stream.pair(Name(b"Length1"), data.len() as i32),
+            FontKind::Cff => stream.pair(Name(b"Subtype"), Name(b"OpenType")),
+        };
+    }
+}
+
+fn set_shading_color_space(shading: &mut pdf_writer::writers::FunctionShading<'_>, alpha: bool) {
+    if alpha {
+        shading.color_space().device_gray();
+    } else {
+        shading.color_space().device_rgb();
+    }
+}
+
+fn set_stroke(content: &mut Content, stroke: &Stroke) {
+    content.set_line_width(stroke.width as f32);
+    // PDF only has one cap style for both ends.
+    content.set_line_cap(match stroke.start_cap {
+        Cap::Butt => LineCapStyle::ButtCap,
+        Cap::Round => LineCapStyle::RoundCap,
+        Cap::Square => LineCapStyle::ProjectingSquareCap,
+    });
+    content.set_line_join(match stroke.join {
+        Join::Miter => LineJoinStyle::MiterJoin,
+        Join::Round => LineJoinStyle::RoundJoin,
+        Join::Bevel => LineJoinStyle::BevelJoin,
+    });
+    content.set_miter_limit(stroke.miter_limit as f32);
+}
+
+/// The transform of `transform` relative to `base`.
+fn relative(base: Affine, transform: Affine) -> Option<Affine> {
+    let inverse = base.inverse();
+    inverse.is_finite().then(|| inverse * transform)
+}
+
+fn coeffs(transform: Affine) -> [f32; 6] {
+    transform.as_coeffs().map(|c| c as f32)
+}
+
+fn write_path(content: &mut Content, path: &BezPath) {
+    let mut current = Point::ZERO;
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => {
+                content.move_to(p.x as f32, p.y as f32);
+                current = p;
+            }
+            PathEl::LineTo(p) => {
+                content.line_to(p.x as f32, p.y as f32);
+                current = p;
+            }
+            PathEl::QuadTo(p1, p2) => {
+                // PDF only has cubic Béziers.
+                let c1 = current + (p1 - current) * (2.0 / 3.0);
+                let c2 = p2 + (p1 - p2) * (2.0 / 3.0);
+                content.cubic_to(
+                    c1.x as f32,
+                    c1.y as f32,
+                    c2.x as f32,
+                    c2.y as f32,
+                    p2.x as f32,
+                    p2.y as f32,
+                );
+                current = p2;
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                content.cubic_to(
+                    p1.x as f32,
+                    p1.y as f32,
+                    p2.x as f32,
+                    p2.y as f32,
+                    p3.x as f32,
+                    p3.y as f32,
+                );
+                current = p3;
+            }
+            PathEl::ClosePath => {
+                content.close_path();
+            }
+        }
+    }
+}
+
+fn blend_mode(mix: Mix) -> PdfBlendMode {
+    match mix {
+        Mix::Normal | Mix::Clip => PdfBlendMode::Normal,
+        Mix::Multiply => PdfBlendMode::Multiply,
+        Mix::Screen => PdfBlendMode::Screen,
+        Mix::Overlay => PdfBlendMode::Overlay,
+        Mix::Darken => PdfBlendMode::Darken,
+        Mix::Lighten => PdfBlendMode::Lighten,
+        Mix::ColorDodge => PdfBlendMode::ColorDodge,
+        Mix::ColorBurn => PdfBlendMode::ColorBurn,
+        Mix::HardLight => PdfBlendMode::HardLight,
+        Mix::SoftLight => PdfBlendMode::SoftLight,
+        Mix::Difference => PdfBlendMode::Difference,
+        Mix::Exclusion => PdfBlendMode::Exclusion,
+        Mix::Hue => PdfBlendMode::Hue,
+        Mix::Saturation => PdfBlendMode::Saturation,
+        Mix::Color => PdfBlendMode::Color,
+        Mix::Luminosity => PdfBlendMode::Luminosity,
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! An image of the topmost tagged draw object at each pixel, which can be read back to pick
+//! the draw object under the cursor.
+//!
+//! The pick image holds a 32 bit index at each pixel, which the fine stage writes without
+//! blending: it is the index of the topmost draw object covering at least half of the pixel.
+//! Coarse rasterization only records the colors of solid draw objects, not which draw object
+//! they belong to, so the image is rendered by a second pass of the pipeline. The second
+//! pass renders a copy of the encoding in which every draw object is filled with a color
+//! holding its index. Only the draw tags and draw data are rewritten, the paths, transforms
+//! and glyphs of the copy are those of the scene.
+//!
+//! The indices of the placeholder images of filter layers refer to the layer, and picking
+//! them hit tests the layer's content against its unfiltered geometry.
+
+use std::collections::HashMap;
+
+use peniko::Brush;
+use peniko::kurbo::{Affine, Point};
+use vello_encoding::{DrawTag, Encoding, Patch};
+use wgpu::{Device, Queue};
+
+use crate::decode::{self, Command};
+use crate::filter::FilterLayer;
+use crate::wgpu_engine::ExternalResource;
+use crate::{DrawId, RenderParams, Renderer, Result, Scene, hit_test, render};
+
+/// The largest number of entries of a pick image, as indices are stored in the low 24 bits
+/// of the colors of the pick encoding.
+const MAX_ENTRIES: usize = 0xff_ffff;
+
+/// The alpha of the colors of the pick encoding, which are opaque so that coarse
+/// rasterization treats them like the colors of any other opaque draw object.
+const OPAQUE: u32 = 0xff00_0000;
+
+/// The pick image of the last render, and what its indices refer to.
+pub(crate) struct PickBuffer {
+    texture: wgpu::Texture,
+    /// The entry of each index in the image, offset by one as index zero is untagged.
+    entries: Vec<PickEntry>,
+    /// The filter layers of the scene, whose content is hit tested when they are picked.
+    filters: Vec<FilterLayer>,
+}
+
+/// What an index of a pick image refers to.
+#[derive(Copy, Clone)]
+enum PickEntry {
+    /// The draw objects tagged with an id.
+    Id(DrawId),
+    /// The placeholder image of a filter layer, with the transform from the coordinates of
+    /// the target to those of the layer's content.
+    Layer { layer: usize, transform: Affine },
+}
+
+/// The entries of a pick image.
+#[derive(Default)]
+struct PickEntries {
+    entries: Vec<PickEntry>,
+    /// The index of each id, as draw objects with the same id share an entry.
+    ids: HashMap<DrawId, u32>,
+}
+
+impl PickEntries {
+    /// Add an entry, returning its index, which is zero if there are too many entries.
+    fn push(&mut self, entry: PickEntry) -> u32 {
+        if self.entries.len() >= MAX_ENTRIES {
+            log::warn!("Too many draw ids for the pick buffer, drawing them untagged");
+            return 0;
+        }
+        self.entries.push(entry);
+        self.entries.len() as u32
+    }
+
+    /// The index of the entry of `id`.
+    fn id(&mut self, id: DrawId) -> u32 {
+        if let Some(&index) = self.ids.get(&id) {
+            return index;
+        }
+        let index = self.push(PickEntry::Id(id));
+        if index != 0 {
+            self.ids.insert(id, index);
+        }
+        index
+    }
+}
+
+impl Renderer {
+    /// Render the pick image of `scene`, if it is enabled in the options of the renderer.
+    pub(crate) fn render_pick_buffer(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        params: &RenderParams,
+    ) -> Result<()> {
+        if !self.options.pick_buffer {
+            return Ok(());
+        }
+        if params.width == 0 || params.height == 0 {
+            self.pick = None;
+            return Ok(());
+        }
+        let scene = scene.with_filter_layers_closed();
+        let mut entries = PickEntries::default();
+        let encoding = pick_encoding(&scene, &mut entries);
+        let texture = match self.pick.take() {
+            Some(pick)
+                if pick.texture.width() == params.width
+                    && pick.texture.height() == params.height =>
+            {
+                pick.texture
+            }
+            _ => device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("vello.pick_buffer"),
+                size: wgpu::Extent3d {
+                    width: params.width,
+                    height: params.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R32Uint,
+                usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            }),
+        };
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let (recording, target) = render::render_pick(
+            &encoding,
+            &mut self.resolver,
+            &self.shaders,
+            params,
+            self.bump_sizes,
+        );
+        let external_resources = [ExternalResource::Image(*target.as_image().unwrap(), &view)];
+        self.engine.run_recording(
+            device,
+            queue,
+            &recording,
+            &external_resources,
+            "render_pick_buffer",
+            #[cfg(feature = "wgpu-profiler")]
+            &mut self.profiler,
+        )?;
+        self.pick = Some(PickBuffer {
+            texture,
+            entries: entries.entries,
+            filters: scene.filters.clone(),
+        });
+        Ok(())
+    }
+
+    /// Returns the id of the topmost tagged draw object at the pixel `(x, y)` of the last
+    /// render, or `None` if there is no tagged draw object there.
+    ///
+    /// This requires [`RendererOptions::pick_buffer`](crate::RendererOptions::pick_buffer)
+    /// to be enabled, and returns `None` otherwise. While it is enabled, every render runs
+    /// the pipeline a second time to draw the pick image, so it roughly doubles the cost of
+    /// rendering whether or not this is called. Unlike [`Scene::hit_test`], draw objects
+    /// below an untagged draw object can't be picked, and the result follows the shapes as
+    /// they were rasterized: a draw object is picked where it covers at least half of the
+    /// pixel.
+    ///
+    /// The pick image is downloaded asynchronously, in the same way as the dynamically
+    /// allocated buffers of [`Renderer::render_to_texture_async`], so the device must be
+    /// polled for the returned future to complete.
+    pub async fn pick(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        x: u32,
+        y: u32,
+    ) -> Result<Option<DrawId>> {
+        let Some(pick) = &self.pick else {
+            return Ok(None);
+        };
+        if x >= pick.texture.width() || y >= pick.texture.height() {
+            return Ok(None);
+        }
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("vello.pick"),
+            size: 4,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("vello.pick"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::TexelCopyTextureInfo {
+                texture: &pick.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit([encoder.finish()]);
+
+        let buf_slice = buffer.slice(..);
+        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+        buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+        receiver.receive().await.expect("channel was closed")?;
+        let mapped = buf_slice.get_mapped_range();
+        let index = u32::from_le_bytes(mapped[..4].try_into().unwrap());
+        drop(mapped);
+        buffer.unmap();
+        let Some(entry) = (index as usize)
+            .checked_sub(1)
+            .and_then(|ix| pick.entries.get(ix))
+        else {
+            return Ok(None);
+        };
+        Ok(match *entry {
+            PickEntry::Id(id) => Some(id),
+            PickEntry::Layer { layer, transform } => {
+                let point = transform * Point::new(f64::from(x) + 0.5, f64::from(y) + 0.5);
+                hit_test::hit_test_layer(&pick.filters[layer], &pick.filters, point)
+            }
+        })
+    }
+}
+
+/// Copy the encoding of `scene` with every draw object filled with a color holding the
+/// index of its entry, adding the entries to `entries`.
+///
+/// Untagged draw objects are filled with index zero, so that they hide the draw objects
+/// below them. Clip layers are kept, and only clip in the pick image. Blurred rectangles are
+/// mostly used for shadows, so they are kept as they are, and the pick shader skips them.
+fn pick_encoding(scene: &Scene, entries: &mut PickEntries) -> Encoding {
+    let ids: HashMap<usize, DrawId> = scene.draw_ids.iter().copied().collect();
+    let placeholders = placeholders(scene);
+    let source = &scene.encoding;
+    let mut encoding = source.clone();
+    encoding.draw_data.clear();
+    // The offset in the rewritten draw data of the draw data of each draw object.
+    let mut data_offsets = HashMap::new();
+    let mut data_ix = 0;
+    for (draw_ix, tag) in encoding.draw_tags.iter_mut().enumerate() {
+        // The number of words of draw data is stored in the tag.
+        let size = ((tag.0 >> 2) & 0x7) as usize;
+        let Some(data) = source.draw_data.get(data_ix..data_ix + size) else {
+            break;
+        };
+        data_offsets.insert(data_ix, encoding.draw_data.len());
+        data_ix += size;
+        if [DrawTag::BEGIN_CLIP, DrawTag::END_CLIP, DrawTag::BLUR_RECT].contains(tag) {
+            encoding.draw_data.extend_from_slice(data);
+            continue;
+        }
+        let index = match placeholders.get(&draw_ix) {
+            Some(&(layer, transform)) => entries.push(PickEntry::Layer { layer, transform }),
+            None => ids.get(&draw_ix).map_or(0, |&id| entries.id(id)),
+        };
+        *tag = DrawTag::COLOR;
+        encoding.draw_data.push(OPAQUE | index);
+    }
+    // The brushes of glyph runs are repeated for each glyph from their draw data.
+    let len = encoding.draw_data.len();
+    for run in &mut encoding.resources.glyph_runs {
+        let offset = &mut run.stream_offsets.draw_data;
+        *offset = data_offsets.get(offset).copied().unwrap_or(len);
+    }
+    // Gradients and images are no longer drawn.
+    encoding
+        .resources
+        .patches
+        .retain(|patch| matches!(patch, Patch::GlyphRun { .. }));
+    encoding
+}
+
+/// The placeholder images of the filter layers of `scene`, by the index of their draw
+/// object, with the index of their layer and the transform from the coordinates of the
+/// target to those of the layer's content.
+fn placeholders(scene: &Scene) -> HashMap<usize, (usize, Affine)> {
+    let mut placeholders = HashMap::new();
+    if scene.filters.is_empty() {
+        return placeholders;
+    }
+    let layers: HashMap<u64, usize> = scene
+        .filters
+        .iter()
+        .enumerate()
+        .map(|(ix, layer)| (layer.image.data.id(), ix))
+        .collect();
+    decode::decode_indexed(&scene.encoding, |draw_ix, command| {
+        let Command::Draw {
+            transform,
+            brush: Brush::Image(image),
+            brush_transform,
+            ..
+        } = command
+        else {
+            return;
+        };
+        let Some(&layer) = layers.get(&image.data.id()) else {
+            return;
+        };
+        // The image covers the content from the origin of the layer.
+        let image_transform = transform * brush_transform.unwrap_or(Affine::IDENTITY);
+        if image_transform.determinant() == 0.0 {
+            return;
+        }
+        let origin = scene.filters[layer].origin.to_vec2();
+        let transform = Affine::translate(origin) * image_transform.inverse();
+        placeholders.insert(draw_ix, (layer, transform));
+    });
+    placeholders
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::color::palette::css;
+    use peniko::kurbo::Rect;
+    use peniko::{Fill, Gradient, Mix};
+
+    use super::*;
+    use crate::Filter;
+
+    fn fill<'a>(scene: &mut Scene, id: Option<u64>, brush: impl Into<peniko::BrushRef<'a>>) {
+        let rect = Rect::new(0.0, 0.0, 20.0, 20.0);
+        match id {
+            Some(id) => {
+                let (transform, fill) = (Affine::IDENTITY, Fill::NonZero);
+                scene.fill_with_id(DrawId(id), fill, transform, brush, None, &rect);
+            }
+            None => scene.fill(Fill::NonZero, Affine::IDENTITY, brush, None, &rect),
+        }
+    }
+
+    #[test]
+    fn draw_objects_are_filled_with_their_indices() {
+        let mut scene = Scene::new();
+        let gradient =
+            Gradient::new_linear((0.0, 0.0), (20.0, 0.0)).with_stops([css::RED, css::BLUE]);
+        fill(&mut scene, Some(7), &gradient);
+        scene.push_layer(
+            Mix::Clip,
+            1.0,
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 10.0, 10.0),
+        );
+        fill(&mut scene, None, css::RED);
+        fill(&mut scene, Some(3), css::RED);
+        fill(&mut scene, Some(7), css::BLUE);
+        scene.pop_layer();
+
+        let mut entries = PickEntries::default();
+        let encoding = pick_encoding(&scene, &mut entries);
+        let tags = &encoding.draw_tags;
+        assert_eq!(tags.len(), scene.encoding.draw_tags.len());
+        assert_eq!(tags[1], DrawTag::BEGIN_CLIP);
+        assert_eq!(*tags.last().unwrap(), DrawTag::END_CLIP);
+        // Draw objects with the same id share an index, and untagged draw objects are zero.
+        assert_eq!(
+            decode_colors(&encoding),
+            [OPAQUE | 1, OPAQUE, OPAQUE | 2, OPAQUE | 1]
+        );
+        assert!(matches!(
+            entries.entries.as_slice(),
+            [PickEntry::Id(DrawId(7)), PickEntry::Id(DrawId(3))]
+        ));
+        // The ramp of the gradient is no longer resolved.
+        assert!(encoding.resources.patches.is_empty());
+    }
+
+    #[test]
+    fn filter_layers_refer_to_their_content() {
+        let mut scene = Scene::new();
+        scene.push_filter_layer(
+            Filter::grayscale(1.0),
+            Affine::translate((10.0, 10.0)),
+            &Rect::new(0.0, 0.0, 20.0, 20.0),
+        );
+        fill(&mut scene, Some(1), css::RED);
+        scene.pop_layer();
+        let scene = scene.with_filter_layers_closed();
+
+        let mut entries = PickEntries::default();
+        let encoding = pick_encoding(&scene, &mut entries);
+        assert_eq!(decode_colors(&encoding), [OPAQUE | 1]);
+        let [PickEntry::Layer { layer, transform }] = entries.entries[..] else {
+            panic!("the placeholder image should be an entry");
+        };
+        let point = transform * Point::new(15.0, 15.0);
+        let hit = hit_test::hit_test_layer(&scene.filters[layer], &scene.filters, point);
+        assert_eq!(hit, Some(DrawId(1)));
+    }
+
+    /// The colors of the draw objects of a pick encoding which aren't clips.
+    fn decode_colors(encoding: &Encoding) -> Vec<u32> {
+        let mut colors = Vec::new();
+        decode::decode(encoding, |command| {
+            if let Command::Draw {
+                brush: Brush::Solid(color),
+                ..
+            } = command
+            {
+                let [r, g, b, a] = color.to_rgba8().to_u8_array();
+                colors.push(u32::from_le_bytes([r, g, b, a]));
+            }
+        });
+        colors
+    }
+}
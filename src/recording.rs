@@ -2,9 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use std::num::NonZeroU64;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
-use peniko::Image;
+use peniko::{Blob, Image};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct ShaderId(pub usize);
@@ -38,6 +39,10 @@ pub struct BufferProxy {
 pub enum ImageFormat {
     Rgba8,
     Bgra8,
+    /// Half precision floating point RGBA, used for linear-light output.
+    Rgba16Float,
+    /// A single unsigned integer, used for the indices of pick images.
+    R32Uint,
 }
 
 /// Proxy used as a handle to an image.
@@ -69,6 +74,11 @@ pub enum Command {
     /// Commands the data to be uploaded to the given image.
     UploadImage(ImageProxy, Vec<u8>),
     WriteImage(ImageProxy, [u32; 2], Image),
+    /// Commands the first image to be copied into the second image at the given offset.
+    CopyImage(ImageProxy, ImageProxy, [u32; 2]),
+    /// Commands the rectangle `[x, y, width, height]` of the first image to be copied into
+    /// the second image at the given offset.
+    CopyImageRegion(ImageProxy, [u32; 4], ImageProxy, [u32; 2]),
     Download(BufferProxy),
     /// Commands to clear the buffer from an offset on for a length of the given size.
     /// If the size is [None], it clears until the end.
@@ -152,10 +162,40 @@ impl Recording {
         image_proxy
     }
 
+    /// Commands to write `image` into `proxy`, with its top left corner at `x`, `y`.
+    ///
+    /// Images whose data doesn't cover their size, such as the placeholders of filter layers
+    /// which weren't rendered, are written as transparent.
     pub fn write_image(&mut self, proxy: ImageProxy, x: u32, y: u32, image: Image) {
+        let size = image.format.size_in_bytes(image.width, image.height);
+        let image = match size {
+            Some(size) if image.data.data().len() < size => Image {
+                data: Blob::new(Arc::new(vec![0; size])),
+                ..image
+            },
+            _ => image,
+        };
         self.push(Command::WriteImage(proxy, [x, y], image));
     }
 
+    /// Commands to copy the whole of `src` into `dst`, with its top left corner at `x`, `y`.
+    pub fn copy_image(&mut self, src: ImageProxy, dst: ImageProxy, x: u32, y: u32) {
+        self.push(Command::CopyImage(src, dst, [x, y]));
+    }
+
+    /// Commands to copy the rectangle `[x, y, width, height]` of `src` into `dst`, with its
+    /// top left corner at `x`, `y`.
+    pub fn copy_image_region(
+        &mut self,
+        src: ImageProxy,
+        rect: [u32; 4],
+        dst: ImageProxy,
+        x: u32,
+        y: u32,
+    ) {
+        self.push(Command::CopyImageRegion(src, rect, dst, [x, y]));
+    }
+
     pub fn dispatch<R>(&mut self, shader: ShaderId, wg_size: (u32, u32, u32), resources: R)
     where
         R: IntoIterator,
@@ -241,11 +281,21 @@ impl BufferProxy {
 }
 
 impl ImageFormat {
+    /// The size of a pixel in bytes.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgba8 | Self::Bgra8 | Self::R32Uint => 4,
+            Self::Rgba16Float => 8,
+        }
+    }
+
     #[cfg(feature = "wgpu")]
     pub fn to_wgpu(self) -> wgpu::TextureFormat {
         match self {
             Self::Rgba8 => wgpu::TextureFormat::Rgba8Unorm,
             Self::Bgra8 => wgpu::TextureFormat::Bgra8Unorm,
+            Self::Rgba16Float => wgpu::TextureFormat::Rgba16Float,
+            Self::R32Uint => wgpu::TextureFormat::R32Uint,
         }
     }
 
@@ -254,6 +304,8 @@ impl ImageFormat {
         match format {
             wgpu::TextureFormat::Rgba8Unorm => Some(Self::Rgba8),
             wgpu::TextureFormat::Bgra8Unorm => Some(Self::Bgra8),
+            wgpu::TextureFormat::Rgba16Float => Some(Self::Rgba16Float),
+            wgpu::TextureFormat::R32Uint => Some(Self::R32Uint),
             _ => None,
         }
     }
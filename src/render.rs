@@ -3,24 +3,157 @@
 
 //! Take an encoded scene and create a graph to render it
 
-use vello_encoding::{Encoding, Resolver, WorkgroupSize, make_mask_lut, make_mask_lut_16};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+use peniko::kurbo::Affine;
 #[cfg(feature = "wgpu")]
-use crate::Scene;
+use peniko::kurbo::Rect;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+use vello_encoding::Transform;
+use vello_encoding::{
+    BufferSize, Encoding, RenderConfig, Resolver, WorkgroupSize, make_mask_lut, make_mask_lut_16,
+};
+
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+use crate::filter::{Filter, MaskType, kernel_radius};
+use crate::image_atlas::{ImageAtlasStats, ImageCache, ImageKey};
+use crate::mipmap::{self, MipLevels};
 use crate::recording::{BufferProxy, ImageFormat, ImageProxy, Recording, ResourceProxy};
 use crate::shaders::FullShaders;
 use crate::{AaConfig, RenderParams};
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+use crate::{Scene, color_convert::srgb_to_linear};
+
+// Failure flags of the stages which use bump allocated buffers, matching `shared/bump.wgsl`.
+const STAGE_BINNING: u32 = 0x1;
+const STAGE_TILE_ALLOC: u32 = 0x2;
+const STAGE_FLATTEN: u32 = 0x4;
+const STAGE_PATH_COUNT: u32 = 0x8;
+const STAGE_COARSE: u32 = 0x10;
+
+/// The capacities of the buffers which the GPU pipeline allocates from dynamically, in
+/// elements.
+///
+/// The size of each buffer is estimated from the scene, and is at least the capacity given
+/// here. Capacities of zero leave the estimate unchanged.
+///
+/// When a render overflows one of these buffers and
+/// [`RendererOptions::max_coarse_retries`] is non-zero, [`Renderer`] grows the capacities
+/// and runs the pipeline again. The capacities which were used for the last render can be
+/// read back with [`Renderer::bump_sizes`], and passed to [`Renderer::set_bump_sizes`] to
+/// size the buffers for the next frame up front.
+///
+/// [`Renderer`]: crate::Renderer
+/// [`RendererOptions::max_coarse_retries`]: crate::RendererOptions::max_coarse_retries
+/// [`Renderer::bump_sizes`]: crate::Renderer::bump_sizes
+/// [`Renderer::set_bump_sizes`]: crate::Renderer::set_bump_sizes
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct BumpSizes {
+    /// Line segments produced by flattening.
+    pub lines: u32,
+    /// Draw object indices written by binning.
+    pub binning: u32,
+    /// Tiles covered by paths.
+    pub tiles: u32,
+    /// Per-tile segment counts.
+    pub seg_counts: u32,
+    /// Path segments assigned to tiles.
+    pub segments: u32,
+    /// Spilled blend stack entries.
+    pub blend: u32,
+    /// Words of the per-tile command lists.
+    pub ptcl: u32,
+}
+
+impl BumpSizes {
+    /// Raise the buffer sizes of `config` to at least these capacities, and update these
+    /// capacities to the resulting sizes.
+    fn apply(&mut self, config: &mut RenderConfig) {
+        let sizes = &mut config.buffer_sizes;
+        let gpu = &mut config.gpu;
+        self.lines = self.lines.max(sizes.lines.len());
+        sizes.lines = BufferSize::new(self.lines);
+        gpu.lines_size = self.lines;
+        // The binning output shares its buffer with the draw info, which comes first.
+        let bin_data_start = gpu.layout.bin_data_start;
+        self.binning = self
+            .binning
+            .max(sizes.bin_data.len().saturating_sub(bin_data_start));
+        sizes.bin_data = BufferSize::new(bin_data_start + self.binning);
+        gpu.binning_size = self.binning;
+        self.tiles = self.tiles.max(sizes.tiles.len());
+        sizes.tiles = BufferSize::new(self.tiles);
+        gpu.tiles_size = self.tiles;
+        self.seg_counts = self.seg_counts.max(sizes.seg_counts.len());
+        sizes.seg_counts = BufferSize::new(self.seg_counts);
+        gpu.seg_counts_size = self.seg_counts;
+        self.segments = self.segments.max(sizes.segments.len());
+        sizes.segments = BufferSize::new(self.segments);
+        gpu.segments_size = self.segments;
+        self.blend = self.blend.max(sizes.blend_spill.len());
+        sizes.blend_spill = BufferSize::new(self.blend);
+        gpu.blend_size = self.blend;
+        self.ptcl = self.ptcl.max(sizes.ptcl.len());
+        sizes.ptcl = BufferSize::new(self.ptcl);
+        gpu.ptcl_size = self.ptcl;
+    }
+
+    /// Grow the capacities which were too small for a render, given the bump allocator
+    /// state read back after its coarse phase.
+    ///
+    /// Returns whether any capacity changed, that is whether the render must be run again.
+    #[cfg(feature = "wgpu")]
+    pub(crate) fn grow(&mut self, bump: &vello_encoding::BumpAllocators) -> bool {
+        let before = *self;
+        // A stage which failed may have stopped allocating early, so its count is only a
+        // lower bound on what it needs. Doubling the capacity ensures progress regardless.
+        let grow = |capacity: &mut u32, used: u32, failed: bool| {
+            if failed || used > *capacity {
+                *capacity = used.max(capacity.saturating_mul(2)).max(1);
+            }
+        };
+        let failed = |stage| bump.failed & stage != 0;
+        grow(&mut self.lines, bump.lines, failed(STAGE_FLATTEN));
+        grow(&mut self.binning, bump.binning, failed(STAGE_BINNING));
+        grow(&mut self.tiles, bump.tile, failed(STAGE_TILE_ALLOC));
+        grow(
+            &mut self.seg_counts,
+            bump.seg_counts,
+            failed(STAGE_PATH_COUNT),
+        );
+        grow(&mut self.segments, bump.segments, false);
+        grow(&mut self.blend, bump.blend, false);
+        grow(&mut self.ptcl, bump.ptcl, failed(STAGE_COARSE));
+        *self != before
+    }
+}
 
 /// State for a render in progress.
 pub struct Render {
     fine_wg_count: Option<WorkgroupSize>,
     fine_resources: Option<FineResources>,
     mask_buf: Option<ResourceProxy>,
+    bump_sizes: BumpSizes,
+    filter_images: FilterImages,
+    /// The format of the output image, if it isn't the output format of the shaders.
+    output_format: Option<ImageFormat>,
+    /// The resources kept between renders, if this is a persistent render.
+    resident: Option<Resident>,
+    /// The ids of the images which are overridden, so can change without their data changing.
+    overridden_images: HashSet<u64>,
+    /// The mip levels of the images which the encoding draws, by the id of their data.
+    mip_levels: MipLevels,
 
     #[cfg(feature = "debug_layers")]
     captured_buffers: Option<CapturedBuffers>,
 }
 
+/// The rendered images of filter layers, keyed by the id of the data of their placeholder
+/// images.
+pub(crate) type FilterImages = HashMap<u64, ImageProxy>;
+
 #[cfg(feature = "debug_layers")]
 impl Drop for Render {
     fn drop(&mut self) {
@@ -42,11 +175,199 @@ struct FineResources {
     gradient_image: ResourceProxy,
     info_bin_data_buf: ResourceProxy,
     image_atlas: ResourceProxy,
+    mip_table_buf: ResourceProxy,
     blend_spill_buf: ResourceProxy,
 
     out_image: ImageProxy,
 }
 
+impl FineResources {
+    /// Free the resources, other than those which are kept resident.
+    fn free(self, recording: &mut Recording, resident: Option<&Resident>) {
+        recording.free_resource(self.config_buf);
+        recording.free_resource(self.tile_buf);
+        recording.free_resource(self.segments_buf);
+        recording.free_resource(self.ptcl_buf);
+        for image in [self.gradient_image, self.image_atlas] {
+            if !resident.is_some_and(|resident| resident.holds(image)) {
+                recording.free_resource(image);
+            }
+        }
+        recording.free_resource(self.info_bin_data_buf);
+        recording.free_resource(self.mip_table_buf);
+        recording.free_resource(self.blend_spill_buf);
+    }
+}
+
+/// The resources which a persistent [`Render`] keeps between renders.
+///
+/// Each resource has a single copy, which is updated by writes recorded before the
+/// dispatches which read it. The writes of a recording are queued when it is run, after
+/// the work of earlier recordings has been submitted, so a resource is never updated while
+/// an earlier frame which is still in flight reads it, and several frames can be in flight
+/// without keeping a copy for each of them.
+#[derive(Default)]
+struct Resident {
+    /// The mask lookup tables of the MSAA modes, in the order of [`AaConfig::Msaa8`] and
+    /// [`AaConfig::Msaa16`].
+    mask_luts: [Option<ResourceProxy>; 2],
+    ramps: Option<ResidentRamps>,
+    atlas: Option<ResidentAtlas>,
+    /// The images drawn by recent renders, which are copied into the atlas.
+    image_cache: ImageCache,
+}
+
+/// The gradient ramps uploaded by the last render.
+struct ResidentRamps {
+    image: ImageProxy,
+    /// The ramps in the image, which has room for more rows below them.
+    data: Vec<u32>,
+}
+
+/// The image atlas used by the last render.
+struct ResidentAtlas {
+    image: ImageProxy,
+    /// The position of each image written to the atlas.
+    contents: HashMap<ImageKey, [u32; 2]>,
+}
+
+impl Resident {
+    /// Whether `resource` is one of the resident resources.
+    fn holds(&self, resource: ResourceProxy) -> bool {
+        let ResourceProxy::Image(image) = resource else {
+            return false;
+        };
+        let ramps = self.ramps.as_ref().map(|ramps| ramps.image.id);
+        let atlas = self.atlas.as_ref().map(|atlas| atlas.image.id);
+        ramps == Some(image.id) || atlas == Some(image.id)
+    }
+
+    /// The mask lookup table of an MSAA mode, which is uploaded by the first render using it.
+    fn mask_lut(&mut self, aa_config: AaConfig, recording: &mut Recording) -> ResourceProxy {
+        let ix = usize::from(aa_config == AaConfig::Msaa16);
+        *self.mask_luts[ix].get_or_insert_with(|| upload_mask_lut(aa_config, recording))
+    }
+
+    /// Update the gradient ramps to `data`, only writing the rows which changed since the
+    /// last render.
+    fn update_ramps(
+        &mut self,
+        recording: &mut Recording,
+        width: u32,
+        height: u32,
+        data: &[u32],
+    ) -> ImageProxy {
+        let row_len = width as usize;
+        match &mut self.ramps {
+            Some(ramps) if ramps.image.width == width && ramps.image.height >= height => {
+                let changed = |y: usize| {
+                    let row = y * row_len..(y + 1) * row_len;
+                    ramps.data.get(row.clone()) != Some(&data[row])
+                };
+                let mut y = 0;
+                while y < height as usize {
+                    if !changed(y) {
+                        y += 1;
+                        continue;
+                    }
+                    let start = y;
+                    while y < height as usize && changed(y) {
+                        y += 1;
+                    }
+                    let rows = &data[start * row_len..y * row_len];
+                    let bytes: Vec<u8> = bytemuck::cast_slice(rows).to_vec();
+                    let image = peniko::Image::new(
+                        peniko::Blob::new(Arc::new(bytes)),
+                        peniko::ImageFormat::Rgba8,
+                        width,
+                        (y - start) as u32,
+                    );
+                    recording.write_image(ramps.image, 0, start as u32, image);
+                }
+                ramps.data.clear();
+                ramps.data.extend_from_slice(data);
+                ramps.image
+            }
+            _ => {
+                if let Some(old) = self.ramps.take() {
+                    recording.free_image(old.image);
+                }
+                // Gradients are added and removed a few at a time, so leave room for them.
+                let capacity = height.next_power_of_two();
+                let mut bytes: Vec<u8> = bytemuck::cast_slice(data).to_vec();
+                bytes.resize(row_len * capacity as usize * 4, 0);
+                let image = recording.upload_image(width, capacity, ImageFormat::Rgba8, bytes);
+                self.ramps = Some(ResidentRamps {
+                    image,
+                    data: data.to_vec(),
+                });
+                image
+            }
+        }
+    }
+
+    /// Free all of the resident resources.
+    fn free(&mut self, recording: &mut Recording) {
+        for mask_lut in self.mask_luts.iter_mut().filter_map(Option::take) {
+            recording.free_resource(mask_lut);
+        }
+        if let Some(ramps) = self.ramps.take() {
+            recording.free_image(ramps.image);
+        }
+        if let Some(atlas) = self.atlas.take() {
+            recording.free_image(atlas.image);
+        }
+        self.image_cache.free(recording);
+    }
+}
+
+impl ResidentAtlas {
+    /// The image atlas of the given size, which is reused if the last render used one of the
+    /// same size.
+    fn resize<'a>(
+        atlas: &'a mut Option<Self>,
+        recording: &mut Recording,
+        width: u32,
+        height: u32,
+    ) -> &'a mut Self {
+        if atlas
+            .as_ref()
+            .is_some_and(|atlas| atlas.image.width != width || atlas.image.height != height)
+        {
+            recording.free_image(atlas.take().unwrap().image);
+        }
+        atlas.get_or_insert_with(|| Self {
+            image: ImageProxy::new(width, height, ImageFormat::Rgba8),
+            contents: HashMap::new(),
+        })
+    }
+}
+
+/// Upload the mask lookup table of an MSAA mode.
+fn upload_mask_lut(aa_config: AaConfig, recording: &mut Recording) -> ResourceProxy {
+    let mask_lut = match aa_config {
+        AaConfig::Msaa16 => make_mask_lut_16(),
+        AaConfig::Msaa8 => make_mask_lut(),
+        _ => unreachable!(),
+    };
+    recording.upload("vello.mask_lut", mask_lut).into()
+}
+
+/// Write an image to the image atlas, or copy the rendered image of a filter layer in place
+/// of its placeholder image.
+fn write_atlas_image(
+    recording: &mut Recording,
+    filter_images: &FilterImages,
+    atlas: ImageProxy,
+    image: &peniko::Image,
+    [x, y]: [u32; 2],
+) {
+    match filter_images.get(&image.data.id()) {
+        Some(filtered) => recording.copy_image(*filtered, atlas, x, y),
+        None => recording.write_image(atlas, x, y, image.clone()),
+    }
+}
+
 /// A collection of internal buffers that are used for debug visualization when the
 /// `debug_layers` feature is enabled. The contents of these buffers remain GPU resident
 /// and must be freed directly by the caller.
@@ -72,34 +393,646 @@ impl CapturedBuffers {
     }
 }
 
-#[cfg(feature = "wgpu")]
+/// Create a single recording rendering the filter layers of the scene, then the scene
+/// with `render`.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
 pub(crate) fn render_full(
     scene: &Scene,
     resolver: &mut Resolver,
     shaders: &FullShaders,
     params: &RenderParams,
+    bump_sizes: BumpSizes,
+    mip_levels: &MipLevels,
+    render: &mut Render,
 ) -> (Recording, ResourceProxy) {
-    render_encoding_full(scene.encoding(), resolver, shaders, params)
+    let mut recording = Recording::default();
+    let filter_images = render_filters(
+        scene,
+        resolver,
+        shaders,
+        params,
+        bump_sizes,
+        mip_levels,
+        &mut recording,
+    );
+    let (mut scene_recording, target) = render_encoding_full(
+        scene.encoding(),
+        resolver,
+        shaders,
+        params,
+        bump_sizes,
+        &filter_images,
+        mip_levels,
+        render,
+    );
+    recording.commands.append(&mut scene_recording.commands);
+    for image in filter_images.into_values() {
+        recording.free_image(image);
+    }
+    (recording, target)
 }
 
-#[cfg(feature = "wgpu")]
+/// The configuration of the `filter_blur` and `filter_composite` shaders.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub(crate) struct FilterConfig {
+    pub size: [u32; 2],
+    /// The offset of the blurred content, for drop shadows.
+    pub offset: [i32; 2],
+    /// The premultiplied color of drop shadows, in the color space of the content.
+    pub color: [f32; 4],
+    pub std_dev: f32,
+    pub radius: u32,
+    /// Either [`FILTER_BLUR`] or [`FILTER_DROP_SHADOW`].
+    pub kind: u32,
+    /// Whether the content is in linear light, rather than sRGB encoded.
+    pub linear: u32,
+    /// The columns of the color matrix applied to the separated sRGB result.
+    pub matrix: [[f32; 4]; 5],
+    /// Either [`MASK_NONE`], [`MASK_ALPHA`] or [`MASK_LUMINANCE`].
+    pub mask: u32,
+    pub padding: [u32; 3],
+}
+
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) const FILTER_BLUR: u32 = 0;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) const FILTER_DROP_SHADOW: u32 = 1;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) const MASK_NONE: u32 = 0;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) const MASK_ALPHA: u32 = 1;
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) const MASK_LUMINANCE: u32 = 2;
+
+/// Record the rendering of the filter layers of a scene.
+///
+/// Each layer's content is rendered into an intermediate image, which is blurred
+/// horizontally by `filter_blur`, and then vertically by `filter_composite`, which also
+/// composites drop shadows with the content, applies color matrices and multiplies the
+/// result by the layer's mask, which is rendered like the content. Filters which don't
+/// blur use a kernel of a single texel. The returned images must be freed once the
+/// scene has been rendered.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn render_filters(
+    scene: &Scene,
+    resolver: &mut Resolver,
+    shaders: &FullShaders,
+    params: &RenderParams,
+    bump_sizes: BumpSizes,
+    mip_levels: &MipLevels,
+    recording: &mut Recording,
+) -> FilterImages {
+    let linear = shaders.output_format == ImageFormat::Rgba16Float;
+    let mut filter_images = FilterImages::new();
+    let mut encoding = Encoding::new();
+    for layer in &scene.filters {
+        let id = layer.image.data.id();
+        // A scene which was appended more than once contains its layers more than once.
+        if filter_images.contains_key(&id) {
+            continue;
+        }
+        let (width, height) = (layer.image.width, layer.image.height);
+        let layer_params = RenderParams {
+            base_color: peniko::color::palette::css::TRANSPARENT,
+            width,
+            height,
+            antialiasing_method: params.antialiasing_method,
+        };
+        let offset = Some(Transform::from_kurbo(&Affine::translate(
+            -layer.origin.to_vec2(),
+        )));
+        let mut render_layer = |content: &Encoding, recording: &mut Recording| {
+            encoding.reset();
+            encoding.append(content, &offset);
+            let (mut layer_recording, image) = render_encoding_full(
+                &encoding,
+                resolver,
+                shaders,
+                &layer_params,
+                bump_sizes,
+                &filter_images,
+                mip_levels,
+                &mut Render::new(),
+            );
+            recording.commands.append(&mut layer_recording.commands);
+            image
+        };
+        let content = render_layer(&layer.content, recording);
+        let mask = layer
+            .mask
+            .as_ref()
+            .map(|mask| (mask.kind, render_layer(&mask.content, recording)));
+
+        let std_dev = layer.filter.std_dev() as f32;
+        let (kind, offset, color) = match layer.filter {
+            Filter::GaussianBlur { .. } | Filter::ColorMatrix(_) => (FILTER_BLUR, [0; 2], [0.0; 4]),
+            Filter::DropShadow { offset, color, .. } => {
+                let [r, g, b, a] = color.components;
+                let rgb = if linear {
+                    [r, g, b].map(srgb_to_linear)
+                } else {
+                    [r, g, b]
+                };
+                let offset = [offset.x.round() as i32, offset.y.round() as i32];
+                (
+                    FILTER_DROP_SHADOW,
+                    offset,
+                    [rgb[0] * a, rgb[1] * a, rgb[2] * a, a],
+                )
+            }
+        };
+        let config = FilterConfig {
+            size: [width, height],
+            offset,
+            color,
+            std_dev,
+            radius: kernel_radius(std_dev),
+            kind,
+            linear: linear.into(),
+            matrix: std::array::from_fn(|col| {
+                std::array::from_fn(|row| layer.filter.color_matrix()[row * 5 + col])
+            }),
+            mask: match mask {
+                None => MASK_NONE,
+                Some((MaskType::Alpha, _)) => MASK_ALPHA,
+                Some((MaskType::Luminance, _)) => MASK_LUMINANCE,
+            },
+            padding: [0; 3],
+        };
+        let config_buf =
+            recording.upload_uniform("vello.filter_config", bytemuck::bytes_of(&config).to_vec());
+        let blurred = ImageProxy::new(width, height, ImageFormat::Rgba16Float);
+        let output = ImageProxy::new(width, height, ImageFormat::Rgba8);
+        let wg_counts = (width.div_ceil(16), height.div_ceil(16), 1);
+        recording.dispatch(
+            shaders.filter_blur,
+            wg_counts,
+            [
+                ResourceProxy::Buffer(config_buf),
+                content,
+                ResourceProxy::Image(blurred),
+            ],
+        );
+        recording.dispatch(
+            shaders.filter_composite,
+            wg_counts,
+            [
+                ResourceProxy::Buffer(config_buf),
+                ResourceProxy::Image(blurred),
+                content,
+                // Layers without a mask bind their content in its place, which is ignored.
+                mask.map_or(content, |(_, mask)| mask),
+                ResourceProxy::Image(output),
+            ],
+        );
+        recording.free_buffer(config_buf);
+        recording.free_image(blurred);
+        recording.free_resource(content);
+        if let Some((_, mask)) = mask {
+            recording.free_resource(mask);
+        }
+        filter_images.insert(id, output);
+    }
+    filter_images
+}
+
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
 /// Create a single recording with both coarse and fine render stages.
 ///
 /// This function is not recommended when the scene can be complex, as it does not
 /// implement robust dynamic memory.
+///
+/// The encoding is rendered with `render`, unless it is rendered in tiles.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn render_encoding_full(
     encoding: &Encoding,
     resolver: &mut Resolver,
     shaders: &FullShaders,
     params: &RenderParams,
+    bump_sizes: BumpSizes,
+    filter_images: &FilterImages,
+    mip_levels: &MipLevels,
+    render: &mut Render,
 ) -> (Recording, ResourceProxy) {
-    let mut render = Render::new();
+    if needs_tiles(params) {
+        return render_encoding_tiled(
+            encoding,
+            resolver,
+            shaders,
+            params,
+            bump_sizes,
+            filter_images,
+            mip_levels,
+        );
+    }
+    render.set_bump_sizes(bump_sizes);
+    render.set_filter_images(filter_images.clone());
+    render.set_mip_levels(mip_levels.clone());
     let mut recording = render.render_encoding_coarse(encoding, resolver, shaders, params, false);
     let out_image = render.out_image();
     render.record_fine(shaders, &mut recording);
     (recording, out_image.into())
 }
 
+/// Create a recording which renders the pick image of an encoding, see [`crate::pick`].
+///
+/// Pick images are always rasterized with area anti-aliasing. Large targets are rendered in
+/// tiles, like other targets.
+#[cfg(feature = "wgpu")]
+pub(crate) fn render_pick(
+    encoding: &Encoding,
+    resolver: &mut Resolver,
+    shaders: &FullShaders,
+    params: &RenderParams,
+    bump_sizes: BumpSizes,
+) -> (Recording, ResourceProxy) {
+    let params = RenderParams {
+        base_color: params.base_color,
+        width: params.width,
+        height: params.height,
+        antialiasing_method: AaConfig::Area,
+    };
+    if needs_tiles(&params) {
+        return render_encoding_regions(
+            encoding,
+            resolver,
+            shaders,
+            &params,
+            bump_sizes,
+            &FilterImages::new(),
+            &MipLevels::new(),
+            ImageFormat::R32Uint,
+            tiles([0, 0, params.width, params.height]).map(RegionPass::whole),
+        );
+    }
+    let mut render = Render::new();
+    render.set_bump_sizes(bump_sizes);
+    render.set_output_format(ImageFormat::R32Uint);
+    let mut recording = render.render_encoding_coarse(encoding, resolver, shaders, &params, false);
+    let out_image = render.out_image();
+    render.record_fine(shaders, &mut recording);
+    (recording, out_image.into())
+}
+
+/// The largest width and height of a render which doesn't need to be split into tiles.
+///
+/// Coarse rasterization supports at most 256 bins of 256×256 pixels, see
+/// <https://github.com/linebender/vello/issues/680>.
+const MAX_TILE_SIZE: u32 = 16 * 256;
+
+/// The width and height of the tiles of fine rasterization, in pixels.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+const FINE_TILE_SIZE: u32 = 16;
+
+/// Whether the target of a render is too large to be rendered in one pass.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn needs_tiles(params: &RenderParams) -> bool {
+    params.width > MAX_TILE_SIZE || params.height > MAX_TILE_SIZE
+}
+
+/// The configuration of the `tile_copy` shader.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub(crate) struct TileCopyConfig {
+    pub offset: [u32; 2],
+    pub tile_size: [u32; 2],
+    pub target_size: [u32; 2],
+    /// The position of the copied tile in the rendered image.
+    pub source: [u32; 2],
+    pub source_size: [u32; 2],
+    pub padding: [u32; 2],
+}
+
+/// The tiles of fine rasterization which one dispatch of it renders, as an offset and size
+/// in tiles.
+///
+/// The layout matches `FineRegion` in the fine shader of this crate. The fine shaders of
+/// `vello_shaders` don't read it, and always render every tile.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub(crate) struct FineRegion {
+    pub offset: [u32; 2],
+    pub size: [u32; 2],
+}
+
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+/// Create a recording which renders a large target as a grid of tiles.
+fn render_encoding_tiled(
+    encoding: &Encoding,
+    resolver: &mut Resolver,
+    shaders: &FullShaders,
+    params: &RenderParams,
+    bump_sizes: BumpSizes,
+    filter_images: &FilterImages,
+    mip_levels: &MipLevels,
+) -> (Recording, ResourceProxy) {
+    render_encoding_regions(
+        encoding,
+        resolver,
+        shaders,
+        params,
+        bump_sizes,
+        filter_images,
+        mip_levels,
+        shaders.output_format,
+        tiles([0, 0, params.width, params.height]).map(RegionPass::whole),
+    )
+}
+
+/// Create a recording which renders the parts of the target covered by `damage`, leaving
+/// the rest of the target unchanged.
+#[cfg(feature = "wgpu")]
+pub(crate) fn render_damage(
+    scene: &Scene,
+    resolver: &mut Resolver,
+    shaders: &FullShaders,
+    params: &RenderParams,
+    bump_sizes: BumpSizes,
+    mip_levels: &MipLevels,
+    damage: &[Rect],
+) -> (Recording, ResourceProxy) {
+    let mut recording = Recording::default();
+    let filter_images = render_filters(
+        scene,
+        resolver,
+        shaders,
+        params,
+        bump_sizes,
+        mip_levels,
+        &mut recording,
+    );
+    let (mut scene_recording, target) = render_encoding_regions(
+        scene.encoding(),
+        resolver,
+        shaders,
+        params,
+        bump_sizes,
+        &filter_images,
+        mip_levels,
+        shaders.output_format,
+        damage_passes(params, damage),
+    );
+    recording.commands.append(&mut scene_recording.commands);
+    for image in filter_images.into_values() {
+        recording.free_image(image);
+    }
+    (recording, target)
+}
+
+/// The area, in pixels, which costs about as much to rasterize as dispatching fine
+/// rasterization and copying the result for one more region of the target.
+///
+/// Two damaged regions are rendered as one when their union covers at most this many pixels
+/// which are in neither of them.
+#[cfg(feature = "wgpu")]
+const REGION_MERGE_AREA: u64 = 64 * 64;
+
+/// A run of the pipeline over the region `bounds` of the target, of which only `regions`
+/// are rasterized by fine rasterization and copied into the target.
+///
+/// Both are given as `[x, y, width, height]` in pixels of the target, and the regions are
+/// within the bounds. Only the bins of the bounds are processed by coarse rasterization.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) struct RegionPass {
+    pub bounds: [u32; 4],
+    pub regions: Vec<[u32; 4]>,
+}
+
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+impl RegionPass {
+    /// A pass rendering all of `bounds`.
+    pub fn whole(bounds: [u32; 4]) -> Self {
+        Self {
+            bounds,
+            regions: vec![bounds],
+        }
+    }
+
+    /// The regions, as `[x, y, width, height]` in tiles of fine rasterization of the render
+    /// of the bounds.
+    pub fn fine_regions(&self) -> Vec<[u32; 4]> {
+        let tile = |value: u32| value / FINE_TILE_SIZE;
+        self.regions
+            .iter()
+            .map(|&[x, y, width, height]| {
+                [
+                    tile(x - self.bounds[0]),
+                    tile(y - self.bounds[1]),
+                    width.div_ceil(FINE_TILE_SIZE),
+                    height.div_ceil(FINE_TILE_SIZE),
+                ]
+            })
+            .collect()
+    }
+}
+
+/// The passes rendering the regions of the target covering `damage`.
+///
+/// The damaged rectangles are rounded out to whole tiles of fine rasterization, so that
+/// the pixels at their edges are rendered exactly as in a render of the whole target.
+/// Rectangles which are near each other are then merged, see [`REGION_MERGE_AREA`]. The
+/// regions are rendered by a single pass over their bounding box, unless it is too large to
+/// be rendered in one pass.
+#[cfg(feature = "wgpu")]
+pub(crate) fn damage_passes(params: &RenderParams, damage: &[Rect]) -> Vec<RegionPass> {
+    // The index of the tile containing a coordinate, clamped to the target.
+    let tile = |value: f64, size: u32| {
+        let tiles = f64::from(size.div_ceil(FINE_TILE_SIZE));
+        (value / f64::from(FINE_TILE_SIZE)).clamp(0.0, tiles)
+    };
+    let mut merged: Vec<[u32; 4]> = Vec::new();
+    for rect in damage {
+        let x0 = tile(rect.x0, params.width).floor() as u32 * FINE_TILE_SIZE;
+        let y0 = tile(rect.y0, params.height).floor() as u32 * FINE_TILE_SIZE;
+        let x1 = tile(rect.x1, params.width).ceil() as u32 * FINE_TILE_SIZE;
+        let y1 = tile(rect.y1, params.height).ceil() as u32 * FINE_TILE_SIZE;
+        let mut rect = [x0, y0, x1.min(params.width), y1.min(params.height)];
+        if rect[0] >= rect[2] || rect[1] >= rect[3] {
+            continue;
+        }
+        // Merging can make a region worth merging with others which it wasn't before.
+        while let Some(ix) = merged.iter().position(|other| worth_merging(*other, rect)) {
+            rect = region_union(rect, merged.swap_remove(ix));
+        }
+        merged.push(rect);
+    }
+    let Some(bounds) = merged.iter().copied().reduce(region_union) else {
+        return Vec::new();
+    };
+    tiles(bounds)
+        .filter_map(|[x, y, width, height]| {
+            let pass = [x, y, x + width, y + height];
+            let regions: Vec<[u32; 4]> = merged
+                .iter()
+                .filter_map(|&rect| region_intersection(rect, pass))
+                .collect();
+            let [x0, y0, x1, y1] = regions.iter().copied().reduce(region_union)?;
+            Some(RegionPass {
+                bounds: [x0, y0, x1 - x0, y1 - y0],
+                regions: regions
+                    .into_iter()
+                    .map(|[x0, y0, x1, y1]| [x0, y0, x1 - x0, y1 - y0])
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Whether the regions `[x0, y0, x1, y1]` are cheaper to render as their union.
+///
+/// Overlapping regions are only merged when their union is barely larger than they are,
+/// as the union of two thin crossing regions can cover most of the target. Otherwise the
+/// overlap is rendered twice, which writes the same pixels.
+#[cfg(feature = "wgpu")]
+fn worth_merging(a: [u32; 4], b: [u32; 4]) -> bool {
+    let area = |[x0, y0, x1, y1]: [u32; 4]| u64::from(x1 - x0) * u64::from(y1 - y0);
+    let overlap = region_intersection(a, b).map_or(0, area);
+    area(region_union(a, b)) + overlap <= area(a) + area(b) + REGION_MERGE_AREA
+}
+
+#[cfg(feature = "wgpu")]
+fn region_union(a: [u32; 4], b: [u32; 4]) -> [u32; 4] {
+    [
+        a[0].min(b[0]),
+        a[1].min(b[1]),
+        a[2].max(b[2]),
+        a[3].max(b[3]),
+    ]
+}
+
+/// The intersection of the regions `[x0, y0, x1, y1]`, if it isn't empty.
+#[cfg(feature = "wgpu")]
+fn region_intersection(a: [u32; 4], b: [u32; 4]) -> Option<[u32; 4]> {
+    let [x0, y0] = [a[0].max(b[0]), a[1].max(b[1])];
+    let [x1, y1] = [a[2].min(b[2]), a[3].min(b[3])];
+    (x0 < x1 && y0 < y1).then_some([x0, y0, x1, y1])
+}
+
+/// Split the rectangle `[x0, y0, x1, y1]` of the target into regions which can be rendered
+/// in one pass, as `[x, y, width, height]`.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn tiles([x0, y0, x1, y1]: [u32; 4]) -> impl Iterator<Item = [u32; 4]> {
+    (y0..y1).step_by(MAX_TILE_SIZE as usize).flat_map(move |y| {
+        (x0..x1)
+            .step_by(MAX_TILE_SIZE as usize)
+            .map(move |x| [x, y, MAX_TILE_SIZE.min(x1 - x), MAX_TILE_SIZE.min(y1 - y)])
+    })
+}
+
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+/// Create a recording which renders regions of the target in `passes`.
+///
+/// Each pass is rendered with the scene translated so that its bounds are at the origin,
+/// and its regions are then copied into place in the target, which has the given format.
+/// Pixels outside of the regions aren't written.
+#[allow(clippy::too_many_arguments)]
+fn render_encoding_regions(
+    encoding: &Encoding,
+    resolver: &mut Resolver,
+    shaders: &FullShaders,
+    params: &RenderParams,
+    bump_sizes: BumpSizes,
+    filter_images: &FilterImages,
+    mip_levels: &MipLevels,
+    format: ImageFormat,
+    passes: impl IntoIterator<Item = RegionPass>,
+) -> (Recording, ResourceProxy) {
+    let mut recording = Recording::default();
+    let target = ImageProxy::new(params.width, params.height, format);
+    let mut tile = Encoding::new();
+    for pass in passes {
+        let tile_params = region_params(params, pass.bounds);
+        translate_to_region(encoding, pass.bounds, &mut tile);
+        let mut render = Render::new();
+        render.set_bump_sizes(bump_sizes);
+        render.set_output_format(format);
+        render.set_filter_images(filter_images.clone());
+        render.set_mip_levels(mip_levels.clone());
+        let mut tile_recording =
+            render.render_encoding_coarse(&tile, resolver, shaders, &tile_params, false);
+        let tile_image = render.out_image();
+        render.record_fine_regions(shaders, &mut tile_recording, &pass.fine_regions());
+        recording.commands.append(&mut tile_recording.commands);
+        record_region_copies(&mut recording, shaders, tile_image, &pass, target);
+    }
+    (recording, target.into())
+}
+
+/// The parameters of a render of the region `[x, y, width, height]` of the target.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn region_params(
+    params: &RenderParams,
+    [_, _, width, height]: [u32; 4],
+) -> RenderParams {
+    RenderParams {
+        base_color: params.base_color,
+        width,
+        height,
+        antialiasing_method: params.antialiasing_method,
+    }
+}
+
+/// Set `tile` to `encoding` translated so that the region `[x, y, width, height]` of the
+/// target is at the origin.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn translate_to_region(
+    encoding: &Encoding,
+    [x, y, _, _]: [u32; 4],
+    tile: &mut Encoding,
+) {
+    tile.reset();
+    let offset = Affine::translate((-f64::from(x), -f64::from(y)));
+    tile.append(encoding, &Some(Transform::from_kurbo(&offset)));
+}
+
+/// Record copying the regions of the render of `pass` into place in `target`, and freeing
+/// the render.
+#[cfg(any(feature = "wgpu", feature = "cpu"))]
+pub(crate) fn record_region_copies(
+    recording: &mut Recording,
+    shaders: &FullShaders,
+    pass_image: ImageProxy,
+    pass: &RegionPass,
+    target: ImageProxy,
+) {
+    let tile_copy = if pass_image.format == ImageFormat::R32Uint {
+        shaders
+            .tile_copy_pick
+            .expect("shaders not configured to render pick images")
+    } else {
+        shaders.tile_copy
+    };
+    for &[x, y, width, height] in &pass.regions {
+        let config = TileCopyConfig {
+            offset: [x, y],
+            tile_size: [width, height],
+            target_size: [target.width, target.height],
+            source: [x - pass.bounds[0], y - pass.bounds[1]],
+            source_size: [pass_image.width, pass_image.height],
+            padding: [0; 2],
+        };
+        let config_buf = recording.upload_uniform(
+            "vello.tile_copy_config",
+            bytemuck::bytes_of(&config).to_vec(),
+        );
+        recording.dispatch(
+            tile_copy,
+            (width.div_ceil(16), height.div_ceil(16), 1),
+            [
+                ResourceProxy::Buffer(config_buf),
+                ResourceProxy::Image(pass_image),
+                ResourceProxy::Image(target),
+            ],
+        );
+        recording.free_buffer(config_buf);
+    }
+    recording.free_image(pass_image);
+}
+
 impl Default for Render {
     fn default() -> Self {
         Self::new()
@@ -112,11 +1045,99 @@ impl Render {
             fine_wg_count: None,
             fine_resources: None,
             mask_buf: None,
+            bump_sizes: BumpSizes::default(),
+            filter_images: FilterImages::new(),
+            output_format: None,
+            resident: None,
+            overridden_images: HashSet::new(),
+            mip_levels: MipLevels::new(),
             #[cfg(feature = "debug_layers")]
             captured_buffers: None,
         }
     }
 
+    /// Creates a render which keeps the MSAA mask lookup tables, the gradient ramps and the
+    /// image atlas resident between renders, and only uploads the parts of them which
+    /// changed. Images are kept in a cache of shelf-packed pages, and copied from there into
+    /// the atlas, so each image is only uploaded once while it is drawn.
+    ///
+    /// A recording may only contain one render of a persistent render, as the writes of a
+    /// recording happen before any of its dispatches, and the recordings must be run in
+    /// order on the same engine. The resident resources are freed by
+    /// [`Self::free_resident`].
+    pub fn persistent() -> Self {
+        let mut render = Self::new();
+        render.resident = Some(Resident::default());
+        render
+    }
+
+    /// Free the resources kept resident by a persistent render.
+    ///
+    /// The render stays persistent, so the resources are created again by the next render.
+    pub fn free_resident(&mut self, recording: &mut Recording) {
+        if let Some(resident) = &mut self.resident {
+            resident.free(recording);
+        }
+    }
+
+    /// Set the minimum capacities of the dynamically allocated buffers.
+    ///
+    /// This must be called before [`Self::render_encoding_coarse`].
+    pub fn set_bump_sizes(&mut self, bump_sizes: BumpSizes) {
+        self.bump_sizes = bump_sizes;
+    }
+
+    /// Set the rendered images of the filter layers which the encoding draws.
+    ///
+    /// These are copied into the image atlas in place of the placeholder images of the
+    /// layers. This must be called before [`Self::render_encoding_coarse`].
+    pub(crate) fn set_filter_images(&mut self, filter_images: FilterImages) {
+        self.filter_images = filter_images;
+    }
+
+    /// Set the format of the output image, which is the output format of the shaders by
+    /// default.
+    ///
+    /// Images of [`ImageFormat::R32Uint`] are pick images, which are rasterized by
+    /// [`FullShaders::fine_pick`] with area anti-aliasing. This must be called before
+    /// [`Self::render_encoding_coarse`].
+    pub(crate) fn set_output_format(&mut self, format: ImageFormat) {
+        self.output_format = Some(format);
+    }
+
+    /// Set the ids of the data of the images whose contents are overridden by a texture.
+    ///
+    /// These are written to the image atlas by every render, as their contents can change
+    /// without their data changing. This must be called before
+    /// [`Self::render_encoding_coarse`].
+    pub(crate) fn set_overridden_images(&mut self, overridden_images: HashSet<u64>) {
+        self.overridden_images = overridden_images;
+    }
+
+    /// Set the mip levels of the images which the encoding draws, see [`crate::mipmap`].
+    ///
+    /// The levels are written to the image atlas below the images, and the fine stage
+    /// samples them when the images are drawn downscaled. This must be called before
+    /// [`Self::render_encoding_coarse`].
+    pub(crate) fn set_mip_levels(&mut self, mip_levels: MipLevels) {
+        self.mip_levels = mip_levels;
+    }
+
+    /// The capacities of the dynamically allocated buffers.
+    ///
+    /// After [`Self::render_encoding_coarse`], these are the capacities which it used.
+    pub fn bump_sizes(&self) -> BumpSizes {
+        self.bump_sizes
+    }
+
+    /// The statistics of the image cache of a persistent render, for the last render.
+    pub fn image_atlas_stats(&self) -> ImageAtlasStats {
+        self.resident
+            .as_ref()
+            .map(|resident| resident.image_cache.stats())
+            .unwrap_or_default()
+    }
+
     /// Prepare a recording for the coarse rasterization phase.
     ///
     /// The `robust` parameter controls whether we're preparing for readback
@@ -129,13 +1150,19 @@ impl Render {
         params: &RenderParams,
         robust: bool,
     ) -> Recording {
-        use vello_encoding::RenderConfig;
         let mut recording = Recording::default();
         let mut packed = vec![];
 
         let (layout, ramps, images) = resolver.resolve(encoding, &mut packed);
         let gradient_image = if ramps.height == 0 {
             ResourceProxy::new_image(1, 1, ImageFormat::Rgba8)
+        } else if let Some(resident) = &mut self.resident {
+            ResourceProxy::Image(resident.update_ramps(
+                &mut recording,
+                ramps.width,
+                ramps.height,
+                ramps.data,
+            ))
         } else {
             let data: &[u8] = bytemuck::cast_slice(ramps.data);
             ResourceProxy::Image(recording.upload_image(
@@ -145,16 +1172,61 @@ impl Render {
                 data,
             ))
         };
+        let filter_images = &self.filter_images;
+        if let Some(resident) = &mut self.resident {
+            resident.image_cache.begin_frame();
+        }
+        let levels =
+            mipmap::place_levels(&self.mip_levels, images.images, images.width, images.height);
+        // The images placed by the resolver, followed by their mip levels.
+        let placed = images
+            .images
+            .iter()
+            .map(|(image, x, y)| (image, [*x, *y]))
+            .chain(levels.levels.iter().copied());
         let image_atlas = if images.images.is_empty() {
             ImageProxy::new(1, 1, ImageFormat::Rgba8)
+        } else if let Some(resident) = &mut self.resident {
+            let cache = &mut resident.image_cache;
+            let atlas = ResidentAtlas::resize(
+                &mut resident.atlas,
+                &mut recording,
+                images.width,
+                levels.height,
+            );
+            let previous = std::mem::take(&mut atlas.contents);
+            for (image, xy) in placed {
+                let id = image.data.id();
+                // Overridden images are copied from their texture, so can't be cached.
+                if filter_images.contains_key(&id) || self.overridden_images.contains(&id) {
+                    write_atlas_image(&mut recording, filter_images, atlas.image, image, xy);
+                    continue;
+                }
+                // The data of an image is immutable, so an image which the last render wrote
+                // to the same place is still there.
+                let key = ImageKey::new(image);
+                if previous.get(&key) == Some(&xy) {
+                    cache.touch(image);
+                } else {
+                    cache.copy_to(&mut recording, image, atlas.image, xy);
+                }
+                atlas.contents.insert(key, xy);
+            }
+            atlas.image
         } else {
-            ImageProxy::new(images.width, images.height, ImageFormat::Rgba8)
+            let atlas = ImageProxy::new(images.width, levels.height, ImageFormat::Rgba8);
+            for (image, xy) in placed {
+                write_atlas_image(&mut recording, filter_images, atlas, image, xy);
+            }
+            atlas
         };
-        for image in images.images {
-            recording.write_image(image_atlas, image.1, image.2, image.0.clone());
-        }
-        let cpu_config =
+        let mip_table_buf = ResourceProxy::Buffer(recording.upload(
+            "vello.mip_table",
+            bytemuck::cast_slice::<_, u8>(&levels.table).to_vec(),
+        ));
+        let mut cpu_config =
             RenderConfig::new(&layout, params.width, params.height, &params.base_color);
+        self.bump_sizes.apply(&mut cpu_config);
         // HACK: The coarse workgroup counts is the number of active bins.
         if (cpu_config.workgroup_counts.coarse.0
             * cpu_config.workgroup_counts.coarse.1
@@ -458,7 +1530,8 @@ impl Render {
         recording.free_resource(draw_monoid_buf);
         recording.free_resource(bin_header_buf);
         recording.free_resource(path_buf);
-        let out_image = ImageProxy::new(params.width, params.height, ImageFormat::Rgba8);
+        let out_format = self.output_format.unwrap_or(shaders.output_format);
+        let out_image = ImageProxy::new(params.width, params.height, out_format);
         let blend_spill_buf = BufferProxy::new(
             buffer_sizes.blend_spill.size_in_bytes().into(),
             "vello.blend_spill",
@@ -475,6 +1548,7 @@ impl Render {
             info_bin_data_buf,
             blend_spill_buf: ResourceProxy::Buffer(blend_spill_buf),
             image_atlas: ResourceProxy::Image(image_atlas),
+            mip_table_buf,
             out_image,
         });
         if robust {
@@ -510,37 +1584,55 @@ impl Render {
 
     /// Run fine rasterization assuming the coarse phase succeeded.
     pub fn record_fine(&mut self, shaders: &FullShaders, recording: &mut Recording) {
-        let fine_wg_count = self.fine_wg_count.take().unwrap();
+        let (width, height, _) = self.fine_wg_count.unwrap();
+        self.record_fine_regions(shaders, recording, &[[0, 0, width, height]]);
+    }
+
+    /// Run fine rasterization of the regions of the target given as `[x, y, width, height]`
+    /// in tiles, assuming the coarse phase succeeded.
+    ///
+    /// The other pixels of the output image are left undefined. The fine shaders of
+    /// `vello_shaders`, used for MSAA into [`ImageFormat::Rgba8`] targets, always render the
+    /// whole target.
+    pub fn record_fine_regions(
+        &mut self,
+        shaders: &FullShaders,
+        recording: &mut Recording,
+        regions: &[[u32; 4]],
+    ) {
+        let (width, height, _) = self.fine_wg_count.take().unwrap();
         let fine = self.fine_resources.take().unwrap();
-        match fine.aa_config {
+        let (fine_shader, mut bindings, reads_region) = match fine.aa_config {
             AaConfig::Area => {
-                recording.dispatch(
+                let fine_shader = if fine.out_image.format == ImageFormat::R32Uint {
+                    shaders
+                        .fine_pick
+                        .expect("shaders not configured to render pick images")
+                } else {
                     shaders
                         .fine_area
-                        .expect("shaders not configured to support AA mode: area"),
-                    fine_wg_count,
-                    [
-                        fine.config_buf,
-                        fine.segments_buf,
-                        fine.ptcl_buf,
-                        fine.info_bin_data_buf,
-                        fine.blend_spill_buf,
-                        ResourceProxy::Image(fine.out_image),
-                        fine.gradient_image,
-                        fine.image_atlas,
-                    ],
-                );
+                        .expect("shaders not configured to support AA mode: area")
+                };
+                let bindings = vec![
+                    fine.config_buf,
+                    fine.segments_buf,
+                    fine.ptcl_buf,
+                    fine.info_bin_data_buf,
+                    fine.blend_spill_buf,
+                    ResourceProxy::Image(fine.out_image),
+                    fine.gradient_image,
+                    fine.image_atlas,
+                    fine.mip_table_buf,
+                ];
+                (fine_shader, bindings, true)
             }
             _ => {
-                if self.mask_buf.is_none() {
-                    let mask_lut = match fine.aa_config {
-                        AaConfig::Msaa16 => make_mask_lut_16(),
-                        AaConfig::Msaa8 => make_mask_lut(),
-                        _ => unreachable!(),
-                    };
-                    let buf = recording.upload("vello.mask_lut", mask_lut);
-                    self.mask_buf = Some(buf.into());
-                }
+                let mask_buf = match &mut self.resident {
+                    Some(resident) => resident.mask_lut(fine.aa_config, recording),
+                    None => *self
+                        .mask_buf
+                        .get_or_insert_with(|| upload_mask_lut(fine.aa_config, recording)),
+                };
                 let fine_shader = match fine.aa_config {
                     AaConfig::Msaa16 => shaders
                         .fine_msaa16
@@ -550,37 +1642,52 @@ impl Render {
                         .expect("shaders not configured to support AA mode: msaa8"),
                     _ => unreachable!(),
                 };
-                recording.dispatch(
-                    fine_shader,
-                    fine_wg_count,
-                    [
-                        fine.config_buf,
-                        fine.segments_buf,
-                        fine.ptcl_buf,
-                        fine.info_bin_data_buf,
-                        fine.blend_spill_buf,
-                        ResourceProxy::Image(fine.out_image),
-                        fine.gradient_image,
-                        fine.image_atlas,
-                        self.mask_buf.unwrap(),
-                    ],
-                );
+                let bindings = vec![
+                    fine.config_buf,
+                    fine.segments_buf,
+                    fine.ptcl_buf,
+                    fine.info_bin_data_buf,
+                    fine.blend_spill_buf,
+                    ResourceProxy::Image(fine.out_image),
+                    fine.gradient_image,
+                    fine.image_atlas,
+                    mask_buf,
+                    fine.mip_table_buf,
+                ];
+                (fine_shader, bindings, shaders.msaa_fine_regions)
             }
+        };
+        let whole = [[0, 0, width, height]];
+        let regions = if reads_region { regions } else { &whole };
+        for &[x, y, width, height] in regions {
+            let region = FineRegion {
+                offset: [x, y],
+                size: [width, height],
+            };
+            let region_buf =
+                recording.upload_uniform("vello.fine_region", bytemuck::bytes_of(&region).to_vec());
+            bindings.push(ResourceProxy::Buffer(region_buf));
+            recording.dispatch(fine_shader, (width, height, 1), bindings.iter().copied());
+            bindings.pop();
+            recording.free_buffer(region_buf);
         }
-        recording.free_resource(fine.config_buf);
-        recording.free_resource(fine.tile_buf);
-        recording.free_resource(fine.segments_buf);
-        recording.free_resource(fine.ptcl_buf);
-        recording.free_resource(fine.gradient_image);
-        recording.free_resource(fine.image_atlas);
-        recording.free_resource(fine.info_bin_data_buf);
-        recording.free_resource(fine.blend_spill_buf);
-        // TODO: make mask buf persistent
+        fine.free(recording, self.resident.as_ref());
         if let Some(mask_buf) = self.mask_buf.take() {
             recording.free_resource(mask_buf);
         }
     }
 
+    /// Free the resources of the coarse phase without running fine rasterization.
+    ///
+    /// This is used when the coarse phase overflowed its buffers, so that it can be run
+    /// again with larger ones.
+    pub fn discard_fine(&mut self, recording: &mut Recording) {
+        self.fine_wg_count = None;
+        if let Some(fine) = self.fine_resources.take() {
+            fine.free(recording, self.resident.as_ref());
+        }
+    }
+
     /// Get the output image.
     ///
     /// This is going away, as the caller will add the output image to the bind
@@ -604,3 +1711,172 @@ impl Render {
         self.captured_buffers.take()
     }
 }
+
+#[cfg(all(test, feature = "wgpu"))]
+mod tests {
+    use vello_encoding::BumpAllocators;
+
+    use super::*;
+
+    fn sizes() -> BumpSizes {
+        BumpSizes {
+            lines: 100,
+            binning: 100,
+            tiles: 100,
+            seg_counts: 100,
+            segments: 100,
+            blend: 100,
+            ptcl: 100,
+        }
+    }
+
+    #[test]
+    fn renders_within_capacity_are_not_retried() {
+        let mut bump_sizes = sizes();
+        let bump = BumpAllocators {
+            lines: 100,
+            segments: 50,
+            ..Default::default()
+        };
+        assert!(!bump_sizes.grow(&bump));
+        assert_eq!(bump_sizes, sizes());
+    }
+
+    #[test]
+    fn overflowing_buffers_are_grown() {
+        let mut bump_sizes = sizes();
+        let bump = BumpAllocators {
+            lines: 150,
+            segments: 1000,
+            ..Default::default()
+        };
+        assert!(bump_sizes.grow(&bump));
+        assert_eq!(bump_sizes.lines, 200);
+        assert_eq!(bump_sizes.segments, 1000);
+        assert_eq!(bump_sizes.ptcl, 100);
+    }
+
+    #[test]
+    fn failed_stages_are_grown() {
+        // A failed stage may not have counted everything it needs.
+        let mut bump_sizes = sizes();
+        let bump = BumpAllocators {
+            failed: STAGE_COARSE | STAGE_TILE_ALLOC,
+            ptcl: 80,
+            ..Default::default()
+        };
+        assert!(bump_sizes.grow(&bump));
+        assert_eq!(bump_sizes.ptcl, 200);
+        assert_eq!(bump_sizes.tiles, 200);
+        assert_eq!(bump_sizes.lines, 100);
+        // Empty capacities still grow.
+        let mut bump_sizes = BumpSizes::default();
+        assert!(bump_sizes.grow(&bump));
+        assert_eq!(bump_sizes.ptcl, 80);
+        assert_eq!(bump_sizes.tiles, 1);
+    }
+
+    #[test]
+    fn large_targets_are_split_into_tiles() {
+        let regions: Vec<_> = tiles([0, 0, 5000, 300]).collect();
+        assert_eq!(regions, [[0, 0, 4096, 300], [4096, 0, 904, 300]]);
+        let params = RenderParams {
+            base_color: peniko::Color::BLACK,
+            width: 5000,
+            height: 300,
+            antialiasing_method: AaConfig::Area,
+        };
+        assert!(needs_tiles(&params));
+        let passes = damage_passes(&params, &[Rect::new(4090.0, 10.0, 4100.0, 20.5)]);
+        assert_eq!(regions(&passes), [[4080, 0, 32, 32]]);
+    }
+
+    /// The regions rendered by `passes`.
+    fn regions(passes: &[RegionPass]) -> Vec<[u32; 4]> {
+        passes
+            .iter()
+            .flat_map(|pass| pass.regions.iter().copied())
+            .collect()
+    }
+
+    fn damage_params() -> RenderParams {
+        RenderParams {
+            base_color: peniko::Color::BLACK,
+            width: 2000,
+            height: 2000,
+            antialiasing_method: AaConfig::Area,
+        }
+    }
+
+    #[test]
+    fn nearby_damage_is_merged() {
+        let damage = [
+            Rect::new(0.0, 0.0, 10.0, 10.0),
+            Rect::new(100.0, 0.0, 110.0, 10.0),
+        ];
+        let passes = damage_passes(&damage_params(), &damage);
+        assert_eq!(regions(&passes), [[0, 0, 112, 16]]);
+    }
+
+    #[test]
+    fn mostly_overlapping_damage_is_merged() {
+        let damage = [
+            Rect::new(0.0, 0.0, 100.0, 100.0),
+            Rect::new(20.0, 20.0, 120.0, 120.0),
+        ];
+        let passes = damage_passes(&damage_params(), &damage);
+        assert_eq!(regions(&passes), [[0, 0, 128, 128]]);
+    }
+
+    #[test]
+    fn crossing_damage_is_rendered_separately() {
+        // The union of an L shape covers much more than its arms, so the corner is
+        // rasterized twice instead.
+        let damage = [
+            Rect::new(0.0, 0.0, 1000.0, 100.0),
+            Rect::new(0.0, 0.0, 100.0, 1000.0),
+        ];
+        let passes = damage_passes(&damage_params(), &damage);
+        assert_eq!(regions(&passes), [[0, 0, 1008, 112], [0, 0, 112, 1008]]);
+    }
+
+    #[test]
+    fn distant_damage_is_rendered_separately() {
+        let damage = [
+            Rect::new(0.0, 0.0, 10.0, 10.0),
+            Rect::new(1500.0, 1500.0, 1510.0, 1510.0),
+            Rect::new(1600.0, 1600.0, 1600.0, 1700.0),
+            Rect::new(-100.0, 2500.0, 3000.0, 3000.0),
+        ];
+        let passes = damage_passes(&damage_params(), &damage);
+        assert_eq!(regions(&passes), [[0, 0, 16, 16], [1488, 1488, 32, 32]]);
+    }
+
+    #[test]
+    fn damage_is_rendered_in_one_pass() {
+        let damage = [
+            Rect::new(0.0, 0.0, 10.0, 10.0),
+            Rect::new(1500.0, 1500.0, 1510.0, 1510.0),
+        ];
+        let passes = damage_passes(&damage_params(), &damage);
+        assert_eq!(passes.len(), 1);
+        assert_eq!(passes[0].bounds, [0, 0, 1520, 1520]);
+        assert_eq!(passes[0].fine_regions(), [[0, 0, 1, 1], [93, 93, 2, 2]]);
+    }
+
+    #[test]
+    fn damage_of_large_targets_is_split_into_passes() {
+        let params = RenderParams {
+            width: 5000,
+            ..damage_params()
+        };
+        let damage = [
+            Rect::new(0.0, 0.0, 10.0, 10.0),
+            Rect::new(4990.0, 0.0, 5000.0, 10.0),
+        ];
+        let passes = damage_passes(&params, &damage);
+        let bounds: Vec<_> = passes.iter().map(|pass| pass.bounds).collect();
+        assert_eq!(bounds, [[0, 0, 16, 16], [4976, 0, 24, 16]]);
+        assert_eq!(passes[1].fine_regions(), [[0, 0, 2, 1]]);
+    }
+}
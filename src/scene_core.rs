@@ -3,13 +3,17 @@
 //! This module contains the Scene struct and its core methods.
 
 use peniko::{
-    BlendMode, Fill, Mix,
+    BlendMode, BrushRef, Fill, Mix,
     kurbo::{Affine, Shape},
 };
 #[cfg(feature = "bump_estimate")]
 use vello_encoding::BumpAllocatorMemory;
 use vello_encoding::{Encoding, Transform};
 
+use crate::filter::{FilterLayer, OpenFilterLayer};
+use crate::gradient::{self, GradientCache};
+use crate::hit_test::DrawId;
+
 /// The main datatype for rendering graphics.
 ///
 /// A `Scene` stores a sequence of drawing commands, their context, and the
@@ -26,6 +30,15 @@ pub struct Scene {
     pub(crate) encoding: Encoding,
     #[cfg(feature = "bump_estimate")]
     pub(crate) estimator: vello_encoding::BumpEstimator,
+    /// The filter layers drawn by the scene, in the order they must be rendered.
+    pub(crate) filters: Vec<FilterLayer>,
+    pub(crate) open_filters: Vec<OpenFilterLayer>,
+    /// The ids of the tagged draw objects in the encoding, with the index of their draw tag,
+    /// in ascending order.
+    pub(crate) draw_ids: Vec<(usize, DrawId)>,
+    /// The stops of the gradients drawn into the scene which interpolate in other color
+    /// spaces than sRGB, kept across resets.
+    pub(crate) gradients: GradientCache,
 }
 static_assertions::assert_impl_all!(Scene: Send, Sync);
 
@@ -42,6 +55,10 @@ impl Scene {
         self.encoding.reset();
         #[cfg(feature = "bump_estimate")]
         self.estimator.reset();
+        self.filters.clear();
+        self.open_filters.clear();
+        self.draw_ids.clear();
+        self.gradients.prune();
     }
 
     /// Tally up the bump allocator estimate for the current state of the encoding,
@@ -54,6 +71,8 @@ impl Scene {
     }
 
     /// Returns the underlying raw encoding.
+    ///
+    /// While a filter or mask layer is open, this is the encoding of the layer's content.
     #[inline]
     pub fn encoding(&self) -> &Encoding {
         &self.encoding
@@ -67,6 +86,12 @@ impl Scene {
         &mut self.encoding
     }
 
+    /// Encode `brush`, approximating gradients which interpolate in other color spaces than
+    /// sRGB.
+    pub(crate) fn encode_brush(&mut self, brush: BrushRef<'_>, alpha: f32) {
+        gradient::encode_brush(&mut self.encoding, brush, alpha, Some(&mut self.gradients));
+    }
+
     /// Pushes a new layer clipped by the specified shape and composed with
     /// previous layers using the specified blend mode.
     ///
@@ -110,20 +135,38 @@ impl Scene {
     }
 
     /// Pops the current layer.
+    ///
+    /// This also pops layers pushed with [`Scene::push_filter_layer`] and
+    /// [`Scene::push_mask`].
     #[inline]
     pub fn pop_layer(&mut self) {
-        self.encoding.encode_end_clip();
+        if !self.pop_filter_layer() {
+            self.encoding.encode_end_clip();
+        }
     }
 
     /// Appends a child scene.
     ///
     /// The given transform is applied to every transform in the child.
     /// This is an O(N) operation.
+    ///
+    /// Filter layers which haven't been popped in the child are closed first, as if they
+    /// had been popped at its end, so that they don't capture content drawn into this scene
+    /// afterwards.
     pub fn append(&mut self, other: &Self, transform: Option<Affine>) {
+        let other = other.with_filter_layers_closed();
         let t = transform.as_ref().map(Transform::from_kurbo);
+        let draw_offset = self.encoding.draw_tags.len();
         self.encoding.append(&other.encoding, &t);
+        self.draw_ids.extend(
+            other
+                .draw_ids
+                .iter()
+                .map(|&(draw_ix, id)| (draw_ix + draw_offset, id)),
+        );
         #[cfg(feature = "bump_estimate")]
         self.estimator.append(&other.estimator, t.as_ref());
+        self.filters.extend(other.filters.iter().cloned());
     }
 }
 
@@ -135,6 +178,10 @@ impl From<Encoding> for Scene {
             encoding,
             #[cfg(feature = "bump_estimate")]
             estimator: vello_encoding::BumpEstimator::default(),
+            filters: Vec::new(),
+            open_filters: Vec::new(),
+            draw_ids: Vec::new(),
+            gradients: GradientCache::default(),
         }
     }
 }
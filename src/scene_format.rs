@@ -0,0 +1,1035 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Binary serialization of scenes, for recording and replaying frames.
+//!
+//! A serialized scene starts with the magic bytes `VELLOSCN` and a format version, followed
+//! by the images and fonts it uses, its filter and mask layers, and the encoding streams and
+//! resources of the scene and of each layer. All values are little-endian. Images and fonts
+//! are stored once per distinct content, keyed by a hash of their data. The images which
+//! filter layers are rendered into are stored as references to their layers.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use peniko::color::{ColorSpaceTag, DynamicColor, Flags, Missing};
+use peniko::kurbo::{Point, Vec2};
+use peniko::{Blob, Color, ColorStop, Extend, Font, Image, ImageFormat, ImageQuality};
+use thiserror::Error;
+use vello_encoding::{
+    DrawTag, Encoding, Glyph, GlyphRun, Patch, PathTag, StreamOffsets, Style, Transform,
+};
+
+use crate::decode::{PATH_TAG_PATH, PATH_TAG_SEGMENT_MASK};
+use crate::filter::{FilterLayer, MAX_FILTER_SIZE, Mask, placeholder_image};
+use crate::{DrawId, Filter, MaskType, Scene};
+
+const MAGIC: &[u8; 8] = b"VELLOSCN";
+
+/// The version of the format written by [`Scene::serialize`].
+pub const SCENE_FORMAT_VERSION: u32 = 3;
+
+const PATCH_RAMP: u8 = 0;
+const PATCH_GLYPH_RUN: u8 = 1;
+const PATCH_IMAGE: u8 = 2;
+const PATCH_FILTER_IMAGE: u8 = 3;
+
+const FILTER_GAUSSIAN_BLUR: u8 = 0;
+const FILTER_DROP_SHADOW: u8 = 1;
+const FILTER_COLOR_MATRIX: u8 = 2;
+
+/// Errors from [`Scene::deserialize`].
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DeserializeError {
+    /// The data doesn't start with the scene format's magic bytes.
+    #[error("Data is not a serialized scene")]
+    InvalidMagic,
+    /// The scene was written by an incompatible version of Vello.
+    #[error("Unsupported scene format version {0}")]
+    UnsupportedVersion(u32),
+    /// The data ended before the scene was complete.
+    #[error("Serialized scene is truncated")]
+    UnexpectedEof,
+    /// The data is structurally invalid.
+    #[error("Serialized scene is malformed: {0}")]
+    Malformed(&'static str),
+}
+
+impl Scene {
+    /// Serialize the scene into a versioned binary format.
+    ///
+    /// The result can be loaded with [`Scene::deserialize`], including by later versions of
+    /// Vello which support [`SCENE_FORMAT_VERSION`]. Images and fonts used by the scene are
+    /// embedded in the output, as are its filter and mask layers and the ids of its tagged
+    /// draw objects. Filter layers which haven't been popped are closed first.
+    pub fn serialize(&self) -> Vec<u8> {
+        let scene = self.with_filter_layers_closed();
+        // The placeholder images of filter layers are stored as references to their layers,
+        // as their contents are rendered by the pipeline.
+        let placeholders = scene
+            .filters
+            .iter()
+            .enumerate()
+            .map(|(ix, layer)| (layer.image.data.id(), ix as u32))
+            .collect::<HashMap<_, _>>();
+        // Blobs are deduplicated by content, so the same data used through different
+        // `Blob`s is only stored once. They are written before the encodings which use them.
+        let mut blobs = BlobTable::default();
+        let mut body = Writer::default();
+        for layer in &scene.filters {
+            body.filter(&layer.filter);
+            body.f64(layer.origin.x);
+            body.f64(layer.origin.y);
+            body.u32(layer.image.width);
+            body.u32(layer.image.height);
+            body.u8(match layer.mask.as_ref().map(|mask| mask.kind) {
+                None => 0,
+                Some(MaskType::Luminance) => 1,
+                Some(MaskType::Alpha) => 2,
+            });
+        }
+        body.encoding(&scene.encoding, &mut blobs, &placeholders);
+        body.draw_ids(&scene.draw_ids);
+        for layer in &scene.filters {
+            body.encoding(&layer.content, &mut blobs, &placeholders);
+            body.draw_ids(&layer.draw_ids);
+            if let Some(mask) = &layer.mask {
+                body.encoding(&mask.content, &mut blobs, &placeholders);
+            }
+        }
+
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u32(SCENE_FORMAT_VERSION);
+        w.len(blobs.blobs.len());
+        for blob in &blobs.blobs {
+            let data = blob.data();
+            w.u64(content_hash(data));
+            w.len(data.len());
+            w.bytes(data);
+        }
+        w.len(scene.filters.len());
+        w.bytes(&body.out);
+        w.out
+    }
+
+    /// Load a scene written by [`Scene::serialize`].
+    ///
+    /// The data is validated, so that a malformed scene returns an error rather than
+    /// causing a panic during rendering. This checks that the counts of paths, segments
+    /// and clips match the encoded streams, that the draw data matches the draw tags, and
+    /// that every offset, range and index into the streams and resources is in bounds.
+    pub fn deserialize(data: &[u8]) -> Result<Self, DeserializeError> {
+        let mut r = Reader { data };
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(DeserializeError::InvalidMagic);
+        }
+        let version = r.u32()?;
+        if version != SCENE_FORMAT_VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+
+        let n_blobs = r.len()?;
+        let mut blobs = Vec::with_capacity(n_blobs.min(r.data.len()));
+        for _ in 0..n_blobs {
+            let hash = r.u64()?;
+            let len = r.len()?;
+            let data = r.bytes(len)?;
+            if content_hash(data) != hash {
+                return Err(DeserializeError::Malformed("content hash mismatch"));
+            }
+            blobs.push(Blob::from(data.to_vec()));
+        }
+
+        let n_filters = r.len()?;
+        let mut layers = Vec::with_capacity(n_filters.min(r.data.len()));
+        for _ in 0..n_filters {
+            let filter = r.filter()?;
+            let origin = Point::new(r.f64()?, r.f64()?);
+            if !origin.is_finite() {
+                return Err(DeserializeError::Malformed("invalid filter layer origin"));
+            }
+            let (width, height) = (r.u32()?, r.u32()?);
+            if !(1..=MAX_FILTER_SIZE).contains(&width) || !(1..=MAX_FILTER_SIZE).contains(&height) {
+                return Err(DeserializeError::Malformed(
+                    "filter layer size out of range",
+                ));
+            }
+            let mask = match r.u8()? {
+                0 => None,
+                1 => Some(MaskType::Luminance),
+                2 => Some(MaskType::Alpha),
+                _ => return Err(DeserializeError::Malformed("unknown mask type")),
+            };
+            // The size is validated above, and the placeholder only holds a single pixel, so
+            // untrusted sizes can't cause large allocations.
+            layers.push((filter, origin, placeholder_image(width, height), mask));
+        }
+        let placeholders = layers
+            .iter()
+            .map(|(_, _, image, ..)| image.clone())
+            .collect::<Vec<_>>();
+        let resources = SharedResources {
+            blobs: &blobs,
+            placeholders: &placeholders,
+        };
+
+        let mut scene = Self::new();
+        scene.encoding = r.encoding(&resources)?;
+        scene.draw_ids = r.draw_ids(&scene.encoding)?;
+        for (filter, origin, image, mask) in layers {
+            let content = r.encoding(&resources)?;
+            let draw_ids = r.draw_ids(&content)?;
+            let mask = match mask {
+                Some(kind) => Some(Mask {
+                    kind,
+                    content: r.encoding(&resources)?,
+                }),
+                None => None,
+            };
+            scene.filters.push(FilterLayer {
+                filter,
+                mask,
+                content,
+                draw_ids,
+                origin,
+                image,
+            });
+        }
+        if !r.data.is_empty() {
+            return Err(DeserializeError::Malformed("trailing data"));
+        }
+        Ok(scene)
+    }
+}
+
+/// The resources which the encodings of a scene refer to by index.
+struct SharedResources<'a> {
+    blobs: &'a [Blob<u8>],
+    /// The placeholder images of the scene's filter layers.
+    placeholders: &'a [Image],
+}
+
+impl SharedResources<'_> {
+    fn blob(&self, ix: u32) -> Result<Blob<u8>, DeserializeError> {
+        self.blobs
+            .get(ix as usize)
+            .cloned()
+            .ok_or(DeserializeError::Malformed("blob index out of range"))
+    }
+}
+
+/// Check that the counts and offsets stored alongside the streams of `encoding` agree with
+/// the streams, as the resolver and the pipeline trust them.
+fn validate(encoding: &Encoding) -> Result<(), DeserializeError> {
+    let path_tags = &encoding.path_tags;
+    let n_paths = path_tags
+        .iter()
+        .filter(|tag| tag.0 & PATH_TAG_PATH != 0)
+        .count();
+    let n_segments = path_tags
+        .iter()
+        .filter(|tag| tag.0 & PATH_TAG_SEGMENT_MASK != 0)
+        .count();
+    if n_paths != encoding.n_paths as usize || n_segments != encoding.n_path_segments as usize {
+        return Err(DeserializeError::Malformed(
+            "path counts don't match path tags",
+        ));
+    }
+    let draw_tags = &encoding.draw_tags;
+    let begin_clips = draw_tags
+        .iter()
+        .filter(|tag| **tag == DrawTag::BEGIN_CLIP)
+        .count();
+    let end_clips = draw_tags
+        .iter()
+        .filter(|tag| **tag == DrawTag::END_CLIP)
+        .count();
+    if begin_clips + end_clips != encoding.n_clips as usize
+        || begin_clips.checked_sub(end_clips) != Some(encoding.n_open_clips as usize)
+    {
+        return Err(DeserializeError::Malformed(
+            "clip counts don't match draw tags",
+        ));
+    }
+    // The number of words of draw data is stored in each tag.
+    let draw_data_len = draw_tags
+        .iter()
+        .map(|tag| ((tag.0 >> 2) & 0x7) as usize)
+        .sum::<usize>();
+    if draw_data_len != encoding.draw_data.len() {
+        return Err(DeserializeError::Malformed(
+            "draw data doesn't match draw tags",
+        ));
+    }
+    let lens = [
+        path_tags.len(),
+        encoding.path_data.len(),
+        draw_tags.len(),
+        encoding.draw_data.len(),
+        encoding.transforms.len(),
+        encoding.styles.len(),
+    ];
+    let mut previous = [0; 6];
+    for run in &encoding.resources.glyph_runs {
+        let offsets = stream_offsets(&run.stream_offsets);
+        // The resolver splits the streams at the offsets of each run, in order.
+        for ((offset, len), previous) in offsets.iter().zip(lens).zip(&mut previous) {
+            if *offset > len || *offset < *previous {
+                return Err(DeserializeError::Malformed(
+                    "glyph run stream offset out of range",
+                ));
+            }
+            *previous = *offset;
+        }
+    }
+    Ok(())
+}
+
+fn stream_offsets(offsets: &StreamOffsets) -> [usize; 6] {
+    [
+        offsets.path_tags,
+        offsets.path_data,
+        offsets.draw_tags,
+        offsets.draw_data,
+        offsets.transforms,
+        offsets.styles,
+    ]
+}
+
+/// Blobs referenced by a scene, deduplicated by content.
+#[derive(Default)]
+struct BlobTable {
+    blobs: Vec<Blob<u8>>,
+    by_id: HashMap<u64, u32>,
+    by_hash: HashMap<u64, u32>,
+}
+
+impl BlobTable {
+    fn insert(&mut self, blob: &Blob<u8>) -> u32 {
+        if let Some(ix) = self.by_id.get(&blob.id()) {
+            return *ix;
+        }
+        let ix = *self
+            .by_hash
+            .entry(content_hash(blob.data()))
+            .or_insert_with(|| {
+                self.blobs.push(blob.clone());
+                (self.blobs.len() - 1) as u32
+            });
+        self.by_id.insert(blob.id(), ix);
+        ix
+    }
+}
+
+/// 64-bit FNV-1a, which is stable across platforms and Rust versions.
+fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn extend(value: u8) -> Result<Extend, DeserializeError> {
+    match value {
+        0 => Ok(Extend::Pad),
+        1 => Ok(Extend::Repeat),
+        2 => Ok(Extend::Reflect),
+        _ => Err(DeserializeError::Malformed("unknown extend mode")),
+    }
+}
+
+fn color_space_tag(value: u8) -> Result<ColorSpaceTag, DeserializeError> {
+    use ColorSpaceTag::*;
+
+    [
+        Srgb,
+        LinearSrgb,
+        Lab,
+        Lch,
+        Hsl,
+        Hwb,
+        Oklab,
+        Oklch,
+        DisplayP3,
+        A98Rgb,
+        ProphotoRgb,
+        Rec2020,
+        Aces2065_1,
+        AcesCg,
+        XyzD50,
+        XyzD65,
+    ]
+    .into_iter()
+    .find(|cs| *cs as u8 == value)
+    .ok_or(DeserializeError::Malformed("unknown color space"))
+}
+
+#[derive(Default)]
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.out.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len.try_into().expect("scene is too large to serialize"));
+    }
+
+    fn range(&mut self, range: &Range<usize>) {
+        self.len(range.start);
+        self.len(range.end);
+    }
+
+    /// Write a length-prefixed sequence of words.
+    fn words(&mut self, words: &[u32]) {
+        self.len(words.len());
+        for word in words {
+            self.u32(*word);
+        }
+    }
+
+    fn transform(&mut self, transform: &Transform) {
+        for value in transform.matrix.iter().chain(&transform.translation) {
+            self.f32(*value);
+        }
+    }
+
+    /// Write the streams and resources of `encoding`, adding the blobs it uses to `blobs`.
+    ///
+    /// Images in `placeholders` are written as references to the filter layer with the
+    /// given index.
+    fn encoding(
+        &mut self,
+        encoding: &Encoding,
+        blobs: &mut BlobTable,
+        placeholders: &HashMap<u64, u32>,
+    ) {
+        let resources = &encoding.resources;
+        self.u32(encoding.n_paths);
+        self.u32(encoding.n_path_segments);
+        self.u32(encoding.n_clips);
+        self.u32(encoding.n_open_clips);
+        self.u32(encoding.flags);
+
+        self.len(encoding.path_tags.len());
+        self.bytes(bytemuck::cast_slice(&encoding.path_tags));
+        self.words(&encoding.path_data);
+        self.words(bytemuck::cast_slice(&encoding.draw_tags));
+        self.words(&encoding.draw_data);
+        self.words(bytemuck::cast_slice(&encoding.transforms));
+        self.words(bytemuck::cast_slice(&encoding.styles));
+
+        self.len(resources.color_stops.len());
+        for stop in &resources.color_stops {
+            self.f32(stop.offset);
+            self.u8(stop.color.cs as u8);
+            let missing = stop.color.flags.missing();
+            self.u8((0..4)
+                .filter(|ix| missing.contains(*ix))
+                .fold(0, |m, ix| m | (1 << ix)));
+            for component in stop.color.components {
+                self.f32(component);
+            }
+        }
+
+        self.len(resources.glyphs.len());
+        for glyph in &resources.glyphs {
+            self.u32(glyph.id);
+            self.f32(glyph.x);
+            self.f32(glyph.y);
+        }
+
+        self.len(resources.normalized_coords.len());
+        for coord in &resources.normalized_coords {
+            self.bytes(&coord.to_le_bytes());
+        }
+
+        self.len(resources.glyph_runs.len());
+        for run in &resources.glyph_runs {
+            self.u32(blobs.insert(&run.font.data));
+            self.u32(run.font.index);
+            self.transform(&run.transform);
+            match &run.glyph_transform {
+                Some(transform) => {
+                    self.u8(1);
+                    self.transform(transform);
+                }
+                None => self.u8(0),
+            }
+            self.f32(run.font_size);
+            self.u8(run.hint.into());
+            self.range(&run.glyphs);
+            self.range(&run.normalized_coords);
+            self.u32(run.style.flags_and_miter_limit);
+            self.f32(run.style.line_width);
+            for offset in stream_offsets(&run.stream_offsets) {
+                self.len(offset);
+            }
+        }
+
+        self.len(resources.patches.len());
+        for patch in &resources.patches {
+            match patch {
+                Patch::Ramp {
+                    draw_data_offset,
+                    stops,
+                    extend,
+                } => {
+                    self.u8(PATCH_RAMP);
+                    self.len(*draw_data_offset);
+                    self.range(stops);
+                    self.u8(*extend as u8);
+                }
+                Patch::GlyphRun { index } => {
+                    self.u8(PATCH_GLYPH_RUN);
+                    self.len(*index);
+                }
+                Patch::Image {
+                    image,
+                    draw_data_offset,
+                } => {
+                    if let Some(filter_ix) = placeholders.get(&image.data.id()) {
+                        self.u8(PATCH_FILTER_IMAGE);
+                        self.len(*draw_data_offset);
+                        self.u32(*filter_ix);
+                        continue;
+                    }
+                    self.u8(PATCH_IMAGE);
+                    self.len(*draw_data_offset);
+                    self.u32(blobs.insert(&image.data));
+                    self.u8(image.format as u8);
+                    self.u32(image.width);
+                    self.u32(image.height);
+                    self.u8(image.x_extend as u8);
+                    self.u8(image.y_extend as u8);
+                    self.u8(image.quality as u8);
+                    self.f32(image.alpha);
+                }
+            }
+        }
+    }
+
+    /// Write the ids of the tagged draw objects of an encoding.
+    fn draw_ids(&mut self, draw_ids: &[(usize, DrawId)]) {
+        self.len(draw_ids.len());
+        for (draw_ix, id) in draw_ids {
+            self.len(*draw_ix);
+            self.u64(id.0);
+        }
+    }
+
+    fn filter(&mut self, filter: &Filter) {
+        match filter {
+            Filter::GaussianBlur { std_dev } => {
+                self.u8(FILTER_GAUSSIAN_BLUR);
+                self.f64(*std_dev);
+            }
+            Filter::DropShadow {
+                offset,
+                std_dev,
+                color,
+            } => {
+                self.u8(FILTER_DROP_SHADOW);
+                self.f64(offset.x);
+                self.f64(offset.y);
+                self.f64(*std_dev);
+                for component in color.components {
+                    self.f32(component);
+                }
+            }
+            Filter::ColorMatrix(matrix) => {
+                self.u8(FILTER_COLOR_MATRIX);
+                for value in matrix {
+                    self.f32(*value);
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DeserializeError> {
+        if len > self.data.len() {
+            return Err(DeserializeError::UnexpectedEof);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DeserializeError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, DeserializeError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, DeserializeError> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn f64(&mut self) -> Result<f64, DeserializeError> {
+        self.u64().map(f64::from_bits)
+    }
+
+    fn len(&mut self) -> Result<usize, DeserializeError> {
+        self.u32().map(|len| len as usize)
+    }
+
+    /// Read an offset, which must be less than `len`.
+    fn offset(&mut self, len: usize) -> Result<usize, DeserializeError> {
+        let offset = self.len()?;
+        if offset >= len {
+            return Err(DeserializeError::Malformed("offset out of range"));
+        }
+        Ok(offset)
+    }
+
+    /// Read a range, which must lie within `0..len`.
+    fn range(&mut self, len: usize) -> Result<Range<usize>, DeserializeError> {
+        let range = self.len()?..self.len()?;
+        if range.start > range.end || range.end > len {
+            return Err(DeserializeError::Malformed("range out of bounds"));
+        }
+        Ok(range)
+    }
+
+    fn words(&mut self) -> Result<Vec<u32>, DeserializeError> {
+        let len = self.len()?;
+        let bytes = self.bytes(len.checked_mul(4).ok_or(DeserializeError::UnexpectedEof)?)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Read a sequence of words, reinterpreted as values of `T`.
+    fn pods<T: bytemuck::Pod>(&mut self) -> Result<Vec<T>, DeserializeError> {
+        let words = self.words()?;
+        bytemuck::try_cast_slice(&words)
+            .map(<[T]>::to_vec)
+            .map_err(|_| DeserializeError::Malformed("stream has an invalid length"))
+    }
+
+    fn transform(&mut self) -> Result<Transform, DeserializeError> {
+        Ok(Transform {
+            matrix: [self.f32()?, self.f32()?, self.f32()?, self.f32()?],
+            translation: [self.f32()?, self.f32()?],
+        })
+    }
+
+    /// Read an encoding written by [`Writer::encoding`], and validate it.
+    fn encoding(&mut self, shared: &SharedResources<'_>) -> Result<Encoding, DeserializeError> {
+        let mut encoding = Encoding::new();
+        encoding.n_paths = self.u32()?;
+        encoding.n_path_segments = self.u32()?;
+        encoding.n_clips = self.u32()?;
+        encoding.n_open_clips = self.u32()?;
+        encoding.flags = self.u32()?;
+
+        let n_path_tags = self.len()?;
+        encoding.path_tags = self
+            .bytes(n_path_tags)?
+            .iter()
+            .map(|tag| PathTag(*tag))
+            .collect();
+        encoding.path_data = self.words()?;
+        encoding.draw_tags = self.words()?.into_iter().map(DrawTag).collect();
+        encoding.draw_data = self.words()?;
+        encoding.transforms = self.pods::<Transform>()?;
+        encoding.styles = self.pods::<Style>()?;
+
+        let draw_data_len = encoding.draw_data.len();
+        let resources = &mut encoding.resources;
+        for _ in 0..self.len()? {
+            let offset = self.f32()?;
+            let cs = color_space_tag(self.u8()?)?;
+            let missing_bits = self.u8()?;
+            let mut missing = Missing::EMPTY;
+            for ix in (0..4).filter(|ix| missing_bits & (1 << ix) != 0) {
+                missing.insert(ix);
+            }
+            let components = [self.f32()?, self.f32()?, self.f32()?, self.f32()?];
+            resources.color_stops.push(ColorStop {
+                offset,
+                color: DynamicColor {
+                    cs,
+                    flags: Flags::from_missing(missing),
+                    components,
+                },
+            });
+        }
+
+        for _ in 0..self.len()? {
+            resources.glyphs.push(Glyph {
+                id: self.u32()?,
+                x: self.f32()?,
+                y: self.f32()?,
+            });
+        }
+
+        for _ in 0..self.len()? {
+            let bytes = self.bytes(2)?;
+            resources
+                .normalized_coords
+                .push(i16::from_le_bytes([bytes[0], bytes[1]]));
+        }
+
+        for _ in 0..self.len()? {
+            let font = Font::new(shared.blob(self.u32()?)?, self.u32()?);
+            let transform = self.transform()?;
+            let glyph_transform = match self.u8()? {
+                0 => None,
+                _ => Some(self.transform()?),
+            };
+            let font_size = self.f32()?;
+            let hint = self.u8()? != 0;
+            let glyphs = self.range(resources.glyphs.len())?;
+            let normalized_coords = self.range(resources.normalized_coords.len())?;
+            let style = Style {
+                flags_and_miter_limit: self.u32()?,
+                line_width: self.f32()?,
+            };
+            let stream_offsets = StreamOffsets {
+                path_tags: self.len()?,
+                path_data: self.len()?,
+                draw_tags: self.len()?,
+                draw_data: self.len()?,
+                transforms: self.len()?,
+                styles: self.len()?,
+            };
+            resources.glyph_runs.push(GlyphRun {
+                font,
+                transform,
+                glyph_transform,
+                font_size,
+                hint,
+                glyphs,
+                normalized_coords,
+                style,
+                stream_offsets,
+                buffer: None,
+            });
+        }
+
+        for _ in 0..self.len()? {
+            let patch = match self.u8()? {
+                PATCH_RAMP => Patch::Ramp {
+                    draw_data_offset: self.offset(draw_data_len)?,
+                    stops: self.range(resources.color_stops.len())?,
+                    extend: extend(self.u8()?)?,
+                },
+                PATCH_GLYPH_RUN => {
+                    let index = self.len()?;
+                    if index >= resources.glyph_runs.len() {
+                        return Err(DeserializeError::Malformed("glyph run index out of range"));
+                    }
+                    Patch::GlyphRun { index }
+                }
+                PATCH_IMAGE => {
+                    let draw_data_offset = self.offset(draw_data_len)?;
+                    let data = shared.blob(self.u32()?)?;
+                    let format = match self.u8()? {
+                        0 => ImageFormat::Rgba8,
+                        _ => return Err(DeserializeError::Malformed("unknown image format")),
+                    };
+                    let width = self.u32()?;
+                    let height = self.u32()?;
+                    if format.size_in_bytes(width, height) != Some(data.len()) {
+                        return Err(DeserializeError::Malformed("image size mismatch"));
+                    }
+                    let x_extend = extend(self.u8()?)?;
+                    let y_extend = extend(self.u8()?)?;
+                    let quality = match self.u8()? {
+                        0 => ImageQuality::Low,
+                        1 => ImageQuality::Medium,
+                        2 => ImageQuality::High,
+                        _ => return Err(DeserializeError::Malformed("unknown image quality")),
+                    };
+                    let image = Image {
+                        data,
+                        format,
+                        width,
+                        height,
+                        x_extend,
+                        y_extend,
+                        quality,
+                        alpha: self.f32()?,
+                    };
+                    Patch::Image {
+                        image,
+                        draw_data_offset,
+                    }
+                }
+                PATCH_FILTER_IMAGE => {
+                    let draw_data_offset = self.offset(draw_data_len)?;
+                    let image = shared
+                        .placeholders
+                        .get(self.len()?)
+                        .cloned()
+                        .ok_or(DeserializeError::Malformed("filter index out of range"))?;
+                    Patch::Image {
+                        image,
+                        draw_data_offset,
+                    }
+                }
+                _ => return Err(DeserializeError::Malformed("unknown resource patch")),
+            };
+            resources.patches.push(patch);
+        }
+        validate(&encoding)?;
+        Ok(encoding)
+    }
+
+    /// Read the ids of the tagged draw objects of `encoding`.
+    fn draw_ids(&mut self, encoding: &Encoding) -> Result<Vec<(usize, DrawId)>, DeserializeError> {
+        let len = self.len()?;
+        let mut draw_ids = Vec::with_capacity(len.min(encoding.draw_tags.len()));
+        for _ in 0..len {
+            let draw_ix = self.offset(encoding.draw_tags.len())?;
+            draw_ids.push((draw_ix, DrawId(self.u64()?)));
+        }
+        Ok(draw_ids)
+    }
+
+    fn filter(&mut self) -> Result<Filter, DeserializeError> {
+        let filter = match self.u8()? {
+            FILTER_GAUSSIAN_BLUR => Filter::GaussianBlur {
+                std_dev: self.f64()?,
+            },
+            FILTER_DROP_SHADOW => Filter::DropShadow {
+                offset: Vec2::new(self.f64()?, self.f64()?),
+                std_dev: self.f64()?,
+                color: Color::new([self.f32()?, self.f32()?, self.f32()?, self.f32()?]),
+            },
+            FILTER_COLOR_MATRIX => {
+                let mut matrix = [0.0; 20];
+                for value in &mut matrix {
+                    *value = self.f32()?;
+                }
+                Filter::ColorMatrix(matrix)
+            }
+            _ => return Err(DeserializeError::Malformed("unknown filter")),
+        };
+        // The size of the blur kernel is derived from the standard deviation.
+        let valid = match &filter {
+            Filter::GaussianBlur { std_dev } => std_dev.is_finite() && *std_dev >= 0.0,
+            Filter::DropShadow {
+                offset, std_dev, ..
+            } => offset.is_finite() && std_dev.is_finite() && *std_dev >= 0.0,
+            Filter::ColorMatrix(_) => true,
+        };
+        if !valid {
+            return Err(DeserializeError::Malformed("invalid filter parameters"));
+        }
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use peniko::color::palette::css;
+    use peniko::kurbo::{Affine, Rect};
+    use peniko::{Fill, Mix};
+
+    use super::*;
+
+    fn scene() -> Scene {
+        let rect = Rect::new(0.0, 0.0, 16.0, 16.0);
+        let mut scene = Scene::new();
+        scene.fill_with_id(
+            DrawId(1),
+            Fill::NonZero,
+            Affine::IDENTITY,
+            css::RED,
+            None,
+            &rect,
+        );
+        scene.push_layer(Mix::Normal, 0.5, Affine::IDENTITY, &rect);
+        scene.push_filter_layer(
+            Filter::GaussianBlur { std_dev: 2.0 },
+            Affine::IDENTITY,
+            &rect,
+        );
+        scene.fill_with_id(
+            DrawId(2),
+            Fill::EvenOdd,
+            Affine::translate((4.0, 4.0)),
+            css::BLUE,
+            None,
+            &Rect::new(0.0, 0.0, 8.0, 8.0),
+        );
+        scene.pop_layer();
+        scene.pop_layer();
+        scene
+    }
+
+    fn round_trip(scene: &Scene) -> Result<Scene, DeserializeError> {
+        Scene::deserialize(&scene.serialize())
+    }
+
+    #[test]
+    fn scenes_round_trip() {
+        let scene = scene();
+        let loaded = round_trip(&scene).unwrap();
+        assert_eq!(loaded.serialize(), scene.serialize());
+        assert_eq!(loaded.draw_ids, scene.draw_ids);
+        assert_eq!(loaded.filters.len(), 1);
+        let (layer, original) = (&loaded.filters[0], &scene.filters[0]);
+        assert_eq!(layer.filter, original.filter);
+        assert_eq!(layer.draw_ids, original.draw_ids);
+        assert_eq!(layer.content.draw_data, original.content.draw_data);
+        assert_eq!(layer.image.width, original.image.width);
+        assert_eq!(layer.image.height, original.image.height);
+        // The loaded scene draws the placeholder image of its own layer.
+        let placeholder = loaded
+            .encoding
+            .resources
+            .patches
+            .iter()
+            .find_map(|patch| match patch {
+                Patch::Image { image, .. } => Some(image.data.id()),
+                _ => None,
+            });
+        assert_eq!(placeholder, Some(layer.image.data.id()));
+        assert_eq!(
+            loaded.hit_test(Point::new(6.0, 6.0)),
+            scene.hit_test(Point::new(6.0, 6.0))
+        );
+    }
+
+    #[test]
+    fn open_filter_layers_are_closed() {
+        let mut scene = Scene::new();
+        scene.push_filter_layer(
+            Filter::grayscale(1.0),
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 8.0, 8.0),
+        );
+        let loaded = round_trip(&scene).unwrap();
+        assert_eq!(loaded.filters.len(), 1);
+        assert!(loaded.open_filters.is_empty());
+    }
+
+    #[test]
+    fn large_filter_layers_are_loaded_without_their_pixels() {
+        let mut scene = Scene::new();
+        scene.push_filter_layer(
+            Filter::grayscale(1.0),
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, 8000.0, 8000.0),
+        );
+        scene.pop_layer();
+        let loaded = round_trip(&scene).unwrap();
+        let image = &loaded.filters[0].image;
+        assert_eq!((image.width, image.height), (8000, 8000));
+        assert_eq!(image.data.data().len(), 4);
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        assert!(matches!(
+            Scene::deserialize(b"VELLOSCX"),
+            Err(DeserializeError::InvalidMagic)
+        ));
+        let data = scene().serialize();
+        assert!(matches!(
+            Scene::deserialize(&data[..data.len() - 1]),
+            Err(DeserializeError::UnexpectedEof)
+        ));
+        let mut version = data.clone();
+        version[8] = 1;
+        assert!(matches!(
+            Scene::deserialize(&version),
+            Err(DeserializeError::UnsupportedVersion(1))
+        ));
+    }
+
+    /// Check that `scene` is rejected after `corrupt` is applied to its encoding.
+    fn check_malformed(corrupt: impl FnOnce(&mut Encoding)) {
+        let mut scene = scene();
+        corrupt(&mut scene.encoding);
+        assert!(matches!(
+            round_trip(&scene),
+            Err(DeserializeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn mismatched_counts_are_rejected() {
+        check_malformed(|encoding| encoding.n_paths += 1);
+        check_malformed(|encoding| encoding.n_path_segments -= 1);
+        check_malformed(|encoding| encoding.n_clips += 1);
+        check_malformed(|encoding| encoding.n_open_clips += 1);
+        check_malformed(|encoding| {
+            encoding.draw_data.pop();
+        });
+    }
+
+    #[test]
+    fn out_of_range_stream_offsets_are_rejected() {
+        let mut scene = scene();
+        let font = Font::new(Blob::new(Arc::new(vec![0_u8; 16])), 0);
+        scene.draw_glyphs(&font).draw(
+            Fill::NonZero,
+            [Glyph {
+                id: 1,
+                x: 0.0,
+                y: 0.0,
+            }]
+            .into_iter(),
+        );
+        round_trip(&scene).unwrap();
+        let run = &mut scene.encoding.resources.glyph_runs[0];
+        run.stream_offsets.draw_tags = scene.encoding.draw_tags.len() + 1;
+        assert!(matches!(
+            round_trip(&scene),
+            Err(DeserializeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        let mut scene = scene();
+        scene.filters[0].filter = Filter::GaussianBlur { std_dev: f64::NAN };
+        assert!(matches!(
+            round_trip(&scene),
+            Err(DeserializeError::Malformed(_))
+        ));
+    }
+}
@@ -3,16 +3,18 @@
 
 //! Load rendering shaders.
 
+#[cfg(feature = "wgpu")]
+use std::borrow::Cow;
+
 #[cfg(feature = "wgpu")]
 use wgpu::Device;
 
 use crate::ShaderId;
+#[cfg(feature = "cpu")]
+use crate::cpu::CpuEngine;
+use crate::recording::ImageFormat;
 #[cfg(feature = "wgpu")]
-use crate::{
-    Error, RendererOptions,
-    recording::{BindType, ImageFormat},
-    wgpu_engine::WgpuEngine,
-};
+use crate::{Error, OutputFormat, RendererOptions, recording::BindType, wgpu_engine::WgpuEngine};
 
 // Shaders for the full pipeline
 pub struct FullShaders {
@@ -38,6 +40,22 @@ pub struct FullShaders {
     pub fine_area: Option<ShaderId>,
     pub fine_msaa8: Option<ShaderId>,
     pub fine_msaa16: Option<ShaderId>,
+    /// Whether the MSAA fine shaders can be dispatched for regions of the target, see
+    /// [`crate::render::FineRegion`]. The fine shaders of `vello_shaders` can't.
+    pub msaa_fine_regions: bool,
+    /// Copies a rendered tile into the target, for targets which are rendered in tiles.
+    pub tile_copy: ShaderId,
+    /// Fine rasterization of pick images, which stores the index of the topmost draw object
+    /// at each pixel in an image of [`ImageFormat::R32Uint`].
+    pub fine_pick: Option<ShaderId>,
+    /// Copies a rendered tile of a pick image into the target.
+    pub tile_copy_pick: Option<ShaderId>,
+    /// Blurs the content of a filter layer horizontally.
+    pub filter_blur: ShaderId,
+    /// Blurs the content of a filter layer vertically, and composites the filter's result.
+    pub filter_composite: ShaderId,
+    /// The format of the images written by fine rasterization and `tile_copy`.
+    pub output_format: ImageFormat,
     // 2-level dispatch works for CPU pathtag scan even for large
     // inputs, 3-level is not yet implemented.
     pub pathtag_is_cpu: bool,
@@ -73,14 +91,13 @@ pub(crate) fn full_shaders(
             let source = shaders
                 .remove(stringify!($name))
                 .expect(stringify!($name))
-                .source
-                .into();
+                .source;
             #[cfg(not(feature = "hot_reload"))]
             let source = shaders.$name.wgsl.code;
             engine.add_compute_shader(
                 device,
                 concat!("vello.", $label),
-                source,
+                Cow::from(source),
                 &$bindings,
                 if force_gpu {
                     CpuShaderType::Missing
@@ -204,47 +221,147 @@ pub(crate) fn full_shaders(
             Buffer,
         ]
     );
+    let output_format = options.output_format.image_format();
+    let fine_area_resources = [
+        Uniform,
+        BufReadOnly,
+        BufReadOnly,
+        BufReadOnly,
+        Buffer,
+        Image(output_format),
+        ImageRead(ImageFormat::Rgba8),
+        ImageRead(ImageFormat::Rgba8),
+        // Mip table, see `crate::mipmap`.
+        BufReadOnly,
+        // The tiles rendered by the dispatch, see `crate::render::FineRegion`.
+        Uniform,
+    ];
     let fine_resources = [
         Uniform,
         BufReadOnly,
         BufReadOnly,
         BufReadOnly,
         Buffer,
-        Image(ImageFormat::Rgba8),
+        Image(output_format),
         ImageRead(ImageFormat::Rgba8),
         ImageRead(ImageFormat::Rgba8),
         // Mask LUT buffer, used only when MSAA is enabled.
         BufReadOnly,
+        // Mip table and region, which the fine shaders of `vello_shaders` don't read.
+        BufReadOnly,
+        Uniform,
     ];
 
+    // Area anti-aliasing, and float targets with any method, use the fine shader of this
+    // crate, which samples the mip levels of images and, for float targets, blends in linear
+    // light. Like the CPU kernel, it only implements area anti-aliasing, and ignores the mask
+    // LUT bound for MSAA.
+    let defines = output_defines(options.output_format);
+    let linear = options.output_format == OutputFormat::Rgba16Float;
+    let add_fine = |engine: &mut WgpuEngine, label: &'static str, msaa: bool| {
+        let defines: Vec<&str> = defines
+            .iter()
+            .copied()
+            .chain(msaa.then_some("msaa"))
+            .collect();
+        let bindings: &[BindType] = if msaa {
+            &fine_resources
+        } else {
+            &fine_area_resources
+        };
+        engine.add_compute_shader(
+            device,
+            label,
+            preprocess(FINE_WGSL, &defines).into(),
+            bindings,
+            CpuShaderType::Missing,
+        )
+    };
     let aa_support = &options.antialiasing_support;
-    let fine_area = if aa_support.area {
-        Some(add_shader!(
-            fine_area,
-            fine_resources[..fine_resources.len() - 1],
-            CpuShaderType::Missing
-        ))
-    } else {
+    let fine_area = aa_support
+        .area
+        .then(|| add_fine(engine, "vello.fine_area", false));
+    let fine_msaa8 = if !aa_support.msaa8 {
         None
-    };
-    let fine_msaa8 = if aa_support.msaa8 {
+    } else if linear {
+        Some(add_fine(engine, "vello.fine_msaa8", true))
+    } else {
         Some(add_shader!(
             fine_msaa8,
             fine_resources,
             CpuShaderType::Missing
         ))
-    } else {
-        None
     };
-    let fine_msaa16 = if aa_support.msaa16 {
+    let fine_msaa16 = if !aa_support.msaa16 {
+        None
+    } else if linear {
+        Some(add_fine(engine, "vello.fine_msaa16", true))
+    } else {
         Some(add_shader!(
             fine_msaa16,
             fine_resources,
             CpuShaderType::Missing
         ))
+    };
+
+    let tile_copy = engine.add_compute_shader(
+        device,
+        "vello.tile_copy",
+        preprocess(TILE_COPY_WGSL, defines).into(),
+        &[Uniform, ImageRead(output_format), Image(output_format)],
+        CpuShaderType::Missing,
+    );
+    // Pick images are also written by the fine shader of this crate, which stores the index
+    // encoded in the color of the topmost draw object instead of blending colors.
+    let (fine_pick, tile_copy_pick) = if options.pick_buffer {
+        let mut pick_resources = fine_area_resources;
+        pick_resources[5] = Image(ImageFormat::R32Uint);
+        let fine_pick = engine.add_compute_shader(
+            device,
+            "vello.fine_pick",
+            preprocess(FINE_WGSL, PICK_DEFINES).into(),
+            &pick_resources,
+            CpuShaderType::Missing,
+        );
+        let tile_copy_pick = engine.add_compute_shader(
+            device,
+            "vello.tile_copy_pick",
+            preprocess(TILE_COPY_WGSL, PICK_DEFINES).into(),
+            &[
+                Uniform,
+                ImageRead(ImageFormat::R32Uint),
+                Image(ImageFormat::R32Uint),
+            ],
+            CpuShaderType::Missing,
+        );
+        (Some(fine_pick), Some(tile_copy_pick))
     } else {
-        None
+        (None, None)
     };
+    let filter_blur = engine.add_compute_shader(
+        device,
+        "vello.filter_blur",
+        format!("{FILTER_COMMON_WGSL}{FILTER_BLUR_WGSL}").into(),
+        &[
+            Uniform,
+            ImageRead(output_format),
+            Image(ImageFormat::Rgba16Float),
+        ],
+        CpuShaderType::Missing,
+    );
+    let filter_composite = engine.add_compute_shader(
+        device,
+        "vello.filter_composite",
+        format!("{FILTER_COMMON_WGSL}{FILTER_COMPOSITE_WGSL}").into(),
+        &[
+            Uniform,
+            ImageRead(ImageFormat::Rgba16Float),
+            ImageRead(output_format),
+            ImageRead(output_format),
+            Image(ImageFormat::Rgba8),
+        ],
+        CpuShaderType::Missing,
+    );
 
     Ok(FullShaders {
         pathtag_reduce,
@@ -269,6 +386,1352 @@ pub(crate) fn full_shaders(
         fine_area,
         fine_msaa8,
         fine_msaa16,
+        msaa_fine_regions: linear,
+        tile_copy,
+        fine_pick,
+        tile_copy_pick,
+        filter_blur,
+        filter_composite,
+        output_format,
         pathtag_is_cpu: options.use_cpu,
     })
 }
+
+/// Register the shaders for running the full pipeline on the CPU.
+///
+/// Every anti-aliasing method uses the same area anti-aliased fine kernel.
+#[cfg(feature = "cpu")]
+pub(crate) fn cpu_shaders(engine: &mut CpuEngine, output_format: ImageFormat) -> FullShaders {
+    use vello_shaders::cpu;
+
+    FullShaders {
+        pathtag_reduce: engine.add_shader("vello.pathtag_reduce", Some(cpu::pathtag_reduce)),
+        pathtag_reduce2: engine.add_shader("vello.pathtag_reduce2", None),
+        pathtag_scan1: engine.add_shader("vello.pathtag_scan1", None),
+        pathtag_scan: engine.add_shader("vello.pathtag_scan_small", Some(cpu::pathtag_scan)),
+        pathtag_scan_large: engine.add_shader("vello.pathtag_scan_large", None),
+        bbox_clear: engine.add_shader("vello.bbox_clear", Some(cpu::bbox_clear)),
+        flatten: engine.add_shader("vello.flatten", Some(cpu::flatten)),
+        draw_reduce: engine.add_shader("vello.draw_reduce", Some(cpu::draw_reduce)),
+        draw_leaf: engine.add_shader("vello.draw_leaf", Some(cpu::draw_leaf)),
+        clip_reduce: engine.add_shader("vello.clip_reduce", Some(cpu::clip_reduce)),
+        clip_leaf: engine.add_shader("vello.clip_leaf", Some(cpu::clip_leaf)),
+        binning: engine.add_shader("vello.binning", Some(cpu::binning)),
+        tile_alloc: engine.add_shader("vello.tile_alloc", Some(cpu::tile_alloc)),
+        backdrop: engine.add_shader("vello.backdrop_dyn", Some(cpu::backdrop)),
+        path_count_setup: engine.add_shader("vello.path_count_setup", Some(cpu::path_count_setup)),
+        path_count: engine.add_shader("vello.path_count", Some(cpu::path_count)),
+        coarse: engine.add_shader("vello.coarse", Some(cpu::coarse)),
+        path_tiling_setup: engine
+            .add_shader("vello.path_tiling_setup", Some(cpu::path_tiling_setup)),
+        path_tiling: engine.add_shader("vello.path_tiling", Some(cpu::path_tiling)),
+        fine_area: Some(engine.add_shader("vello.fine_area", Some(crate::cpu::fine))),
+        fine_msaa8: Some(engine.add_shader("vello.fine_msaa8", Some(crate::cpu::fine))),
+        fine_msaa16: Some(engine.add_shader("vello.fine_msaa16", Some(crate::cpu::fine))),
+        msaa_fine_regions: true,
+        tile_copy: engine.add_shader("vello.tile_copy", Some(crate::cpu::tile_copy)),
+        fine_pick: None,
+        tile_copy_pick: None,
+        filter_blur: engine.add_shader("vello.filter_blur", Some(crate::cpu::filter_blur)),
+        filter_composite: engine
+            .add_shader("vello.filter_composite", Some(crate::cpu::filter_composite)),
+        output_format,
+        pathtag_is_cpu: true,
+    }
+}
+
+/// The names defined when preprocessing the shaders of this crate for an output format.
+#[cfg(feature = "wgpu")]
+fn output_defines(format: OutputFormat) -> &'static [&'static str] {
+    match format {
+        OutputFormat::Rgba8 => &[],
+        OutputFormat::Rgba16Float => &["linear_output"],
+    }
+}
+
+/// The names defined when preprocessing the shaders of this crate for pick images.
+#[cfg(feature = "wgpu")]
+const PICK_DEFINES: &[&str] = &["pick"];
+
+/// Evaluate the `#ifdef`, `#else` and `#endif` directives of a shader of this crate, keeping
+/// the lines of the branches whose name is in `defines`.
+///
+/// The directives follow those of the shaders in `vello_shaders`. This panics if they aren't
+/// balanced, as the shaders are constants.
+#[cfg(feature = "wgpu")]
+fn preprocess(source: &str, defines: &[&str]) -> String {
+    // Whether the lines of each enclosing branch are kept.
+    let mut branches: Vec<bool> = Vec::new();
+    let mut output = String::with_capacity(source.len());
+    for line in source.lines() {
+        let directive = line.trim();
+        if let Some(name) = directive.strip_prefix("#ifdef ") {
+            branches.push(defines.contains(&name.trim()));
+        } else if directive == "#else" {
+            let keep = branches.last_mut().expect("`#else` outside of `#ifdef`");
+            *keep = !*keep;
+        } else if directive == "#endif" {
+            branches.pop().expect("`#endif` outside of `#ifdef`");
+        } else if branches.iter().all(|keep| *keep) {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    assert!(branches.is_empty(), "`#ifdef` without `#endif`");
+    output
+}
+
+/// Copy a tile of a rendered image into the render target at an offset.
+///
+/// The layout of the config matches [`crate::render::TileCopyConfig`].
+#[cfg(feature = "wgpu")]
+const TILE_COPY_WGSL: &str = r#"
+struct Config {
+    offset: vec2<u32>,
+    tile_size: vec2<u32>,
+    target_size: vec2<u32>,
+    source: vec2<u32>,
+    source_size: vec2<u32>,
+    padding: vec2<u32>,
+}
+
+@group(0) @binding(0)
+var<uniform> config: Config;
+
+@group(0) @binding(1)
+#ifdef pick
+var tile: texture_2d<u32>;
+#else
+var tile: texture_2d<f32>;
+#endif
+
+@group(0) @binding(2)
+#ifdef pick
+var output: texture_storage_2d<r32uint, write>;
+#else
+#ifdef linear_output
+var output: texture_storage_2d<rgba16float, write>;
+#else
+var output: texture_storage_2d<rgba8unorm, write>;
+#endif
+#endif
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let xy = global_id.xy + config.offset;
+    if any(global_id.xy >= config.tile_size) || any(xy >= config.target_size) {
+        return;
+    }
+    textureStore(output, xy, textureLoad(tile, global_id.xy + config.source, 0));
+}
+"#;
+
+/// Fine rasterization with area anti-aliasing, used for every method other than MSAA into
+/// [`OutputFormat::Rgba8`] targets.
+///
+/// This interprets the per-tile command lists written by coarse rasterization, like
+/// `fine.wgsl`, and mirrors the CPU kernel in `cpu/fine.rs`. Images are sampled from their
+/// mip levels, see [`crate::mipmap`]. With `linear_output` defined, colors are converted to
+/// linear light as they are read, blended in linear light, and stored without being clamped.
+/// With `pick` defined, it writes pick images instead, see [`crate::pick`]. Each invocation
+/// renders one pixel of a 16x16 tile.
+///
+/// The bindings match `fine_area`, which binds the mip table and then the region of the
+/// target rendered by the dispatch last. With `msaa` defined, they are bound after the mask
+/// LUT of the MSAA modes instead. The layout of the config is a prefix of
+/// [`vello_encoding::ConfigUniform`].
+#[cfg(feature = "wgpu")]
+const FINE_WGSL: &str = r#"
+struct Config {
+    width_in_tiles: u32,
+    height_in_tiles: u32,
+    target_width: u32,
+    target_height: u32,
+    base_color: u32,
+}
+
+struct Segment {
+    point0: vec2<f32>,
+    point1: vec2<f32>,
+    y_edge: f32,
+}
+
+struct Transform {
+    matrx: vec4<f32>,
+    translate: vec2<f32>,
+}
+
+struct ImageBrush {
+    transform: Transform,
+    atlas_offset: vec2<f32>,
+    extents: vec2<f32>,
+    x_extend: u32,
+    y_extend: u32,
+    quality: u32,
+    alpha: f32,
+}
+
+// The tiles rendered by a dispatch, see `crate::render::FineRegion`.
+struct FineRegion {
+    offset: vec2<u32>,
+    size: vec2<u32>,
+}
+
+// The mip levels of an image, stacked in a column of the atlas, see `crate::mipmap`.
+struct MipLevels {
+    atlas_offset: vec2<f32>,
+    count: u32,
+}
+
+const TILE_WIDTH: u32 = 16u;
+const TILE_HEIGHT: u32 = 16u;
+// Layout of the per-tile command list, see `shared/ptcl.wgsl`.
+const PTCL_INITIAL_ALLOC: u32 = 64u;
+// Clip levels deeper than this are spilled to `blend_spill`, as allocated by coarse.
+const BLEND_STACK_SPLIT: u32 = 4u;
+
+const CMD_FILL: u32 = 1u;
+const CMD_STROKE: u32 = 2u;
+const CMD_SOLID: u32 = 3u;
+const CMD_COLOR: u32 = 5u;
+const CMD_LIN_GRAD: u32 = 6u;
+const CMD_RAD_GRAD: u32 = 7u;
+const CMD_SWEEP_GRAD: u32 = 8u;
+const CMD_IMAGE: u32 = 9u;
+const CMD_BEGIN_CLIP: u32 = 10u;
+const CMD_END_CLIP: u32 = 11u;
+const CMD_JUMP: u32 = 12u;
+const CMD_BLUR_RECT: u32 = 13u;
+
+const GRADIENT_WIDTH: i32 = 512;
+
+const EXTEND_PAD: u32 = 0u;
+const EXTEND_REPEAT: u32 = 1u;
+
+const IMAGE_QUALITY_LOW: u32 = 0u;
+const IMAGE_QUALITY_MEDIUM: u32 = 1u;
+
+const RAD_GRAD_KIND_CIRCULAR: u32 = 1u;
+const RAD_GRAD_KIND_STRIP: u32 = 2u;
+const RAD_GRAD_KIND_FOCAL_ON_CIRCLE: u32 = 3u;
+const RAD_GRAD_SWAPPED: u32 = 1u;
+const RAD_GRAD_WELL_BEHAVED: u32 = 2u;
+
+const TAU: f32 = 6.283185307;
+const FRAC_1_SQRT_2: f32 = 0.707106781;
+const FRAC_2_SQRT_PI: f32 = 1.128379167;
+
+@group(0) @binding(0)
+var<uniform> config: Config;
+
+@group(0) @binding(1)
+var<storage> segments: array<Segment>;
+
+@group(0) @binding(2)
+var<storage> ptcl: array<u32>;
+
+@group(0) @binding(3)
+var<storage> info: array<u32>;
+
+@group(0) @binding(4)
+var<storage, read_write> blend_spill: array<u32>;
+
+@group(0) @binding(5)
+#ifdef pick
+var output: texture_storage_2d<r32uint, write>;
+#else
+#ifdef linear_output
+var output: texture_storage_2d<rgba16float, write>;
+#else
+var output: texture_storage_2d<rgba8unorm, write>;
+#endif
+#endif
+
+@group(0) @binding(6)
+var gradients: texture_2d<f32>;
+
+@group(0) @binding(7)
+var image_atlas: texture_2d<f32>;
+
+#ifdef msaa
+@group(0) @binding(9)
+#else
+@group(0) @binding(8)
+#endif
+var<storage> mip_table: array<u32>;
+
+#ifdef msaa
+@group(0) @binding(10)
+#else
+@group(0) @binding(9)
+#endif
+var<uniform> region: FineRegion;
+
+fn srgb_to_linear(srgb: vec3<f32>) -> vec3<f32> {
+    let c = clamp(srgb, vec3(0.0), vec3(1.0));
+    return select(pow((c + 0.055) / 1.055, vec3(2.4)), c / 12.92, c <= vec3(0.04045));
+}
+
+fn linear_to_srgb(rgb: vec3<f32>) -> vec3<f32> {
+    let c = clamp(rgb, vec3(0.0), vec3(1.0));
+    return select(1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3(0.0031308));
+}
+
+// Convert a premultiplied sRGB color, as encoded in the scene, to the space colors are
+// blended in.
+fn decode(rgba: vec4<f32>) -> vec4<f32> {
+#ifdef linear_output
+    let rgb = rgba.rgb / max(rgba.a, 1e-6);
+    return vec4(srgb_to_linear(rgb) * rgba.a, rgba.a);
+#else
+    return rgba;
+#endif
+}
+
+// Pack a blended color into a slot of `blend_spill`. The slots only hold eight bits per
+// channel, so colors in linear light are stored sRGB encoded, and clamped to `[0, 1]`.
+fn pack_spill(rgba: vec4<f32>) -> u32 {
+#ifdef linear_output
+    let rgb = rgba.rgb / max(rgba.a, 1e-6);
+    return pack4x8unorm(vec4(linear_to_srgb(rgb) * rgba.a, rgba.a));
+#else
+    return pack4x8unorm(rgba);
+#endif
+}
+
+fn read_f32(ix: u32) -> f32 {
+    return bitcast<f32>(info[ix]);
+}
+
+// Read an affine transform stored as a 2x2 matrix followed by a translation.
+fn read_transform(ix: u32) -> Transform {
+    let matrx = vec4(read_f32(ix), read_f32(ix + 1u), read_f32(ix + 2u), read_f32(ix + 3u));
+    let translate = vec2(read_f32(ix + 4u), read_f32(ix + 5u));
+    return Transform(matrx, translate);
+}
+
+fn apply_transform(transform: Transform, p: vec2<f32>) -> vec2<f32> {
+    return transform.matrx.xy * p.x + transform.matrx.zw * p.y + transform.translate;
+}
+
+// Compute the coverage of a path at a pixel of the tile, using exact area coverage.
+fn fill_path(size_and_rule: u32, seg_data: u32, backdrop: i32, xy: vec2<f32>) -> f32 {
+    let n_segs = size_and_rule >> 1u;
+    let even_odd = (size_and_rule & 1u) != 0u;
+    var area = f32(backdrop);
+    for (var i = 0u; i < n_segs; i += 1u) {
+        let segment = segments[seg_data + i];
+        let delta = segment.point1 - segment.point0;
+        let y = segment.point0.y - xy.y;
+        let y0 = clamp(y, 0.0, 1.0);
+        let y1 = clamp(y + delta.y, 0.0, 1.0);
+        let dy = y0 - y1;
+        if dy != 0.0 {
+            let vec_y_recip = 1.0 / delta.y;
+            let t0 = (y0 - y) * vec_y_recip;
+            let t1 = (y1 - y) * vec_y_recip;
+            let x0 = segment.point0.x + t0 * delta.x;
+            let x1 = segment.point0.x + t1 * delta.x;
+            let xmin = min(min(x0, x1) - xy.x, 1.0) - 1.0e-6;
+            let xmax = max(x0, x1) - xy.x;
+            let b = min(xmax, 1.0);
+            let c = max(b, 0.0);
+            let d = max(xmin, 0.0);
+            let a = (b + 0.5 * (d * d - c * c) - xmin) / (xmax - xmin);
+            area += a * dy;
+        }
+        area += sign(delta.x) * clamp(xy.y - segment.y_edge + 1.0, 0.0, 1.0);
+    }
+    if even_odd {
+        return abs(area - 2.0 * round(0.5 * area));
+    }
+    return min(abs(area), 1.0);
+}
+
+fn extend_mode(t: f32, mode: u32) -> f32 {
+    if mode == EXTEND_PAD {
+        return clamp(t, 0.0, 1.0);
+    } else if mode == EXTEND_REPEAT {
+        return fract(t);
+    }
+    return abs(t - 2.0 * round(0.5 * t));
+}
+
+fn sample_ramp(index: u32, t: f32) -> vec4<f32> {
+    let x = clamp(i32(round(t * f32(GRADIENT_WIDTH - 1))), 0, GRADIENT_WIDTH - 1);
+    return decode(textureLoad(gradients, vec2(x, i32(index)), 0));
+}
+
+// Evaluate a radial gradient at a point, returning the parameter and whether the gradient is
+// defined there.
+fn radial_gradient(info_offset: u32, xy: vec2<f32>, extend: u32) -> vec2<f32> {
+    let p = apply_transform(read_transform(info_offset), xy);
+    let focal_x = read_f32(info_offset + 6u);
+    let radius = read_f32(info_offset + 7u);
+    let kind = info[info_offset + 8u] & 0x7u;
+    let flags = info[info_offset + 8u] >> 3u;
+    let is_swapped = (flags & RAD_GRAD_SWAPPED) != 0u;
+    let is_circular = kind == RAD_GRAD_KIND_CIRCULAR;
+    let r1_recip = select(1.0 / radius, 0.0, is_circular);
+    let less_scale = select(1.0, -1.0, is_swapped || (1.0 - focal_x) < 0.0);
+    let t_sign = sign(1.0 - focal_x);
+    let xx = p.x * p.x;
+    let yy = p.y * p.y;
+    var t = 0.0;
+    var is_valid = true;
+    if kind == RAD_GRAD_KIND_STRIP {
+        let a = radius - yy;
+        t = sqrt(max(a, 0.0)) + p.x;
+        is_valid = a >= 0.0;
+    } else if kind == RAD_GRAD_KIND_FOCAL_ON_CIRCLE {
+        t = (xx + yy) / p.x;
+        is_valid = p.x != 0.0 && t >= 0.0;
+    } else if is_circular {
+        t = sqrt(xx + yy);
+    } else if (flags & RAD_GRAD_WELL_BEHAVED) != 0u {
+        t = sqrt(xx + yy) - p.x * r1_recip;
+    } else {
+        let disc = xx - yy;
+        t = less_scale * sqrt(max(disc, 0.0)) - p.x * r1_recip;
+        is_valid = disc >= 0.0 && t >= 0.0;
+    }
+    t = extend_mode(focal_x + t_sign * t, extend);
+    if is_swapped {
+        t = 1.0 - t;
+    }
+    return vec2(t, select(0.0, 1.0, is_valid));
+}
+
+fn read_image(info_offset: u32) -> ImageBrush {
+    let xy = info[info_offset + 6u];
+    let width_height = info[info_offset + 7u];
+    let sample_alpha = info[info_offset + 8u];
+    return ImageBrush(
+        read_transform(info_offset),
+        vec2(f32(xy >> 16u), f32(xy & 0xffffu)),
+        vec2(f32(width_height >> 16u), f32(width_height & 0xffffu)),
+        (sample_alpha >> 10u) & 0x3u,
+        (sample_alpha >> 8u) & 0x3u,
+        sample_alpha >> 12u,
+        f32(sample_alpha & 0xffu) / 255.0,
+    );
+}
+
+// Map an integral texel coordinate into `0..size` according to an extend mode.
+fn extend_texel(x: f32, size: f32, mode: u32) -> f32 {
+    if mode == EXTEND_PAD {
+        return clamp(x, 0.0, size - 1.0);
+    } else if mode == EXTEND_REPEAT {
+        return x - size * floor(x / size);
+    }
+    let period = 2.0 * size;
+    let m = x - period * floor(x / period);
+    return select(period - 1.0 - m, m, m < size);
+}
+
+fn load_image(image: ImageBrush, uv: vec2<f32>) -> vec4<f32> {
+    let x = extend_texel(uv.x, image.extents.x, image.x_extend) + image.atlas_offset.x;
+    let y = extend_texel(uv.y, image.extents.y, image.y_extend) + image.atlas_offset.y;
+    let c = textureLoad(image_atlas, vec2(i32(x), i32(y)), 0);
+    return decode(vec4(c.rgb * c.a, c.a));
+}
+
+fn sample_bilinear(image: ImageBrush, uv: vec2<f32>) -> vec4<f32> {
+    let p = uv - 0.5;
+    let p0 = floor(p);
+    let f = p - p0;
+    let a = load_image(image, p0);
+    let b = load_image(image, p0 + vec2(1.0, 0.0));
+    let c = load_image(image, p0 + vec2(0.0, 1.0));
+    let d = load_image(image, p0 + vec2(1.0, 1.0));
+    return mix(mix(a, b, f.x), mix(c, d, f.x), f.y);
+}
+
+// Find the mip levels of the image at an atlas offset, packed as in the image brush, by a
+// binary search of the mip table. Images without levels have a count of zero.
+fn find_mip_levels(xy: u32) -> MipLevels {
+    var lo = 0u;
+    var hi = arrayLength(&mip_table) / 3u;
+    while lo < hi {
+        let mid = (lo + hi) / 2u;
+        let key = mip_table[mid * 3u];
+        if key == xy {
+            let offset = mip_table[mid * 3u + 1u];
+            let atlas_offset = vec2(f32(offset >> 16u), f32(offset & 0xffffu));
+            return MipLevels(atlas_offset, mip_table[mid * 3u + 2u]);
+        } else if key < xy {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return MipLevels(vec2(0.0), 0u);
+}
+
+// Sample a mip level bilinearly, where level zero is the image itself. The texels of each
+// level cover the same area as those of the image.
+fn sample_level(image: ImageBrush, levels: MipLevels, level: u32, uv: vec2<f32>) -> vec4<f32> {
+    if level == 0u {
+        return sample_bilinear(image, uv);
+    }
+    let size = vec2<u32>(image.extents);
+    var y = levels.atlas_offset.y;
+    for (var i = 1u; i < level; i += 1u) {
+        y += f32((size.y + (1u << i) - 1u) >> i);
+    }
+    var level_image = image;
+    level_image.atlas_offset = vec2(levels.atlas_offset.x, y);
+    level_image.extents = vec2<f32>((size + vec2((1u << level) - 1u)) >> vec2(level));
+    return sample_bilinear(level_image, uv * level_image.extents / image.extents);
+}
+
+fn sample_image(image: ImageBrush, xy: vec2<f32>) -> vec4<f32> {
+    let uv = apply_transform(image.transform, xy);
+    if image.quality == IMAGE_QUALITY_LOW {
+        return load_image(image, floor(uv)) * image.alpha;
+    }
+    // The level of detail is the log of the largest derivative of the texel coordinates.
+    let packed_offset = (u32(image.atlas_offset.x) << 16u) | u32(image.atlas_offset.y);
+    let levels = find_mip_levels(packed_offset);
+    let derivative = max(length(image.transform.matrx.xy), length(image.transform.matrx.zw));
+    let lod = clamp(log2(derivative), 0.0, f32(levels.count));
+    if image.quality == IMAGE_QUALITY_MEDIUM {
+        return sample_level(image, levels, u32(round(lod)), uv) * image.alpha;
+    }
+    // Bicubic filtering isn't implemented, so high quality blends the two nearest levels.
+    let level = floor(lod);
+    let t = lod - level;
+    let above = sample_level(image, levels, u32(level), uv);
+    if t == 0.0 {
+        return above * image.alpha;
+    }
+    let below = sample_level(image, levels, u32(level) + 1u, uv);
+    return mix(above, below, t) * image.alpha;
+}
+
+// Approximation of the error function, accurate to about 1e-4.
+fn erf7(x: f32) -> f32 {
+    let y = x * FRAC_2_SQRT_PI;
+    let yy = y * y;
+    let z = y + (0.24295 + (0.03395 + 0.0104 * yy) * yy) * (y * yy);
+    return z / sqrt(1.0 + z * z);
+}
+
+// Coverage of a rounded rectangle centered on the origin convolved with a gaussian.
+fn blurred_rounded_rect(p: vec2<f32>, size: vec2<f32>, radius: f32, std_dev: f32) -> f32 {
+    let s = max(std_dev, 1e-6);
+    let s_inv = 1.0 / s;
+    let rmax = 0.5 * min(size.x, size.y);
+    let r0 = min(length(vec2(radius, 1.15 * s)), rmax);
+    let r1 = min(length(vec2(radius, 2.0 * s)), rmax);
+    let exponent = 2.0 * r1 / r0;
+    // Pull in the long end, making the shape less eccentric.
+    let e = 0.5 * s_inv * size;
+    let delta = 1.25 * s * (exp(-e.x * e.x) - exp(-e.y * e.y));
+    let w = size.x + min(delta, 0.0);
+    let h = size.y - max(delta, 0.0);
+    let p0 = abs(p) - 0.5 * vec2(w, h) + r1;
+    let p_pos = max(p0, vec2(0.0));
+    let d_pos = pow(pow(p_pos.x, exponent) + pow(p_pos.y, exponent), 1.0 / exponent);
+    let d_neg = min(max(p0.x, p0.y), 0.0);
+    let d = d_pos + d_neg - r1;
+    // Small rectangles never reach full opacity.
+    let peak = erf7(0.5 * FRAC_1_SQRT_2 * s_inv * w) * erf7(0.5 * FRAC_1_SQRT_2 * s_inv * h);
+    return peak * 0.5 * (1.0 - erf7(FRAC_1_SQRT_2 * s_inv * d));
+}
+
+// Blend modes, see `blend.wgsl`.
+const MIX_MULTIPLY: u32 = 1u;
+const MIX_SCREEN: u32 = 2u;
+const MIX_OVERLAY: u32 = 3u;
+const MIX_DARKEN: u32 = 4u;
+const MIX_LIGHTEN: u32 = 5u;
+const MIX_COLOR_DODGE: u32 = 6u;
+const MIX_COLOR_BURN: u32 = 7u;
+const MIX_HARD_LIGHT: u32 = 8u;
+const MIX_SOFT_LIGHT: u32 = 9u;
+const MIX_DIFFERENCE: u32 = 10u;
+const MIX_EXCLUSION: u32 = 11u;
+const MIX_HUE: u32 = 12u;
+const MIX_SATURATION: u32 = 13u;
+const MIX_COLOR: u32 = 14u;
+const MIX_LUMINOSITY: u32 = 15u;
+
+const COMPOSE_CLEAR: u32 = 0u;
+const COMPOSE_COPY: u32 = 1u;
+const COMPOSE_DEST: u32 = 2u;
+const COMPOSE_SRC_OVER: u32 = 3u;
+const COMPOSE_DEST_OVER: u32 = 4u;
+const COMPOSE_SRC_IN: u32 = 5u;
+const COMPOSE_DEST_IN: u32 = 6u;
+const COMPOSE_SRC_OUT: u32 = 7u;
+const COMPOSE_DEST_OUT: u32 = 8u;
+const COMPOSE_SRC_ATOP: u32 = 9u;
+const COMPOSE_DEST_ATOP: u32 = 10u;
+const COMPOSE_XOR: u32 = 11u;
+const COMPOSE_PLUS: u32 = 12u;
+const COMPOSE_PLUS_LIGHTER: u32 = 13u;
+
+// Normal mixing with source-over compositing.
+const BLEND_DEFAULT: u32 = 3u;
+const EPSILON: f32 = 1e-10;
+
+fn screen(cb: vec3<f32>, cs: vec3<f32>) -> vec3<f32> {
+    return cb + cs - cb * cs;
+}
+
+fn color_dodge(cb: f32, cs: f32) -> f32 {
+    if cb == 0.0 {
+        return 0.0;
+    } else if cs == 1.0 {
+        return 1.0;
+    }
+    return min(cb / (1.0 - cs), 1.0);
+}
+
+fn color_burn(cb: f32, cs: f32) -> f32 {
+    if cb == 1.0 {
+        return 1.0;
+    } else if cs == 0.0 {
+        return 0.0;
+    }
+    return 1.0 - min((1.0 - cb) / cs, 1.0);
+}
+
+fn hard_light(cb: vec3<f32>, cs: vec3<f32>) -> vec3<f32> {
+    return select(screen(cb, 2.0 * cs - 1.0), cb * 2.0 * cs, cs <= vec3(0.5));
+}
+
+fn soft_light(cb: vec3<f32>, cs: vec3<f32>) -> vec3<f32> {
+    let d = select(sqrt(cb), ((16.0 * cb - 12.0) * cb + 4.0) * cb, cb <= vec3(0.25));
+    return select(
+        cb + (2.0 * cs - 1.0) * (d - cb),
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb),
+        cs <= vec3(0.5),
+    );
+}
+
+fn lum(c: vec3<f32>) -> f32 {
+    return dot(c, vec3(0.3, 0.59, 0.11));
+}
+
+fn sat(c: vec3<f32>) -> f32 {
+    return max(c.x, max(c.y, c.z)) - min(c.x, min(c.y, c.z));
+}
+
+fn clip_color(c_in: vec3<f32>) -> vec3<f32> {
+    var c = c_in;
+    let l = lum(c);
+    let n = min(c.x, min(c.y, c.z));
+    let x = max(c.x, max(c.y, c.z));
+    if n < 0.0 {
+        c = l + (c - l) * l / (l - n + EPSILON);
+    }
+    if x > 1.0 {
+        c = l + (c - l) * (1.0 - l) / (x - l + EPSILON);
+    }
+    return c;
+}
+
+fn set_lum(c: vec3<f32>, l: f32) -> vec3<f32> {
+    return clip_color(c + (l - lum(c)));
+}
+
+fn set_sat(c: vec3<f32>, s: f32) -> vec3<f32> {
+    let max_c = max(c.x, max(c.y, c.z));
+    let min_c = min(c.x, min(c.y, c.z));
+    if max_c > min_c {
+        return (c - min_c) * s / (max_c - min_c);
+    }
+    return vec3(0.0);
+}
+
+fn blend_mix(cb: vec3<f32>, cs: vec3<f32>, mode: u32) -> vec3<f32> {
+    var mixed = cs;
+    switch mode {
+        case MIX_MULTIPLY: {
+            mixed = cb * cs;
+        }
+        case MIX_SCREEN: {
+            mixed = screen(cb, cs);
+        }
+        case MIX_OVERLAY: {
+            mixed = hard_light(cs, cb);
+        }
+        case MIX_DARKEN: {
+            mixed = min(cb, cs);
+        }
+        case MIX_LIGHTEN: {
+            mixed = max(cb, cs);
+        }
+        case MIX_COLOR_DODGE: {
+            mixed = vec3(
+                color_dodge(cb.x, cs.x),
+                color_dodge(cb.y, cs.y),
+                color_dodge(cb.z, cs.z),
+            );
+        }
+        case MIX_COLOR_BURN: {
+            mixed = vec3(
+                color_burn(cb.x, cs.x),
+                color_burn(cb.y, cs.y),
+                color_burn(cb.z, cs.z),
+            );
+        }
+        case MIX_HARD_LIGHT: {
+            mixed = hard_light(cb, cs);
+        }
+        case MIX_SOFT_LIGHT: {
+            mixed = soft_light(cb, cs);
+        }
+        case MIX_DIFFERENCE: {
+            mixed = abs(cb - cs);
+        }
+        case MIX_EXCLUSION: {
+            mixed = cb + cs - 2.0 * cb * cs;
+        }
+        case MIX_HUE: {
+            mixed = set_lum(set_sat(cs, sat(cb)), lum(cb));
+        }
+        case MIX_SATURATION: {
+            mixed = set_lum(set_sat(cb, sat(cs)), lum(cb));
+        }
+        case MIX_COLOR: {
+            mixed = set_lum(cs, lum(cb));
+        }
+        case MIX_LUMINOSITY: {
+            mixed = set_lum(cb, lum(cs));
+        }
+        default: {}
+    }
+    return mixed;
+}
+
+// Porter-Duff compositing of separated colors, returning a premultiplied result.
+fn blend_compose(cb: vec3<f32>, cs: vec3<f32>, ab: f32, as_: f32, mode: u32) -> vec4<f32> {
+    var fa = 1.0;
+    var fb = 1.0 - as_;
+    switch mode {
+        case COMPOSE_CLEAR: {
+            fa = 0.0;
+            fb = 0.0;
+        }
+        case COMPOSE_COPY: {
+            fa = 1.0;
+            fb = 0.0;
+        }
+        case COMPOSE_DEST: {
+            fa = 0.0;
+            fb = 1.0;
+        }
+        case COMPOSE_DEST_OVER: {
+            fa = 1.0 - ab;
+            fb = 1.0;
+        }
+        case COMPOSE_SRC_IN: {
+            fa = ab;
+            fb = 0.0;
+        }
+        case COMPOSE_DEST_IN: {
+            fa = 0.0;
+            fb = as_;
+        }
+        case COMPOSE_SRC_OUT: {
+            fa = 1.0 - ab;
+            fb = 0.0;
+        }
+        case COMPOSE_DEST_OUT: {
+            fa = 0.0;
+            fb = 1.0 - as_;
+        }
+        case COMPOSE_SRC_ATOP: {
+            fa = ab;
+            fb = 1.0 - as_;
+        }
+        case COMPOSE_DEST_ATOP: {
+            fa = 1.0 - ab;
+            fb = as_;
+        }
+        case COMPOSE_XOR: {
+            fa = 1.0 - ab;
+            fb = 1.0 - as_;
+        }
+        case COMPOSE_PLUS: {
+            fa = 1.0;
+            fb = 1.0;
+        }
+        case COMPOSE_PLUS_LIGHTER: {
+            return vec4(min(as_ * cs + ab * cb, vec3(1.0)), min(as_ + ab, 1.0));
+        }
+        default: {}
+    }
+    let as_fa = as_ * fa;
+    let ab_fb = ab * fb;
+    return vec4(as_fa * cs + ab_fb * cb, min(as_fa + ab_fb, 1.0));
+}
+
+// Blend premultiplied `src` onto premultiplied `backdrop`.
+//
+// `mode` packs the mix mode in bits 8-15 and the compose mode in the low byte. The clip mix
+// mode (128) behaves like normal source-over compositing.
+fn blend_mix_compose(backdrop: vec4<f32>, src: vec4<f32>, mode: u32) -> vec4<f32> {
+    if (mode & 0x7fffu) == BLEND_DEFAULT {
+        return backdrop * (1.0 - src.a) + src;
+    }
+    let cs_in = src.rgb / (src.a + EPSILON);
+    let cb = backdrop.rgb / (backdrop.a + EPSILON);
+    let mixed = blend_mix(cb, cs_in, (mode >> 8u) & 0x7fu);
+    let cs = mix(cs_in, mixed, backdrop.a);
+    let compose_mode = mode & 0xffu;
+    if compose_mode == COMPOSE_SRC_OVER {
+        let co = mix(backdrop.rgb, cs, src.a);
+        return vec4(co, src.a + backdrop.a * (1.0 - src.a));
+    }
+    return blend_compose(cb, cs, backdrop.a, src.a, compose_mode);
+}
+
+// Composite a paint over `rgba`, weighted by the coverage `area`.
+fn paint_over(rgba: vec4<f32>, paint: vec4<f32>, area: f32) -> vec4<f32> {
+    if area == 0.0 {
+        return rgba;
+    }
+    let fg = paint * area;
+    return rgba * (1.0 - fg.a) + fg;
+}
+
+#ifdef pick
+// The low bits of the color of a draw object in a pick encoding are its index.
+const PICK_INDEX_MASK: u32 = 0xffffffu;
+// The index of a clip layer in which nothing has been drawn, which leaves the index below
+// the layer unchanged.
+const PICK_NONE: u32 = 0xffffffffu;
+
+// Write the index of the topmost draw object which covers at least half of each pixel.
+// Indices are replaced rather than blended, and clip layers only clip.
+@compute @workgroup_size(16, 16)
+fn main(
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(workgroup_id) wg_id: vec3<u32>,
+) {
+    let tile_xy = wg_id.xy + region.offset;
+    let tile_ix = tile_xy.y * config.width_in_tiles + tile_xy.x;
+    let pixel = tile_xy * vec2(TILE_WIDTH, TILE_HEIGHT) + local_id.xy;
+    let local_xy = vec2<f32>(local_id.xy);
+    let spill_ix = local_id.y * TILE_WIDTH + local_id.x;
+    var index = 0u;
+    var area = 0.0;
+    var index_stack: array<u32, BLEND_STACK_SPLIT>;
+    var clip_depth = 0u;
+    var cmd_ix = tile_ix * PTCL_INITIAL_ALLOC;
+    let blend_offset = ptcl[cmd_ix];
+    cmd_ix += 1u;
+    var done = false;
+    while !done {
+        switch ptcl[cmd_ix] {
+            case CMD_FILL: {
+                let size_and_rule = ptcl[cmd_ix + 1u];
+                let seg_data = ptcl[cmd_ix + 2u];
+                let backdrop = bitcast<i32>(ptcl[cmd_ix + 3u]);
+                area = fill_path(size_and_rule, seg_data, backdrop, local_xy);
+                cmd_ix += 4u;
+            }
+            case CMD_STROKE: {
+                cmd_ix += 3u;
+            }
+            case CMD_SOLID: {
+                area = 1.0;
+                cmd_ix += 1u;
+            }
+            case CMD_COLOR: {
+                if area >= 0.5 {
+                    index = ptcl[cmd_ix + 1u] & PICK_INDEX_MASK;
+                }
+                cmd_ix += 2u;
+            }
+            // Pick encodings only paint solid colors, and blurred rectangles don't hide the
+            // draw objects below them.
+            case CMD_LIN_GRAD, CMD_RAD_GRAD, CMD_SWEEP_GRAD, CMD_BLUR_RECT: {
+                cmd_ix += 3u;
+            }
+            case CMD_IMAGE: {
+                cmd_ix += 2u;
+            }
+            case CMD_BEGIN_CLIP: {
+                if clip_depth < BLEND_STACK_SPLIT {
+                    index_stack[clip_depth] = index;
+                } else {
+                    let level = clip_depth - BLEND_STACK_SPLIT;
+                    blend_spill[blend_offset + level * TILE_WIDTH * TILE_HEIGHT + spill_ix] = index;
+                }
+                clip_depth += 1u;
+                index = PICK_NONE;
+                cmd_ix += 1u;
+            }
+            case CMD_END_CLIP: {
+                clip_depth -= 1u;
+                var below: u32;
+                if clip_depth < BLEND_STACK_SPLIT {
+                    below = index_stack[clip_depth];
+                } else {
+                    let level = clip_depth - BLEND_STACK_SPLIT;
+                    below = blend_spill[blend_offset + level * TILE_WIDTH * TILE_HEIGHT + spill_ix];
+                }
+                if area < 0.5 || index == PICK_NONE {
+                    index = below;
+                }
+                cmd_ix += 3u;
+            }
+            case CMD_JUMP: {
+                cmd_ix = ptcl[cmd_ix + 1u];
+            }
+            default: {
+                done = true;
+            }
+        }
+    }
+    if all(pixel < vec2(config.target_width, config.target_height)) {
+        textureStore(output, vec2<i32>(pixel), vec4(index, 0u, 0u, 0u));
+    }
+}
+#else
+@compute @workgroup_size(16, 16)
+fn main(
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(workgroup_id) wg_id: vec3<u32>,
+) {
+    let tile_xy = wg_id.xy + region.offset;
+    let tile_ix = tile_xy.y * config.width_in_tiles + tile_xy.x;
+    // The position of the pixel in the tile and in the target, and of its center.
+    let pixel = tile_xy * vec2(TILE_WIDTH, TILE_HEIGHT) + local_id.xy;
+    let local_xy = vec2<f32>(local_id.xy);
+    let xy = vec2<f32>(pixel) + 0.5;
+    let spill_ix = local_id.y * TILE_WIDTH + local_id.x;
+    var rgba = decode(unpack4x8unorm(config.base_color));
+    var area = 0.0;
+    var blend_stack: array<vec4<f32>, BLEND_STACK_SPLIT>;
+    var clip_depth = 0u;
+    var cmd_ix = tile_ix * PTCL_INITIAL_ALLOC;
+    // The first word of each command list is the blend spill offset.
+    let blend_offset = ptcl[cmd_ix];
+    cmd_ix += 1u;
+    var done = false;
+    while !done {
+        switch ptcl[cmd_ix] {
+            case CMD_FILL: {
+                let size_and_rule = ptcl[cmd_ix + 1u];
+                let seg_data = ptcl[cmd_ix + 2u];
+                let backdrop = bitcast<i32>(ptcl[cmd_ix + 3u]);
+                area = fill_path(size_and_rule, seg_data, backdrop, local_xy);
+                cmd_ix += 4u;
+            }
+            case CMD_STROKE: {
+                // Strokes are expanded to fills before coarse rasterization.
+                cmd_ix += 3u;
+            }
+            case CMD_SOLID: {
+                area = 1.0;
+                cmd_ix += 1u;
+            }
+            case CMD_COLOR: {
+                let color = decode(unpack4x8unorm(ptcl[cmd_ix + 1u]));
+                rgba = paint_over(rgba, color, area);
+                cmd_ix += 2u;
+            }
+            case CMD_LIN_GRAD: {
+                let index_mode = ptcl[cmd_ix + 1u];
+                let info_offset = ptcl[cmd_ix + 2u];
+                let line_x = read_f32(info_offset);
+                let line_y = read_f32(info_offset + 1u);
+                let line_c = read_f32(info_offset + 2u);
+                let t = extend_mode(line_x * xy.x + line_y * xy.y + line_c, index_mode & 0x3u);
+                rgba = paint_over(rgba, sample_ramp(index_mode >> 2u, t), area);
+                cmd_ix += 3u;
+            }
+            case CMD_RAD_GRAD: {
+                let index_mode = ptcl[cmd_ix + 1u];
+                let info_offset = ptcl[cmd_ix + 2u];
+                let t = radial_gradient(info_offset, xy, index_mode & 0x3u);
+                let color = sample_ramp(index_mode >> 2u, t.x) * t.y;
+                rgba = paint_over(rgba, color, area);
+                cmd_ix += 3u;
+            }
+            case CMD_SWEEP_GRAD: {
+                let index_mode = ptcl[cmd_ix + 1u];
+                let info_offset = ptcl[cmd_ix + 2u];
+                let p = apply_transform(read_transform(info_offset), xy);
+                let t0 = read_f32(info_offset + 6u);
+                let t1 = read_f32(info_offset + 7u);
+                let angle = fract(atan2(p.y, p.x) / TAU);
+                let t = extend_mode((angle - t0) / (t1 - t0), index_mode & 0x3u);
+                rgba = paint_over(rgba, sample_ramp(index_mode >> 2u, t), area);
+                cmd_ix += 3u;
+            }
+            case CMD_IMAGE: {
+                let image = read_image(ptcl[cmd_ix + 1u]);
+                rgba = paint_over(rgba, sample_image(image, xy), area);
+                cmd_ix += 2u;
+            }
+            case CMD_BEGIN_CLIP: {
+                if clip_depth < BLEND_STACK_SPLIT {
+                    blend_stack[clip_depth] = rgba;
+                } else {
+                    let level = clip_depth - BLEND_STACK_SPLIT;
+                    let ix = blend_offset + level * TILE_WIDTH * TILE_HEIGHT + spill_ix;
+                    blend_spill[ix] = pack_spill(rgba);
+                }
+                clip_depth += 1u;
+                rgba = vec4(0.0);
+                cmd_ix += 1u;
+            }
+            case CMD_END_CLIP: {
+                let blend = ptcl[cmd_ix + 1u];
+                let alpha = bitcast<f32>(ptcl[cmd_ix + 2u]);
+                clip_depth -= 1u;
+                var bg: vec4<f32>;
+                if clip_depth < BLEND_STACK_SPLIT {
+                    bg = blend_stack[clip_depth];
+                } else {
+                    let level = clip_depth - BLEND_STACK_SPLIT;
+                    let ix = blend_offset + level * TILE_WIDTH * TILE_HEIGHT + spill_ix;
+                    bg = decode(unpack4x8unorm(blend_spill[ix]));
+                }
+                rgba = blend_mix_compose(bg, rgba * (area * alpha), blend);
+                cmd_ix += 3u;
+            }
+            case CMD_JUMP: {
+                cmd_ix = ptcl[cmd_ix + 1u];
+            }
+            case CMD_BLUR_RECT: {
+                let info_offset = ptcl[cmd_ix + 1u];
+                let color = decode(unpack4x8unorm(ptcl[cmd_ix + 2u]));
+                let p = apply_transform(read_transform(info_offset), xy);
+                let size = vec2(read_f32(info_offset + 6u), read_f32(info_offset + 7u));
+                let radius = read_f32(info_offset + 8u);
+                let std_dev = read_f32(info_offset + 9u);
+                let coverage = blurred_rounded_rect(p, size, radius, std_dev);
+                rgba = paint_over(rgba, color * coverage, area);
+                cmd_ix += 3u;
+            }
+            // `CMD_END`, or an unknown command.
+            default: {
+                done = true;
+            }
+        }
+    }
+    if all(pixel < vec2(config.target_width, config.target_height)) {
+        let rgba_sep = vec4(rgba.rgb / max(rgba.a, 1e-6), rgba.a);
+        textureStore(output, vec2<i32>(pixel), rgba_sep);
+    }
+}
+#endif
+"#;
+
+/// The parts of the filter shaders which are shared between them, which are prepended to
+/// [`FILTER_BLUR_WGSL`] and [`FILTER_COMPOSITE_WGSL`].
+///
+/// The layout of the config matches [`crate::render::FilterConfig`].
+#[cfg(feature = "wgpu")]
+const FILTER_COMMON_WGSL: &str = r#"
+struct Config {
+    size: vec2<u32>,
+    offset: vec2<i32>,
+    color: vec4<f32>,
+    std_dev: f32,
+    radius: u32,
+    kind: u32,
+    linear: u32,
+    matrix: array<vec4<f32>, 5>,
+    mask: u32,
+}
+
+const FILTER_DROP_SHADOW: u32 = 1u;
+const MASK_NONE: u32 = 0u;
+const MASK_LUMINANCE: u32 = 2u;
+
+@group(0) @binding(0)
+var<uniform> config: Config;
+
+fn in_bounds(xy: vec2<i32>) -> bool {
+    return all(xy >= vec2(0)) && all(xy < vec2<i32>(config.size));
+}
+
+// The weight of a texel `k` texels away in the gaussian kernel.
+fn weight(k: i32) -> f32 {
+    let x = f32(k) / max(config.std_dev, 1e-6);
+    return exp(-0.5 * x * x);
+}
+"#;
+
+/// Blur the content of a filter layer horizontally, into a premultiplied image.
+#[cfg(feature = "wgpu")]
+const FILTER_BLUR_WGSL: &str = r#"
+@group(0) @binding(1)
+var content: texture_2d<f32>;
+
+@group(0) @binding(2)
+var output: texture_storage_2d<rgba16float, write>;
+
+fn load_premul(xy: vec2<i32>) -> vec4<f32> {
+    if !in_bounds(xy) {
+        return vec4(0.0);
+    }
+    let c = textureLoad(content, xy, 0);
+    return vec4(c.rgb * c.a, c.a);
+}
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if any(global_id.xy >= config.size) {
+        return;
+    }
+    let xy = vec2<i32>(global_id.xy);
+    let radius = i32(config.radius);
+    var sum = vec4(0.0);
+    var total = 0.0;
+    for (var k = -radius; k <= radius; k += 1) {
+        let w = weight(k);
+        sum += w * load_premul(xy + vec2(k, 0));
+        total += w;
+    }
+    textureStore(output, xy, sum / total);
+}
+"#;
+
+/// Blur the output of `filter_blur` vertically, and write the result of the filter as
+/// separated sRGB, after applying the color matrix and the mask.
+#[cfg(feature = "wgpu")]
+const FILTER_COMPOSITE_WGSL: &str = r#"
+@group(0) @binding(1)
+var blurred: texture_2d<f32>;
+
+@group(0) @binding(2)
+var content: texture_2d<f32>;
+
+@group(0) @binding(3)
+var mask: texture_2d<f32>;
+
+@group(0) @binding(4)
+var output: texture_storage_2d<rgba8unorm, write>;
+
+fn load_blurred(xy: vec2<i32>) -> vec4<f32> {
+    if !in_bounds(xy) {
+        return vec4(0.0);
+    }
+    return textureLoad(blurred, xy, 0);
+}
+
+fn linear_to_srgb(rgb: vec3<f32>) -> vec3<f32> {
+    let c = clamp(rgb, vec3(0.0), vec3(1.0));
+    return select(1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3(0.0031308));
+}
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if any(global_id.xy >= config.size) {
+        return;
+    }
+    let xy = vec2<i32>(global_id.xy);
+    let src = xy - config.offset;
+    let radius = i32(config.radius);
+    var sum = vec4(0.0);
+    var total = 0.0;
+    for (var k = -radius; k <= radius; k += 1) {
+        let w = weight(k);
+        sum += w * load_blurred(src + vec2(0, k));
+        total += w;
+    }
+    var rgba = sum / total;
+    if config.kind == FILTER_DROP_SHADOW {
+        let c = textureLoad(content, xy, 0);
+        let fg = vec4(c.rgb * c.a, c.a);
+        rgba = fg + config.color * rgba.a * (1.0 - fg.a);
+    }
+    var rgb = rgba.rgb / max(rgba.a, 1e-6);
+    if config.linear != 0u {
+        rgb = linear_to_srgb(rgb);
+    }
+    let m = config.matrix;
+    let transformed = m[0] * rgb.r + m[1] * rgb.g + m[2] * rgb.b + m[3] * rgba.a + m[4];
+    var result = clamp(transformed, vec4(0.0), vec4(1.0));
+    if config.mask != MASK_NONE {
+        let mask_rgba = textureLoad(mask, xy, 0);
+        var coverage = mask_rgba.a;
+        if config.mask == MASK_LUMINANCE {
+            var mask_rgb = mask_rgba.rgb;
+            if config.linear != 0u {
+                mask_rgb = linear_to_srgb(mask_rgb);
+            }
+            coverage *= dot(mask_rgb, vec3(0.2126, 0.7152, 0.0722));
+        }
+        result.a *= coverage;
+    }
+    textureStore(output, xy, result);
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "wgpu")]
+    mod source {
+        use super::super::*;
+
+        const SOURCE: &str = "a
+#ifdef one
+b
+    #ifdef two
+c
+    #else
+d
+    #endif
+#else
+e
+#endif
+f
+";
+
+        #[test]
+        fn branches_of_defined_names_are_kept() {
+            assert_eq!(preprocess(SOURCE, &[]), "a\ne\nf\n");
+            assert_eq!(preprocess(SOURCE, &["one"]), "a\nb\nd\nf\n");
+            assert_eq!(preprocess(SOURCE, &["one", "two"]), "a\nb\nc\nf\n");
+            assert_eq!(preprocess(SOURCE, &["two"]), "a\ne\nf\n");
+        }
+
+        #[test]
+        #[should_panic(expected = "`#ifdef` without `#endif`")]
+        fn unbalanced_directives_panic() {
+            preprocess("#ifdef one\na\n", &[]);
+        }
+
+        #[test]
+        fn rgba8_shaders_store_unorm_colors() {
+            let defines = output_defines(OutputFormat::Rgba8);
+            for source in [FINE_WGSL, TILE_COPY_WGSL] {
+                let source = preprocess(source, defines);
+                assert!(source.contains("texture_storage_2d<rgba8unorm, write>"));
+                assert!(!source.contains("rgba16float"));
+                assert!(!source.contains('#'));
+            }
+            // Colors are blended as they are encoded.
+            let fine = preprocess(FINE_WGSL, defines);
+            assert!(!fine.contains("srgb_to_linear(rgb)"));
+        }
+
+        #[test]
+        fn float_shaders_store_linear_colors() {
+            let defines = output_defines(OutputFormat::Rgba16Float);
+            for source in [FINE_WGSL, TILE_COPY_WGSL] {
+                let source = preprocess(source, defines);
+                assert!(source.contains("texture_storage_2d<rgba16float, write>"));
+                assert!(!source.contains("rgba8unorm"));
+                assert!(!source.contains('#'));
+            }
+            let fine = preprocess(FINE_WGSL, defines);
+            assert!(fine.contains("return vec4(srgb_to_linear(rgb) * rgba.a, rgba.a);"));
+        }
+
+        #[test]
+        fn pick_shaders_store_indices() {
+            for source in [FINE_WGSL, TILE_COPY_WGSL] {
+                let source = preprocess(source, PICK_DEFINES);
+                assert!(source.contains("texture_storage_2d<r32uint, write>"));
+                assert!(!source.contains("rgba8unorm") && !source.contains("rgba16float"));
+                assert!(!source.contains('#'));
+            }
+            // Only the main function of pick images is kept.
+            let fine = preprocess(FINE_WGSL, PICK_DEFINES);
+            assert_eq!(fine.matches("fn main(").count(), 1);
+            assert!(fine.contains("PICK_INDEX_MASK"));
+        }
+
+        #[test]
+        fn msaa_shaders_bind_the_mip_table_after_the_mask_lut() {
+            let area = preprocess(FINE_WGSL, &[]);
+            assert!(area.contains("@group(0) @binding(8)\nvar<storage> mip_table"));
+            let msaa = preprocess(FINE_WGSL, &["msaa"]);
+            assert!(msaa.contains("@group(0) @binding(9)\nvar<storage> mip_table"));
+        }
+
+        #[test]
+        fn fine_shaders_bind_the_region_last() {
+            let area = preprocess(FINE_WGSL, &[]);
+            assert!(area.contains("@group(0) @binding(9)\nvar<uniform> region"));
+            let msaa = preprocess(FINE_WGSL, &["msaa"]);
+            assert!(msaa.contains("@group(0) @binding(10)\nvar<uniform> region"));
+            let pick = preprocess(FINE_WGSL, PICK_DEFINES);
+            assert!(pick.contains("@group(0) @binding(9)\nvar<uniform> region"));
+        }
+    }
+
+    /// Reference renders of the CPU pipeline, at the precision of float targets.
+    #[cfg(feature = "cpu")]
+    mod render {
+        use peniko::kurbo::{Affine, Rect};
+        use peniko::{BlendMode, Compose, Fill, Mix};
+
+        use crate::color_convert::srgb_to_linear;
+        use crate::peniko::Color;
+        use crate::{AaConfig, CpuRenderer, OutputFormat, RenderParams, Scene};
+
+        /// The linear color of a pixel of `color` drawn over black.
+        fn render(color: Color) -> [f32; 4] {
+            let mut scene = Scene::new();
+            let rect = Rect::new(0.0, 0.0, 4.0, 4.0);
+            scene.fill(Fill::NonZero, Affine::IDENTITY, color, None, &rect);
+            render_scene(&scene)
+        }
+
+        /// The linear color of the first pixel of `scene` drawn over black.
+        fn render_scene(scene: &Scene) -> [f32; 4] {
+            let params = RenderParams {
+                base_color: Color::BLACK,
+                width: 4,
+                height: 4,
+                antialiasing_method: AaConfig::Area,
+            };
+            let pixels = CpuRenderer::with_output_format(OutputFormat::Rgba16Float)
+                .render_to_rgba_f32(scene, &params);
+            pixels[..4].try_into().unwrap()
+        }
+
+        fn check(actual: [f32; 4], expected: [f32; 4]) {
+            let close = actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| (a - e).abs() <= 2e-3);
+            assert!(close, "expected {expected:?}, got {actual:?}");
+        }
+
+        #[test]
+        fn float_output_is_linear() {
+            let gray = srgb_to_linear(128.0 / 255.0);
+            check(
+                render(Color::from_rgb8(128, 128, 128)),
+                [gray, gray, gray, 1.0],
+            );
+        }
+
+        #[test]
+        fn colors_are_blended_in_linear_light() {
+            // Half of white over black is half of the light, not of the encoded value.
+            let blended = 128.0 / 255.0;
+            check(
+                render(Color::from_rgba8(255, 255, 255, 128)),
+                [blended, blended, blended, 1.0],
+            );
+        }
+
+        #[test]
+        fn blended_colors_are_not_clamped() {
+            // Adding white to white is twice as bright as white.
+            let mut scene = Scene::new();
+            let rect = Rect::new(0.0, 0.0, 4.0, 4.0);
+            scene.fill(Fill::NonZero, Affine::IDENTITY, Color::WHITE, None, &rect);
+            let plus = BlendMode::new(Mix::Normal, Compose::Plus);
+            scene.push_layer(plus, 1.0, Affine::IDENTITY, &rect);
+            scene.fill(Fill::NonZero, Affine::IDENTITY, Color::WHITE, None, &rect);
+            scene.pop_layer();
+            check(render_scene(&scene), [2.0, 2.0, 2.0, 1.0]);
+        }
+    }
+}
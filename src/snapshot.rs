@@ -0,0 +1,348 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Golden image comparisons.
+//!
+//! [`Snapshots`] compares rendered images against reference PNG files ("golden images")
+//! stored in a directory, which is useful for testing code which draws into a [`Scene`].
+//! When a comparison fails, the rendered image and an image highlighting the differing
+//! pixels are written next to the golden image.
+//!
+//! Golden images are created or replaced by running with the `VELLO_UPDATE_SNAPSHOTS`
+//! environment variable set. The golden images of the crate's own tests are in
+//! `tests/snapshots`, and are checked when testing with the `cpu` feature.
+//!
+//! [`Scene`]: crate::Scene
+
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+pub use crate::image_io::{decode_png, encode_png};
+
+/// Environment variable which, when set, causes golden images to be overwritten.
+pub const UPDATE_SNAPSHOTS_ENV: &str = "VELLO_UPDATE_SNAPSHOTS";
+
+/// Errors from comparing an image against its golden image.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    /// There is no golden image for this snapshot.
+    #[error(
+        "Golden image {} doesn't exist; run with `VELLO_UPDATE_SNAPSHOTS=1` to create it",
+        .0.display()
+    )]
+    MissingGolden(PathBuf),
+    /// The rendered image has different dimensions to the golden image.
+    #[error("Expected a {expected:?} image but rendered {actual:?}")]
+    SizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// The pixels of an image don't have the length its dimensions need.
+    #[error("Expected {expected} bytes of pixels but got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    /// Too many pixels differ from the golden image.
+    #[error(
+        "{differing_pixels} pixels differ by up to {max_difference} from the golden image, see {}",
+        .diff_path.display()
+    )]
+    Mismatch {
+        differing_pixels: usize,
+        max_difference: u8,
+        diff_path: PathBuf,
+    },
+    /// Reading or writing a file failed.
+    #[error("Snapshot I/O failed")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to decode golden image")]
+    PngDecoding(#[from] png::DecodingError),
+    #[error("Failed to encode snapshot image")]
+    PngEncoding(#[from] png::EncodingError),
+}
+
+/// The result of comparing two images of the same size.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    /// The number of pixels where a channel differs by more than the tolerance.
+    pub differing_pixels: usize,
+    /// The largest difference of any channel.
+    pub max_difference: u8,
+    /// An RGBA8 image, where differing pixels are red and others are a faded copy of
+    /// the expected image.
+    pub diff: Vec<u8>,
+}
+
+/// Compare two RGBA8 images of the same size, pixel by pixel.
+///
+/// A pixel differs if any of its channels differ by more than `tolerance`. Fails with
+/// [`SnapshotError::LengthMismatch`] if the images have different lengths.
+pub fn compare(
+    expected: &[u8],
+    actual: &[u8],
+    tolerance: u8,
+) -> Result<Comparison, SnapshotError> {
+    if expected.len() != actual.len() {
+        return Err(SnapshotError::LengthMismatch {
+            expected: expected.len(),
+            actual: actual.len(),
+        });
+    }
+    let mut differing_pixels = 0;
+    let mut max_difference = 0;
+    let mut diff = Vec::with_capacity(expected.len());
+    for (expected, actual) in expected.chunks_exact(4).zip(actual.chunks_exact(4)) {
+        let difference = expected
+            .iter()
+            .zip(actual)
+            .map(|(e, a)| e.abs_diff(*a))
+            .max()
+            .unwrap_or_default();
+        max_difference = max_difference.max(difference);
+        if difference > tolerance {
+            differing_pixels += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            diff.extend_from_slice(&[expected[0], expected[1], expected[2], expected[3] / 4]);
+        }
+    }
+    Ok(Comparison {
+        differing_pixels,
+        max_difference,
+        diff,
+    })
+}
+
+/// A directory of golden images, and the tolerances used when comparing against them.
+#[derive(Clone, Debug)]
+pub struct Snapshots {
+    directory: PathBuf,
+    tolerance: u8,
+    max_differing_pixels: usize,
+}
+
+impl Snapshots {
+    /// Use golden images stored in `directory`.
+    ///
+    /// By default, images must match exactly.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            tolerance: 0,
+            max_differing_pixels: 0,
+        }
+    }
+
+    /// Set the largest difference in any channel for which pixels are still considered equal.
+    pub fn tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Set the number of pixels which may exceed the tolerance before a comparison fails.
+    pub fn max_differing_pixels(mut self, max_differing_pixels: usize) -> Self {
+        self.max_differing_pixels = max_differing_pixels;
+        self
+    }
+
+    /// The path of the golden image for the snapshot called `name`.
+    pub fn golden_path(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{name}.png"))
+    }
+
+    /// Compare an RGBA8 image against the golden image called `name`.
+    ///
+    /// On failure, the image is written to `{name}.actual.png` and the differences to
+    /// `{name}.diff.png`.
+    pub fn check(
+        &self,
+        name: &str,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Result<(), SnapshotError> {
+        let len = width as usize * height as usize * 4;
+        if rgba.len() != len {
+            return Err(SnapshotError::LengthMismatch {
+                expected: len,
+                actual: rgba.len(),
+            });
+        }
+        let golden_path = self.golden_path(name);
+        if std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some() {
+            write_png(&golden_path, width, height, rgba)?;
+            return Ok(());
+        }
+        if !golden_path.exists() {
+            return Err(SnapshotError::MissingGolden(golden_path));
+        }
+        let (expected_width, expected_height, expected) =
+            decode_png(&std::fs::read(&golden_path)?)?;
+        if (expected_width, expected_height) != (width, height) {
+            write_png(&self.output_path(name, "actual"), width, height, rgba)?;
+            return Err(SnapshotError::SizeMismatch {
+                expected: (expected_width, expected_height),
+                actual: (width, height),
+            });
+        }
+        let comparison = compare(&expected, rgba, self.tolerance)?;
+        if comparison.differing_pixels <= self.max_differing_pixels {
+            return Ok(());
+        }
+        let diff_path = self.output_path(name, "diff");
+        write_png(&self.output_path(name, "actual"), width, height, rgba)?;
+        write_png(&diff_path, width, height, &comparison.diff)?;
+        Err(SnapshotError::Mismatch {
+            differing_pixels: comparison.differing_pixels,
+            max_difference: comparison.max_difference,
+            diff_path,
+        })
+    }
+
+    /// Render `scene` on the CPU and compare it against the golden image called `name`.
+    #[cfg(feature = "cpu")]
+    pub fn check_scene(
+        &self,
+        renderer: &mut crate::CpuRenderer,
+        name: &str,
+        scene: &crate::Scene,
+        params: &crate::RenderParams,
+    ) -> Result<(), SnapshotError> {
+        let rgba = renderer.render_to_rgba(scene, params);
+        self.check(name, params.width, params.height, &rgba)
+    }
+
+    fn output_path(&self, name: &str, kind: &str) -> PathBuf {
+        self.directory.join(format!("{name}.{kind}.png"))
+    }
+}
+
+fn write_png(path: &Path, width: u32, height: u32, rgba: &[u8]) -> Result<(), SnapshotError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, encode_png(width, height, rgba)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_counts_differing_pixels() {
+        let expected = [10, 20, 30, 255, 0, 0, 0, 255];
+        let actual = [11, 20, 30, 255, 0, 0, 9, 255];
+        let comparison = compare(&expected, &actual, 1).unwrap();
+        assert_eq!(comparison.differing_pixels, 1);
+        assert_eq!(comparison.max_difference, 9);
+        assert_eq!(&comparison.diff[4..], &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn mismatched_lengths_are_errors() {
+        assert!(matches!(
+            compare(&[0; 8], &[0; 4], 0),
+            Err(SnapshotError::LengthMismatch {
+                expected: 8,
+                actual: 4
+            })
+        ));
+        let snapshots = Snapshots::new(std::env::temp_dir());
+        assert!(matches!(
+            snapshots.check("short", 2, 2, &[0; 12]),
+            Err(SnapshotError::LengthMismatch {
+                expected: 16,
+                actual: 12
+            })
+        ));
+    }
+
+    /// Golden images of scenes rendered by the CPU pipeline.
+    ///
+    /// The shapes are aligned to pixels, so the images only depend on blending.
+    #[cfg(feature = "cpu")]
+    mod scenes {
+        use std::path::Path;
+
+        use peniko::kurbo::{Affine, BezPath, Rect, Shape};
+        use peniko::{Color, Fill, Mix};
+
+        use crate::snapshot::Snapshots;
+        use crate::{AaConfig, CpuRenderer, RenderParams, Scene};
+
+        fn check(name: &str, scene: &Scene) {
+            let snapshots =
+                Snapshots::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots"))
+                    .tolerance(1);
+            let params = RenderParams {
+                base_color: Color::WHITE,
+                width: 32,
+                height: 32,
+                antialiasing_method: AaConfig::Area,
+            };
+            let mut renderer = CpuRenderer::new();
+            if let Err(err) = snapshots.check_scene(&mut renderer, name, scene, &params) {
+                panic!("{err}");
+            }
+        }
+
+        fn fill(scene: &mut Scene, color: Color, shape: &impl Shape) {
+            scene.fill(Fill::NonZero, Affine::IDENTITY, color, None, shape);
+        }
+
+        #[test]
+        fn rects() {
+            let mut scene = Scene::new();
+            let red = Color::from_rgb8(255, 0, 0);
+            fill(&mut scene, red, &Rect::new(4.0, 4.0, 28.0, 20.0));
+            let blue = Color::from_rgba8(0, 0, 255, 128);
+            fill(&mut scene, blue, &Rect::new(12.0, 12.0, 28.0, 28.0));
+            check("rects", &scene);
+        }
+
+        #[test]
+        fn clip() {
+            let mut scene = Scene::new();
+            let clip = Rect::new(8.0, 8.0, 24.0, 24.0);
+            scene.push_layer(Mix::Clip, 1.0, Affine::IDENTITY, &clip);
+            let green = Color::from_rgb8(0, 128, 0);
+            fill(&mut scene, green, &Rect::new(0.0, 0.0, 32.0, 32.0));
+            scene.pop_layer();
+            check("clip", &scene);
+        }
+
+        #[test]
+        fn layer_alpha() {
+            let mut scene = Scene::new();
+            let bounds = Rect::new(0.0, 0.0, 32.0, 32.0);
+            scene.push_layer(Mix::Normal, 0.5, Affine::IDENTITY, &bounds);
+            fill(&mut scene, Color::BLACK, &Rect::new(8.0, 8.0, 24.0, 24.0));
+            scene.pop_layer();
+            check("layer_alpha", &scene);
+        }
+
+        #[test]
+        fn transform() {
+            let mut scene = Scene::new();
+            let teal = Color::from_rgb8(0, 128, 128);
+            let square = Rect::new(0.0, 0.0, 8.0, 8.0);
+            let transform = Affine::translate((4.0, 4.0)) * Affine::scale(2.0);
+            scene.fill(Fill::NonZero, transform, teal, None, &square);
+            let transform = Affine::translate((24.0, 24.0)) * Affine::scale(0.5);
+            scene.fill(Fill::NonZero, transform, teal, None, &square);
+            check("transform", &scene);
+        }
+
+        #[test]
+        fn even_odd() {
+            let mut ring = BezPath::new();
+            ring.extend(Rect::new(4.0, 4.0, 28.0, 28.0).path_elements(0.1));
+            ring.extend(Rect::new(10.0, 10.0, 22.0, 22.0).path_elements(0.1));
+            let mut scene = Scene::new();
+            let blue = Color::from_rgb8(0, 0, 255);
+            scene.fill(Fill::EvenOdd, Affine::IDENTITY, blue, None, &ring);
+            check("even_odd", &scene);
+        }
+    }
+}
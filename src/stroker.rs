@@ -0,0 +1,566 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Expansion of strokes into fills on the CPU, as a reference for the GPU stroker.
+//!
+//! The scene's encoding is decoded and drawn again into a new scene, with every stroke
+//! replaced by a fill of its outline computed by [`kurbo::stroke`](peniko::kurbo::stroke).
+//! Dashes have already been expanded when the stroke was drawn, so only the dash segments
+//! are stroked here.
+
+use std::borrow::Cow;
+
+use peniko::kurbo::{self, StrokeOpts};
+use peniko::{Fill, Style};
+use vello_encoding::Encoding;
+
+use crate::Scene;
+use crate::decode::{self, Command};
+use crate::filter::{FilterLayer, Mask};
+
+/// The largest distance between the expanded outline of a stroke and the exact outline,
+/// in pixels.
+const TOLERANCE: f64 = 0.01;
+
+/// Where the outlines of strokes are computed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum StrokeExpansion {
+    /// Strokes are expanded by the flatten stage of the pipeline.
+    #[default]
+    Gpu,
+    /// Strokes are expanded on the CPU with kurbo's stroker before rendering, and drawn
+    /// as fills.
+    ///
+    /// This is much slower, as the scene is re-encoded for every render, and is intended
+    /// as a reference to compare the output of the GPU stroker against. As the transform of
+    /// each stroke is known, the outlines are computed to within a hundredth of a pixel.
+    Cpu,
+}
+
+/// The scene to render with the given stroke expansion.
+pub(crate) fn expand_strokes(scene: &Scene, expansion: StrokeExpansion) -> Cow<'_, Scene> {
+    if expansion == StrokeExpansion::Gpu {
+        return Cow::Borrowed(scene);
+    }
+    let mut expanded = Scene::new();
+    expanded.encoding = expand(&scene.encoding);
+    expanded.filters = scene
+        .filters
+        .iter()
+        .map(|layer| FilterLayer {
+            filter: layer.filter,
+            mask: layer.mask.as_ref().map(|mask| Mask {
+                kind: mask.kind,
+                content: expand(&mask.content),
+            }),
+            content: expand(&layer.content),
+            // Hit testing uses the original scene, so the ids aren't needed.
+            draw_ids: Vec::new(),
+            origin: layer.origin,
+            image: layer.image.clone(),
+        })
+        .collect();
+    Cow::Owned(expanded)
+}
+
+/// Re-encode `encoding` with its strokes expanded into fills.
+fn expand(encoding: &Encoding) -> Encoding {
+    let mut scene = Scene::new();
+    decode::decode(encoding, |command| draw(&mut scene, encoding, command));
+    scene.encoding
+}
+
+/// Draw a decoded command into `scene`, expanding it if it is a stroke.
+fn draw<'a>(scene: &mut Scene, encoding: &'a Encoding, command: Command<'a>) {
+    match command {
+        Command::Draw {
+            path,
+            transform,
+            style: Style::Stroke(stroke),
+            brush,
+            brush_transform,
+        } => {
+            let scale = decode::max_scale(transform);
+            if scale == 0.0 || !scale.is_finite() {
+                return;
+            }
+            let outline = kurbo::stroke(&path, &stroke, &StrokeOpts::default(), TOLERANCE / scale);
+            scene.fill(Fill::NonZero, transform, &brush, brush_transform, &outline);
+        }
+        // Stroked glyphs are expanded like other strokes, unless their font can't be read.
+        Command::Glyphs { run, glyphs, brush }
+            if matches!(decode::decode_style(&run.style), Style::Stroke(_)) =>
+        {
+            if !decode::outline_glyphs(run, glyphs, &brush, |command| {
+                draw(scene, encoding, command);
+            }) {
+                decode::draw_glyph_run(scene, encoding, run, glyphs, &brush);
+            }
+        }
+        command => decode::redraw(scene, encoding, command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::color::palette::css;
+    use peniko::kurbo::{Affine, BezPath, Cap, Join, Point, Shape, Stroke};
+
+    use super::*;
+
+    const JOINS: [Join; 3] = [Join::Bevel, Join::Miter, Join::Round];
+    const CAPS: [Cap; 3] = [Cap::Butt, Cap::Square, Cap::Round];
+    const DASHES: &[&[f64]] = &[&[], &[4.0, 2.0]];
+    /// Dashes of zero length, which only draw their caps.
+    const ZERO_LENGTH_DASHES: &[&[f64]] = &[&[0.0, 3.0]];
+
+    /// Paths with curves, corners and closed subpaths, whose points are exact in `f32`.
+    fn paths() -> Vec<BezPath> {
+        [
+            "M4 4 C28 4 4 28 28 28",
+            "M4 28 L16 4 L28 28",
+            "M6 6 L26 8 L14 26 Z",
+            "M4 16 Q16 0 28 16 T28 28",
+        ]
+        .into_iter()
+        .map(|svg| BezPath::from_svg(svg).unwrap())
+        .collect()
+    }
+
+    /// Paths with segments of zero length, control points which coincide with their end
+    /// points, and turns back on themselves.
+    fn degenerate_paths() -> Vec<BezPath> {
+        [
+            "M16 16 L16 16",
+            "M4 4 C4 4 28 28 28 28",
+            "M8 8 L24 24 L24 24 L8 24",
+            "M4 16 L28 16 L8 16",
+            "M16 16 C16 16 16 16 16 16",
+        ]
+        .into_iter()
+        .map(|svg| BezPath::from_svg(svg).unwrap())
+        .collect()
+    }
+
+    /// Every combination of joins, caps and dash patterns, with a stroke of the given width.
+    fn strokes(width: f64, dashes: &'static [&'static [f64]]) -> impl Iterator<Item = Stroke> {
+        JOINS.into_iter().flat_map(move |join| {
+            CAPS.into_iter().flat_map(move |cap| {
+                dashes.iter().map(move |dashes| {
+                    Stroke::new(width)
+                        .with_join(join)
+                        .with_caps(cap)
+                        .with_dashes(1.0, dashes.iter().copied())
+                })
+            })
+        })
+    }
+
+    fn transforms() -> [Affine; 3] {
+        [
+            Affine::IDENTITY,
+            Affine::translate((16.0, 16.0)) * Affine::rotate(0.3) * Affine::scale(0.5),
+            Affine::scale_non_uniform(3.0, 0.25),
+        ]
+    }
+
+    /// A xorshift generator, so that the random cases are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u32) -> u32 {
+            (self.next() % u64::from(n)) as u32
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len() as u32) as usize]
+        }
+
+        /// A point within the target, on a grid of quarter pixels so that it's exact in `f32`.
+        fn point(&mut self) -> Point {
+            let mut coordinate = || f64::from(self.below(129)) * 0.25;
+            Point::new(coordinate(), coordinate())
+        }
+    }
+
+    /// Random paths of lines, quadratics and cubics, each with a random stroke whose caps,
+    /// join, miter limit and dashes are chosen independently.
+    fn random_strokes() -> Vec<(BezPath, Stroke)> {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        (0..64)
+            .map(|_| {
+                let mut path = BezPath::new();
+                for _ in 0..1 + rng.below(2) {
+                    path.move_to(rng.point());
+                    for _ in 0..1 + rng.below(4) {
+                        match rng.below(3) {
+                            0 => path.line_to(rng.point()),
+                            1 => path.quad_to(rng.point(), rng.point()),
+                            _ => path.curve_to(rng.point(), rng.point(), rng.point()),
+                        }
+                    }
+                    if rng.below(3) == 0 {
+                        path.close_path();
+                    }
+                }
+                let stroke = Stroke::new(rng.pick(&[0.5, 1.0, 3.0, 6.0]))
+                    .with_join(rng.pick(&JOINS))
+                    .with_start_cap(rng.pick(&CAPS))
+                    .with_end_cap(rng.pick(&CAPS))
+                    .with_miter_limit(rng.pick(&[1.0, 4.0, 10.0]))
+                    .with_dashes(f64::from(rng.below(4)), rng.pick(DASHES).iter().copied());
+                (path, stroke)
+            })
+            .collect()
+    }
+
+    fn stroked(stroke: &Stroke, transform: Affine, path: &BezPath) -> Scene {
+        let mut scene = Scene::new();
+        scene.stroke(stroke, transform, css::RED, None, path);
+        scene
+    }
+
+    /// The draws of `scene`, as their paths, transforms and styles.
+    fn draws(scene: &Scene) -> Vec<(BezPath, Affine, Style)> {
+        let mut draws = Vec::new();
+        decode::decode(&scene.encoding, |command| {
+            if let Command::Draw {
+                path,
+                transform,
+                style,
+                ..
+            } = command
+            {
+                draws.push((path, transform, style));
+            }
+        });
+        draws
+    }
+
+    /// Check that strokes are replaced by non-zero fills drawn with the same transforms,
+    /// whose outlines lie within the reach of the stroke around the path.
+    fn check_expansion(path: &BezPath, strokes: impl Iterator<Item = Stroke>, exact_count: bool) {
+        for stroke in strokes {
+            for transform in transforms() {
+                let scene = stroked(&stroke, transform, path);
+                let expanded = expand_strokes(&scene, StrokeExpansion::Cpu);
+                let (original, expanded) = (draws(&scene), draws(&expanded));
+                if exact_count {
+                    assert_eq!(original.len(), expanded.len(), "{stroke:?} {transform:?}");
+                } else {
+                    assert!(original.len() >= expanded.len());
+                }
+                // Miters reach furthest from the path, and square caps from its ends.
+                let width = stroke.width;
+                let reach = 0.5 * width * stroke.miter_limit.max(std::f64::consts::SQRT_2);
+                let bounds = path.bounding_box().inflate(reach, reach);
+                for ((_, original_transform, _), (outline, transform, style)) in
+                    original.iter().zip(&expanded)
+                {
+                    assert!(matches!(style, Style::Fill(Fill::NonZero)));
+                    assert_eq!(transform, original_transform);
+                    let outline_bounds = outline.bounding_box();
+                    let slack = 0.05 + width * 1e-6;
+                    assert!(
+                        outline_bounds.is_finite()
+                            && bounds.inflate(slack, slack).contains_rect(outline_bounds),
+                        "{stroke:?} {transform:?}: {outline_bounds:?} outside {bounds:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gpu_expansion_keeps_the_scene() {
+        let scene = stroked(&Stroke::new(2.0), Affine::IDENTITY, &paths()[0]);
+        assert!(matches!(
+            expand_strokes(&scene, StrokeExpansion::Gpu),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn strokes_are_expanded_into_fills() {
+        for path in paths() {
+            check_expansion(&path, strokes(3.0, DASHES), true);
+        }
+    }
+
+    #[test]
+    fn thin_strokes_are_expanded() {
+        for path in paths() {
+            check_expansion(&path, strokes(0.05, DASHES), true);
+        }
+    }
+
+    #[test]
+    fn huge_strokes_are_expanded() {
+        for path in paths() {
+            check_expansion(&path, strokes(1e5, DASHES), true);
+        }
+    }
+
+    #[test]
+    fn degenerate_segments_are_expanded() {
+        for path in degenerate_paths() {
+            check_expansion(&path, strokes(3.0, DASHES), false);
+            check_expansion(&path, strokes(1e5, DASHES), false);
+        }
+    }
+
+    #[test]
+    fn zero_length_dashes_are_expanded() {
+        for path in paths().iter().chain(&degenerate_paths()) {
+            check_expansion(path, strokes(3.0, ZERO_LENGTH_DASHES), false);
+        }
+    }
+
+    #[test]
+    fn random_strokes_are_expanded() {
+        for (path, stroke) in random_strokes() {
+            check_expansion(&path, std::iter::once(stroke), false);
+        }
+    }
+
+    #[test]
+    fn strokes_without_area_are_dropped() {
+        let path = &paths()[0];
+        for transform in [Affine::scale(0.0), Affine::scale_non_uniform(f64::NAN, 1.0)] {
+            let scene = stroked(&Stroke::new(2.0), transform, path);
+            assert!(draws(&expand_strokes(&scene, StrokeExpansion::Cpu)).is_empty());
+        }
+    }
+
+    #[test]
+    fn fills_are_kept() {
+        let mut scene = Scene::new();
+        let transform = Affine::translate((2.0, 3.0));
+        scene.fill(Fill::EvenOdd, transform, css::RED, None, &paths()[2]);
+        let expanded = draws(&expand_strokes(&scene, StrokeExpansion::Cpu));
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(expanded[0].0, draws(&scene)[0].0);
+        assert_eq!(expanded[0].1, transform);
+        assert!(matches!(expanded[0].2, Style::Fill(Fill::EvenOdd)));
+    }
+
+    /// Checks of the line soup produced by the flatten stage of the CPU pipeline, which is
+    /// what the `VALIDATION` debug layer checks on the GPU.
+    #[cfg(all(feature = "cpu", feature = "debug_layers"))]
+    mod validation {
+        use peniko::Color;
+        use vello_encoding::{LineSoup, Resolver};
+
+        use super::*;
+        use crate::cpu::CpuEngine;
+        use crate::debug::validate::{LineEndpoint, validate_line_soup};
+        use crate::recording::ImageFormat;
+        use crate::render::Render;
+        use crate::{AaConfig, RenderParams, shaders};
+
+        /// The endpoints of the lines which `scene` is flattened into that aren't shared with
+        /// another line of the same path.
+        fn unpaired_endpoints(scene: &Scene) -> Vec<LineEndpoint> {
+            let params = RenderParams {
+                base_color: Color::WHITE,
+                width: 32,
+                height: 32,
+                antialiasing_method: AaConfig::Area,
+            };
+            let mut engine = CpuEngine::new();
+            let shaders = shaders::cpu_shaders(&mut engine, ImageFormat::Rgba8);
+            let mut render = Render::new();
+            let recording = render.render_encoding_coarse(
+                scene.encoding(),
+                &mut Resolver::new(),
+                &shaders,
+                &params,
+                true,
+            );
+            engine.run_recording(&recording);
+            let captured = render.take_captured_buffers().unwrap();
+            let lines = engine.take_buffer(captured.lines).unwrap();
+            // The unused end of the buffer is zeroed, and each of those lines pairs its own
+            // endpoints.
+            validate_line_soup(&bytemuck::pod_collect_to_vec::<u8, LineSoup>(&lines))
+        }
+
+        /// Check that strokes are flattened into closed outlines, whether they are expanded
+        /// by the GPU stroker or are fills of the outlines computed by kurbo.
+        #[test]
+        fn random_strokes_are_watertight() {
+            for (path, stroke) in random_strokes() {
+                for transform in transforms() {
+                    let scene = stroked(&stroke, transform, &path);
+                    for expansion in [StrokeExpansion::Gpu, StrokeExpansion::Cpu] {
+                        let expanded = expand_strokes(&scene, expansion);
+                        let unpaired = unpaired_endpoints(&expanded);
+                        assert!(
+                            unpaired.is_empty(),
+                            "{path:?} {stroke:?} {transform:?} {expansion:?}: {unpaired:?}"
+                        );
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn joins_and_caps_are_watertight() {
+            for path in paths().iter().chain(&degenerate_paths()) {
+                for stroke in strokes(3.0, DASHES) {
+                    let scene = stroked(&stroke, Affine::IDENTITY, path);
+                    let unpaired = unpaired_endpoints(&scene);
+                    assert!(unpaired.is_empty(), "{path:?} {stroke:?}: {unpaired:?}");
+                }
+            }
+        }
+    }
+
+    /// Renders of expanded strokes by the CPU pipeline.
+    #[cfg(feature = "cpu")]
+    mod render {
+        use peniko::Color;
+
+        use super::*;
+        use crate::{AaConfig, CpuRenderer, RenderParams};
+
+        const SIZE: u32 = 32;
+
+        fn render(scene: &Scene, expansion: StrokeExpansion) -> Vec<u8> {
+            let params = RenderParams {
+                base_color: Color::WHITE,
+                width: SIZE,
+                height: SIZE,
+                antialiasing_method: AaConfig::Area,
+            };
+            let mut renderer = CpuRenderer::new();
+            renderer.set_stroke_expansion(expansion);
+            renderer.render_to_rgba(scene, &params)
+        }
+
+        /// The fill of the outline of a stroke computed by kurbo from the original path.
+        fn kurbo_outline(stroke: &Stroke, transform: Affine, path: &BezPath) -> Scene {
+            let tolerance = TOLERANCE / decode::max_scale(transform);
+            let outline = kurbo::stroke(path, stroke, &StrokeOpts::default(), tolerance);
+            let mut scene = Scene::new();
+            scene.fill(Fill::NonZero, transform, css::RED, None, &outline);
+            scene
+        }
+
+        /// The largest difference between the channels of two renders.
+        fn max_difference(a: &[u8], b: &[u8]) -> u8 {
+            a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap()
+        }
+
+        /// The fraction of pixels which differ by more than half of their coverage.
+        fn mismatched_pixels(a: &[u8], b: &[u8]) -> f64 {
+            let mismatched = a
+                .chunks_exact(4)
+                .zip(b.chunks_exact(4))
+                .filter(|(a, b)| max_difference(a, b) > 128)
+                .count();
+            mismatched as f64 / f64::from(SIZE * SIZE)
+        }
+
+        #[test]
+        fn expanded_strokes_match_kurbo() {
+            for path in paths() {
+                for stroke in strokes(3.0, DASHES) {
+                    for transform in transforms() {
+                        let scene = stroked(&stroke, transform, &path);
+                        let expected = render(
+                            &kurbo_outline(&stroke, transform, &path),
+                            StrokeExpansion::Gpu,
+                        );
+                        let expanded = render(&scene, StrokeExpansion::Cpu);
+                        assert!(
+                            max_difference(&expanded, &expected) <= 2,
+                            "{path:?} {stroke:?} {transform:?}"
+                        );
+                    }
+                }
+            }
+        }
+
+        /// Check that the GPU stroker covers the same pixels as the reference, up to
+        /// antialiasing along the edges.
+        #[test]
+        fn gpu_strokes_match_expanded_strokes() {
+            for path in paths() {
+                for stroke in strokes(3.0, DASHES) {
+                    for transform in transforms() {
+                        let scene = stroked(&stroke, transform, &path);
+                        let gpu = render(&scene, StrokeExpansion::Gpu);
+                        let cpu = render(&scene, StrokeExpansion::Cpu);
+                        assert!(
+                            mismatched_pixels(&gpu, &cpu) < 0.01,
+                            "{path:?} {stroke:?} {transform:?}"
+                        );
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn random_gpu_strokes_match_expanded_strokes() {
+            for (path, stroke) in random_strokes() {
+                for transform in transforms() {
+                    let scene = stroked(&stroke, transform, &path);
+                    let gpu = render(&scene, StrokeExpansion::Gpu);
+                    let cpu = render(&scene, StrokeExpansion::Cpu);
+                    assert!(
+                        mismatched_pixels(&gpu, &cpu) < 0.01,
+                        "{path:?} {stroke:?} {transform:?}"
+                    );
+                }
+            }
+        }
+
+        /// Check that degenerate segments only draw within the reach of their strokes.
+        #[test]
+        fn degenerate_strokes_stay_in_bounds() {
+            for path in degenerate_paths() {
+                for stroke in strokes(3.0, DASHES) {
+                    let reach = 0.5 * stroke.width * stroke.miter_limit + 1.0;
+                    let bounds = path.bounding_box().inflate(reach, reach);
+                    let scene = stroked(&stroke, Affine::IDENTITY, &path);
+                    for expansion in [StrokeExpansion::Gpu, StrokeExpansion::Cpu] {
+                        let pixels = render(&scene, expansion);
+                        for (ix, pixel) in pixels.chunks_exact(4).enumerate() {
+                            let (x, y) = (ix as u32 % SIZE, ix as u32 / SIZE);
+                            let center = (f64::from(x) + 0.5, f64::from(y) + 0.5);
+                            assert!(
+                                bounds.contains(center) || pixel == [255; 4],
+                                "{path:?} {stroke:?} {expansion:?} at {x}, {y}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Check that strokes much wider than the target cover all of it.
+        #[test]
+        fn huge_strokes_cover_the_target() {
+            let path = BezPath::from_svg("M-100 16 L16 16 L16 200").unwrap();
+            let red = [255, 0, 0, 255];
+            for stroke in strokes(1e6, &[&[]]) {
+                let scene = stroked(&stroke, Affine::IDENTITY, &path);
+                for expansion in [StrokeExpansion::Gpu, StrokeExpansion::Cpu] {
+                    let pixels = render(&scene, expansion);
+                    assert!(
+                        pixels.chunks_exact(4).all(|pixel| pixel == red),
+                        "{stroke:?} {expansion:?}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Conversion between SVG documents and [`Scene`]s.
+//!
+//! Use [`Scene::append_svg`] to draw an SVG document into a scene. The importer supports
+//! the static subset of SVG commonly used for icons and illustrations: paths and basic
+//! shapes, groups and `use` references with transforms, solid and gradient fills and
+//! strokes, opacity, clip paths and masks. Content which can't be imported is skipped and
+//! reported as an [`SvgWarning`]. Masks are drawn with [`Scene::push_mask`], so like filter
+//! layers they are only applied by the renderers.
+//!
+//! Use [`Scene::to_svg`] to write the contents of a scene as an SVG document, for
+//! inspecting what an application drew or comparing scenes without rendering them.
+//!
+//! This module requires the `svg` feature.
+
+mod export;
+mod import;
+
+use thiserror::Error;
+
+#[cfg(doc)]
+use crate::Scene;
+
+/// Errors which prevent an SVG document from being imported.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SvgError {
+    /// The document isn't well-formed XML.
+    #[error("Failed to parse SVG document")]
+    Parse(#[from] roxmltree::Error),
+    /// The root element of the document isn't `<svg>`.
+    #[error("Document root is `<{0}>`, not `<svg>`")]
+    NotSvg(String),
+}
+
+/// A part of an SVG document which was skipped during import.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SvgWarning {
+    /// The name of the element containing the unsupported content.
+    pub element: String,
+    /// The `id` of the element, if it has one.
+    pub id: Option<String>,
+    /// What couldn't be imported.
+    pub kind: SvgWarningKind,
+}
+
+/// The reason for an [`SvgWarning`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SvgWarningKind {
+    /// The element isn't supported, so it and its children were skipped.
+    UnsupportedElement,
+    /// The attribute or property with this name uses a feature which isn't supported.
+    UnsupportedAttribute(String),
+    /// The value of the attribute or property with this name couldn't be parsed.
+    InvalidAttribute(String),
+    /// A `url(#id)` or `href` reference to this id couldn't be resolved.
+    UnresolvedReference(String),
+}
@@ -0,0 +1,519 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use peniko::color::Srgb;
+use peniko::kurbo::{Affine, BezPath, Cap, Join, PathEl};
+use peniko::{BlendMode, Brush, Color, Extend, Fill, Gradient, GradientKind, Image, Mix, Style};
+
+use crate::Scene;
+use crate::decode::{Command, decode, outline_glyphs};
+use crate::image_io::encode_png;
+
+impl Scene {
+    /// Write the scene as an SVG document with a viewport of `width` by `height` pixels.
+    ///
+    /// The output is deterministic, so it can be compared textually. Features which SVG
+    /// can't express are approximated: sweep gradients are drawn with the color of their
+    /// first stop, layers only use their mix mode, and images which don't repeat on both
+    /// axes aren't extended beyond their bounds. Filter and mask layers aren't exported.
+    /// Each of these is marked with a comment in the output, and a warning is logged if
+    /// the document contains any.
+    pub fn to_svg(&self, width: u32, height: u32) -> String {
+        let scene = self.with_filter_layers_closed();
+        let mut writer = SvgWriter {
+            // The images drawn in place of filter layers are rendered by the renderers.
+            placeholders: scene
+                .filters
+                .iter()
+                .map(|layer| layer.image.data.id())
+                .collect(),
+            ..SvgWriter::default()
+        };
+        let _ = writeln!(
+            writer.out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+        );
+        decode(&scene.encoding, |command| writer.command(command));
+        while writer.depth > 0 {
+            writer.depth -= 1;
+            writer.line("</g>");
+        }
+        writer.out.push_str("</svg>\n");
+        if writer.unsupported > 0 {
+            log::warn!(
+                "{} draws in the scene can't be exported to SVG exactly, and are marked with \
+                comments in the document",
+                writer.unsupported
+            );
+        }
+        writer.out
+    }
+}
+
+#[derive(Default)]
+struct SvgWriter {
+    out: String,
+    /// The number of open layers.
+    depth: usize,
+    next_id: usize,
+    /// The ids of the data of the images drawn in place of filter layers.
+    placeholders: HashSet<u64>,
+    /// The number of draws which were approximated or left out.
+    unsupported: usize,
+}
+
+impl SvgWriter {
+    fn line(&mut self, line: &str) {
+        for _ in 0..=self.depth {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    /// Mark a draw which SVG can't express in the output.
+    fn unsupported(&mut self, comment: &str) {
+        self.unsupported += 1;
+        self.line(&format!("<!-- {comment} -->"));
+    }
+
+    fn id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{prefix}{}", self.next_id)
+    }
+
+    fn command(&mut self, command: Command<'_>) {
+        match command {
+            Command::Draw {
+                path,
+                transform,
+                style,
+                brush,
+                brush_transform,
+            } => self.draw(&path, transform, &style, &brush, brush_transform),
+            Command::BlurredRect {
+                path,
+                transform,
+                rect_transform,
+                rect,
+                color,
+                radius,
+                std_dev,
+            } => {
+                let clip = self.clip_path(&path, transform, Fill::NonZero);
+                let filter = self.id("filter");
+                // The filter region is in user space, so it needs to include the blur.
+                let region = rect.inflate(3.0 * std_dev as f64, 3.0 * std_dev as f64);
+                self.line(&format!(
+                    r#"<filter id="{filter}" filterUnits="userSpaceOnUse" x="{}" y="{}" width="{}" height="{}"><feGaussianBlur stdDeviation="{}"/></filter>"#,
+                    num(region.x0),
+                    num(region.y0),
+                    num(region.width()),
+                    num(region.height()),
+                    std_dev
+                ));
+                let (fill, opacity) = color(color);
+                self.line(&format!(
+                    r#"<g clip-path="url(#{clip})"><rect{} x="{}" y="{}" width="{}" height="{}" rx="{radius}" fill="{fill}"{} filter="url(#{filter})"/></g>"#,
+                    transform_attr("transform", rect_transform),
+                    num(rect.x0),
+                    num(rect.y0),
+                    num(rect.width()),
+                    num(rect.height()),
+                    opacity_attr("fill-opacity", opacity),
+                ));
+            }
+            Command::PushLayer {
+                clip,
+                transform,
+                fill,
+                blend,
+                alpha,
+            } => {
+                let clip = self.clip_path(&clip, transform, fill);
+                let mut attrs = format!(r#"clip-path="url(#{clip})""#);
+                attrs.push_str(&opacity_attr("opacity", alpha));
+                if let Some(mode) = blend_mode(blend) {
+                    let _ = write!(attrs, r#" style="mix-blend-mode:{mode}""#);
+                }
+                self.line(&format!("<g {attrs}>"));
+                self.depth += 1;
+            }
+            Command::PopLayer => {
+                if self.depth > 0 {
+                    self.depth -= 1;
+                    self.line("</g>");
+                }
+            }
+            Command::Glyphs { run, glyphs, brush } => {
+                if !outline_glyphs(run, glyphs, &brush, |command| self.command(command)) {
+                    self.unsupported(&format!("{} glyphs with an unreadable font", glyphs.len()));
+                }
+            }
+        }
+    }
+
+    fn draw(
+        &mut self,
+        path: &BezPath,
+        transform: Affine,
+        style: &Style,
+        brush: &Brush,
+        brush_transform: Option<Affine>,
+    ) {
+        if let Brush::Image(image) = brush {
+            if self.placeholders.contains(&image.data.id()) {
+                self.unsupported("filter layer");
+                return;
+            }
+        }
+        let d = path_data(path);
+        if let Brush::Image(image) = brush {
+            if image.x_extend != Extend::Repeat || image.y_extend != Extend::Repeat {
+                self.image(image, &d, transform, style, brush_transform);
+                return;
+            }
+        }
+        let (paint, opacity) = self.paint(brush, brush_transform);
+        let mut attrs = String::new();
+        match style {
+            Style::Fill(fill) => {
+                let _ = write!(attrs, r#" fill="{paint}""#);
+                attrs.push_str(&opacity_attr("fill-opacity", opacity));
+                if *fill == Fill::EvenOdd {
+                    attrs.push_str(r#" fill-rule="evenodd""#);
+                }
+            }
+            Style::Stroke(stroke) => {
+                let _ = write!(
+                    attrs,
+                    r#" fill="none" stroke="{paint}" stroke-width="{}""#,
+                    num(stroke.width)
+                );
+                attrs.push_str(&opacity_attr("stroke-opacity", opacity));
+                // SVG only has one cap style for both ends.
+                match stroke.start_cap {
+                    Cap::Butt => {}
+                    Cap::Square => attrs.push_str(r#" stroke-linecap="square""#),
+                    Cap::Round => attrs.push_str(r#" stroke-linecap="round""#),
+                }
+                match stroke.join {
+                    Join::Miter if stroke.miter_limit != 4.0 => {
+                        let limit = num(stroke.miter_limit);
+                        let _ = write!(attrs, r#" stroke-miterlimit="{limit}""#);
+                    }
+                    Join::Miter => {}
+                    Join::Round => attrs.push_str(r#" stroke-linejoin="round""#),
+                    Join::Bevel => attrs.push_str(r#" stroke-linejoin="bevel""#),
+                }
+            }
+        }
+        self.line(&format!(
+            r#"<path{} d="{d}"{attrs}/>"#,
+            transform_attr("transform", transform)
+        ));
+    }
+
+    /// Draw an image which doesn't repeat as an `<image>` clipped to the filled shape.
+    fn image(
+        &mut self,
+        image: &Image,
+        d: &str,
+        transform: Affine,
+        style: &Style,
+        brush_transform: Option<Affine>,
+    ) {
+        let clip = match style {
+            Style::Fill(fill) => {
+                let clip = self.id("clip");
+                self.line(&format!(
+                    r#"<clipPath id="{clip}"><path{} d="{d}"{}/></clipPath>"#,
+                    transform_attr("transform", transform),
+                    clip_rule(*fill)
+                ));
+                clip
+            }
+            // Strokes would need to be expanded into outlines to be used as clips.
+            Style::Stroke(_) => {
+                self.unsupported("stroke with an image brush");
+                return;
+            }
+        };
+        self.line(&format!(
+            r#"<g clip-path="url(#{clip})"><image{} width="{}" height="{}"{} href="{}"/></g>"#,
+            transform_attr("transform", transform * brush_transform.unwrap_or_default()),
+            image.width,
+            image.height,
+            opacity_attr("opacity", image.alpha),
+            data_uri(image),
+        ));
+    }
+
+    /// Write the definitions needed for `brush`, returning the value and opacity to use
+    /// for a `fill` or `stroke` attribute.
+    fn paint(&mut self, brush: &Brush, brush_transform: Option<Affine>) -> (String, f32) {
+        match brush {
+            Brush::Solid(c) => color(*c),
+            Brush::Gradient(gradient) => self.gradient(gradient, brush_transform),
+            Brush::Image(image) => {
+                let id = self.id("pattern");
+                self.line(&format!(
+                    r#"<pattern id="{id}" patternUnits="userSpaceOnUse" width="{}" height="{}"{}><image width="{}" height="{}"{} href="{}"/></pattern>"#,
+                    image.width,
+                    image.height,
+                    transform_attr("patternTransform", brush_transform.unwrap_or_default()),
+                    image.width,
+                    image.height,
+                    opacity_attr("opacity", image.alpha),
+                    data_uri(image),
+                ));
+                (format!("url(#{id})"), 1.0)
+            }
+        }
+    }
+
+    fn gradient(&mut self, gradient: &Gradient, brush_transform: Option<Affine>) -> (String, f32) {
+        let id = self.id("gradient");
+        let spread = match gradient.extend {
+            Extend::Pad => "",
+            Extend::Repeat => r#" spreadMethod="repeat""#,
+            Extend::Reflect => r#" spreadMethod="reflect""#,
+        };
+        let gradient_transform =
+            transform_attr("gradientTransform", brush_transform.unwrap_or_default());
+        let (element, geometry) = match gradient.kind {
+            GradientKind::Linear { start, end } => (
+                "linearGradient",
+                format!(
+                    r#"x1="{}" y1="{}" x2="{}" y2="{}""#,
+                    num(start.x),
+                    num(start.y),
+                    num(end.x),
+                    num(end.y)
+                ),
+            ),
+            GradientKind::Radial {
+                start_center,
+                start_radius,
+                end_center,
+                end_radius,
+            } => (
+                "radialGradient",
+                format!(
+                    r#"cx="{}" cy="{}" r="{end_radius}" fx="{}" fy="{}" fr="{start_radius}""#,
+                    num(end_center.x),
+                    num(end_center.y),
+                    num(start_center.x),
+                    num(start_center.y)
+                ),
+            ),
+            GradientKind::Sweep { .. } => {
+                self.unsupported("sweep gradient, drawn with the color of its first stop");
+                return gradient
+                    .stops
+                    .first()
+                    .map_or((String::from("none"), 1.0), |stop| {
+                        color(stop.color.to_alpha_color::<Srgb>())
+                    });
+            }
+        };
+        self.line(&format!(
+            r#"<{element} id="{id}" gradientUnits="userSpaceOnUse" {geometry}{gradient_transform}{spread}>"#
+        ));
+        for stop in gradient.stops.iter() {
+            let (stop_color, opacity) = color(stop.color.to_alpha_color::<Srgb>());
+            self.line(&format!(
+                r#"  <stop offset="{}" stop-color="{stop_color}"{}/>"#,
+                stop.offset,
+                opacity_attr("stop-opacity", opacity)
+            ));
+        }
+        self.line(&format!("</{element}>"));
+        (format!("url(#{id})"), 1.0)
+    }
+
+    fn clip_path(&mut self, path: &BezPath, transform: Affine, fill: Fill) -> String {
+        let id = self.id("clip");
+        self.line(&format!(
+            r#"<clipPath id="{id}"><path{} d="{}"{}/></clipPath>"#,
+            transform_attr("transform", transform),
+            path_data(path),
+            clip_rule(fill)
+        ));
+        id
+    }
+}
+
+/// Round a coordinate to the precision of the encoding.
+fn num(value: f64) -> f32 {
+    value as f32
+}
+
+fn path_data(path: &BezPath) -> String {
+    let mut d = String::new();
+    for el in path.elements() {
+        let _ = match el {
+            PathEl::MoveTo(p) => write!(d, "M{},{}", num(p.x), num(p.y)),
+            PathEl::LineTo(p) => write!(d, "L{},{}", num(p.x), num(p.y)),
+            PathEl::QuadTo(p1, p2) => write!(
+                d,
+                "Q{},{} {},{}",
+                num(p1.x),
+                num(p1.y),
+                num(p2.x),
+                num(p2.y)
+            ),
+            PathEl::CurveTo(p1, p2, p3) => write!(
+                d,
+                "C{},{} {},{} {},{}",
+                num(p1.x),
+                num(p1.y),
+                num(p2.x),
+                num(p2.y),
+                num(p3.x),
+                num(p3.y)
+            ),
+            PathEl::ClosePath => write!(d, "Z"),
+        };
+    }
+    d
+}
+
+fn transform_attr(name: &str, transform: Affine) -> String {
+    if transform == Affine::IDENTITY {
+        return String::new();
+    }
+    let [a, b, c, d, e, f] = transform.as_coeffs().map(num);
+    format!(r#" {name}="matrix({a} {b} {c} {d} {e} {f})""#)
+}
+
+fn opacity_attr(name: &str, opacity: f32) -> String {
+    if opacity < 1.0 {
+        format!(r#" {name}="{opacity}""#)
+    } else {
+        String::new()
+    }
+}
+
+fn clip_rule(fill: Fill) -> &'static str {
+    match fill {
+        Fill::NonZero => "",
+        Fill::EvenOdd => r#" clip-rule="evenodd""#,
+    }
+}
+
+/// Format a color as `#rrggbb`, returning its alpha separately.
+fn color(color: Color) -> (String, f32) {
+    let rgba = color.to_rgba8();
+    (
+        format!("#{:02x}{:02x}{:02x}", rgba.r, rgba.g, rgba.b),
+        color.components[3],
+    )
+}
+
+/// The CSS `mix-blend-mode` for a layer, if it isn't `normal`.
+///
+/// Compose modes have no equivalent in SVG, so they are ignored.
+fn blend_mode(blend: BlendMode) -> Option<&'static str> {
+    Some(match blend.mix {
+        Mix::Normal | Mix::Clip => return None,
+        Mix::Multiply => "multiply",
+        Mix::Screen => "screen",
+        Mix::Overlay => "overlay",
+        Mix::Darken => "darken",
+        Mix::Lighten => "lighten",
+        Mix::ColorDodge => "color-dodge",
+        Mix::ColorBurn => "color-burn",
+        Mix::HardLight => "hard-light",
+        Mix::SoftLight => "soft-light",
+        Mix::Difference => "difference",
+        Mix::Exclusion => "exclusion",
+        Mix::Hue => "hue",
+        Mix::Saturation => "saturation",
+        Mix::Color => "color",
+        Mix::Luminosity => "luminosity",
+    })
+}
+
+/// Encode an image as a PNG `data:` URI.
+fn data_uri(image: &Image) -> String {
+    let png = encode_png(image.width, image.height, image.data.data()).unwrap_or_default();
+    let mut uri = String::from("data:image/png;base64,");
+    base64(&png, &mut uri);
+    uri
+}
+
+fn base64(data: &[u8], out: &mut String) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::color::palette::css;
+    use peniko::kurbo::Rect;
+
+    use super::*;
+    use crate::Filter;
+
+    const RECT: Rect = Rect::new(0.0, 0.0, 20.0, 20.0);
+
+    #[test]
+    fn sweep_gradients_are_marked() {
+        let mut scene = Scene::new();
+        let gradient =
+            Gradient::new_sweep((10.0, 10.0), 0.0, 6.0).with_stops([css::RED, css::BLUE]);
+        scene.fill(Fill::NonZero, Affine::IDENTITY, &gradient, None, &RECT);
+        let svg = scene.to_svg(20, 20);
+        assert!(svg.contains("<!-- sweep gradient"));
+        assert!(svg.contains(r##"fill="#ff0000""##));
+        assert!(!svg.contains("Gradient"));
+    }
+
+    #[test]
+    fn filter_layers_are_skipped() {
+        let mut scene = Scene::new();
+        scene.push_filter_layer(Filter::grayscale(1.0), Affine::IDENTITY, &RECT);
+        scene.fill(Fill::NonZero, Affine::IDENTITY, css::RED, None, &RECT);
+        scene.pop_layer();
+        scene.fill(Fill::NonZero, Affine::IDENTITY, css::BLUE, None, &RECT);
+        let svg = scene.to_svg(20, 20);
+        assert!(svg.contains("<!-- filter layer -->"));
+        assert!(!svg.contains("<image"));
+        assert!(!svg.contains("#ff0000"));
+        assert!(svg.contains("#0000ff"));
+    }
+
+    #[test]
+    fn images_are_embedded() {
+        let data = vec![255_u8; 2 * 2 * 4];
+        let image = Image::new(
+            peniko::Blob::new(std::sync::Arc::new(data)),
+            peniko::ImageFormat::Rgba8,
+            2,
+            2,
+        );
+        let mut scene = Scene::new();
+        scene.draw_image(&image, Affine::IDENTITY);
+        let svg = scene.to_svg(20, 20);
+        assert!(svg.contains(r#"href="data:image/png;base64,"#));
+        assert!(!svg.contains("<!--"));
+    }
+}
@@ -0,0 +1,1038 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::HashMap;
+
+use peniko::color::{DynamicColor, palette, parse_color};
+use peniko::kurbo::{
+    Affine, BezPath, Cap, Circle, Ellipse, Join, Line, Point, Rect, RoundedRect, Shape, Stroke,
+};
+use peniko::{Brush, Color, ColorStop, Extend, Fill, Gradient, Mix};
+use roxmltree::{Document, Node};
+
+use super::{SvgError, SvgWarning, SvgWarningKind};
+use crate::{MaskType, Scene};
+
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+/// The tolerance used when converting shapes to paths.
+const TOLERANCE: f64 = 0.1;
+
+/// Limits the depth of `use` and `href` chains, which can be cyclic.
+const MAX_REFERENCE_DEPTH: usize = 16;
+
+/// The size of an SVG viewport if neither its size nor its `viewBox` is specified.
+const DEFAULT_VIEWPORT_SIZE: (f64, f64) = (300.0, 150.0);
+
+const SHAPES: &[&str] = &[
+    "path", "rect", "circle", "ellipse", "line", "polyline", "polygon",
+];
+
+impl Scene {
+    /// Parse an SVG document and draw its contents into the scene.
+    ///
+    /// The root element's `viewBox` is mapped to its `width` and `height`, and the result
+    /// is placed in the scene using `transform`.
+    ///
+    /// Returns the parts of the document which were skipped because they aren't supported.
+    /// See the [`svg`](crate::svg) module for what is supported.
+    pub fn append_svg(
+        &mut self,
+        svg: &str,
+        transform: Affine,
+    ) -> Result<Vec<SvgWarning>, SvgError> {
+        let document = Document::parse(svg)?;
+        let root = document.root_element();
+        if !root.has_tag_name("svg") {
+            return Err(SvgError::NotSvg(root.tag_name().name().to_owned()));
+        }
+        let ids = document
+            .descendants()
+            .filter_map(|node| Some((node.attribute("id")?, node)))
+            .collect();
+        let mut importer = Importer {
+            scene: self,
+            ids,
+            warnings: Vec::new(),
+            viewport: Rect::ZERO,
+            viewport_transform: transform,
+            depth: 0,
+        };
+        importer.viewport = importer.svg_viewport(root, root, false).0;
+        importer.element(root, transform, &State::default());
+        Ok(importer.warnings)
+    }
+}
+
+/// A fill or stroke paint.
+#[derive(Clone, Debug)]
+enum Paint {
+    None,
+    Color(Color),
+    CurrentColor,
+    Url {
+        id: String,
+        fallback: Option<Box<Paint>>,
+    },
+}
+
+/// The inherited presentation properties which apply to an element.
+#[derive(Clone, Debug)]
+struct State {
+    color: Color,
+    fill: Paint,
+    fill_opacity: f32,
+    fill_rule: Fill,
+    stroke: Paint,
+    stroke_opacity: f32,
+    stroke_width: f64,
+    line_cap: Cap,
+    line_join: Join,
+    miter_limit: f64,
+    dash_array: Vec<f64>,
+    dash_offset: f64,
+    visible: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            color: palette::css::BLACK,
+            fill: Paint::Color(palette::css::BLACK),
+            fill_opacity: 1.0,
+            fill_rule: Fill::NonZero,
+            stroke: Paint::None,
+            stroke_opacity: 1.0,
+            stroke_width: 1.0,
+            line_cap: Cap::Butt,
+            line_join: Join::Miter,
+            miter_limit: 4.0,
+            dash_array: Vec::new(),
+            dash_offset: 0.0,
+            visible: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+    Diagonal,
+}
+
+struct Importer<'s, 'a, 'input> {
+    scene: &'s mut Scene,
+    ids: HashMap<&'a str, Node<'a, 'input>>,
+    warnings: Vec<SvgWarning>,
+    /// The viewport of the root element, used as the bounds of opacity layers.
+    viewport: Rect,
+    viewport_transform: Affine,
+    /// The number of `use` elements currently being expanded.
+    depth: usize,
+}
+
+impl<'a, 'input> Importer<'_, 'a, 'input> {
+    fn warn(&mut self, node: Node<'a, 'input>, kind: SvgWarningKind) {
+        self.warnings.push(SvgWarning {
+            element: node.tag_name().name().to_owned(),
+            id: node.attribute("id").map(str::to_owned),
+            kind,
+        });
+    }
+
+    fn element(&mut self, node: Node<'a, 'input>, transform: Affine, parent: &State) {
+        let name = node.tag_name().name();
+        match name {
+            // Only drawn when referenced.
+            "defs" | "symbol" | "linearGradient" | "radialGradient" | "clipPath" | "mask" => {
+                return;
+            }
+            "title" | "desc" | "metadata" => return,
+            "svg" | "g" | "use" => {}
+            _ if SHAPES.contains(&name) => {}
+            _ => {
+                self.warn(node, SvgWarningKind::UnsupportedElement);
+                return;
+            }
+        }
+        if property(node, "display") == Some("none") {
+            return;
+        }
+        let state = self.inherit(node, parent);
+        let mut transform = transform;
+        if let Some(value) = node.attribute("transform") {
+            transform *= self.transform_attr(node, "transform", value);
+        }
+        let path = if SHAPES.contains(&name) {
+            self.shape_path(node)
+        } else {
+            None
+        };
+
+        let mut layers = 0;
+        if let Some(clip) = property(node, "clip-path").filter(|clip| *clip != "none") {
+            let bbox = path.as_ref().map(Shape::bounding_box);
+            if self.push_clip(node, clip, transform, bbox) {
+                layers += 1;
+            }
+        }
+        if let Some(mask) = property(node, "mask").filter(|mask| *mask != "none") {
+            let bbox = path.as_ref().map(Shape::bounding_box);
+            if self.push_mask(node, mask, transform, bbox) {
+                layers += 1;
+            }
+        }
+        if let Some(opacity) = self.opacity(node, "opacity") {
+            if opacity < 1.0 {
+                self.scene.push_layer(
+                    Mix::Normal,
+                    opacity,
+                    self.viewport_transform,
+                    &self.viewport,
+                );
+                layers += 1;
+            }
+        }
+
+        match name {
+            "svg" => {
+                let is_root = node.parent_element().is_none();
+                let (viewport, view_box) = self.svg_viewport(node, node, !is_root);
+                self.scene
+                    .push_layer(Mix::Clip, 1.0, transform, &viewport);
+                layers += 1;
+                self.children(node, transform * view_box, &state);
+            }
+            "g" => self.children(node, transform, &state),
+            "use" => self.use_element(node, transform, &state),
+            _ => {
+                if let Some(path) = path {
+                    self.draw(node, &path, transform, &state);
+                }
+            }
+        }
+        for _ in 0..layers {
+            self.scene.pop_layer();
+        }
+    }
+
+    fn children(&mut self, node: Node<'a, 'input>, transform: Affine, state: &State) {
+        for child in node.children().filter(Node::is_element) {
+            self.element(child, transform, state);
+        }
+    }
+
+    fn use_element(&mut self, node: Node<'a, 'input>, transform: Affine, state: &State) {
+        let Some(id) = href(node) else {
+            self.warn(node, SvgWarningKind::InvalidAttribute("href".into()));
+            return;
+        };
+        let Some(target) = self.ids.get(id).copied() else {
+            self.warn(node, SvgWarningKind::UnresolvedReference(id.into()));
+            return;
+        };
+        if self.depth >= MAX_REFERENCE_DEPTH {
+            self.warn(node, SvgWarningKind::UnsupportedAttribute("href".into()));
+            return;
+        }
+        let x = self.length_attr(node, "x").unwrap_or(0.0);
+        let y = self.length_attr(node, "y").unwrap_or(0.0);
+        let transform = transform * Affine::translate((x, y));
+        self.depth += 1;
+        if target.has_tag_name("symbol") {
+            let state = self.inherit(target, state);
+            let (viewport, view_box) = self.svg_viewport(target, node, false);
+            self.scene.push_layer(Mix::Clip, 1.0, transform, &viewport);
+            self.children(target, transform * view_box, &state);
+            self.scene.pop_layer();
+        } else {
+            self.element(target, transform, state);
+        }
+        self.depth -= 1;
+    }
+
+    /// Compute the viewport of an `svg` or `symbol` element, and the transform from its
+    /// `viewBox` to the viewport.
+    ///
+    /// The size of the viewport is read from `size_from`, which is the `use` element
+    /// for symbols.
+    fn svg_viewport(
+        &mut self,
+        node: Node<'a, 'input>,
+        size_from: Node<'a, 'input>,
+        positioned: bool,
+    ) -> (Rect, Affine) {
+        let view_box = node.attribute("viewBox").and_then(|value| {
+            let view_box = numbers(value).filter(|v| v.len() == 4 && v[2] > 0.0 && v[3] > 0.0);
+            if view_box.is_none() {
+                self.warn(node, SvgWarningKind::InvalidAttribute("viewBox".into()));
+            }
+            view_box
+        });
+        let (x, y) = if positioned {
+            (
+                self.length_attr(node, "x").unwrap_or(0.0),
+                self.length_attr(node, "y").unwrap_or(0.0),
+            )
+        } else {
+            (0.0, 0.0)
+        };
+        let width = self
+            .length_attr(size_from, "width")
+            .or(view_box.as_ref().map(|v| v[2]))
+            .unwrap_or(DEFAULT_VIEWPORT_SIZE.0);
+        let height = self
+            .length_attr(size_from, "height")
+            .or(view_box.as_ref().map(|v| v[3]))
+            .unwrap_or(DEFAULT_VIEWPORT_SIZE.1);
+        let viewport = Rect::new(x, y, x + width, y + height);
+        let mut transform = Affine::translate((x, y));
+        if let Some(vb) = view_box {
+            let (sx, sy) = (width / vb[2], height / vb[3]);
+            let aspect = node
+                .attribute("preserveAspectRatio")
+                .unwrap_or("xMidYMid meet");
+            let scale = if aspect.trim() == "none" {
+                Affine::scale_non_uniform(sx, sy)
+            } else {
+                let s = if aspect.contains("slice") {
+                    sx.max(sy)
+                } else {
+                    sx.min(sy)
+                };
+                let align = |min: &str, max: &str| {
+                    if aspect.contains(min) {
+                        0.0
+                    } else if aspect.contains(max) {
+                        1.0
+                    } else {
+                        0.5
+                    }
+                };
+                let dx = (width - vb[2] * s) * align("xMin", "xMax");
+                let dy = (height - vb[3] * s) * align("YMin", "YMax");
+                Affine::translate((dx, dy)) * Affine::scale(s)
+            };
+            transform = transform * scale * Affine::translate((-vb[0], -vb[1]));
+        }
+        (viewport, transform)
+    }
+
+    /// Push a clip layer for the `clip-path` property of an element.
+    ///
+    /// Returns whether a layer was pushed.
+    fn push_clip(
+        &mut self,
+        node: Node<'a, 'input>,
+        value: &str,
+        transform: Affine,
+        bbox: Option<Rect>,
+    ) -> bool {
+        let Some(id) = url_id(value) else {
+            self.warn(node, SvgWarningKind::InvalidAttribute("clip-path".into()));
+            return false;
+        };
+        let Some(clip) = self
+            .ids
+            .get(id)
+            .copied()
+            .filter(|clip| clip.has_tag_name("clipPath"))
+        else {
+            self.warn(node, SvgWarningKind::UnresolvedReference(id.into()));
+            return false;
+        };
+        let mut clip_transform = match clip.attribute("transform") {
+            Some(value) => self.transform_attr(clip, "transform", value),
+            None => Affine::IDENTITY,
+        };
+        if clip.attribute("clipPathUnits") == Some("objectBoundingBox") {
+            let Some(bbox) = bbox else {
+                self.warn(
+                    clip,
+                    SvgWarningKind::UnsupportedAttribute("clipPathUnits".into()),
+                );
+                return false;
+            };
+            clip_transform = bbox_transform(bbox) * clip_transform;
+        }
+        let mut path = BezPath::new();
+        for child in clip.children().filter(Node::is_element) {
+            let name = child.tag_name().name();
+            if !SHAPES.contains(&name) {
+                self.warn(child, SvgWarningKind::UnsupportedElement);
+                continue;
+            }
+            if property(child, "display") == Some("none") {
+                continue;
+            }
+            let Some(child_path) = self.shape_path(child) else {
+                continue;
+            };
+            let child_transform = match child.attribute("transform") {
+                Some(value) => self.transform_attr(child, "transform", value),
+                None => Affine::IDENTITY,
+            };
+            path.extend((child_transform * child_path).iter());
+        }
+        // An empty clip path hides the element, which `push_layer` handles.
+        self.scene
+            .push_layer(Mix::Clip, 1.0, transform * clip_transform, &path);
+        true
+    }
+
+    /// Push a mask layer for the `mask` property of an element.
+    ///
+    /// Returns whether a layer was pushed.
+    fn push_mask(
+        &mut self,
+        node: Node<'a, 'input>,
+        value: &str,
+        transform: Affine,
+        bbox: Option<Rect>,
+    ) -> bool {
+        let Some(id) = url_id(value) else {
+            self.warn(node, SvgWarningKind::InvalidAttribute("mask".into()));
+            return false;
+        };
+        let Some(mask) = self
+            .ids
+            .get(id)
+            .copied()
+            .filter(|mask| mask.has_tag_name("mask"))
+        else {
+            self.warn(node, SvgWarningKind::UnresolvedReference(id.into()));
+            return false;
+        };
+        // Masks can reference themselves through their content.
+        if self.depth >= MAX_REFERENCE_DEPTH {
+            self.warn(node, SvgWarningKind::UnsupportedAttribute("mask".into()));
+            return false;
+        }
+        let bbox_units = mask.attribute("maskUnits") != Some("userSpaceOnUse");
+        let content_bbox_units = mask.attribute("maskContentUnits") == Some("objectBoundingBox");
+        // Object bounding box units are undefined for zero-area geometry.
+        let bbox = bbox.filter(|bbox| bbox.width() != 0.0 && bbox.height() != 0.0);
+        if bbox.is_none() && (bbox_units || content_bbox_units) {
+            self.warn(
+                mask,
+                SvgWarningKind::UnsupportedAttribute("maskUnits".into()),
+            );
+            return false;
+        }
+        let bbox = bbox.unwrap_or(Rect::ZERO);
+
+        let mut coord = |name: &str, default: f64, axis: Axis| {
+            let value = mask.attribute(name);
+            let fraction = value.and_then(parse_fraction).unwrap_or(default);
+            match value {
+                Some(value) if !bbox_units && !value.trim().ends_with('%') => {
+                    self.length(mask, name, value).unwrap_or(0.0)
+                }
+                _ => default_coordinate(fraction, axis, bbox_units, self.viewport),
+            }
+        };
+        let region = Rect::from_origin_size(
+            (coord("x", -0.1, Axis::X), coord("y", -0.1, Axis::Y)),
+            (coord("width", 1.2, Axis::X), coord("height", 1.2, Axis::Y)),
+        );
+        let region = if bbox_units {
+            bbox_transform(bbox).transform_rect_bbox(region)
+        } else {
+            region
+        };
+        let content_transform = if content_bbox_units {
+            bbox_transform(bbox)
+        } else {
+            Affine::IDENTITY
+        };
+        let mask_type = match property(mask, "mask-type") {
+            Some("alpha") => MaskType::Alpha,
+            _ => MaskType::Luminance,
+        };
+
+        // The content of the mask is drawn into its own scene, inheriting the properties
+        // of the mask element rather than of the masked element.
+        let outer = std::mem::take(self.scene);
+        let state = self.inherit(mask, &State::default());
+        self.depth += 1;
+        self.children(mask, content_transform, &state);
+        self.depth -= 1;
+        let mask_scene = std::mem::replace(self.scene, outer);
+        self.scene.push_mask(mask_type, transform, &region, &mask_scene);
+        true
+    }
+
+    fn draw(&mut self, node: Node<'a, 'input>, path: &BezPath, transform: Affine, state: &State) {
+        if !state.visible {
+            return;
+        }
+        if let Some((brush, brush_transform)) =
+            self.brush(node, &state.fill, state.fill_opacity, state, path)
+        {
+            self.scene
+                .fill(state.fill_rule, transform, &brush, brush_transform, path);
+        }
+        if state.stroke_width <= 0.0 {
+            return;
+        }
+        if let Some((brush, brush_transform)) =
+            self.brush(node, &state.stroke, state.stroke_opacity, state, path)
+        {
+            let mut stroke = Stroke::new(state.stroke_width)
+                .with_caps(state.line_cap)
+                .with_join(state.line_join)
+                .with_miter_limit(state.miter_limit);
+            if !state.dash_array.is_empty() {
+                stroke = stroke.with_dashes(state.dash_offset, state.dash_array.iter().copied());
+            }
+            self.scene
+                .stroke(&stroke, transform, &brush, brush_transform, path);
+        }
+    }
+
+    fn brush(
+        &mut self,
+        node: Node<'a, 'input>,
+        paint: &Paint,
+        opacity: f32,
+        state: &State,
+        path: &BezPath,
+    ) -> Option<(Brush, Option<Affine>)> {
+        match paint {
+            Paint::None => None,
+            Paint::Color(color) => Some((color.multiply_alpha(opacity).into(), None)),
+            Paint::CurrentColor => Some((state.color.multiply_alpha(opacity).into(), None)),
+            Paint::Url { id, fallback } => {
+                let target = self.ids.get(id.as_str()).copied();
+                match target {
+                    Some(target)
+                        if target.has_tag_name("linearGradient")
+                            || target.has_tag_name("radialGradient") =>
+                    {
+                        self.gradient(target, opacity, path)
+                    }
+                    _ => {
+                        if let Some(fallback) = fallback {
+                            return self.brush(node, fallback, opacity, state, path);
+                        }
+                        match target {
+                            Some(target) => self.warn(target, SvgWarningKind::UnsupportedElement),
+                            None => {
+                                self.warn(node, SvgWarningKind::UnresolvedReference(id.clone()));
+                            }
+                        }
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    fn gradient(
+        &mut self,
+        node: Node<'a, 'input>,
+        opacity: f32,
+        path: &BezPath,
+    ) -> Option<(Brush, Option<Affine>)> {
+        // Attributes and stops which aren't specified are inherited through `href`.
+        let chain = self.href_chain(node);
+        let attr = |name: &str| chain.iter().find_map(|node| node.attribute(name));
+        let bbox_units = attr("gradientUnits") != Some("userSpaceOnUse");
+        let gradient_transform = match attr("gradientTransform") {
+            Some(value) => self.transform_attr(node, "gradientTransform", value),
+            None => Affine::IDENTITY,
+        };
+        let extend = match attr("spreadMethod") {
+            Some("reflect") => Extend::Reflect,
+            Some("repeat") => Extend::Repeat,
+            _ => Extend::Pad,
+        };
+
+        let mut stops = Vec::new();
+        let stops_node = chain
+            .iter()
+            .find(|node| node.children().any(|child| child.has_tag_name("stop")));
+        if let Some(stops_node) = stops_node {
+            let mut last_offset = 0.0_f32;
+            for stop in stops_node.children().filter(|child| child.has_tag_name("stop")) {
+                let offset = stop
+                    .attribute("offset")
+                    .and_then(parse_fraction)
+                    .unwrap_or(0.0)
+                    .clamp(0.0, 1.0) as f32;
+                last_offset = last_offset.max(offset);
+                let color = property(stop, "stop-color")
+                    .and_then(|value| parse_color(value).ok())
+                    .map_or(palette::css::BLACK, |color| color.to_alpha_color());
+                let stop_opacity = self.opacity(stop, "stop-opacity").unwrap_or(1.0);
+                stops.push(ColorStop {
+                    offset: last_offset,
+                    color: DynamicColor::from_alpha_color(
+                        color.multiply_alpha(stop_opacity * opacity),
+                    ),
+                });
+            }
+        }
+        match stops.as_slice() {
+            [] => return None,
+            [stop] => return Some((Brush::Solid(stop.color.to_alpha_color()), None)),
+            _ => {}
+        }
+
+        let coord = |name: &str, default: f64, axis: Axis| {
+            let Some(value) = attr(name) else {
+                return default_coordinate(default, axis, bbox_units, self.viewport);
+            };
+            match value.trim().strip_suffix('%') {
+                Some(percent) => percent.trim().parse::<f64>().ok().map(|percent| {
+                    default_coordinate(percent / 100.0, axis, bbox_units, self.viewport)
+                }),
+                None => parse_length(value),
+            }
+            .unwrap_or_else(|| default_coordinate(default, axis, bbox_units, self.viewport))
+        };
+        let gradient = if node.has_tag_name("linearGradient") {
+            Gradient::new_linear(
+                (coord("x1", 0.0, Axis::X), coord("y1", 0.0, Axis::Y)),
+                (coord("x2", 1.0, Axis::X), coord("y2", 0.0, Axis::Y)),
+            )
+        } else {
+            let cx = coord("cx", 0.5, Axis::X);
+            let cy = coord("cy", 0.5, Axis::Y);
+            let r = coord("r", 0.5, Axis::Diagonal);
+            let focus = match (attr("fx"), attr("fy")) {
+                (None, None) => Point::new(cx, cy),
+                _ => Point::new(coord("fx", 0.5, Axis::X), coord("fy", 0.5, Axis::Y)),
+            };
+            let fr = coord("fr", 0.0, Axis::Diagonal);
+            Gradient::new_two_point_radial(focus, fr as f32, (cx, cy), r as f32)
+        }
+        .with_extend(extend)
+        .with_stops(stops.as_slice());
+
+        let brush_transform = if bbox_units {
+            let bbox = path.bounding_box();
+            // Object bounding box units are undefined for zero-area geometry.
+            if bbox.width() == 0.0 || bbox.height() == 0.0 {
+                return None;
+            }
+            Some(bbox_transform(bbox) * gradient_transform)
+        } else {
+            Some(gradient_transform).filter(|t| *t != Affine::IDENTITY)
+        };
+        Some((gradient.into(), brush_transform))
+    }
+
+    /// The nodes reached by following `href` references from `node`, starting with `node`.
+    fn href_chain(&self, node: Node<'a, 'input>) -> Vec<Node<'a, 'input>> {
+        let mut chain = vec![node];
+        while chain.len() < MAX_REFERENCE_DEPTH {
+            let next = href(chain[chain.len() - 1]).and_then(|id| self.ids.get(id).copied());
+            match next {
+                Some(next) if !chain.contains(&next) => chain.push(next),
+                _ => break,
+            }
+        }
+        chain
+    }
+
+    fn shape_path(&mut self, node: Node<'a, 'input>) -> Option<BezPath> {
+        match node.tag_name().name() {
+            "path" => {
+                let d = node.attribute("d")?;
+                match BezPath::from_svg(d) {
+                    Ok(path) => Some(path),
+                    Err(_) => {
+                        self.warn(node, SvgWarningKind::InvalidAttribute("d".into()));
+                        None
+                    }
+                }
+            }
+            "rect" => {
+                let x = self.length_attr(node, "x").unwrap_or(0.0);
+                let y = self.length_attr(node, "y").unwrap_or(0.0);
+                let width = self.length_attr(node, "width")?;
+                let height = self.length_attr(node, "height")?;
+                if width <= 0.0 || height <= 0.0 {
+                    return None;
+                }
+                let rx = self.length_attr(node, "rx");
+                let ry = self.length_attr(node, "ry");
+                let (rx, ry) = match (rx, ry) {
+                    (Some(rx), Some(ry)) => (rx, ry),
+                    (Some(r), None) | (None, Some(r)) => (r, r),
+                    (None, None) => (0.0, 0.0),
+                };
+                let rx = rx.min(0.5 * width);
+                let ry = ry.min(0.5 * height);
+                let path = if rx <= 0.0 || ry <= 0.0 {
+                    Rect::new(x, y, x + width, y + height).to_path(TOLERANCE)
+                } else {
+                    // Elliptical corners are circular corners in a vertically scaled space.
+                    let sy = ry / rx;
+                    let rect = RoundedRect::new(x, y / sy, x + width, (y + height) / sy, rx);
+                    Affine::scale_non_uniform(1.0, sy) * rect.to_path(TOLERANCE)
+                };
+                Some(path)
+            }
+            "circle" => {
+                let cx = self.length_attr(node, "cx").unwrap_or(0.0);
+                let cy = self.length_attr(node, "cy").unwrap_or(0.0);
+                let r = self.length_attr(node, "r").filter(|r| *r > 0.0)?;
+                Some(Circle::new((cx, cy), r).to_path(TOLERANCE))
+            }
+            "ellipse" => {
+                let cx = self.length_attr(node, "cx").unwrap_or(0.0);
+                let cy = self.length_attr(node, "cy").unwrap_or(0.0);
+                let rx = self.length_attr(node, "rx");
+                let ry = self.length_attr(node, "ry");
+                let (rx, ry) = (rx.or(ry)?, ry.or(rx)?);
+                if rx <= 0.0 || ry <= 0.0 {
+                    return None;
+                }
+                Some(Ellipse::new((cx, cy), (rx, ry), 0.0).to_path(TOLERANCE))
+            }
+            "line" => {
+                let x1 = self.length_attr(node, "x1").unwrap_or(0.0);
+                let y1 = self.length_attr(node, "y1").unwrap_or(0.0);
+                let x2 = self.length_attr(node, "x2").unwrap_or(0.0);
+                let y2 = self.length_attr(node, "y2").unwrap_or(0.0);
+                Some(Line::new((x1, y1), (x2, y2)).to_path(TOLERANCE))
+            }
+            name @ ("polyline" | "polygon") => {
+                let Some(points) = numbers(node.attribute("points")?) else {
+                    self.warn(node, SvgWarningKind::InvalidAttribute("points".into()));
+                    return None;
+                };
+                let mut points = points.chunks_exact(2).map(|p| Point::new(p[0], p[1]));
+                let mut path = BezPath::new();
+                path.move_to(points.next()?);
+                for point in points {
+                    path.line_to(point);
+                }
+                if name == "polygon" {
+                    path.close_path();
+                }
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Apply the presentation properties of `node` to the state inherited from its parent.
+    fn inherit(&mut self, node: Node<'a, 'input>, parent: &State) -> State {
+        let mut state = parent.clone();
+        if let Some(value) = property(node, "color") {
+            match parse_color(value) {
+                Ok(color) => state.color = color.to_alpha_color(),
+                Err(_) => self.warn(node, SvgWarningKind::InvalidAttribute("color".into())),
+            }
+        }
+        if let Some(paint) = property(node, "fill").and_then(|value| self.paint(node, "fill", value)) {
+            state.fill = paint;
+        }
+        if let Some(paint) =
+            property(node, "stroke").and_then(|value| self.paint(node, "stroke", value))
+        {
+            state.stroke = paint;
+        }
+        if let Some(opacity) = self.opacity(node, "fill-opacity") {
+            state.fill_opacity = opacity;
+        }
+        if let Some(opacity) = self.opacity(node, "stroke-opacity") {
+            state.stroke_opacity = opacity;
+        }
+        match property(node, "fill-rule") {
+            Some("nonzero") => state.fill_rule = Fill::NonZero,
+            Some("evenodd") => state.fill_rule = Fill::EvenOdd,
+            Some(_) => self.warn(node, SvgWarningKind::InvalidAttribute("fill-rule".into())),
+            None => {}
+        }
+        if let Some(width) = self.length_property(node, "stroke-width") {
+            state.stroke_width = width;
+        }
+        match property(node, "stroke-linecap") {
+            Some("butt") => state.line_cap = Cap::Butt,
+            Some("round") => state.line_cap = Cap::Round,
+            Some("square") => state.line_cap = Cap::Square,
+            Some(_) => self.warn(
+                node,
+                SvgWarningKind::InvalidAttribute("stroke-linecap".into()),
+            ),
+            None => {}
+        }
+        match property(node, "stroke-linejoin") {
+            Some("miter") => state.line_join = Join::Miter,
+            Some("round") => state.line_join = Join::Round,
+            Some("bevel") => state.line_join = Join::Bevel,
+            Some(_) => self.warn(
+                node,
+                SvgWarningKind::UnsupportedAttribute("stroke-linejoin".into()),
+            ),
+            None => {}
+        }
+        if let Some(value) = property(node, "stroke-miterlimit") {
+            match value.parse::<f64>() {
+                Ok(limit) if limit >= 1.0 => state.miter_limit = limit,
+                _ => self.warn(
+                    node,
+                    SvgWarningKind::InvalidAttribute("stroke-miterlimit".into()),
+                ),
+            }
+        }
+        if let Some(value) = property(node, "stroke-dasharray") {
+            state.dash_array = if value == "none" {
+                Vec::new()
+            } else {
+                match numbers(value) {
+                    Some(mut dashes) if dashes.iter().all(|dash| *dash >= 0.0) => {
+                        if dashes.len() % 2 == 1 {
+                            dashes.extend_from_within(..);
+                        }
+                        // A pattern with no length is the same as a solid line.
+                        if dashes.iter().sum::<f64>() > 0.0 {
+                            dashes
+                        } else {
+                            Vec::new()
+                        }
+                    }
+                    _ => {
+                        self.warn(
+                            node,
+                            SvgWarningKind::InvalidAttribute("stroke-dasharray".into()),
+                        );
+                        Vec::new()
+                    }
+                }
+            };
+        }
+        if let Some(offset) = self.length_property(node, "stroke-dashoffset") {
+            state.dash_offset = offset;
+        }
+        match property(node, "visibility") {
+            Some("visible") => state.visible = true,
+            Some("hidden" | "collapse") => state.visible = false,
+            _ => {}
+        }
+        state
+    }
+
+    fn paint(&mut self, node: Node<'a, 'input>, name: &str, value: &str) -> Option<Paint> {
+        match value {
+            "none" => return Some(Paint::None),
+            "currentColor" => return Some(Paint::CurrentColor),
+            _ => {}
+        }
+        if value.starts_with("url(") {
+            let Some(id) = url_id(value) else {
+                self.warn(node, SvgWarningKind::InvalidAttribute(name.into()));
+                return None;
+            };
+            let fallback = value.split_once(')').map_or("", |(_, rest)| rest).trim();
+            let fallback = if fallback.is_empty() {
+                None
+            } else {
+                self.paint(node, name, fallback).map(Box::new)
+            };
+            return Some(Paint::Url {
+                id: id.to_owned(),
+                fallback,
+            });
+        }
+        match parse_color(value) {
+            Ok(color) => Some(Paint::Color(color.to_alpha_color())),
+            Err(_) => {
+                self.warn(node, SvgWarningKind::InvalidAttribute(name.into()));
+                None
+            }
+        }
+    }
+
+    fn opacity(&mut self, node: Node<'a, 'input>, name: &str) -> Option<f32> {
+        let value = property(node, name)?;
+        let opacity = parse_fraction(value);
+        if opacity.is_none() {
+            self.warn(node, SvgWarningKind::InvalidAttribute(name.into()));
+        }
+        opacity.map(|opacity| opacity.clamp(0.0, 1.0) as f32)
+    }
+
+    fn transform_attr(&mut self, node: Node<'a, 'input>, name: &str, value: &str) -> Affine {
+        parse_transform(value).unwrap_or_else(|| {
+            self.warn(node, SvgWarningKind::InvalidAttribute(name.into()));
+            Affine::IDENTITY
+        })
+    }
+
+    fn length_attr(&mut self, node: Node<'a, 'input>, name: &str) -> Option<f64> {
+        let value = node.attribute(name)?;
+        self.length(node, name, value)
+    }
+
+    fn length_property(&mut self, node: Node<'a, 'input>, name: &str) -> Option<f64> {
+        let value = property(node, name)?;
+        self.length(node, name, value)
+    }
+
+    fn length(&mut self, node: Node<'a, 'input>, name: &str, value: &str) -> Option<f64> {
+        let length = parse_length(value);
+        if length.is_none() {
+            let value = value.trim();
+            let kind = if value.ends_with('%') || value.ends_with("em") || value.ends_with("ex") {
+                SvgWarningKind::UnsupportedAttribute(name.into())
+            } else {
+                SvgWarningKind::InvalidAttribute(name.into())
+            };
+            self.warn(node, kind);
+        }
+        length
+    }
+}
+
+/// Look up a presentation property, which can be set in the `style` attribute or as an
+/// attribute of the same name.
+///
+/// Returns `None` if the property isn't set or is `inherit`.
+fn property<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    let from_style = node.attribute("style").and_then(|style| {
+        style
+            .split(';')
+            .filter_map(|declaration| declaration.split_once(':'))
+            .rfind(|(key, _)| key.trim() == name)
+            .map(|(_, value)| value)
+    });
+    from_style
+        .or_else(|| node.attribute(name))
+        .map(|value| value.trim().trim_end_matches("!important").trim())
+        .filter(|value| *value != "inherit")
+}
+
+fn href<'a>(node: Node<'a, '_>) -> Option<&'a str> {
+    node.attribute((XLINK_NS, "href"))
+        .or_else(|| node.attribute("href"))
+        .and_then(|href| href.trim().strip_prefix('#'))
+}
+
+/// Extract the id from a `url(#id)` reference.
+fn url_id(value: &str) -> Option<&str> {
+    let url = value.trim().strip_prefix("url(")?.split_once(')')?.0;
+    url.trim()
+        .trim_matches(['\'', '"'])
+        .strip_prefix('#')
+}
+
+/// The transform from object bounding box units to user space.
+fn bbox_transform(bbox: Rect) -> Affine {
+    Affine::new([bbox.width(), 0.0, 0.0, bbox.height(), bbox.x0, bbox.y0])
+}
+
+/// Resolve a gradient coordinate given as a fraction of the bounding box or viewport.
+fn default_coordinate(fraction: f64, axis: Axis, bbox_units: bool, viewport: Rect) -> f64 {
+    if bbox_units {
+        return fraction;
+    }
+    let size = match axis {
+        Axis::X => viewport.width(),
+        Axis::Y => viewport.height(),
+        Axis::Diagonal => (0.5 * (viewport.width().powi(2) + viewport.height().powi(2))).sqrt(),
+    };
+    fraction * size
+}
+
+/// Parse a number or a percentage, returning a fraction.
+fn parse_fraction(value: &str) -> Option<f64> {
+    let value = value.trim();
+    match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok().map(|p| p / 100.0),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parse a length in an absolute unit, returning its size in pixels.
+fn parse_length(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale = match unit {
+        "" | "px" => 1.0,
+        "pt" => 4.0 / 3.0,
+        "pc" => 16.0,
+        "mm" => 96.0 / 25.4,
+        "cm" => 96.0 / 2.54,
+        "in" => 96.0,
+        _ => return None,
+    };
+    number.trim().parse::<f64>().ok().map(|n| n * scale)
+}
+
+/// Parse a list of numbers separated by whitespace and/or commas.
+///
+/// Separators can be omitted where unambiguous, as in `10-5.5.5`.
+fn numbers(value: &str) -> Option<Vec<f64>> {
+    let bytes = value.as_bytes();
+    let mut numbers = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b',') {
+            i += 1;
+        }
+        if i == bytes.len() {
+            return Some(numbers);
+        }
+        let start = i;
+        if matches!(bytes[i], b'+' | b'-') {
+            i += 1;
+        }
+        let mut seen_point = false;
+        while i < bytes.len() && (bytes[i].is_ascii_digit() || (bytes[i] == b'.' && !seen_point)) {
+            seen_point |= bytes[i] == b'.';
+            i += 1;
+        }
+        if i < bytes.len() && matches!(bytes[i], b'e' | b'E') {
+            let mantissa_end = i;
+            i += 1;
+            if i < bytes.len() && matches!(bytes[i], b'+' | b'-') {
+                i += 1;
+            }
+            if i < bytes.len() && bytes[i].is_ascii_digit() {
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            } else {
+                i = mantissa_end;
+            }
+        }
+        numbers.push(value[start..i].parse().ok()?);
+    }
+}
+
+/// Parse the value of a `transform` attribute.
+fn parse_transform(value: &str) -> Option<Affine> {
+    let mut transform = Affine::IDENTITY;
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            return Some(transform);
+        }
+        let (name, after) = rest.split_once('(')?;
+        let (args, after) = after.split_once(')')?;
+        let args = numbers(args)?;
+        transform *= match (name.trim(), args.as_slice()) {
+            ("matrix", &[a, b, c, d, e, f]) => Affine::new([a, b, c, d, e, f]),
+            ("translate", &[x]) => Affine::translate((x, 0.0)),
+            ("translate", &[x, y]) => Affine::translate((x, y)),
+            ("scale", &[s]) => Affine::scale(s),
+            ("scale", &[x, y]) => Affine::scale_non_uniform(x, y),
+            ("rotate", &[angle]) => Affine::rotate(angle.to_radians()),
+            ("rotate", &[angle, x, y]) => Affine::rotate_about(angle.to_radians(), (x, y).into()),
+            ("skewX", &[angle]) => Affine::skew(angle.to_radians().tan(), 0.0),
+            ("skewY", &[angle]) => Affine::skew(0.0, angle.to_radians().tan()),
+            _ => return None,
+        };
+        rest = after;
+    }
+}
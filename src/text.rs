@@ -0,0 +1,176 @@
+// Copyright 2025 the Vello Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Drawing of text strings into a [`Scene`].
+//!
+//! Text is shaped and laid out using the cached pipeline in `advanced_text`, and
+//! then encoded as ordinary glyph runs.
+
+use std::cell::RefCell;
+
+use peniko::{BrushRef, Fill, kurbo::Affine};
+
+use crate::advanced_text::AdvancedTextRenderer;
+use crate::scene_core::Scene;
+
+thread_local! {
+    /// Creating a `FontSystem` loads the system font database, which is expensive,
+    /// so a single text renderer (and its shape cache) is kept per thread.
+    static TEXT_RENDERER: RefCell<AdvancedTextRenderer> =
+        RefCell::new(AdvancedTextRenderer::default());
+}
+
+/// Horizontal alignment of lines within a block of text.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TextAlign {
+    /// Align lines to the left edge.
+    #[default]
+    Left,
+    /// Center lines horizontally.
+    Center,
+    /// Align lines to the right edge.
+    Right,
+    /// Stretch the spacing between words so that wrapped lines fill the full width.
+    Justify,
+}
+
+/// Styling and layout properties used by [`Scene::draw_text`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextStyle {
+    /// Name of the font family to use.
+    ///
+    /// If `None`, or if no font with this name is installed, the system's
+    /// sans-serif font is used.
+    pub font_family: Option<String>,
+    /// Font size in pixels per em.
+    pub font_size: f32,
+    /// Font weight, where 400 is regular and 700 is bold.
+    pub font_weight: u16,
+    /// Width at which lines are wrapped.
+    ///
+    /// If `None`, lines are only broken at explicit line breaks.
+    pub max_width: Option<f32>,
+    /// Distance between consecutive baselines, as a multiple of the font size.
+    pub line_height: f32,
+    /// Horizontal alignment of each line.
+    ///
+    /// Alignment is relative to `max_width` if set, or to the longest line
+    /// in the paragraph otherwise.
+    pub align: TextAlign,
+}
+
+impl TextStyle {
+    /// Creates a style with the given font size and default values for all other properties.
+    pub fn new(font_size: f32) -> Self {
+        Self {
+            font_size,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            font_family: None,
+            font_size: 16.0,
+            font_weight: 400,
+            max_width: None,
+            line_height: 1.2,
+            align: TextAlign::Left,
+        }
+    }
+}
+
+impl Scene {
+    /// Shapes `text` with the given style and fills it with the specified brush.
+    ///
+    /// The top-left corner of the laid out text is placed at the origin of `transform`.
+    /// Shaped text is cached, so drawing the same string with the same style on every
+    /// frame only pays for shaping once.
+    ///
+    /// If the text cannot be shaped, a warning is logged and nothing is drawn.
+    #[expect(
+        single_use_lifetimes,
+        reason = "False positive: https://github.com/rust-lang/rust/issues/129255"
+    )]
+    pub fn draw_text<'b>(
+        &mut self,
+        text: &str,
+        style: &TextStyle,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+    ) {
+        if text.is_empty() {
+            return;
+        }
+        let brush = brush.into();
+        let runs = TEXT_RENDERER.with(|renderer| renderer.borrow_mut().layout_text(text, style));
+        match runs {
+            Ok(runs) => {
+                for run in runs {
+                    self.draw_glyphs(&run.font)
+                        .font_size(run.font_size)
+                        .transform(transform)
+                        .brush(brush)
+                        .draw(Fill::NonZero, run.glyphs.into_iter());
+                }
+            }
+            Err(err) => log::warn!("Failed to draw text: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::color::palette::css;
+
+    use super::*;
+
+    /// Draws `text` with the test renderer, which only has a single monospace font.
+    fn draw(text: &str, style: &TextStyle, transform: Affine) -> Scene {
+        TEXT_RENDERER.set(crate::advanced_text::tests::renderer());
+        let mut scene = Scene::new();
+        scene.draw_text(text, style, transform, css::BLACK);
+        scene
+    }
+
+    #[test]
+    fn empty_and_invalid_text_draws_nothing() {
+        for text in ["", "a\u{7}b"] {
+            let scene = draw(text, &TextStyle::new(10.0), Affine::IDENTITY);
+            assert!(scene.encoding().resources.glyph_runs.is_empty());
+        }
+    }
+
+    #[test]
+    fn text_is_drawn_as_a_glyph_run() {
+        let transform = Affine::translate((10.0, 20.0));
+        let scene = draw("ab", &TextStyle::new(10.0), transform);
+        let resources = &scene.encoding().resources;
+        let [run] = resources.glyph_runs.as_slice() else {
+            panic!("expected one glyph run");
+        };
+        assert_eq!(run.font_size, 10.0);
+        assert_eq!(run.transform.translation, [10.0, 20.0]);
+        let glyphs = &resources.glyphs[run.glyphs.clone()];
+        assert_eq!(glyphs.len(), 2);
+        assert!(glyphs[0].x < glyphs[1].x);
+        assert_eq!(glyphs[0].y, glyphs[1].y);
+    }
+
+    #[test]
+    fn wrapped_text_is_drawn_on_separate_lines() {
+        let style = TextStyle {
+            max_width: Some(40.0),
+            ..TextStyle::new(10.0)
+        };
+        let scene = draw("aaa bbb ccc", &style, Affine::IDENTITY);
+        let glyphs = &scene.encoding().resources.glyphs;
+        assert!(glyphs.iter().all(|glyph| glyph.x < 40.0));
+        let mut baselines: Vec<f32> = glyphs.iter().map(|glyph| glyph.y).collect();
+        baselines.dedup();
+        assert!(baselines.len() > 1);
+        assert!(baselines.is_sorted());
+    }
+}
@@ -3,15 +3,29 @@
 
 //! Simple helpers for managing wgpu state and surfaces.
 
+use std::collections::HashMap;
 use std::future::Future;
 
 use wgpu::{
-    Adapter, Device, Instance, Limits, Queue, Surface, SurfaceConfiguration, SurfaceTarget,
-    Texture, TextureFormat, TextureView, util::TextureBlitter,
+    Adapter, CommandEncoder, Device, Instance, Limits, Queue, Surface, SurfaceConfiguration,
+    SurfaceTarget, Texture, TextureFormat, TextureView, util::TextureBlitter,
 };
 
 use crate::{Error, Result};
 
+/// The surface formats which a [`RenderSurface`] can present to.
+///
+/// Scenes are always rendered to an [`Rgba8Unorm`](TextureFormat::Rgba8Unorm) texture, which
+/// is then blitted to the surface. For sRGB and floating point surfaces, the blit decodes the
+/// rendered colors, so that the surface shows the same colors as the others.
+pub const SUPPORTED_SURFACE_FORMATS: &[TextureFormat] = &[
+    TextureFormat::Rgba8Unorm,
+    TextureFormat::Bgra8Unorm,
+    TextureFormat::Rgba8UnormSrgb,
+    TextureFormat::Bgra8UnormSrgb,
+    TextureFormat::Rgba16Float,
+];
+
 /// Simple render context that maintains wgpu state for rendering the pipeline.
 pub struct RenderContext {
     pub instance: Instance,
@@ -77,10 +91,11 @@ impl RenderContext {
 
         let device_handle = &self.devices[dev_id];
         let capabilities = surface.get_capabilities(&device_handle.adapter);
+        // The capabilities list the surface's preferred format first.
         let format = capabilities
             .formats
             .into_iter()
-            .find(|it| matches!(it, TextureFormat::Rgba8Unorm | TextureFormat::Bgra8Unorm))
+            .find(|it| SUPPORTED_SURFACE_FORMATS.contains(it))
             .ok_or(Error::UnsupportedSurfaceFormat)?;
 
         let config = SurfaceConfiguration {
@@ -93,7 +108,8 @@ impl RenderContext {
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![],
         };
-        let (target_texture, target_view) = create_targets(width, height, &device_handle.device);
+        let (target_texture, target_view, target_view_srgb) =
+            create_targets(width, height, &device_handle.device);
         let surface = RenderSurface {
             surface,
             config,
@@ -101,7 +117,8 @@ impl RenderContext {
             format,
             target_texture,
             target_view,
-            blitter: TextureBlitter::new(&device_handle.device, format),
+            target_view_srgb,
+            blitters: HashMap::new(),
         };
         self.configure_surface(&surface);
         Ok(surface)
@@ -109,11 +126,13 @@ impl RenderContext {
 
     /// Resizes the surface to the new dimensions.
     pub fn resize_surface(&self, surface: &mut RenderSurface<'_>, width: u32, height: u32) {
-        let (texture, view) = create_targets(width, height, &self.devices[surface.dev_id].device);
+        let (texture, view, view_srgb) =
+            create_targets(width, height, &self.devices[surface.dev_id].device);
         // TODO: Use clever resize semantics to avoid thrashing the memory allocator during a resize
         // especially important on metal.
         surface.target_texture = texture;
         surface.target_view = view;
+        surface.target_view_srgb = view_srgb;
         surface.config.width = width;
         surface.config.height = height;
         self.configure_surface(surface);
@@ -128,6 +147,26 @@ impl RenderContext {
         self.configure_surface(surface);
     }
 
+    /// Changes the format of the surface.
+    ///
+    /// The format must be one of the [`SUPPORTED_SURFACE_FORMATS`], and supported by the
+    /// surface on its device.
+    pub fn set_surface_format(
+        &self,
+        surface: &mut RenderSurface<'_>,
+        format: TextureFormat,
+    ) -> Result<()> {
+        let adapter = &self.devices[surface.dev_id].adapter;
+        let capabilities = surface.surface.get_capabilities(adapter);
+        if !SUPPORTED_SURFACE_FORMATS.contains(&format) || !capabilities.formats.contains(&format) {
+            return Err(Error::UnsupportedSurfaceFormat);
+        }
+        surface.format = format;
+        surface.config.format = format;
+        self.configure_surface(surface);
+        Ok(())
+    }
+
     fn configure_surface(&self, surface: &RenderSurface<'_>) {
         let device = &self.devices[surface.dev_id].device;
         surface.surface.configure(device, &surface.config);
@@ -185,7 +224,10 @@ impl RenderContext {
 /// texture in most cases.
 ///
 /// Because of this, we need to create an "intermediate" texture which we render to, and then blit to the surface.
-fn create_targets(width: u32, height: u32, device: &Device) -> (Texture, TextureView) {
+///
+/// The texture can also be viewed as [`Rgba8UnormSrgb`](TextureFormat::Rgba8UnormSrgb), which
+/// is used to blit it to surfaces which don't store sRGB encoded colors directly.
+fn create_targets(width: u32, height: u32, device: &Device) -> (Texture, TextureView, TextureView) {
     let target_texture = device.create_texture(&wgpu::TextureDescriptor {
         label: None,
         size: wgpu::Extent3d {
@@ -198,10 +240,14 @@ fn create_targets(width: u32, height: u32, device: &Device) -> (Texture, Texture
         dimension: wgpu::TextureDimension::D2,
         usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
         format: TextureFormat::Rgba8Unorm,
-        view_formats: &[],
+        view_formats: &[TextureFormat::Rgba8UnormSrgb],
     });
     let target_view = target_texture.create_view(&wgpu::TextureViewDescriptor::default());
-    (target_texture, target_view)
+    let target_view_srgb = target_texture.create_view(&wgpu::TextureViewDescriptor {
+        format: Some(TextureFormat::Rgba8UnormSrgb),
+        ..Default::default()
+    });
+    (target_texture, target_view, target_view_srgb)
 }
 
 impl DeviceHandle {
@@ -217,9 +263,45 @@ pub struct RenderSurface<'s> {
     pub config: SurfaceConfiguration,
     pub dev_id: usize,
     pub format: TextureFormat,
+    /// The texture which scenes are rendered to, before being blitted to the surface.
     pub target_texture: Texture,
     pub target_view: TextureView,
-    pub blitter: TextureBlitter,
+    target_view_srgb: TextureView,
+    /// The blit pipelines for each format which has been blitted to, created on demand.
+    blitters: HashMap<TextureFormat, TextureBlitter>,
+}
+
+impl RenderSurface<'_> {
+    /// Returns the blitter from the target texture to textures of `format`, creating it if
+    /// needed.
+    pub fn blitter(&mut self, device: &Device, format: TextureFormat) -> &TextureBlitter {
+        self.blitters
+            .entry(format)
+            .or_insert_with(|| TextureBlitter::new(device, format))
+    }
+
+    /// Blits the target texture to `view`, which is usually a view of the current surface
+    /// texture.
+    ///
+    /// `view` must have the surface's [`format`](Self::format).
+    pub fn blit(&mut self, device: &Device, encoder: &mut CommandEncoder, view: &TextureView) {
+        let format = self.format;
+        // Sampling the sRGB view decodes the rendered colors to linear values, which is what
+        // sRGB and floating point targets store.
+        let decode = !matches!(
+            format,
+            TextureFormat::Rgba8Unorm | TextureFormat::Bgra8Unorm
+        );
+        self.blitters
+            .entry(format)
+            .or_insert_with(|| TextureBlitter::new(device, format));
+        let source = if decode {
+            &self.target_view_srgb
+        } else {
+            &self.target_view
+        };
+        self.blitters[&format].copy(device, encoder, source, view);
+    }
 }
 
 impl std::fmt::Debug for RenderSurface<'_> {
@@ -231,7 +313,8 @@ impl std::fmt::Debug for RenderSurface<'_> {
             .field("format", &self.format)
             .field("target_texture", &self.target_texture)
             .field("target_view", &self.target_view)
-            .field("blitter", &"(Not Debug)")
+            .field("target_view_srgb", &self.target_view_srgb)
+            .field("blitters", &self.blitters.keys().collect::<Vec<_>>())
             .finish()
     }
 }
@@ -263,3 +346,84 @@ pub fn block_on_wgpu<F: Future>(device: &Device, fut: F) -> F::Output {
         }
     }
 }
+
+/// Copy the contents of an [`Rgba8Unorm`](TextureFormat::Rgba8Unorm) texture to the CPU.
+///
+/// The texture must have been created with the [`wgpu::TextureUsages::COPY_SRC`] usage.
+/// The returned pixels are tightly packed, with four bytes per pixel.
+///
+/// This blocks until the GPU has finished all submitted work, so it is intended for
+/// tests and offline rendering.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_texture_rgba8(device: &Device, queue: &Queue, texture: &Texture) -> Result<Vec<u8>> {
+    read_texture(device, queue, texture, 4)
+}
+
+/// Copy the contents of an [`Rgba16Float`](TextureFormat::Rgba16Float) texture to the CPU.
+///
+/// The texture must have been created with the [`wgpu::TextureUsages::COPY_SRC`] usage.
+/// The returned pixels are tightly packed, with four floats per pixel.
+///
+/// This blocks until the GPU has finished all submitted work, so it is intended for
+/// tests and offline rendering.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_texture_rgba16float(
+    device: &Device,
+    queue: &Queue,
+    texture: &Texture,
+) -> Result<Vec<f32>> {
+    let pixels = read_texture(device, queue, texture, 8)?;
+    Ok(pixels
+        .chunks_exact(2)
+        .map(|half| crate::color_convert::f16_to_f32(u16::from_le_bytes([half[0], half[1]])))
+        .collect())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_texture(
+    device: &Device,
+    queue: &Queue,
+    texture: &Texture,
+    bytes_per_pixel: u32,
+) -> Result<Vec<u8>> {
+    let width = texture.width();
+    let height = texture.height();
+    let row_len = (width * bytes_per_pixel) as usize;
+    let bytes_per_row =
+        (width * bytes_per_pixel).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("vello.readback"),
+        size: u64::from(bytes_per_row) * u64::from(height),
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("vello.readback"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::TexelCopyBufferInfo {
+            buffer: &buffer,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: None,
+            },
+        },
+        texture.size(),
+    );
+    queue.submit([encoder.finish()]);
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+    slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+    block_on_wgpu(device, receiver.receive()).expect("channel was closed")?;
+    let mapped = slice.get_mapped_range();
+    let mut pixels = Vec::with_capacity(row_len * height as usize);
+    for row in mapped.chunks_exact(bytes_per_row as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+    drop(mapped);
+    buffer.unmap();
+    Ok(pixels)
+}
@@ -16,7 +16,7 @@ use wgpu::{
 use crate::{
     Error, Result,
     low_level::{BufferProxy, Command, ImageProxy, Recording, ResourceId, ResourceProxy, ShaderId},
-    recording::BindType,
+    recording::{BindType, ImageFormat},
 };
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -121,6 +121,22 @@ struct BufferProperties {
 #[derive(Default)]
 struct ResourcePool {
     bufs: HashMap<BufferProperties, Vec<Buffer>>,
+    /// The resources created and data uploaded since the counts were last taken.
+    stats: RenderStats,
+}
+
+/// Counts of the GPU resources created and the data uploaded by a render, for profiling.
+///
+/// Buffers are reused between renders when they are freed, so a renderer which has
+/// warmed up creates few of them. See [`Renderer::render_stats`](crate::Renderer::render_stats).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /// The number of buffers created, rather than reused from earlier renders.
+    pub buffers_created: u32,
+    /// The number of textures created.
+    pub textures_created: u32,
+    /// The number of bytes written to buffers and textures from the CPU.
+    pub upload_bytes: u64,
 }
 
 /// The transient bind map contains short-lifetime resources.
@@ -411,6 +427,7 @@ impl WgpuEngine {
                     // TODO: if buffer is newly created, might be better to make it mapped at creation
                     // and copy. However, we expect reuse will be most common.
                     queue.write_buffer(&buf, 0, bytes);
+                    self.pool.stats.upload_bytes += bytes.len() as u64;
                     self.bind_map.insert_buf(buf_proxy, buf);
                 }
                 Command::UploadUniform(buf_proxy, bytes) => {
@@ -423,6 +440,7 @@ impl WgpuEngine {
                         .pool
                         .get_buf(buf_proxy.size, buf_proxy.name, usage, device);
                     queue.write_buffer(&buf, 0, bytes);
+                    self.pool.stats.upload_bytes += bytes.len() as u64;
                     self.bind_map.insert_buf(buf_proxy, buf);
                 }
                 Command::UploadImage(image_proxy, bytes) => {
@@ -474,11 +492,15 @@ impl WgpuEngine {
                             depth_or_array_layers: 1,
                         },
                     );
+                    self.pool.stats.textures_created += 1;
+                    self.pool.stats.upload_bytes += bytes.len() as u64;
                     self.bind_map
                         .insert_image(image_proxy.id, texture, texture_view);
                 }
                 Command::WriteImage(proxy, [x, y], image) => {
-                    let (texture, _) = self.bind_map.get_or_create_image(*proxy, device);
+                    let (texture, _) =
+                        self.bind_map
+                            .get_or_create_image(*proxy, device, &mut self.pool.stats);
                     let format = proxy.format.to_wgpu();
                     let block_size = format
                         .block_copy_size(None)
@@ -523,8 +545,64 @@ impl WgpuEngine {
                                 depth_or_array_layers: 1,
                             },
                         );
+                        self.pool.stats.upload_bytes += image.data.data().len() as u64;
                     }
                 }
+                Command::CopyImage(src, dst, [x, y]) => {
+                    let src_texture = self
+                        .bind_map
+                        .image_map
+                        .get(&src.id)
+                        .map(|(texture, _)| texture.clone())
+                        .ok_or(Error::UnavailableImageUsed("copy_image"))?;
+                    let (texture, _) =
+                        self.bind_map
+                            .get_or_create_image(*dst, device, &mut self.pool.stats);
+                    encoder.copy_texture_to_texture(
+                        src_texture.as_image_copy(),
+                        wgpu::TexelCopyTextureInfo {
+                            texture,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d { x: *x, y: *y, z: 0 },
+                            aspect: TextureAspect::All,
+                        },
+                        src_texture.size(),
+                    );
+                }
+                Command::CopyImageRegion(src, [src_x, src_y, width, height], dst, [x, y]) => {
+                    let src_texture = self
+                        .bind_map
+                        .image_map
+                        .get(&src.id)
+                        .map(|(texture, _)| texture.clone())
+                        .ok_or(Error::UnavailableImageUsed("copy_image_region"))?;
+                    let (texture, _) =
+                        self.bind_map
+                            .get_or_create_image(*dst, device, &mut self.pool.stats);
+                    encoder.copy_texture_to_texture(
+                        wgpu::TexelCopyTextureInfo {
+                            texture: &src_texture,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d {
+                                x: *src_x,
+                                y: *src_y,
+                                z: 0,
+                            },
+                            aspect: TextureAspect::All,
+                        },
+                        wgpu::TexelCopyTextureInfo {
+                            texture,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d { x: *x, y: *y, z: 0 },
+                            aspect: TextureAspect::All,
+                        },
+                        wgpu::Extent3d {
+                            width: *width,
+                            height: *height,
+                            depth_or_array_layers: 1,
+                        },
+                    );
+                }
                 Command::Dispatch(shader_id, wg_size, bindings) => {
                     let (x, y, z) = *wg_size;
                     // println!("dispatching {:?} with {} bindings", wg_size, bindings.len());
@@ -767,6 +845,12 @@ impl WgpuEngine {
         Ok(())
     }
 
+    /// Returns the counts of the resources created and data uploaded since they were last
+    /// taken, and resets them.
+    pub fn take_stats(&mut self) -> RenderStats {
+        std::mem::take(&mut self.pool.stats)
+    }
+
     pub fn get_download(&self, buf: BufferProxy) -> Option<&Buffer> {
         self.downloads.get(&buf.id)
     }
@@ -809,7 +893,10 @@ impl WgpuEngine {
                         visibility,
                         ty: if bind_type == BindType::ImageRead(format) {
                             wgpu::BindingType::Texture {
-                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                sample_type: match format {
+                                    ImageFormat::R32Uint => wgpu::TextureSampleType::Uint,
+                                    _ => wgpu::TextureSampleType::Float { filterable: true },
+                                },
                                 view_dimension: TextureViewDimension::D2,
                                 multisampled: false,
                             }
@@ -924,10 +1011,12 @@ impl BindMap {
         &mut self,
         proxy: ImageProxy,
         device: &Device,
+        stats: &mut RenderStats,
     ) -> &(Texture, TextureView) {
         match self.image_map.entry(proxy.id) {
             Entry::Occupied(occupied) => occupied.into_mut(),
             Entry::Vacant(vacant) => {
+                stats.textures_created += 1;
                 let format = proxy.format.to_wgpu();
                 let texture = device.create_texture(&wgpu::TextureDescriptor {
                     label: None,
@@ -939,7 +1028,10 @@ impl BindMap {
                     mip_level_count: 1,
                     sample_count: 1,
                     dimension: wgpu::TextureDimension::D2,
-                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    // Pages of the image cache are copied into the image atlas.
+                    usage: TextureUsages::TEXTURE_BINDING
+                        | TextureUsages::COPY_DST
+                        | TextureUsages::COPY_SRC,
                     format,
                     view_formats: &[],
                 });
@@ -982,6 +1074,7 @@ impl ResourcePool {
                 return buf;
             }
         }
+        self.stats.buffers_created += 1;
         device.create_buffer(&wgpu::BufferDescriptor {
             label: Some(name),
             size: rounded_size,
@@ -1022,6 +1115,7 @@ impl BindMapBuffer {
                 | BufferUsages::INDIRECT;
             let buf = pool.get_buf(proxy.size, proxy.name, usage, device);
             queue.write_buffer(&buf, 0, &cpu_buf.borrow());
+            pool.stats.upload_bytes += proxy.size;
             self.buffer = MaterializedBuffer::Gpu(buf);
         }
     }
@@ -1118,6 +1212,15 @@ impl<'a> TransientBindMap<'a> {
                     }
                     if let Entry::Vacant(v) = bind_map.image_map.entry(proxy.id) {
                         let format = proxy.format.to_wgpu();
+                        // Transient images may be the output of a shader, such as the
+                        // tiles of a tiled render.
+                        let storage = match proxy.format {
+                            ImageFormat::Rgba8
+                            | ImageFormat::Rgba16Float
+                            | ImageFormat::R32Uint => TextureUsages::STORAGE_BINDING,
+                            ImageFormat::Bgra8 => TextureUsages::empty(),
+                        };
+                        pool.stats.textures_created += 1;
                         let texture = device.create_texture(&wgpu::TextureDescriptor {
                             label: None,
                             size: wgpu::Extent3d {
@@ -1128,7 +1231,10 @@ impl<'a> TransientBindMap<'a> {
                             mip_level_count: 1,
                             sample_count: 1,
                             dimension: wgpu::TextureDimension::D2,
-                            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                            usage: TextureUsages::TEXTURE_BINDING
+                                | TextureUsages::COPY_SRC
+                                | TextureUsages::COPY_DST
+                                | storage,
                             format,
                             view_formats: &[],
                         });